@@ -0,0 +1,64 @@
+-- Schema as the last release before migrations created it with --init-db.
+-- Databases from older releases lack the jobs columns after retry_count
+-- (priority .. traceparent) and the job_logs / job_credentials tables;
+-- db::upgrade_legacy_schema adds those columns before this runs, and
+-- IF NOT EXISTS lets the rest apply on top of any such database.
+
+-- Timestamps are TIMESTAMPTZ; queries render them through ts_text() so the
+-- API keeps returning the same 'YYYY-MM-DD HH:MM:SS' (UTC) strings as SQLite.
+CREATE OR REPLACE FUNCTION ts_text(t TIMESTAMPTZ) RETURNS TEXT AS $$
+    SELECT to_char(t AT TIME ZONE 'UTC', 'YYYY-MM-DD HH24:MI:SS')
+$$ LANGUAGE sql STABLE;
+
+CREATE TABLE IF NOT EXISTS jobs (
+    id           TEXT PRIMARY KEY,
+    image        TEXT NOT NULL,
+    status       TEXT NOT NULL DEFAULT 'queued',
+    result       TEXT,
+    error_detail TEXT,
+    retry_count  BIGINT NOT NULL DEFAULT 0,
+    priority     INTEGER NOT NULL DEFAULT 0,
+    dry_run      BOOLEAN NOT NULL DEFAULT FALSE,
+    pre_remove   BOOLEAN,
+    post_remove  BOOLEAN,
+    expected_digest TEXT,
+    require_digest_match BOOLEAN NOT NULL DEFAULT FALSE,
+    callback_url TEXT,
+    max_attempts BIGINT NOT NULL DEFAULT 3,
+    created_at   TIMESTAMPTZ NOT NULL DEFAULT now(),
+    finished_at  TIMESTAMPTZ,
+    next_attempt_at TIMESTAMPTZ,
+    lease_expires_at TIMESTAMPTZ,
+    request_id   TEXT,
+    traceparent  TEXT
+);
+
+CREATE TABLE IF NOT EXISTS metrics (
+    id           BIGSERIAL PRIMARY KEY,
+    job_id       TEXT NOT NULL REFERENCES jobs(id) ON DELETE CASCADE,
+    key          TEXT NOT NULL,
+    value        DOUBLE PRECISION NOT NULL,
+    unit         TEXT,
+    labels_json  TEXT,
+    created_at   TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+-- Pull logs (full docker create_image output, one row per attempt)
+CREATE TABLE IF NOT EXISTS job_logs (
+    id           BIGSERIAL PRIMARY KEY,
+    job_id       TEXT NOT NULL REFERENCES jobs(id) ON DELETE CASCADE,
+    log_text     TEXT NOT NULL,
+    created_at   TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS idx_metrics_job_created ON metrics(job_id, created_at DESC);
+CREATE INDEX IF NOT EXISTS idx_job_logs_job ON job_logs(job_id);
+CREATE INDEX IF NOT EXISTS idx_jobs_claim ON jobs(status, priority DESC, created_at);
+
+-- Per-job registry credentials. password_b64 is only base64-encoded, NOT
+-- encrypted: anyone who can read the database can read the passwords.
+CREATE TABLE IF NOT EXISTS job_credentials (
+    job_id       TEXT PRIMARY KEY REFERENCES jobs(id) ON DELETE CASCADE,
+    username     TEXT NOT NULL,
+    password_b64 TEXT NOT NULL
+);
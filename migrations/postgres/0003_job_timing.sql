@@ -0,0 +1,6 @@
+-- When the current (or last) attempt was claimed, the worker's last lease
+-- renewal, and the last write to the row. Jobs from before this migration
+-- have none of them; reads fall back to created_at for updated_at.
+ALTER TABLE jobs ADD COLUMN IF NOT EXISTS started_at TIMESTAMPTZ;
+ALTER TABLE jobs ADD COLUMN IF NOT EXISTS last_heartbeat TIMESTAMPTZ;
+ALTER TABLE jobs ADD COLUMN IF NOT EXISTS updated_at TIMESTAMPTZ;
@@ -0,0 +1,2 @@
+-- Caller-supplied key/value strings (`metadata` on POST /jobs), as a JSON object.
+ALTER TABLE jobs ADD COLUMN IF NOT EXISTS metadata_json TEXT;
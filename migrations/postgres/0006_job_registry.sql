@@ -0,0 +1,15 @@
+-- Registry host of `image` (`worker::parse_registry`), so the claim can pass
+-- over registries whose pull slots are all taken. Filled in for jobs still
+-- waiting to be claimed; a NULL registry is never passed over.
+ALTER TABLE jobs ADD COLUMN IF NOT EXISTS registry TEXT;
+
+UPDATE jobs
+   SET registry = CASE
+           WHEN position('/' in image) > 0
+                AND (split_part(image, '/', 1) LIKE '%.%'
+                     OR split_part(image, '/', 1) LIKE '%:%'
+                     OR split_part(image, '/', 1) = 'localhost')
+           THEN split_part(image, '/', 1)
+           ELSE 'docker.io'
+       END
+ WHERE status = 'queued' AND registry IS NULL;
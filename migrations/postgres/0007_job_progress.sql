@@ -0,0 +1,2 @@
+-- Rough download progress (0-100) of the running pull; NULL while unknown.
+ALTER TABLE jobs ADD COLUMN IF NOT EXISTS progress_pct INTEGER;
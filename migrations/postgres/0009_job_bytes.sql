@@ -0,0 +1,3 @@
+-- Bytes the completed pull downloaded; NULL for dry runs, skipped pulls and
+-- jobs completed before this migration.
+ALTER TABLE jobs ADD COLUMN IF NOT EXISTS bytes_downloaded BIGINT;
@@ -0,0 +1,58 @@
+-- Schema as the last release before migrations created it with --init-db.
+-- Databases from older releases lack the jobs columns after retry_count
+-- (priority .. traceparent) and the job_logs / job_credentials tables;
+-- db::upgrade_legacy_schema adds those columns before this runs, and
+-- IF NOT EXISTS lets the rest apply on top of any such database.
+
+CREATE TABLE IF NOT EXISTS jobs (
+    id           TEXT PRIMARY KEY,
+    image        TEXT NOT NULL,
+    status       TEXT NOT NULL DEFAULT 'queued',
+    result       TEXT,
+    error_detail TEXT,
+    retry_count  INTEGER NOT NULL DEFAULT 0,
+    priority     INTEGER NOT NULL DEFAULT 0,
+    dry_run      INTEGER NOT NULL DEFAULT 0,
+    pre_remove   INTEGER,
+    post_remove  INTEGER,
+    expected_digest TEXT,
+    require_digest_match INTEGER NOT NULL DEFAULT 0,
+    callback_url TEXT,
+    max_attempts INTEGER NOT NULL DEFAULT 3,
+    created_at   TEXT NOT NULL DEFAULT (datetime('now')),
+    finished_at  TEXT,
+    next_attempt_at TEXT,
+    lease_expires_at TEXT,
+    request_id   TEXT,
+    traceparent  TEXT
+);
+
+CREATE TABLE IF NOT EXISTS metrics (
+    id           INTEGER PRIMARY KEY AUTOINCREMENT,
+    job_id       TEXT NOT NULL REFERENCES jobs(id) ON DELETE CASCADE,
+    key          TEXT NOT NULL,
+    value        REAL NOT NULL,
+    unit         TEXT,
+    labels_json  TEXT,
+    created_at   TEXT NOT NULL DEFAULT (datetime('now'))
+);
+
+-- Pull logs (full docker create_image output, one row per attempt)
+CREATE TABLE IF NOT EXISTS job_logs (
+    id           INTEGER PRIMARY KEY AUTOINCREMENT,
+    job_id       TEXT NOT NULL REFERENCES jobs(id) ON DELETE CASCADE,
+    log_text     TEXT NOT NULL,
+    created_at   TEXT NOT NULL DEFAULT (datetime('now'))
+);
+
+CREATE INDEX IF NOT EXISTS idx_metrics_job_created ON metrics(job_id, created_at DESC);
+CREATE INDEX IF NOT EXISTS idx_job_logs_job ON job_logs(job_id);
+CREATE INDEX IF NOT EXISTS idx_jobs_claim ON jobs(status, priority DESC, created_at);
+
+-- Per-job registry credentials. password_b64 is only base64-encoded, NOT
+-- encrypted: anyone who can read the database can read the passwords.
+CREATE TABLE IF NOT EXISTS job_credentials (
+    job_id       TEXT PRIMARY KEY REFERENCES jobs(id) ON DELETE CASCADE,
+    username     TEXT NOT NULL,
+    password_b64 TEXT NOT NULL
+);
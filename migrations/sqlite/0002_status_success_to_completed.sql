@@ -0,0 +1,3 @@
+-- Some older releases wrote 'success' for finished pulls; the status set is
+-- queued, running, completed, failed, canceled.
+UPDATE jobs SET status = 'completed' WHERE status = 'success';
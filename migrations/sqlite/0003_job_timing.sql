@@ -0,0 +1,6 @@
+-- When the current (or last) attempt was claimed, the worker's last lease
+-- renewal, and the last write to the row. Jobs from before this migration
+-- have none of them; reads fall back to created_at for updated_at.
+ALTER TABLE jobs ADD COLUMN started_at TEXT;
+ALTER TABLE jobs ADD COLUMN last_heartbeat TEXT;
+ALTER TABLE jobs ADD COLUMN updated_at TEXT;
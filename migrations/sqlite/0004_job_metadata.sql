@@ -0,0 +1,2 @@
+-- Caller-supplied key/value strings (`metadata` on POST /jobs), as a JSON object.
+ALTER TABLE jobs ADD COLUMN metadata_json TEXT;
@@ -0,0 +1,3 @@
+-- `skip_if_pulled_within_secs` of POST /jobs: complete without pulling when
+-- the same image was pulled successfully at most this long ago.
+ALTER TABLE jobs ADD COLUMN skip_if_pulled_within_secs INTEGER;
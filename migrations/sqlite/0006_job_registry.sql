@@ -0,0 +1,15 @@
+-- Registry host of `image` (`worker::parse_registry`), so the claim can pass
+-- over registries whose pull slots are all taken. Filled in for jobs still
+-- waiting to be claimed; a NULL registry is never passed over.
+ALTER TABLE jobs ADD COLUMN registry TEXT;
+
+UPDATE jobs
+   SET registry = CASE
+           WHEN instr(image, '/') > 0
+                AND (instr(substr(image, 1, instr(image, '/') - 1), '.') > 0
+                     OR instr(substr(image, 1, instr(image, '/') - 1), ':') > 0
+                     OR substr(image, 1, instr(image, '/') - 1) = 'localhost')
+           THEN substr(image, 1, instr(image, '/') - 1)
+           ELSE 'docker.io'
+       END
+ WHERE status = 'queued';
@@ -0,0 +1,10 @@
+-- Queue depth sampled once a minute by the worker, for GET /stats/queue-history.
+-- Purged with the jobs after RETENTION_DAYS.
+CREATE TABLE IF NOT EXISTS queue_samples (
+    id           INTEGER PRIMARY KEY AUTOINCREMENT,
+    sampled_at   TEXT NOT NULL DEFAULT (datetime('now')),
+    queued       INTEGER NOT NULL,
+    running      INTEGER NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_queue_samples_sampled_at ON queue_samples(sampled_at);
@@ -0,0 +1,78 @@
+//! JSON access log lines on the `access_log` target, used instead of actix's
+//! text `Logger` when `LOG_FORMAT=json`:
+//!
+//! `{"method":"GET","path":"/api/v1/jobs","status":200,"latency_ms":1.52,"request_id":"..","bytes":312}`
+//!
+//! `bytes` is null for streamed bodies (SSE) whose size isn't known up front.
+
+use std::str::FromStr;
+use std::time::Instant;
+
+use actix_web::body::{BodySize, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use log::info;
+use serde::Serialize;
+
+use crate::request_id;
+
+pub const TARGET: &str = "access_log";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            other => Err(format!("unknown log format '{}' (text or json)", other)),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct AccessLine<'a> {
+    method: &'a str,
+    path: &'a str,
+    status: u16,
+    latency_ms: f64,
+    request_id: Option<&'a str>,
+    bytes: Option<u64>,
+}
+
+/// Middleware: one JSON line per request once the response is ready.
+pub async fn log_json(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let started = Instant::now();
+    let method = req.method().to_string();
+    let path = req.path().to_string();
+
+    let res = next.call(req).await?;
+
+    let bytes = match res.response().body().size() {
+        BodySize::Sized(n) => Some(n),
+        BodySize::None => Some(0),
+        BodySize::Stream => None,
+    };
+    let line = AccessLine {
+        method: &method,
+        path: &path,
+        status: res.status().as_u16(),
+        latency_ms: (started.elapsed().as_secs_f64() * 1000.0 * 100.0).round() / 100.0,
+        request_id: res.headers().get(&request_id::HEADER).and_then(|v| v.to_str().ok()),
+        bytes,
+    };
+    if let Ok(json) = serde_json::to_string(&line) {
+        info!(target: TARGET, "{}", json);
+    }
+    Ok(res)
+}
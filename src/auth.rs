@@ -0,0 +1,137 @@
+//! Bearer token check for the `/api/v1` scope.
+//!
+//! Keys come from `API_KEYS` (comma-separated). With no keys configured the
+//! check is off, so existing deployments keep working until they set some.
+
+use std::collections::HashSet;
+use std::fmt;
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::AUTHORIZATION;
+use actix_web::middleware::Next;
+use actix_web::web;
+
+use crate::error::AppError;
+use crate::AppState;
+
+/// Accepted API keys. `Debug` only prints how many there are, so the
+/// startup config log never contains them.
+#[derive(Clone, Default, PartialEq)]
+pub struct ApiKeys(HashSet<String>);
+
+impl ApiKeys {
+    /// Parse `a,b, c`; blank entries are ignored.
+    pub fn parse(raw: &str) -> Self {
+        Self(
+            raw.split(',')
+                .map(str::trim)
+                .filter(|k| !k.is_empty())
+                .map(str::to_string)
+                .collect(),
+        )
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn contains(&self, token: &str) -> bool {
+        // compare against every key so timing doesn't reveal a partial match
+        self.0
+            .iter()
+            .fold(false, |found, key| constant_time_eq(key.as_bytes(), token.as_bytes()) | found)
+    }
+}
+
+impl fmt::Debug for ApiKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ApiKeys({} configured)", self.0.len())
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Middleware: reject the request with 401 unless it carries
+/// `Authorization: Bearer <one of API_KEYS>`.
+pub async fn require_api_key(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let keys = match req.app_data::<web::Data<AppState>>() {
+        Some(state) => state.config().api_keys.clone(),
+        None => return Ok(req.error_response(AppError::internal("app state missing")).map_into_right_body()),
+    };
+    if keys.is_empty() {
+        return next.call(req).await.map(ServiceResponse::map_into_left_body);
+    }
+
+    let token = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(str::trim);
+
+    let rejection = match token {
+        None => AppError::unauthorized("missing bearer token"),
+        Some(t) if !keys.contains(t) => AppError::unauthorized("invalid API key"),
+        Some(_) => return next.call(req).await.map(ServiceResponse::map_into_left_body),
+    };
+    // answered here rather than returned as Err so outer middleware still sees a response
+    Ok(req.error_response(rejection).map_into_right_body())
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::StatusCode;
+    use actix_web::test::{self, TestRequest};
+
+    use crate::test_support::{self, pool};
+
+    #[actix_web::test]
+    async fn api_needs_one_of_the_keys() {
+        let state = test_support::state(&[("API_KEYS", "k1, k2")]);
+        let app = test::init_service(test_support::app(state, pool().await)).await;
+        let status = async |auth: Option<&str>| {
+            let mut req = TestRequest::get().uri("/api/v1/health");
+            if let Some(auth) = auth {
+                req = req.insert_header(("Authorization", auth));
+            }
+            test::call_service(&app, req.to_request()).await.status()
+        };
+
+        assert_eq!(status(None).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(status(Some("Bearer nope")).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(status(Some("k1")).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(status(Some("Bearer k1")).await, StatusCode::OK);
+        assert_eq!(status(Some("Bearer k2")).await, StatusCode::OK);
+
+        let res = test::call_service(&app, TestRequest::get().uri("/health").to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn unauthorized_is_an_error_response() {
+        let state = test_support::state(&[("API_KEYS", "k1")]);
+        let app = test::init_service(test_support::app(state, pool().await)).await;
+        let req = TestRequest::get().uri("/api/v1/jobs").insert_header(("Authorization", "Bearer k2"));
+        let res = test::call_service(&app, req.to_request()).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        let body: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(body["status_code"], 401);
+        assert_eq!(body["path"], "/api/v1/jobs");
+    }
+
+    #[actix_web::test]
+    async fn no_keys_leaves_the_api_open() {
+        let app = test::init_service(test_support::app(test_support::state(&[]), pool().await)).await;
+        let res = test::call_service(&app, TestRequest::get().uri("/api/v1/health").to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,182 @@
+//! Pulling into containerd (or any CRI runtime) by shelling out to `crictl`.
+//!
+//! `crictl` finds the runtime through `CONTAINER_RUNTIME_ENDPOINT` or
+//! `/etc/crictl.yaml`, same as on the command line. It reports no per-layer
+//! progress, so layer, byte and speed metrics stay at 0 with this backend;
+//! timing, size, digest and cache hits are recorded as usual.
+
+use std::process::{Output, Stdio};
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bollard::auth::DockerCredentials;
+use log::{debug, warn};
+use tokio::process::Command;
+
+use super::{ManifestInfo, PullBackend, PullCanceled, PullMetrics, PullRequest};
+use crate::config::AppConfig;
+
+/// How often a running `crictl pull` checks the cancel flag
+const CANCEL_POLL: Duration = Duration::from_millis(200);
+
+pub struct ContainerdBackend {
+    crictl: String,
+}
+
+impl ContainerdBackend {
+    pub fn new(cfg: &AppConfig) -> Self {
+        Self { crictl: cfg.crictl_path.clone() }
+    }
+
+    fn command(&self) -> Command {
+        let mut cmd = Command::new(&self.crictl);
+        cmd.stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+        cmd
+    }
+
+    /// `crictl inspecti -o json`, `None` if the image isn't there.
+    async fn inspect(&self, image_ref: &str) -> Option<serde_json::Value> {
+        let out = self.command().args(["inspecti", "-o", "json", image_ref]).output().await.ok()?;
+        if !out.status.success() {
+            return None;
+        }
+        serde_json::from_slice::<serde_json::Value>(&out.stdout).ok()?.get("status").cloned()
+    }
+
+    async fn rmi(&self, image_ref: &str) {
+        match self.command().args(["rmi", image_ref]).output().await {
+            Ok(out) if out.status.success() => {}
+            // usually just "not found"
+            Ok(out) => debug!("crictl rmi {}: {}", image_ref, stderr_text(&out)),
+            Err(e) => warn!("crictl rmi {}: {}", image_ref, e),
+        }
+    }
+}
+
+fn stderr_text(out: &Output) -> String {
+    String::from_utf8_lossy(&out.stderr).trim().to_string()
+}
+
+/// `user:pass` for `--creds`. Identity tokens have no `crictl` equivalent.
+fn creds_arg(credentials: &DockerCredentials) -> Option<String> {
+    match (&credentials.username, &credentials.password) {
+        (Some(u), Some(p)) => Some(format!("{}:{}", u, p)),
+        _ => credentials
+            .auth
+            .as_deref()
+            .and_then(|a| STANDARD.decode(a).ok())
+            .and_then(|raw| String::from_utf8(raw).ok()),
+    }
+}
+
+/// CRI encodes the size as a string (uint64 in protojson), accept a number too.
+fn size_of(status: &serde_json::Value) -> u64 {
+    match status.get("size") {
+        Some(serde_json::Value::String(s)) => s.parse().unwrap_or(0),
+        Some(v) => v.as_u64().unwrap_or(0),
+        None => 0,
+    }
+}
+
+/// Digest part of the first `repoDigests` entry (`docker.io/library/alpine@sha256:..`)
+fn digest_of(status: &serde_json::Value) -> Option<String> {
+    status
+        .get("repoDigests")?
+        .as_array()?
+        .iter()
+        .find_map(|d| d.as_str()?.split_once('@').map(|(_, digest)| digest.to_string()))
+}
+
+impl PullBackend for ContainerdBackend {
+    fn name(&self) -> &'static str {
+        "containerd"
+    }
+
+    fn supports_dry_run(&self) -> bool {
+        false
+    }
+
+    async fn ping(&self) -> Result<(), String> {
+        let out = self
+            .command()
+            .arg("version")
+            .output()
+            .await
+            .map_err(|e| format!("cannot run {}: {}", self.crictl, e))?;
+        if out.status.success() {
+            Ok(())
+        } else {
+            Err(stderr_text(&out))
+        }
+    }
+
+    async fn pull(&self, req: &PullRequest<'_>, metrics: &mut PullMetrics) -> anyhow::Result<()> {
+        let present_before = self.inspect(req.name).await.is_some();
+
+        let mut cmd = self.command();
+        cmd.arg("pull");
+        if let Some(c) = req.credentials.as_ref() {
+            match creds_arg(c) {
+                Some(creds) => {
+                    cmd.args(["--creds", &creds]);
+                }
+                None => warn!("job {}: crictl can't use identity tokens, pulling anonymously", req.job_id),
+            }
+        }
+        cmd.arg(req.name);
+
+        let child = cmd
+            .spawn()
+            .map_err(|e| anyhow::anyhow!("cannot run {}: {}", self.crictl, e))?;
+        // polled alongside the cancel flag; dropping it kills crictl
+        let output = child.wait_with_output();
+        tokio::pin!(output);
+        let out = loop {
+            tokio::select! {
+                out = &mut output => break out?,
+                _ = tokio::time::sleep(CANCEL_POLL) => {
+                    if req.cancel.load(Ordering::SeqCst) {
+                        return Err(PullCanceled.into());
+                    }
+                }
+            }
+        };
+
+        metrics.logs.push_str(&String::from_utf8_lossy(&out.stdout));
+        metrics.logs.push_str(&String::from_utf8_lossy(&out.stderr));
+        if !out.status.success() {
+            anyhow::bail!("crictl pull {} failed: {}", req.name, stderr_text(&out));
+        }
+
+        if let Some(status) = self.inspect(req.name).await {
+            metrics.reported_size_bytes = size_of(&status);
+            metrics.digest = digest_of(&status);
+        }
+        metrics.cache_hit = present_before;
+        Ok(())
+    }
+
+    async fn remove(&self, image_ref: &str, thorough: bool) {
+        // removing by id drops every tag and digest the image has
+        let id = match thorough {
+            true => self
+                .inspect(image_ref)
+                .await
+                .and_then(|s| s.get("id").and_then(|v| v.as_str()).map(str::to_string)),
+            false => None,
+        };
+        self.rmi(id.as_deref().unwrap_or(image_ref)).await;
+    }
+
+    async fn inspect_manifest(
+        &self,
+        _name: &str,
+        _credentials: Option<DockerCredentials>,
+    ) -> anyhow::Result<ManifestInfo> {
+        anyhow::bail!("dry_run is not supported by the containerd backend")
+    }
+}
@@ -0,0 +1,250 @@
+//! Pulling through the Docker daemon named by `DOCKER_HOST`.
+//!
+//! Supported: `unix:///path/to/docker.sock`, `tcp://host:2375` / `http://host:2375`
+//! (plain HTTP), and `https://host:2376` or `tcp://` with `DOCKER_TLS_VERIFY`
+//! set (TLS with `key.pem` / `cert.pem` / `ca.pem` from `DOCKER_CERT_PATH`,
+//! default `~/.docker`). Unset means the default local socket.
+//!
+//! One client is shared by all pulls; bollard pools connections inside it.
+
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+
+use bollard::auth::DockerCredentials;
+use bollard::query_parameters::{CreateImageOptions, RemoveImageOptions};
+use bollard::{Docker, API_DEFAULT_VERSION};
+use futures_util::TryStreamExt;
+use log::{info, warn};
+
+use super::{ManifestInfo, PullBackend, PullCanceled, PullMetrics, PullRequest};
+use crate::config::AppConfig;
+
+/// Per-request read/write timeout, same as bollard's defaults.
+const TIMEOUT_SECS: u64 = 120;
+
+pub const SCHEMES: &[&str] = &["unix://", "tcp://", "http://", "https://"];
+
+/// Daemon address and TLS settings from the config
+#[derive(Debug, Clone)]
+struct Target {
+    host: Option<String>,
+    tls_verify: bool,
+    cert_path: Option<String>,
+}
+
+pub struct DockerBackend {
+    target: Target,
+    /// Connected lazily and dropped after a transport error so the next pull reconnects
+    client: Mutex<Option<Docker>>,
+}
+
+impl DockerBackend {
+    /// Connects right away; if the daemon isn't reachable yet the first pull retries.
+    pub fn new(cfg: &AppConfig) -> Self {
+        let target = Target {
+            host: cfg.docker_host.clone(),
+            tls_verify: cfg.docker_tls_verify,
+            cert_path: cfg.docker_cert_path.clone(),
+        };
+        let client = match connect(&target) {
+            Ok(d) => Some(d),
+            Err(e) => {
+                warn!("cannot connect to the Docker daemon yet, will retry on the first pull: {e}");
+                None
+            }
+        };
+        Self { target, client: Mutex::new(client) }
+    }
+
+    fn client(&self) -> Result<Docker, bollard::errors::Error> {
+        let mut client = self.client.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(docker) = client.as_ref() {
+            return Ok(docker.clone());
+        }
+        let docker = connect(&self.target)?;
+        *client = Some(docker.clone());
+        Ok(docker)
+    }
+
+    fn reset(&self) {
+        *self.client.lock().unwrap_or_else(|e| e.into_inner()) = None;
+    }
+
+    /// Drop the client if `err` says the daemon connection itself is broken.
+    fn reset_on_transport_error(&self, err: &anyhow::Error) {
+        if err.downcast_ref::<bollard::errors::Error>().is_some_and(is_transport_error) {
+            info!("docker: transport error, reconnecting on next use");
+            self.reset();
+        }
+    }
+}
+
+fn connect(target: &Target) -> Result<Docker, bollard::errors::Error> {
+    let Some(host) = target.host.as_deref() else {
+        return Docker::connect_with_unix_defaults();
+    };
+    if host.starts_with("unix://") {
+        return Docker::connect_with_socket(host, TIMEOUT_SECS, API_DEFAULT_VERSION);
+    }
+    if host.starts_with("https://") || target.tls_verify {
+        let certs = cert_dir(target);
+        return Docker::connect_with_ssl(
+            host,
+            &certs.join("key.pem"),
+            &certs.join("cert.pem"),
+            &certs.join("ca.pem"),
+            TIMEOUT_SECS,
+            API_DEFAULT_VERSION,
+        );
+    }
+    Docker::connect_with_http(host, TIMEOUT_SECS, API_DEFAULT_VERSION)
+}
+
+fn cert_dir(target: &Target) -> PathBuf {
+    match target.cert_path.as_deref() {
+        Some(p) => PathBuf::from(p),
+        None => std::env::var_os("HOME").map(PathBuf::from).unwrap_or_default().join(".docker"),
+    }
+}
+
+/// The request never got a proper answer from the daemon (as opposed to the
+/// daemon reporting a registry error).
+pub fn is_transport_error(err: &bollard::errors::Error) -> bool {
+    use bollard::errors::Error as Bollard;
+
+    matches!(
+        err,
+        Bollard::IOError { .. }
+            | Bollard::HyperResponseError { .. }
+            | Bollard::HttpClientError { .. }
+            | Bollard::HyperLegacyError { .. }
+            | Bollard::SocketNotFoundError(_)
+    )
+}
+
+impl PullBackend for DockerBackend {
+    fn name(&self) -> &'static str {
+        "docker"
+    }
+
+    async fn ping(&self) -> Result<(), String> {
+        let docker = self.client().map_err(|e| format!("docker connect error: {e}"))?;
+        docker.ping().await.map(|_| ()).map_err(|e| {
+            self.reset();
+            e.to_string()
+        })
+    }
+
+    async fn pull(&self, req: &PullRequest<'_>, metrics: &mut PullMetrics) -> anyhow::Result<()> {
+        let docker = self.client().map_err(|e| anyhow::anyhow!("docker connect error: {e}"))?;
+
+        // the API takes a digest in `tag` the same way as a tag
+        let opts = CreateImageOptions {
+            from_image: Some(req.from_image.to_string()),
+            tag: Some(req.reference.to_string()),
+            ..Default::default()
+        };
+        // dropping the stream closes the connection, which aborts the pull
+        let mut stream = docker.create_image(Some(opts), None, req.credentials.clone());
+
+        let res = async {
+            while let Some(item) = stream.try_next().await? {
+                if req.cancel.load(Ordering::SeqCst) {
+                    return Err(PullCanceled.into());
+                }
+                if let Some(status) = item.status.as_deref() {
+                    if status.starts_with("Digest:") {
+                        metrics.digest = Some(status.trim_start_matches("Digest:").trim().to_string());
+                    }
+                    let logs = &mut metrics.logs;
+                    logs.push_str(status);
+                    if let Some(id) = item.id.as_deref() {
+                        logs.push_str(" [");
+                        logs.push_str(id);
+                        logs.push(']');
+                    }
+                    if let Some(progress) = item.progress.as_deref() {
+                        logs.push_str(" - ");
+                        logs.push_str(progress);
+                    }
+                    logs.push('\n');
+                }
+
+                if let (Some(id), Some(detail)) = (item.id, item.progress_detail) {
+                    let current = detail.current.unwrap_or(0).max(0) as u64;
+                    let total = detail.total.unwrap_or(0).max(0) as u64;
+                    metrics.layer_progress(req, id, current, total);
+                }
+            }
+            Ok::<_, anyhow::Error>(())
+        }
+        .await;
+        if let Err(e) = &res {
+            self.reset_on_transport_error(e);
+        }
+        res?;
+
+        metrics.reported_size_bytes = docker
+            .inspect_image(req.local_ref)
+            .await
+            .ok()
+            .and_then(|ins| ins.size)
+            .unwrap_or(0)
+            .max(0) as u64;
+        metrics.cache_hit = metrics.logs.contains("Image is up to date") || metrics.bytes_downloaded() == 0;
+        Ok(())
+    }
+
+    async fn remove(&self, image_ref: &str, thorough: bool) {
+        let Ok(docker) = self.client() else {
+            return;
+        };
+        rm_image(&docker, image_ref).await;
+        if !thorough {
+            return;
+        }
+        // then everything inspect knows it by (id/tags/digests)
+        if let Ok(ins) = docker.inspect_image(image_ref).await {
+            if let Some(id) = ins.id {
+                rm_image(&docker, &id).await;
+            }
+            if let Some(tags) = ins.repo_tags {
+                for t in tags {
+                    rm_image(&docker, &t).await;
+                }
+            }
+            if let Some(digests) = ins.repo_digests {
+                for d in digests {
+                    rm_image(&docker, &d).await;
+                }
+            }
+        }
+    }
+
+    async fn inspect_manifest(
+        &self,
+        name: &str,
+        credentials: Option<DockerCredentials>,
+    ) -> anyhow::Result<ManifestInfo> {
+        let docker = self.client().map_err(|e| anyhow::anyhow!("docker connect error: {e}"))?;
+        let inspect = docker
+            .inspect_registry_image(name, credentials)
+            .await
+            .map_err(|e| anyhow::anyhow!("distribution inspect of {} failed: {}", name, e))?;
+        Ok(ManifestInfo {
+            digest: inspect.descriptor.digest,
+            media_type: inspect.descriptor.media_type,
+            size: inspect.descriptor.size.unwrap_or(0),
+            platforms: inspect.platforms.len(),
+        })
+    }
+}
+
+async fn rm_image(docker: &Docker, name: &str) {
+    let opts = Some(RemoveImageOptions { force: true, noprune: false });
+    if let Err(e) = docker.remove_image(name, opts, None).await {
+        #[cfg(debug_assertions)]
+        warn!("remove_image({}): {}", name, e);
+    }
+}
@@ -0,0 +1,302 @@
+//! Where images are pulled: the Docker daemon (default), containerd through
+//! `crictl`, or a scratch directory through `skopeo`, picked with `PULL_BACKEND`.
+//!
+//! A backend only talks to its runtime and fills in [`PullMetrics`];
+//! `routes::job::pull_image_and_record_metrics` decides what to pull, when to
+//! remove it, and turns the measurements into metrics.
+
+pub mod containerd;
+pub mod docker;
+pub mod skopeo;
+
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::time::Instant;
+
+use bollard::auth::DockerCredentials;
+
+use crate::config::AppConfig;
+use crate::events::{self, JobUpdate, JobUpdates};
+
+pub use containerd::ContainerdBackend;
+pub use docker::DockerBackend;
+pub use skopeo::SkopeoBackend;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PullBackendKind {
+    #[default]
+    Docker,
+    Containerd,
+    Skopeo,
+}
+
+impl FromStr for PullBackendKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "docker" => Ok(Self::Docker),
+            "containerd" => Ok(Self::Containerd),
+            "skopeo" => Ok(Self::Skopeo),
+            other => Err(format!("unknown pull backend '{}' (docker, containerd or skopeo)", other)),
+        }
+    }
+}
+
+/// Returned by the pull when its job was canceled through the API.
+#[derive(Debug)]
+pub struct PullCanceled;
+
+impl std::fmt::Display for PullCanceled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("pull canceled")
+    }
+}
+
+impl std::error::Error for PullCanceled {}
+
+/// One pull, with the image ref already split up.
+pub struct PullRequest<'a> {
+    pub job_id: &'a str,
+    /// Repository as the registry knows it (`library/alpine`, `ghcr.io/org/app`)
+    pub from_image: &'a str,
+    /// Tag or digest
+    pub reference: &'a str,
+    /// `from_image` with `reference` (`library/alpine:3.20`, `..@sha256:..`)
+    pub name: &'a str,
+    /// Name the runtime stores the image under (`alpine:3.20`)
+    pub local_ref: &'a str,
+    pub credentials: Option<DockerCredentials>,
+    pub updates: &'a JobUpdates,
+    /// Set by the cancel endpoint; the pull returns [`PullCanceled`] once it sees it
+    pub cancel: &'a AtomicBool,
+    /// Latest [`PullMetrics::progress_pct`], [`NO_PROGRESS`] while unknown;
+    /// the caller copies it to the job row
+    pub progress: &'a AtomicI32,
+}
+
+/// `PullRequest::progress` before any layer reported a size
+pub const NO_PROGRESS: i32 = -1;
+
+/// Progress of one layer as reported by the pull
+pub struct LayerProgress {
+    pub current: u64,
+    pub total: u64,
+    pub first_seen: Instant,
+    pub last_seen: Instant,
+}
+
+/// What a backend measured while pulling. The caller owns it, so a pull that
+/// fails or times out halfway still leaves its log and progress behind.
+#[derive(Default)]
+pub struct PullMetrics {
+    pub first_byte_at: Option<Instant>,
+    pub last_byte_at: Option<Instant>,
+    pub layers: HashMap<String, LayerProgress>,
+    pub logs: String,
+    pub digest: Option<String>,
+    /// Image size according to the runtime after the pull, 0 if unknown
+    pub reported_size_bytes: u64,
+    pub cache_hit: bool,
+}
+
+impl PullMetrics {
+    /// Fold a progress report for `layer` in and publish it to the job's SSE stream.
+    pub fn layer_progress(&mut self, req: &PullRequest<'_>, layer: String, current: u64, total: u64) {
+        let now = Instant::now();
+        if current > 0 {
+            self.first_byte_at.get_or_insert(now);
+            self.last_byte_at = Some(now);
+        }
+        let entry = self.layers.entry(layer.clone()).or_insert(LayerProgress {
+            current: 0,
+            total: 0,
+            first_seen: now,
+            last_seen: now,
+        });
+        if current > entry.current {
+            entry.current = current;
+        }
+        if total > entry.total {
+            entry.total = total;
+        }
+        entry.last_seen = now;
+        let (current, total) = (entry.current, entry.total);
+        req.progress.store(self.progress_pct().unwrap_or(NO_PROGRESS), Ordering::Relaxed);
+
+        events::publish(
+            req.updates,
+            JobUpdate::Progress {
+                job_id: req.job_id.to_string(),
+                layer,
+                current,
+                total,
+            },
+        );
+    }
+
+    /// Layer totals when known, otherwise what was seen downloading
+    pub fn bytes_downloaded(&self) -> u64 {
+        let (sum_cur, sum_tot) = self
+            .layers
+            .values()
+            .fold((0u64, 0u64), |acc, l| (acc.0.saturating_add(l.current), acc.1.saturating_add(l.total)));
+        if sum_tot > 0 { sum_tot } else { sum_cur }
+    }
+
+    /// Share of the known layer sizes downloaded so far, 0–100; `None` while
+    /// no layer reported a size (cache hits, backends without progress).
+    pub fn progress_pct(&self) -> Option<i32> {
+        let (current, total) = self
+            .layers
+            .values()
+            .filter(|l| l.total > 0)
+            .fold((0u64, 0u64), |acc, l| (acc.0.saturating_add(l.current.min(l.total)), acc.1.saturating_add(l.total)));
+        (total > 0).then(|| (current as f64 * 100.0 / total as f64).floor() as i32)
+    }
+}
+
+/// Registry manifest descriptor, for dry runs
+pub struct ManifestInfo {
+    pub digest: Option<String>,
+    pub media_type: Option<String>,
+    pub size: i64,
+    pub platforms: usize,
+}
+
+pub trait PullBackend {
+    /// Name of the readiness check in `/health/deep`
+    fn name(&self) -> &'static str;
+
+    /// Whether [`PullBackend::inspect_manifest`] works, i.e. jobs may ask for `dry_run`
+    fn supports_dry_run(&self) -> bool {
+        true
+    }
+
+    async fn ping(&self) -> Result<(), String>;
+
+    /// Pull `req`, recording progress into `metrics` as it goes.
+    async fn pull(&self, req: &PullRequest<'_>, metrics: &mut PullMetrics) -> anyhow::Result<()>;
+
+    /// Best-effort removal; `thorough` also removes every tag and digest the
+    /// image is known by.
+    async fn remove(&self, image_ref: &str, thorough: bool);
+
+    /// Look up the manifest without pulling any layers.
+    async fn inspect_manifest(
+        &self,
+        name: &str,
+        credentials: Option<DockerCredentials>,
+    ) -> anyhow::Result<ManifestInfo>;
+}
+
+/// The backend chosen by `PULL_BACKEND`
+pub enum Backend {
+    Docker(DockerBackend),
+    Containerd(ContainerdBackend),
+    Skopeo(SkopeoBackend),
+}
+
+impl Backend {
+    pub fn from_config(cfg: &AppConfig) -> Self {
+        match cfg.pull_backend {
+            PullBackendKind::Docker => Self::Docker(DockerBackend::new(cfg)),
+            PullBackendKind::Containerd => Self::Containerd(ContainerdBackend::new(cfg)),
+            PullBackendKind::Skopeo => Self::Skopeo(SkopeoBackend::new(cfg)),
+        }
+    }
+}
+
+impl PullBackend for Backend {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Docker(b) => b.name(),
+            Self::Containerd(b) => b.name(),
+            Self::Skopeo(b) => b.name(),
+        }
+    }
+
+    fn supports_dry_run(&self) -> bool {
+        match self {
+            Self::Docker(b) => b.supports_dry_run(),
+            Self::Containerd(b) => b.supports_dry_run(),
+            Self::Skopeo(b) => b.supports_dry_run(),
+        }
+    }
+
+    async fn ping(&self) -> Result<(), String> {
+        match self {
+            Self::Docker(b) => b.ping().await,
+            Self::Containerd(b) => b.ping().await,
+            Self::Skopeo(b) => b.ping().await,
+        }
+    }
+
+    async fn pull(&self, req: &PullRequest<'_>, metrics: &mut PullMetrics) -> anyhow::Result<()> {
+        match self {
+            Self::Docker(b) => b.pull(req, metrics).await,
+            Self::Containerd(b) => b.pull(req, metrics).await,
+            Self::Skopeo(b) => b.pull(req, metrics).await,
+        }
+    }
+
+    async fn remove(&self, image_ref: &str, thorough: bool) {
+        match self {
+            Self::Docker(b) => b.remove(image_ref, thorough).await,
+            Self::Containerd(b) => b.remove(image_ref, thorough).await,
+            Self::Skopeo(b) => b.remove(image_ref, thorough).await,
+        }
+    }
+
+    async fn inspect_manifest(
+        &self,
+        name: &str,
+        credentials: Option<DockerCredentials>,
+    ) -> anyhow::Result<ManifestInfo> {
+        match self {
+            Self::Docker(b) => b.inspect_manifest(name, credentials).await,
+            Self::Containerd(b) => b.inspect_manifest(name, credentials).await,
+            Self::Skopeo(b) => b.inspect_manifest(name, credentials).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metrics(layers: &[(u64, u64)]) -> PullMetrics {
+        let now = Instant::now();
+        let layers = layers
+            .iter()
+            .enumerate()
+            .map(|(i, &(current, total))| {
+                (format!("sha256:{i}"), LayerProgress { current, total, first_seen: now, last_seen: now })
+            })
+            .collect();
+        PullMetrics { layers, ..Default::default() }
+    }
+
+    #[test]
+    fn progress_needs_a_known_layer_size() {
+        assert_eq!(metrics(&[]).progress_pct(), None);
+        assert_eq!(metrics(&[(500, 0), (0, 0)]).progress_pct(), None);
+    }
+
+    #[test]
+    fn progress_is_floored_and_ignores_unsized_layers() {
+        // 2 of 3 bytes is 66.6..%
+        assert_eq!(metrics(&[(1, 1), (1, 2)]).progress_pct(), Some(66));
+        assert_eq!(metrics(&[(999, 1000)]).progress_pct(), Some(99));
+        assert_eq!(metrics(&[(250, 1000), (4096, 0)]).progress_pct(), Some(25));
+        assert_eq!(metrics(&[(0, 1000), (1000, 1000)]).progress_pct(), Some(50));
+    }
+
+    #[test]
+    fn progress_caps_each_layer_at_its_size() {
+        // an overshooting layer must not make up for one that is behind
+        assert_eq!(metrics(&[(5000, 1000), (0, 1000)]).progress_pct(), Some(50));
+        assert_eq!(metrics(&[(5000, 1000)]).progress_pct(), Some(100));
+    }
+}
@@ -0,0 +1,371 @@
+//! Pulling with `skopeo copy docker://<image> dir:<tmp>`, for rootless
+//! environments without a container daemon.
+//!
+//! The image is copied into a scratch directory under the system temp dir and
+//! deleted again after the pull, so there is nothing to remove before or after
+//! and every pull is cold. skopeo's output only says which blob it is copying,
+//! so layer sizes are read from the copied blobs once the copy is done.
+//! Signature policy (`policy.json`) and registry config are skopeo's own, same
+//! as on the command line. Per-job credentials go into an `auth.json` in the
+//! scratch directory rather than onto the command line.
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bollard::auth::DockerCredentials;
+use log::warn;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
+use tokio::process::Command;
+
+use super::{ManifestInfo, PullBackend, PullCanceled, PullMetrics, PullRequest};
+use crate::config::AppConfig;
+use crate::worker::parse_registry;
+
+/// How often a running copy checks the cancel flag
+const CANCEL_POLL: Duration = Duration::from_millis(200);
+
+/// Layer ids are shortened like the Docker daemon's
+const LAYER_ID_LEN: usize = 12;
+
+pub struct SkopeoBackend {
+    skopeo: String,
+}
+
+impl SkopeoBackend {
+    pub fn new(cfg: &AppConfig) -> Self {
+        Self { skopeo: cfg.skopeo_path.clone() }
+    }
+}
+
+/// Scratch directory of one pull, deleted on drop
+struct ScratchDir(PathBuf);
+
+impl ScratchDir {
+    fn create(job_id: &str) -> std::io::Result<Self> {
+        let path = std::env::temp_dir().join(format!("imgpuller-{}", job_id));
+        // left over from a crashed attempt
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path)?;
+        Ok(Self(path))
+    }
+}
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_dir_all(&self.0) {
+            warn!("cannot remove {}: {}", self.0.display(), e);
+        }
+    }
+}
+
+/// `auth.json` in `dir` for `--src-authfile`, readable by this user only, so
+/// the password stays out of the process list (`--src-creds` would show it).
+/// `None` for identity tokens, which have no skopeo equivalent.
+fn write_authfile(dir: &Path, registry: &str, credentials: &DockerCredentials) -> std::io::Result<Option<PathBuf>> {
+    let auth = match (&credentials.username, &credentials.password) {
+        (Some(u), Some(p)) => STANDARD.encode(format!("{}:{}", u, p)),
+        _ => match &credentials.auth {
+            Some(auth) => auth.clone(),
+            None => return Ok(None),
+        },
+    };
+    let body = serde_json::json!({ "auths": { registry: { "auth": auth } } });
+
+    let path = dir.join("auth.json");
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(&path)?.write_all(body.to_string().as_bytes())?;
+    Ok(Some(path))
+}
+
+/// `Copying blob sha256:9b2a..` -> the blob's hex digest
+fn copied_blob(line: &str) -> Option<&str> {
+    let rest = line.trim().strip_prefix("Copying blob ")?;
+    let digest = rest.split_whitespace().next()?;
+    Some(digest.split_once(':').map(|(_, hex)| hex).unwrap_or(digest))
+}
+
+fn file_size(path: &Path) -> u64 {
+    std::fs::metadata(path).map(|m| m.len()).unwrap_or(0)
+}
+
+/// Total size of the copied image (blobs, config and manifest)
+fn dir_size(dir: &Path) -> u64 {
+    std::fs::read_dir(dir)
+        .map(|entries| entries.filter_map(Result::ok).map(|e| file_size(&e.path())).sum())
+        .unwrap_or(0)
+}
+
+impl PullBackend for SkopeoBackend {
+    fn name(&self) -> &'static str {
+        "skopeo"
+    }
+
+    fn supports_dry_run(&self) -> bool {
+        false
+    }
+
+    async fn ping(&self) -> Result<(), String> {
+        let out = Command::new(&self.skopeo)
+            .arg("--version")
+            .output()
+            .await
+            .map_err(|e| format!("cannot run {}: {}", self.skopeo, e))?;
+        if out.status.success() {
+            Ok(())
+        } else {
+            Err(String::from_utf8_lossy(&out.stderr).trim().to_string())
+        }
+    }
+
+    async fn pull(&self, req: &PullRequest<'_>, metrics: &mut PullMetrics) -> anyhow::Result<()> {
+        let scratch = ScratchDir::create(req.job_id)?;
+        let image_dir = scratch.0.join("image");
+        let digest_file = scratch.0.join("digest");
+
+        let mut cmd = Command::new(&self.skopeo);
+        cmd.arg("copy").arg("--digestfile").arg(&digest_file);
+        if let Some(c) = req.credentials.as_ref() {
+            match write_authfile(&scratch.0, &parse_registry(req.name), c)? {
+                Some(authfile) => {
+                    cmd.arg("--src-authfile").arg(authfile);
+                }
+                None => warn!("job {}: skopeo can't use identity tokens, pulling anonymously", req.job_id),
+            }
+        }
+        cmd.arg(format!("docker://{}", req.name))
+            .arg(format!("dir:{}", image_dir.display()))
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+
+        let mut child = cmd
+            .spawn()
+            .map_err(|e| anyhow::anyhow!("cannot run {}: {}", self.skopeo, e))?;
+        let mut stderr = child.stderr.take().expect("stderr is piped");
+        let stderr_task = tokio::spawn(async move {
+            let mut buf = String::new();
+            let _ = stderr.read_to_string(&mut buf).await;
+            buf
+        });
+
+        // blob hex digest -> when skopeo started copying it
+        let mut blobs: Vec<(String, Instant)> = Vec::new();
+        let mut lines = BufReader::new(child.stdout.take().expect("stdout is piped")).lines();
+        loop {
+            tokio::select! {
+                line = lines.next_line() => {
+                    let Some(line) = line? else { break };
+                    if let Some(hex) = copied_blob(&line) {
+                        let now = Instant::now();
+                        metrics.first_byte_at.get_or_insert(now);
+                        blobs.push((hex.to_string(), now));
+                    }
+                    metrics.logs.push_str(&line);
+                    metrics.logs.push('\n');
+                }
+                _ = tokio::time::sleep(CANCEL_POLL) => {
+                    if req.cancel.load(Ordering::SeqCst) {
+                        return Err(PullCanceled.into());
+                    }
+                }
+            }
+        }
+        let status = child.wait().await?;
+        let stderr = stderr_task.await.unwrap_or_default();
+        metrics.logs.push_str(&stderr);
+        if !status.success() {
+            anyhow::bail!("skopeo copy {} failed: {}", req.name, stderr.trim());
+        }
+
+        // the copy is done, so every blob is complete now
+        for (hex, started) in &blobs {
+            let size = file_size(&image_dir.join(hex));
+            let id: String = hex.chars().take(LAYER_ID_LEN).collect();
+            metrics.layer_progress(req, id.clone(), size, size);
+            if let Some(layer) = metrics.layers.get_mut(&id) {
+                layer.first_seen = *started;
+            }
+        }
+        metrics.reported_size_bytes = dir_size(&image_dir);
+        metrics.digest = std::fs::read_to_string(&digest_file)
+            .ok()
+            .map(|d| d.trim().to_string())
+            .filter(|d| !d.is_empty());
+        metrics.cache_hit = false;
+        Ok(())
+    }
+
+    async fn remove(&self, _image_ref: &str, _thorough: bool) {
+        // nothing is kept between pulls
+    }
+
+    async fn inspect_manifest(
+        &self,
+        _name: &str,
+        _credentials: Option<DockerCredentials>,
+    ) -> anyhow::Result<ManifestInfo> {
+        anyhow::bail!("dry_run is not supported by the skopeo backend")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("imgpuller-test-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn user_pass(user: &str, pass: &str) -> DockerCredentials {
+        DockerCredentials { username: Some(user.into()), password: Some(pass.into()), ..Default::default() }
+    }
+
+    #[test]
+    fn authfile_holds_the_credentials_for_the_registry_only() {
+        let dir = scratch("authfile");
+        let path = write_authfile(&dir, "ghcr.io", &user_pass("bot", "s3cret")).unwrap().unwrap();
+        let body: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(body, serde_json::json!({ "auths": { "ghcr.io": { "auth": STANDARD.encode("bot:s3cret") } } }));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+
+        // a docker config `auth` is already base64("user:pass")
+        let auth = DockerCredentials { auth: Some("dXNlcjpwYXNz".into()), ..Default::default() };
+        let other = scratch("authfile-auth");
+        let path = write_authfile(&other, "docker.io", &auth).unwrap().unwrap();
+        assert!(std::fs::read_to_string(path).unwrap().contains("\"auth\":\"dXNlcjpwYXNz\""));
+
+        let token = DockerCredentials { identitytoken: Some("tok".into()), ..Default::default() };
+        let none = scratch("authfile-token");
+        assert!(write_authfile(&none, "ghcr.io", &token).unwrap().is_none());
+        for d in [dir, other, none] {
+            std::fs::remove_dir_all(d).unwrap();
+        }
+    }
+
+    #[test]
+    fn copied_blob_lines_give_the_digest() {
+        assert_eq!(copied_blob("Copying blob sha256:9b2a3c done"), Some("9b2a3c"));
+        assert_eq!(copied_blob("  Copying blob 9b2a3c"), Some("9b2a3c"));
+        assert_eq!(copied_blob("Copying config sha256:ffff"), None);
+        assert_eq!(copied_blob("Writing manifest to image destination"), None);
+    }
+
+    /// Runs the backend against a script that stands in for skopeo.
+    #[cfg(all(unix, feature = "mock-skopeo"))]
+    mod mocked {
+        use std::os::unix::fs::PermissionsExt;
+        use std::sync::atomic::{AtomicBool, AtomicI32};
+
+        use super::*;
+        use crate::backend::NO_PROGRESS;
+
+        const LAYER: &str = "0123456789abcdef0123";
+
+        /// An executable `skopeo` in `dir` that records its arguments (and a
+        /// copy of the authfile with its mode) next to itself, then copies one
+        /// layer or, with `fail`, exits 1 with a registry error.
+        fn fake_skopeo(dir: &Path, fail: bool) -> String {
+            let d = dir.display();
+            let body = if fail {
+                "echo 'Error: initializing source: unauthorized: authentication required' >&2\nexit 1\n".to_string()
+            } else {
+                format!(
+                    "mkdir -p \"$dest\"\n\
+                     echo 'Copying blob sha256:{LAYER}'\n\
+                     printf 'layer-bytes' > \"$dest/{LAYER}\"\n\
+                     printf '{{}}' > \"$dest/manifest.json\"\n\
+                     echo 'Writing manifest to image destination'\n\
+                     printf 'sha256:feedface' > \"$digest\"\n"
+                )
+            };
+            let script = format!(
+                "#!/bin/sh\n\
+                 printf '%s\\n' \"$@\" > {d}/args\n\
+                 while [ $# -gt 0 ]; do\n\
+                   case \"$1\" in\n\
+                     --digestfile) digest=\"$2\"; shift 2;;\n\
+                     --src-authfile) cp \"$2\" {d}/auth.json; stat -c %a \"$2\" > {d}/auth.mode; shift 2;;\n\
+                     dir:*) dest=\"${{1#dir:}}\"; shift;;\n\
+                     *) shift;;\n\
+                   esac\n\
+                 done\n\
+                 {body}"
+            );
+            let path = dir.join("skopeo");
+            std::fs::write(&path, script).unwrap();
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+            path.display().to_string()
+        }
+
+        async fn pull(skopeo: String, job_id: &str, credentials: Option<DockerCredentials>) -> (anyhow::Result<()>, PullMetrics) {
+            let backend = SkopeoBackend { skopeo };
+            let (updates, _) = tokio::sync::broadcast::channel(16);
+            let (cancel, progress) = (AtomicBool::new(false), AtomicI32::new(NO_PROGRESS));
+            let req = PullRequest {
+                job_id,
+                from_image: "ghcr.io/acme/app",
+                reference: "1.0",
+                name: "ghcr.io/acme/app:1.0",
+                local_ref: "ghcr.io/acme/app:1.0",
+                credentials,
+                updates: &updates,
+                cancel: &cancel,
+                progress: &progress,
+            };
+            let mut metrics = PullMetrics::default();
+            let res = backend.pull(&req, &mut metrics).await;
+            (res, metrics)
+        }
+
+        #[tokio::test]
+        async fn pull_records_layers_digest_and_size() {
+            let dir = scratch("mock-ok");
+            let job_id = format!("mock-ok-{}", std::process::id());
+            let (res, metrics) = pull(fake_skopeo(&dir, false), &job_id, Some(user_pass("bot", "s3cret"))).await;
+            res.unwrap();
+
+            assert_eq!(metrics.digest.as_deref(), Some("sha256:feedface"));
+            let layer = &metrics.layers[&LAYER[..LAYER_ID_LEN]];
+            assert_eq!((layer.current, layer.total), (11, 11));
+            assert_eq!(metrics.reported_size_bytes, 13);
+            assert!(!metrics.cache_hit);
+
+            let args = std::fs::read_to_string(dir.join("args")).unwrap();
+            assert!(args.contains("--src-authfile\n"), "{args}");
+            assert!(args.contains("docker://ghcr.io/acme/app:1.0\n"), "{args}");
+            assert!(!args.contains("s3cret") && !args.contains("--src-creds"), "{args}");
+            assert_eq!(std::fs::read_to_string(dir.join("auth.mode")).unwrap().trim(), "600");
+            let auth = std::fs::read_to_string(dir.join("auth.json")).unwrap();
+            assert!(auth.contains(&STANDARD.encode("bot:s3cret")), "{auth}");
+            // the scratch directory, authfile included, is gone after the pull
+            assert!(!std::env::temp_dir().join(format!("imgpuller-{}", job_id)).exists());
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+
+        #[tokio::test]
+        async fn failed_copy_reports_stderr() {
+            let dir = scratch("mock-fail");
+            let (res, metrics) = pull(fake_skopeo(&dir, true), &format!("mock-fail-{}", std::process::id()), None).await;
+            let err = format!("{:#}", res.unwrap_err());
+            assert!(err.contains("unauthorized: authentication required"), "{err}");
+            assert!(metrics.logs.contains("unauthorized"));
+            assert!(!std::fs::read_to_string(dir.join("args")).unwrap().contains("--src-authfile"));
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+    }
+}
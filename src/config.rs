@@ -1,5 +1,20 @@
-use std::env;
-use validator::{Validate, ValidationError};
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use glob::Pattern;
+use serde::Serialize;
+use serde_json::json;
+use validator::{Validate, ValidationError, ValidationErrors};
+
+use crate::access_log::LogFormat;
+use crate::backend::PullBackendKind;
+use crate::auth::ApiKeys;
+use crate::config_file::ConfigSource;
+
+/// Upper bound for `max_concurrent_pulls` / `per_registry_max`, also enforced by
+/// the runtime override endpoint.
+pub const MAX_PULLS_CEILING: usize = 256;
 
 #[derive(Debug, Validate, Clone)]
 pub struct AppConfig {
@@ -9,48 +24,627 @@ pub struct AppConfig {
     #[validate(range(min = 1, max = 65535))]
     pub app_port: u16,
 
+    /// Interface to listen on with `app_port`, from `BIND_ADDRESS` (default `0.0.0.0`)
+    pub bind_address: IpAddr,
+
+    /// Unix socket to listen on instead of TCP, from `BIND_UDS`
+    pub bind_uds: Option<PathBuf>,
+
+    /// PEM certificate chain (`TLS_CERT_PATH`); with `tls_key_path` the server speaks HTTPS.
+    pub tls_cert_path: Option<PathBuf>,
+
+    /// PEM private key (`TLS_KEY_PATH`) for `tls_cert_path`.
+    pub tls_key_path: Option<PathBuf>,
+
     #[validate(custom(function = "validate_db_url"))]
     pub database_url: String,
 
-    #[validate(range(min = 1, max = 10))]
+    #[validate(range(min = 1, max = MAX_PULLS_CEILING))]
     pub max_concurrent_pulls: usize,
 
-    #[validate(range(min = 1, max = 10))]
+    #[validate(range(min = 1, max = MAX_PULLS_CEILING))]
     pub per_registry_max: usize,
+
+    /// Per-registry overrides of `per_registry_max`
+    /// (`PER_REGISTRY_LIMITS=docker.io=2,registry.internal=10`), keyed by lowercase host.
+    #[validate(custom(function = "validate_registry_limits"))]
+    pub per_registry_limits: BTreeMap<String, usize>,
+
+    /// JSON request body limit in bytes.
+    #[validate(range(min = 1024, max = 10485760))]
+    pub max_json_body_bytes: usize,
+
+    /// Give up on a pull stream after this many seconds.
+    #[validate(range(min = 10, max = 86400))]
+    pub pull_timeout_secs: u64,
+
+    /// Max images accepted by `POST /api/v1/jobs/batch`.
+    #[validate(range(min = 1, max = 10000))]
+    pub max_batch_jobs: usize,
+
+    /// Bytes of `result` shown by `GET /api/v1/jobs/{id}` without `?full=true`.
+    #[validate(range(min = 1, max = 100000))]
+    pub result_truncate_len: usize,
+
+    /// Worker sleep when the queue is empty.
+    #[validate(range(min = 10, max = 60000))]
+    pub worker_idle_ms: u64,
+
+    /// Base worker delay after a failed claim; doubles per consecutive failure.
+    #[validate(range(min = 10, max = 60000))]
+    pub worker_error_ms: u64,
+
+    /// Bearer tokens accepted on `/api/v1` (`API_KEYS`); empty disables auth.
+    pub api_keys: ApiKeys,
+
+    /// Requests per minute per client on `/api/v1`; 0 disables the limit.
+    #[validate(range(max = 100000))]
+    pub rate_limit_rpm: u32,
+
+    /// Allow `POST /api/v1/admin/shutdown` (`ENABLE_ADMIN_SHUTDOWN`).
+    pub enable_admin_shutdown: bool,
+
+    /// Registry hosts jobs may pull from (`ALLOWED_REGISTRIES`, lowercase); empty allows all.
+    pub allowed_registries: Vec<String>,
+
+    /// Images jobs may not pull (`IMAGE_DENY_PATTERNS`, comma-separated globs); wins over the allow list.
+    pub image_deny_patterns: Vec<Pattern>,
+
+    /// If set, only images matching one of these globs are accepted (`IMAGE_ALLOW_PATTERNS`).
+    pub image_allow_patterns: Vec<Pattern>,
+
+    /// Origins allowed to call the API from a browser (`CORS_ALLOWED_ORIGINS`);
+    /// empty means no CORS headers at all (same-origin only). `*` allows any.
+    #[validate(custom(function = "validate_origins"))]
+    pub cors_allowed_origins: Vec<String>,
+
+    /// Finished jobs older than this many days are purged hourly; 0 keeps them forever.
+    #[validate(range(max = 3650))]
+    pub retention_days: u32,
+
+    /// Database pool size. SQLite still serializes writers, so raising this
+    /// mostly helps concurrent reads.
+    #[validate(range(min = 1, max = 100))]
+    pub db_max_connections: u32,
+
+    /// Seconds to wait for a free pool connection.
+    #[validate(range(min = 1, max = 300))]
+    pub db_acquire_timeout_secs: u64,
+
+    /// SQLite `journal_mode` pragma; WAL lets readers run alongside the writer.
+    #[validate(custom(function = "validate_journal_mode"))]
+    pub sqlite_journal_mode: String,
+
+    /// SQLite `synchronous` pragma (off|normal|full|extra).
+    #[validate(custom(function = "validate_synchronous"))]
+    pub sqlite_synchronous: String,
+
+    /// SQLite `busy_timeout` in ms: how long a writer waits for the lock.
+    #[validate(range(min = 0, max = 600000))]
+    pub sqlite_busy_timeout_ms: u64,
+
+    /// Access log style (`LOG_FORMAT=text|json`).
+    pub log_format: LogFormat,
+
+    /// What pulls go through (`PULL_BACKEND=docker|containerd|skopeo`).
+    pub pull_backend: PullBackendKind,
+
+    /// `crictl` binary used by the containerd backend.
+    #[validate(length(min = 1))]
+    pub crictl_path: String,
+
+    /// `skopeo` binary used by the skopeo backend.
+    #[validate(length(min = 1))]
+    pub skopeo_path: String,
+
+    /// Docker daemon to pull with (`DOCKER_HOST`); unset uses the local socket.
+    #[validate(custom(function = "validate_docker_host"))]
+    pub docker_host: Option<String>,
+
+    /// Talk TLS to a `tcp://` `DOCKER_HOST` (`DOCKER_TLS_VERIFY`).
+    pub docker_tls_verify: bool,
+
+    /// Directory with `key.pem`, `cert.pem` and `ca.pem`; defaults to `~/.docker`.
+    pub docker_cert_path: Option<String>,
+
+    /// How long a job's `callback_url` notification may take, retries included.
+    #[validate(range(min = 1, max = 3600))]
+    pub webhook_timeout_secs: u64,
+
+    /// OTLP collector (`OTEL_EXPORTER_OTLP_ENDPOINT`); tracing is off while unset.
+    pub otel_endpoint: Option<String>,
 }
 
 fn validate_db_url(url: &str) -> Result<(), ValidationError> {
-    if !(url.starts_with("postgres://") || url.starts_with("sqlite://")) {
-        return Err(ValidationError::new("invalid_database_url"));
+    if crate::db::is_postgres_url(url) {
+        return Ok(());
+    }
+    crate::db::sqlite_target(url).map(|_| ()).map_err(|e| {
+        let mut err = ValidationError::new("invalid_database_url");
+        err.message = Some(e.into());
+        err
+    })
+}
+
+fn validate_registry_limits(limits: &BTreeMap<String, usize>) -> Result<(), ValidationError> {
+    if limits.values().any(|n| !(1..=MAX_PULLS_CEILING).contains(n)) {
+        return Err(ValidationError::new("per_registry_limit_out_of_range"));
+    }
+    Ok(())
+}
+
+/// Parse `host=n,host=n`; blank entries are ignored, a host may appear once.
+fn parse_registry_limits(raw: &str) -> Result<BTreeMap<String, usize>, String> {
+    let mut limits = BTreeMap::new();
+    for entry in raw.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let (host, limit) = entry
+            .split_once('=')
+            .ok_or_else(|| format!("'{}' is not host=limit", entry))?;
+        let host = host.trim().to_ascii_lowercase();
+        if host.is_empty() {
+            return Err(format!("'{}' has no registry host", entry));
+        }
+        let limit = limit
+            .trim()
+            .parse::<usize>()
+            .map_err(|_| format!("'{}': limit must be a number", entry))?;
+        if limits.insert(host.clone(), limit).is_some() {
+            return Err(format!("registry '{}' is listed twice", host));
+        }
+    }
+    Ok(limits)
+}
+
+/// Comma-separated glob patterns; blank entries are ignored.
+fn parse_patterns(raw: &str) -> Result<Vec<Pattern>, String> {
+    raw.split(',')
+        .map(str::trim)
+        .filter(|p| !p.is_empty())
+        .map(|p| Pattern::new(p).map_err(|e| format!("'{}': {}", p, e)))
+        .collect()
+}
+
+fn validate_origins(origins: &[String]) -> Result<(), ValidationError> {
+    for o in origins {
+        let scheme_ok = o.starts_with("http://") || o.starts_with("https://");
+        if o != "*" && (!scheme_ok || o.ends_with('/')) {
+            return Err(ValidationError::new("invalid_cors_origin"));
+        }
+    }
+    Ok(())
+}
+
+fn validate_docker_host(host: &str) -> Result<(), ValidationError> {
+    if !crate::backend::docker::SCHEMES.iter().any(|s| host.starts_with(s)) {
+        return Err(ValidationError::new("invalid_docker_host"));
     }
     Ok(())
 }
 
+fn validate_journal_mode(mode: &str) -> Result<(), ValidationError> {
+    mode.parse::<sqlx::sqlite::SqliteJournalMode>()
+        .map(|_| ())
+        .map_err(|_| ValidationError::new("invalid_sqlite_journal_mode"))
+}
+
+fn validate_synchronous(mode: &str) -> Result<(), ValidationError> {
+    mode.parse::<sqlx::sqlite::SqliteSynchronous>()
+        .map(|_| ())
+        .map_err(|_| ValidationError::new("invalid_sqlite_synchronous"))
+}
+
 impl AppConfig {
-    pub fn from_env() -> Self {
-        let app_env = env::var("APP_ENV").unwrap_or_else(|_| "development".to_string());
-        let app_port = env::var("APP_PORT")
+    /// Whether `ALLOWED_REGISTRIES` lets jobs pull from `registry`.
+    pub fn registry_allowed(&self, registry: &str) -> bool {
+        self.allowed_registries.is_empty()
+            || self.allowed_registries.iter().any(|h| h.eq_ignore_ascii_case(registry))
+    }
+
+    /// `APP_ENV=development` (the default): error bodies may name internals
+    pub fn is_development(&self) -> bool {
+        self.app_env == "development"
+    }
+
+    /// Pull slots for one registry: its `PER_REGISTRY_LIMITS` entry, else `per_registry_max`.
+    pub fn registry_limit(&self, registry: &str) -> usize {
+        self.per_registry_limits
+            .get(&registry.to_ascii_lowercase())
+            .copied()
+            .unwrap_or(self.per_registry_max)
+    }
+
+    /// Copy the settings that can change without a restart (pull concurrency,
+    /// timeouts, worker delays, retention, result length) from `new`, returning those that differed.
+    pub fn apply_reloadable(&mut self, new: &AppConfig) -> Vec<ConfigChange> {
+        let mut changed = Vec::new();
+        macro_rules! take {
+            ($($field:ident => $var:literal),* $(,)?) => {$(
+                if self.$field != new.$field {
+                    changed.push(ConfigChange {
+                        variable: $var,
+                        previous: json!(self.$field),
+                        current: json!(new.$field),
+                    });
+                    self.$field = new.$field.clone();
+                }
+            )*};
+        }
+        take!(
+            max_concurrent_pulls => "MAX_CONCURRENT_PULLS",
+            per_registry_max => "PER_REGISTRY_MAX",
+            per_registry_limits => "PER_REGISTRY_LIMITS",
+            pull_timeout_secs => "PULL_TIMEOUT_SECS",
+            webhook_timeout_secs => "WEBHOOK_TIMEOUT_SECS",
+            result_truncate_len => "RESULT_TRUNCATE_LEN",
+            worker_idle_ms => "WORKER_IDLE_MS",
+            worker_error_ms => "WORKER_ERROR_MS",
+            retention_days => "RETENTION_DAYS",
+        );
+        changed
+    }
+
+    /// Variables that differ in `new` but are only read at startup.
+    pub fn restart_only_changes(&self, new: &AppConfig) -> Vec<&'static str> {
+        let mut ignored = Vec::new();
+        macro_rules! check {
+            ($($field:ident => $var:literal),* $(,)?) => {$(
+                if self.$field != new.$field {
+                    ignored.push($var);
+                }
+            )*};
+        }
+        check!(
+            app_env => "APP_ENV",
+            app_port => "APP_PORT",
+            bind_address => "BIND_ADDRESS",
+            bind_uds => "BIND_UDS",
+            tls_cert_path => "TLS_CERT_PATH",
+            tls_key_path => "TLS_KEY_PATH",
+            database_url => "DATABASE_URL",
+            max_json_body_bytes => "MAX_JSON_BODY_BYTES",
+            max_batch_jobs => "MAX_BATCH_JOBS",
+            api_keys => "API_KEYS",
+            rate_limit_rpm => "RATE_LIMIT_RPM",
+            enable_admin_shutdown => "ENABLE_ADMIN_SHUTDOWN",
+            allowed_registries => "ALLOWED_REGISTRIES",
+            image_deny_patterns => "IMAGE_DENY_PATTERNS",
+            image_allow_patterns => "IMAGE_ALLOW_PATTERNS",
+            cors_allowed_origins => "CORS_ALLOWED_ORIGINS",
+            db_max_connections => "DB_MAX_CONNECTIONS",
+            db_acquire_timeout_secs => "DB_ACQUIRE_TIMEOUT_SECS",
+            sqlite_journal_mode => "SQLITE_JOURNAL_MODE",
+            sqlite_synchronous => "SQLITE_SYNCHRONOUS",
+            sqlite_busy_timeout_ms => "SQLITE_BUSY_TIMEOUT_MS",
+            log_format => "LOG_FORMAT",
+            pull_backend => "PULL_BACKEND",
+            crictl_path => "CRICTL_PATH",
+            skopeo_path => "SKOPEO_PATH",
+            docker_host => "DOCKER_HOST",
+            docker_tls_verify => "DOCKER_TLS_VERIFY",
+            docker_cert_path => "DOCKER_CERT_PATH",
+            otel_endpoint => "OTEL_EXPORTER_OTLP_ENDPOINT",
+        );
+        ignored
+    }
+
+    /// Read the configuration from the environment, falling back to
+    /// `CONFIG_FILE` for unset variables. Every missing or unparsable variable
+    /// is reported in the returned error, not just the first.
+    pub fn from_env() -> Result<Self, ConfigError> {
+        let src = ConfigSource::load().map_err(|e| ConfigError { errors: vec![format!("CONFIG_FILE: {}", e)] })?;
+        Self::from_source(&src)
+    }
+
+    /// [`AppConfig::from_env`] with the variables looked up in `src`.
+    pub fn from_source(src: &ConfigSource) -> Result<Self, ConfigError> {
+        let mut errors = Vec::new();
+        let app_env = src.var("APP_ENV").unwrap_or_else(|_| "development".to_string());
+        let app_port = src.var("APP_PORT")
             .unwrap_or_else(|_| "8080".to_string())
             .parse::<u16>()
-            .expect("❌ APP_PORT must be a number between 1–65535");
-        let database_url =
-            env::var("DATABASE_URL").expect("❌ DATABASE_URL environment variable not set");
+            .map_err(|_| "APP_PORT: must be a number between 1–65535".to_string());
+        let bind_address = src.var("BIND_ADDRESS")
+            .unwrap_or_else(|_| "0.0.0.0".to_string())
+            .trim()
+            .parse::<IpAddr>()
+            .map_err(|_| "BIND_ADDRESS: must be an IPv4 or IPv6 address".to_string());
+        let database_url = src.var("DATABASE_URL")
+            .map_err(|_| "DATABASE_URL: must be set".to_string());
 
         let cfg = AppConfig {
             app_env,
-            app_port,
-            database_url,
-            max_concurrent_pulls: env::var("MAX_CONCURRENT_PULLS")
-                .ok()
-                .and_then(|v| v.parse().ok())
-                .unwrap_or(5),
-            per_registry_max: env::var("PER_REGISTRY_MAX")
-                .ok()
-                .and_then(|v| v.parse().ok())
-                .unwrap_or(2),
+            app_port: or_note(&mut errors, app_port, 0),
+            bind_address: or_note(&mut errors, bind_address, IpAddr::from([0, 0, 0, 0])),
+            bind_uds: env_path(src, "BIND_UDS"),
+            tls_cert_path: env_path(src, "TLS_CERT_PATH"),
+            tls_key_path: env_path(src, "TLS_KEY_PATH"),
+            database_url: or_note(&mut errors, database_url, String::new()),
+            max_concurrent_pulls: number(src, &mut errors, "MAX_CONCURRENT_PULLS", 5),
+            per_registry_max: number(src, &mut errors, "PER_REGISTRY_MAX", 2),
+            per_registry_limits: or_note(
+                &mut errors,
+                parse_registry_limits(&src.var("PER_REGISTRY_LIMITS").unwrap_or_default())
+                    .map_err(|e| format!("PER_REGISTRY_LIMITS: {e}")),
+                BTreeMap::new(),
+            ),
+            max_json_body_bytes: number(src, &mut errors, "MAX_JSON_BODY_BYTES", 4096),
+            pull_timeout_secs: number(src, &mut errors, "PULL_TIMEOUT_SECS", 600),
+            max_batch_jobs: number(src, &mut errors, "MAX_BATCH_JOBS", 200),
+            result_truncate_len: number(src, &mut errors, "RESULT_TRUNCATE_LEN", 500),
+            worker_idle_ms: number(src, &mut errors, "WORKER_IDLE_MS", 500),
+            worker_error_ms: number(src, &mut errors, "WORKER_ERROR_MS", 1000),
+            api_keys: ApiKeys::parse(&src.var("API_KEYS").unwrap_or_default()),
+            rate_limit_rpm: number(src, &mut errors, "RATE_LIMIT_RPM", 0),
+            enable_admin_shutdown: src.var("ENABLE_ADMIN_SHUTDOWN")
+                .map(|v| !matches!(v.trim(), "" | "0" | "false"))
+                .unwrap_or(false),
+            allowed_registries: src.var("ALLOWED_REGISTRIES")
+                .unwrap_or_default()
+                .split(',')
+                .map(|h| h.trim().to_ascii_lowercase())
+                .filter(|h| !h.is_empty())
+                .collect(),
+            image_deny_patterns: or_note(
+                &mut errors,
+                parse_patterns(&src.var("IMAGE_DENY_PATTERNS").unwrap_or_default()).map_err(|e| format!("IMAGE_DENY_PATTERNS: {e}")),
+                Vec::new(),
+            ),
+            image_allow_patterns: or_note(
+                &mut errors,
+                parse_patterns(&src.var("IMAGE_ALLOW_PATTERNS").unwrap_or_default()).map_err(|e| format!("IMAGE_ALLOW_PATTERNS: {e}")),
+                Vec::new(),
+            ),
+            cors_allowed_origins: src.var("CORS_ALLOWED_ORIGINS")
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|o| !o.is_empty())
+                .map(str::to_string)
+                .collect(),
+            retention_days: number(src, &mut errors, "RETENTION_DAYS", 30),
+            db_max_connections: number(src, &mut errors, "DB_MAX_CONNECTIONS", 5),
+            db_acquire_timeout_secs: number(src, &mut errors, "DB_ACQUIRE_TIMEOUT_SECS", 30),
+            sqlite_journal_mode: src.var("SQLITE_JOURNAL_MODE").unwrap_or_else(|_| "wal".to_string()),
+            sqlite_synchronous: src.var("SQLITE_SYNCHRONOUS").unwrap_or_else(|_| "normal".to_string()),
+            sqlite_busy_timeout_ms: number(src, &mut errors, "SQLITE_BUSY_TIMEOUT_MS", 30000),
+            log_format: keyword(src, &mut errors, "LOG_FORMAT"),
+            pull_backend: keyword(src, &mut errors, "PULL_BACKEND"),
+            crictl_path: src.var("CRICTL_PATH").unwrap_or_else(|_| "crictl".to_string()),
+            skopeo_path: src.var("SKOPEO_PATH").unwrap_or_else(|_| "skopeo".to_string()),
+            docker_host: src.var("DOCKER_HOST").ok().filter(|v| !v.trim().is_empty()),
+            docker_tls_verify: src.var("DOCKER_TLS_VERIFY")
+                .map(|v| !matches!(v.trim(), "" | "0" | "false"))
+                .unwrap_or(false),
+            docker_cert_path: src.var("DOCKER_CERT_PATH").ok().filter(|v| !v.trim().is_empty()),
+            webhook_timeout_secs: number(src, &mut errors, "WEBHOOK_TIMEOUT_SECS", 30),
+            otel_endpoint: src.var("OTEL_EXPORTER_OTLP_ENDPOINT").ok().filter(|v| !v.trim().is_empty()),
         };
 
-        cfg.validate().expect("❌ Invalid configuration values");
-        cfg
+        for key in src.unknown_keys() {
+            errors.push(format!("CONFIG_FILE: unknown setting '{}'", key));
+        }
+        if cfg.tls_cert_path.is_some() != cfg.tls_key_path.is_some() {
+            errors.push("TLS_CERT_PATH and TLS_KEY_PATH must be set together".to_string());
+        }
+        if cfg.tls_cert_path.is_some() && cfg.bind_uds.is_some() {
+            errors.push("TLS_CERT_PATH / TLS_KEY_PATH can't be combined with BIND_UDS".to_string());
+        }
+        if let Err(e) = cfg.validate() {
+            // fields are named after their variable
+            for (var, reason) in validation_failures(&e) {
+                let var = var.to_ascii_uppercase();
+                // a variable that didn't parse already has its line, whatever its fallback fails
+                if !errors.iter().any(|prev| prev.starts_with(&format!("{}:", var))) {
+                    errors.push(format!("{}: {}", var, reason));
+                }
+            }
+        }
+        if !errors.is_empty() {
+            return Err(ConfigError { errors });
+        }
+        Ok(cfg)
+    }
+}
+
+/// `(field, reason)` per failed rule, sorted by field. Also used for request bodies.
+pub fn validation_failures(errors: &ValidationErrors) -> Vec<(String, String)> {
+    let mut fields: Vec<_> = errors.field_errors().into_iter().collect();
+    fields.sort_by(|a, b| a.0.cmp(&b.0));
+    fields
+        .into_iter()
+        .flat_map(|(field, errs)| errs.iter().map(move |e| (field.to_string(), describe(e))))
+        .collect()
+}
+
+/// Readable reason for one failed rule: `must be between 1 and 256, got 0`
+fn describe(err: &ValidationError) -> String {
+    if let Some(msg) = &err.message {
+        return msg.to_string();
+    }
+    let param = |k: &str| err.params.get(k).map(|v| v.to_string());
+    let got = match err.code.as_ref() {
+        // the value of a length rule is the string itself, which may be huge
+        "length" => err.params.get("value").and_then(|v| v.as_str()).map(|s| s.chars().count().to_string()),
+        _ => param("value"),
+    };
+    let got = got.map(|v| format!(", got {}", v)).unwrap_or_default();
+    match (err.code.as_ref(), param("min"), param("max")) {
+        ("range", Some(min), Some(max)) => format!("must be between {} and {}{}", min, max, got),
+        ("range", Some(min), None) => format!("must be at least {}{}", min, got),
+        ("range", None, Some(max)) => format!("must be at most {}{}", max, got),
+        ("length", Some(min), Some(max)) => format!("must be {} to {} characters{}", min, max, got),
+        ("length", Some(min), None) => format!("must be at least {} characters{}", min, got),
+        ("length", None, Some(max)) => format!("must be at most {} characters{}", max, got),
+        (code, ..) => code.replace('_', " "),
+    }
+}
+
+/// One setting changed by a reload
+#[derive(Debug, Serialize, utoipa::ToSchema)]
+pub struct ConfigChange {
+    pub variable: &'static str,
+    pub previous: serde_json::Value,
+    pub current: serde_json::Value,
+}
+
+/// Outcome of `POST /api/v1/admin/reload-config`
+#[derive(Debug, Serialize, utoipa::ToSchema)]
+pub struct ConfigReload {
+    pub changed: Vec<ConfigChange>,
+    /// Changed variables that need a restart to take effect
+    pub ignored: Vec<&'static str>,
+}
+
+/// What is wrong with the environment, one entry per variable.
+#[derive(Debug)]
+pub struct ConfigError {
+    pub errors: Vec<String>,
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("invalid configuration:")?;
+        for e in &self.errors {
+            write!(f, "\n  - {}", e)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// `value`, or `fallback` with the reason recorded in `errors`; `from_env`
+/// returns the errors before anything sees the fallback.
+fn or_note<T>(errors: &mut Vec<String>, value: Result<T, String>, fallback: T) -> T {
+    value.unwrap_or_else(|e| {
+        errors.push(e);
+        fallback
+    })
+}
+
+/// `var` as an integer, `default` while unset or blank; anything else is
+/// noted in `errors`.
+fn number<T: FromStr + Copy>(src: &ConfigSource, errors: &mut Vec<String>, var: &str, default: T) -> T {
+    let value = match src.var(var).ok().filter(|v| !v.trim().is_empty()) {
+        Some(v) => v
+            .trim()
+            .parse()
+            .map_err(|_| format!("{}: must be a non-negative integer, got '{}'", var, v.trim())),
+        None => Ok(default),
+    };
+    or_note(errors, value, default)
+}
+
+/// `var` through its `FromStr`, the default while unset or blank; an unknown
+/// value is noted in `errors`.
+fn keyword<T: FromStr<Err = String> + Default>(src: &ConfigSource, errors: &mut Vec<String>, var: &str) -> T {
+    let value = match src.var(var).ok().filter(|v| !v.trim().is_empty()) {
+        Some(v) => v.trim().parse().map_err(|e| format!("{}: {}", var, e)),
+        None => Ok(T::default()),
+    };
+    or_note(errors, value, T::default())
+}
+
+/// Non-empty path from `var`
+fn env_path(src: &ConfigSource, var: &str) -> Option<PathBuf> {
+    src.var(var)
+        .ok()
+        .map(|p| p.trim().to_string())
+        .filter(|p| !p.is_empty())
+        .map(PathBuf::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(vars: &[(&str, &str)]) -> Result<AppConfig, ConfigError> {
+        let mut all = vec![("DATABASE_URL", "sqlite::memory:")];
+        all.extend_from_slice(vars);
+        AppConfig::from_source(&ConfigSource::from_vars(&all).unwrap())
+    }
+
+    #[test]
+    fn pull_backend_is_docker_unless_named() {
+        assert_eq!(load(&[]).unwrap().pull_backend, PullBackendKind::Docker);
+        let cfg = load(&[("PULL_BACKEND", "Containerd")]).unwrap();
+        assert_eq!(cfg.pull_backend, PullBackendKind::Containerd);
+    }
+
+    #[test]
+    fn unknown_pull_backend_is_an_error() {
+        let err = load(&[("PULL_BACKEND", "podman")]).unwrap_err();
+        assert_eq!(
+            err.errors,
+            ["PULL_BACKEND: unknown pull backend 'podman' (docker, containerd or skopeo)"]
+        );
+    }
+
+    #[test]
+    fn every_unparsable_variable_is_reported() {
+        let err = load(&[("RATE_LIMIT_RPM", "fast"), ("RETENTION_DAYS", "-1"), ("LOG_FORMAT", "xml")]).unwrap_err();
+        assert_eq!(
+            err.errors,
+            [
+                "RATE_LIMIT_RPM: must be a non-negative integer, got 'fast'",
+                "RETENTION_DAYS: must be a non-negative integer, got '-1'",
+                "LOG_FORMAT: unknown log format 'xml' (text or json)",
+            ]
+        );
+    }
+
+    #[test]
+    fn missing_and_invalid_variables_are_reported_together() {
+        let src = ConfigSource::from_vars(&[("MAX_CONCURRENT_PULLS", "5x"), ("APP_PORT", "0x50")]).unwrap();
+        let err = AppConfig::from_source(&src).unwrap_err();
+        assert_eq!(
+            err.errors,
+            [
+                "APP_PORT: must be a number between 1–65535",
+                "DATABASE_URL: must be set",
+                "MAX_CONCURRENT_PULLS: must be a non-negative integer, got '5x'",
+            ]
+        );
+    }
+
+    #[test]
+    fn unparsable_value_is_not_reported_twice() {
+        // the fallback would pass the range rule; a real 0 wouldn't
+        let err = load(&[("MAX_CONCURRENT_PULLS", "many")]).unwrap_err();
+        assert_eq!(err.errors, ["MAX_CONCURRENT_PULLS: must be a non-negative integer, got 'many'"]);
+        let err = load(&[("MAX_CONCURRENT_PULLS", "0")]).unwrap_err();
+        assert_eq!(err.errors.len(), 1);
+        assert!(err.errors[0].starts_with("MAX_CONCURRENT_PULLS: "), "{:?}", err.errors);
+    }
+
+    #[test]
+    fn blank_values_take_the_default() {
+        let cfg = load(&[("PULL_TIMEOUT_SECS", " "), ("LOG_FORMAT", ""), ("MAX_JSON_BODY_BYTES", " 8192 ")]).unwrap();
+        assert_eq!(cfg.pull_timeout_secs, 600);
+        assert_eq!(cfg.log_format, LogFormat::Text);
+        assert_eq!(cfg.max_json_body_bytes, 8192);
+    }
+
+    #[test]
+    fn listed_registries_get_their_own_limit() {
+        let cfg = load(&[("PER_REGISTRY_MAX", "3"), ("PER_REGISTRY_LIMITS", "Docker.io=2, registry.internal=10")]).unwrap();
+        assert_eq!(cfg.registry_limit("docker.io"), 2);
+        assert_eq!(cfg.registry_limit("DOCKER.IO"), 2);
+        assert_eq!(cfg.registry_limit("registry.internal"), 10);
+        assert_eq!(cfg.registry_limit("ghcr.io"), 3);
+        assert_eq!(load(&[]).unwrap().registry_limit("docker.io"), 2);
+    }
+
+    #[test]
+    fn malformed_registry_limits_are_reported() {
+        for (raw, error) in [
+            ("docker.io", "PER_REGISTRY_LIMITS: 'docker.io' is not host=limit"),
+            ("=4", "PER_REGISTRY_LIMITS: '=4' has no registry host"),
+            ("docker.io=many", "PER_REGISTRY_LIMITS: 'docker.io=many': limit must be a number"),
+            ("docker.io=2,DOCKER.IO=3", "PER_REGISTRY_LIMITS: registry 'docker.io' is listed twice"),
+        ] {
+            let err = load(&[("PER_REGISTRY_LIMITS", raw)]).unwrap_err();
+            assert_eq!(err.errors, [error], "{raw}");
+        }
     }
 }
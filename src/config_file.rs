@@ -0,0 +1,142 @@
+//! `CONFIG_FILE`: settings from a TOML (`.toml`) or YAML (`.yaml`, `.yml`)
+//! file, keyed by the environment variable names in lower or upper case:
+//!
+//! ```toml
+//! database_url = "sqlite://data/exportor.db"
+//! max_concurrent_pulls = 8
+//! allowed_registries = ["docker.io", "ghcr.io"]
+//! per_registry_limits = { "docker.io" = 4, "ghcr.io" = 2 }
+//! ```
+//!
+//! Lists become the comma-separated form of the variable and tables its
+//! `key=value,..` form, so every value goes through the same parsing and
+//! validation as the environment. A variable that is set wins over the file.
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::env::{self, VarError};
+use std::path::Path;
+
+use serde::Deserialize;
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Scalar {
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Str(String),
+}
+
+impl Scalar {
+    fn into_string(self) -> String {
+        match self {
+            Scalar::Bool(b) => b.to_string(),
+            Scalar::Int(n) => n.to_string(),
+            Scalar::Float(n) => n.to_string(),
+            Scalar::Str(s) => s,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum FileValue {
+    Scalar(Scalar),
+    List(Vec<Scalar>),
+    Table(BTreeMap<String, Scalar>),
+}
+
+impl FileValue {
+    fn into_string(self) -> String {
+        match self {
+            FileValue::Scalar(s) => s.into_string(),
+            FileValue::List(items) => items.into_iter().map(Scalar::into_string).collect::<Vec<_>>().join(","),
+            FileValue::Table(entries) => entries
+                .into_iter()
+                .map(|(k, v)| format!("{}={}", k, v.into_string()))
+                .collect::<Vec<_>>()
+                .join(","),
+        }
+    }
+}
+
+/// Where `AppConfig::from_env` reads its variables: the environment, then `CONFIG_FILE`.
+pub struct ConfigSource {
+    /// Stands in for the process environment when set (tests)
+    vars: Option<BTreeMap<String, String>>,
+    /// Variable name -> (key as written in the file, value)
+    file: BTreeMap<String, (String, String)>,
+    /// Variables looked up so far, to spot file keys nothing reads
+    used: RefCell<BTreeSet<String>>,
+}
+
+impl ConfigSource {
+    /// The file named by `CONFIG_FILE`, or the environment alone when it is unset.
+    pub fn load() -> Result<Self, String> {
+        Self::with_vars(None)
+    }
+
+    /// `vars` in place of the environment, `CONFIG_FILE` included.
+    #[cfg(test)]
+    pub fn from_vars(vars: &[(&str, &str)]) -> Result<Self, String> {
+        Self::with_vars(Some(vars.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()))
+    }
+
+    fn with_vars(vars: Option<BTreeMap<String, String>>) -> Result<Self, String> {
+        let mut src = Self { vars, file: BTreeMap::new(), used: RefCell::default() };
+        if let Some(path) = src.env_var("CONFIG_FILE").ok().filter(|p| !p.trim().is_empty()) {
+            src.file = read_file(Path::new(path.trim()))?;
+        }
+        Ok(src)
+    }
+
+    fn env_var(&self, name: &str) -> Result<String, VarError> {
+        match &self.vars {
+            Some(vars) => vars.get(name).cloned().ok_or(VarError::NotPresent),
+            None => env::var(name),
+        }
+    }
+
+    /// Same contract as `std::env::var`, falling back to the file.
+    pub fn var(&self, name: &str) -> Result<String, VarError> {
+        self.used.borrow_mut().insert(name.to_string());
+        match self.env_var(name) {
+            Err(VarError::NotPresent) => self
+                .file
+                .get(name)
+                .map(|(_, value)| value.clone())
+                .ok_or(VarError::NotPresent),
+            other => other,
+        }
+    }
+
+    /// File keys that no [`ConfigSource::var`] call asked for, as written in the file.
+    pub fn unknown_keys(&self) -> Vec<&str> {
+        let used = self.used.borrow();
+        self.file
+            .iter()
+            .filter(|(name, _)| !used.contains(*name))
+            .map(|(_, (key, _))| key.as_str())
+            .collect()
+    }
+}
+
+fn read_file(path: &Path) -> Result<BTreeMap<String, (String, String)>, String> {
+    let raw = std::fs::read_to_string(path).map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+    let ext = path.extension().and_then(|e| e.to_str()).unwrap_or("").to_ascii_lowercase();
+    let values: BTreeMap<String, FileValue> = match ext.as_str() {
+        "toml" => toml::from_str(&raw).map_err(|e| format!("{}: {}", path.display(), e))?,
+        "yaml" | "yml" => serde_yaml::from_str(&raw).map_err(|e| format!("{}: {}", path.display(), e))?,
+        _ => return Err(format!("{}: expected a .toml, .yaml or .yml file", path.display())),
+    };
+
+    let mut file = BTreeMap::new();
+    for (key, value) in values {
+        let name = key.to_ascii_uppercase();
+        if let Some((first, _)) = file.insert(name, (key.clone(), value.into_string())) {
+            return Err(format!("'{}' and '{}' are the same setting", first, key));
+        }
+    }
+    Ok(file)
+}
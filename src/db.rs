@@ -1,30 +1,135 @@
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::time::Duration;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{DateTime, Utc};
+use sqlx::postgres::{PgPool, PgPoolOptions};
 use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions, SqliteSynchronous};
-use sqlx::{Row, SqlitePool};
+use log::info;
+use crate::model::{JobStatus, MetricUnit};
+use crate::worker::parse_registry;
+use sqlx::migrate::Migrator;
+use sqlx::{Postgres, QueryBuilder, Sqlite, SqlitePool};
 
-/// ---------- Job row types ----------
+/// Database handle; the backend is picked from the `DATABASE_URL` scheme.
 #[derive(Debug, Clone)]
+pub enum DbPool {
+    Sqlite(SqlitePool),
+    Postgres(PgPool),
+}
+
+/// Run `$body` against whichever backend `$pool` holds, with `$sql` bound to
+/// the query text written for that backend. The body is expanded once per
+/// backend, so the same bind chain works for both.
+macro_rules! on_pool {
+    ($pool:expr, $sqlite:expr, $postgres:expr, |$p:ident, $sql:ident| $body:expr) => {
+        match $pool {
+            DbPool::Sqlite($p) => {
+                let $sql = $sqlite;
+                $body
+            }
+            DbPool::Postgres($p) => {
+                let $sql = $postgres;
+                $body
+            }
+        }
+    };
+}
+
+/// ---------- Job row types ----------
+#[derive(Debug, Clone, sqlx::FromRow)]
 pub struct DbJobListItem {
     pub id: String,
     pub image: String,
-    pub status: String,
+    #[sqlx(try_from = "String")]
+    pub status: JobStatus,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, sqlx::FromRow)]
 pub struct DbJobDetail {
     pub id: String,
     pub image: String,
-    pub status: String,
+    #[sqlx(try_from = "String")]
+    pub status: JobStatus,
     pub result: Option<String>,
     pub error_detail: Option<String>,
     pub retry_count: i64,
+    pub max_attempts: i64,
+    pub priority: i32,
     pub created_at: String,
+    /// Start of the current (or last) attempt
+    pub started_at: Option<String>,
     pub finished_at: Option<String>,
+    /// Last write to the row; `created_at` for jobs untouched since the `0003` migration
+    pub updated_at: String,
+    pub lease_expires_at: Option<String>,
+    pub last_heartbeat: Option<String>,
+    /// `finished_at - started_at`, when both are set
+    pub duration_ms: Option<i64>,
+    /// What the completed pull downloaded, see [`complete_job`]
+    pub bytes_downloaded: Option<i64>,
+    pub metadata_json: Option<String>,
+    pub progress_pct: Option<i32>,
 }
 
-/// ---------- Metric row type ----------
+/// A job handed to the worker by `claim_next_job`
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct ClaimedJob {
+    pub id: String,
+    pub image: String,
+    pub retry_count: i64,
+    /// `X-Request-Id` of the request that created the job
+    pub request_id: Option<String>,
+    /// W3C `traceparent` of the request that created the job, while tracing is on
+    pub traceparent: Option<String>,
+    #[sqlx(flatten)]
+    pub options: JobOptions,
+}
+
+/// Per-job switches chosen at create time
+#[derive(Debug, Clone, Default, sqlx::FromRow)]
+pub struct JobOptions {
+    /// Inspect the registry manifest instead of pulling
+    pub dry_run: bool,
+    /// Override PRE_PULL_REMOVE / POST_PULL_REMOVE; `None` uses the env default
+    pub pre_remove: Option<bool>,
+    pub post_remove: Option<bool>,
+    /// Digest the pull should resolve to (`sha256:...`); recorded as `digest_match`
+    pub expected_digest: Option<String>,
+    /// Fail the job instead of completing it when the digest doesn't match
+    pub require_digest_match: bool,
+    /// Notified by `webhook` once the job reaches a terminal state
+    pub callback_url: Option<String>,
+    /// Complete without pulling if the image was pulled this recently (see `find_recent_pull`)
+    pub skip_if_pulled_within_secs: Option<i64>,
+}
+
+/// Everything `insert_job` needs to queue one job
 #[derive(Debug, Clone)]
+pub struct NewJob<'a> {
+    pub id: &'a str,
+    pub image: &'a str,
+    /// Higher is claimed first
+    pub priority: i32,
+    pub options: JobOptions,
+    /// Per-job registry credentials `(username, password)`
+    pub credentials: Option<(&'a str, &'a str)>,
+    pub request_id: Option<&'a str>,
+    pub traceparent: Option<&'a str>,
+    /// `metadata` of the request as a JSON object
+    pub metadata_json: Option<&'a str>,
+}
+
+/// Stored pull log of one attempt
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct JobLogRow {
+    pub job_id: String,
+    pub log_text: String,
+    pub created_at: String,
+}
+
+/// ---------- Metric row type ----------
+#[derive(Debug, Clone, sqlx::FromRow)]
 pub struct MetricRow {
     pub job_id: String,
     pub key: String,
@@ -34,254 +139,1362 @@ pub struct MetricRow {
     pub created_at: String,
 }
 
-/// Create a SqlitePool
-pub async fn init_pool(database_url: &str) -> Result<SqlitePool, sqlx::Error> {
-    let opts = SqliteConnectOptions::from_str(database_url)?
-        .create_if_missing(true)
-        .journal_mode(SqliteJournalMode::Wal)
-        .synchronous(SqliteSynchronous::Normal)
-        .busy_timeout(Duration::from_secs(30));
+/// Latest value of each metric key per job, joined with the job's image
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct LatestMetricRow {
+    pub job_id: String,
+    pub image: String,
+    pub key: String,
+    pub value: f64,
+    pub unit: Option<String>,
+    pub labels_json: Option<String>,
+}
 
-    SqlitePoolOptions::new()
-        .max_connections(5)
-        .connect_with(opts)
-        .await
+/// Summary of one metric key over a time window
+#[derive(Debug, Clone, serde::Serialize, utoipa::ToSchema)]
+pub struct MetricAggregate {
+    pub key: String,
+    pub window_secs: Option<i64>,
+    /// Unit of the newest row of `key` that has one
+    pub unit: Option<String>,
+    pub count: usize,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    pub avg: Option<f64>,
+    pub p50: Option<f64>,
+    pub p95: Option<f64>,
+    pub p99: Option<f64>,
 }
 
-/// Initialize schema (used by `--init-db`)
-pub async fn init_db(pool: &SqlitePool) -> Result<(), sqlx::Error> {
-    // Jobs
-    sqlx::query(
-        r#"
-        CREATE TABLE IF NOT EXISTS jobs (
-            id           TEXT PRIMARY KEY,
-            image        TEXT NOT NULL,
-            status       TEXT NOT NULL DEFAULT 'queued',
-            result       TEXT,
-            error_detail TEXT,
-            retry_count  INTEGER NOT NULL DEFAULT 0,
-            created_at   TEXT NOT NULL DEFAULT (datetime('now')),
-            finished_at  TEXT
+/// Per-bucket aggregation of `timeseries_metric`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, utoipa::ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum TimeseriesAgg {
+    Avg,
+    Min,
+    Max,
+    Count,
+}
+
+impl TimeseriesAgg {
+    fn sql(self) -> &'static str {
+        match self {
+            Self::Avg => "AVG(value)",
+            Self::Min => "MIN(value)",
+            Self::Max => "MAX(value)",
+            // SQLite reads DOUBLE PRECISION as REAL
+            Self::Count => "CAST(COUNT(*) AS DOUBLE PRECISION)",
+        }
+    }
+}
+
+/// One non-empty bucket of a timeseries
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct TimeseriesBucket {
+    /// Bucket start, Unix seconds; a multiple of the bucket width
+    pub start: i64,
+    pub value: f64,
+}
+
+pub fn is_postgres_url(database_url: &str) -> bool {
+    database_url.starts_with("postgres://") || database_url.starts_with("postgresql://")
+}
+
+/// What a `sqlite:` `DATABASE_URL` points at
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SqliteTarget {
+    Memory,
+    /// Always absolute; relative paths are taken from the working directory
+    File(PathBuf),
+}
+
+/// Resolve a `sqlite:` URL the way the pool opens it, so `--init-db` and the
+/// server agree on the file:
+///
+/// - `sqlite://db.sqlite`, `sqlite:db.sqlite` -> `<cwd>/db.sqlite`
+/// - `sqlite:///abs/path.db` -> `/abs/path.db`
+/// - `sqlite::memory:`, `sqlite://:memory:` -> in memory
+///
+/// Query parameters (`?mode=rwc`) are ignored here.
+pub fn sqlite_target(database_url: &str) -> Result<SqliteTarget, String> {
+    let rest = database_url
+        .strip_prefix("sqlite://")
+        .or_else(|| database_url.strip_prefix("sqlite:"))
+        .ok_or_else(|| format!("'{}' is not a sqlite: URL", database_url))?;
+    let path = rest.split_once('?').map(|(p, _)| p).unwrap_or(rest);
+    if path == ":memory:" {
+        return Ok(SqliteTarget::Memory);
+    }
+    if path.is_empty() {
+        return Err("the sqlite URL has no file path".into());
+    }
+    if path.ends_with('/') {
+        return Err(format!("sqlite path '{}' is a directory, not a file", path));
+    }
+    let path = Path::new(path);
+    if path.is_absolute() {
+        return Ok(SqliteTarget::File(path.to_path_buf()));
+    }
+    let cwd = std::env::current_dir().map_err(|e| format!("cannot resolve '{}': {}", path.display(), e))?;
+    Ok(SqliteTarget::File(cwd.join(path)))
+}
+
+/// Pool sizing (`DB_MAX_CONNECTIONS` / `DB_ACQUIRE_TIMEOUT_SECS`) and the
+/// SQLite pragmas applied to every connection (`SQLITE_*`)
+#[derive(Debug, Clone)]
+pub struct PoolSettings {
+    pub max_connections: u32,
+    /// How long a query waits for a free connection before failing
+    pub acquire_timeout: Duration,
+    pub sqlite_journal_mode: SqliteJournalMode,
+    pub sqlite_synchronous: SqliteSynchronous,
+    /// How long a writer waits on a locked database before `database is locked`
+    pub sqlite_busy_timeout: Duration,
+}
+
+impl PoolSettings {
+    pub fn from_config(cfg: &crate::config::AppConfig) -> Self {
+        Self {
+            max_connections: cfg.db_max_connections,
+            acquire_timeout: Duration::from_secs(cfg.db_acquire_timeout_secs),
+            // both were checked by AppConfig::validate
+            sqlite_journal_mode: cfg.sqlite_journal_mode.parse().unwrap_or(SqliteJournalMode::Wal),
+            sqlite_synchronous: cfg.sqlite_synchronous.parse().unwrap_or(SqliteSynchronous::Normal),
+            sqlite_busy_timeout: Duration::from_millis(cfg.sqlite_busy_timeout_ms),
+        }
+    }
+}
+
+/// Create a pool for `sqlite://...`, `sqlite::memory:` or `postgres://...`
+///
+/// SQLite allows one writer at a time, so connections beyond a handful only
+/// help concurrent reads (list/metrics endpoints); writes still queue up.
+pub async fn init_pool(database_url: &str, settings: &PoolSettings) -> Result<DbPool, sqlx::Error> {
+    if is_postgres_url(database_url) {
+        let pool = PgPoolOptions::new()
+            .max_connections(settings.max_connections)
+            .acquire_timeout(settings.acquire_timeout)
+            .connect(database_url)
+            .await?;
+        return Ok(DbPool::Postgres(pool));
+    }
+
+    let target = sqlite_target(database_url).map_err(|e| sqlx::Error::Configuration(e.into()))?;
+    let opts = match &target {
+        // a fresh named database with shared cache, so every pooled connection sees the same data
+        SqliteTarget::Memory => SqliteConnectOptions::from_str("sqlite::memory:")?.shared_cache(true),
+        SqliteTarget::File(path) => SqliteConnectOptions::from_str(database_url)?
+            .filename(path)
+            .create_if_missing(true)
+            .journal_mode(settings.sqlite_journal_mode),
+    };
+    let opts = opts
+        .foreign_keys(true)
+        .synchronous(settings.sqlite_synchronous)
+        .busy_timeout(settings.sqlite_busy_timeout);
+
+    let mut pool_opts = SqlitePoolOptions::new()
+        .max_connections(settings.max_connections)
+        .acquire_timeout(settings.acquire_timeout);
+    if target == SqliteTarget::Memory {
+        // an in-memory database is dropped with its last connection
+        pool_opts = pool_opts.min_connections(1).idle_timeout(None).max_lifetime(None);
+    }
+    let pool = pool_opts.connect_with(opts).await?;
+    Ok(DbPool::Sqlite(pool))
+}
+
+static SQLITE_MIGRATIONS: Migrator = sqlx::migrate!("migrations/sqlite");
+static POSTGRES_MIGRATIONS: Migrator = sqlx::migrate!("migrations/postgres");
+
+/// `jobs` columns added after the first release, as (name, SQLite type, Postgres type).
+/// Databases set up by `--init-db` before migrations existed may lack some of them.
+const LEGACY_JOB_COLUMNS: &[(&str, &str, &str)] = &[
+    ("priority", "INTEGER NOT NULL DEFAULT 0", "INTEGER NOT NULL DEFAULT 0"),
+    ("dry_run", "INTEGER NOT NULL DEFAULT 0", "BOOLEAN NOT NULL DEFAULT FALSE"),
+    ("pre_remove", "INTEGER", "BOOLEAN"),
+    ("post_remove", "INTEGER", "BOOLEAN"),
+    ("expected_digest", "TEXT", "TEXT"),
+    ("require_digest_match", "INTEGER NOT NULL DEFAULT 0", "BOOLEAN NOT NULL DEFAULT FALSE"),
+    ("callback_url", "TEXT", "TEXT"),
+    ("max_attempts", "INTEGER NOT NULL DEFAULT 3", "BIGINT NOT NULL DEFAULT 3"),
+    ("next_attempt_at", "TEXT", "TIMESTAMPTZ"),
+    ("lease_expires_at", "TEXT", "TIMESTAMPTZ"),
+    ("request_id", "TEXT", "TEXT"),
+    ("traceparent", "TEXT", "TEXT"),
+];
+
+async fn table_exists(pool: &DbPool, table: &str) -> Result<bool, sqlx::Error> {
+    on_pool!(
+        pool,
+        "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?)",
+        "SELECT EXISTS (SELECT 1 FROM information_schema.tables WHERE table_schema = current_schema() AND table_name = $1)",
+        |p, sql| sqlx::query_scalar::<_, bool>(sql).bind(table).fetch_one(p).await
+    )
+}
+
+/// Bring a pre-migrations database up to the `0001_baseline` schema: add the
+/// `jobs` columns it misses, the baseline's `IF NOT EXISTS` covers the rest.
+/// Constraints of existing tables are not changed (SQLite can't add them).
+async fn upgrade_legacy_schema(pool: &DbPool) -> Result<(), sqlx::Error> {
+    let existing: Vec<String> = on_pool!(
+        pool,
+        "SELECT name FROM pragma_table_info('jobs')",
+        "SELECT column_name::TEXT FROM information_schema.columns WHERE table_schema = current_schema() AND table_name = 'jobs'",
+        |p, sql| sqlx::query_scalar(sql).fetch_all(p).await?
+    );
+    for (name, sqlite_type, pg_type) in LEGACY_JOB_COLUMNS {
+        if existing.iter().any(|c| c == name) {
+            continue;
+        }
+        info!("📦 legacy schema: adding jobs.{}", name);
+        on_pool!(
+            pool,
+            format!("ALTER TABLE jobs ADD COLUMN {} {}", name, sqlite_type),
+            format!("ALTER TABLE jobs ADD COLUMN {} {}", name, pg_type),
+            |p, sql| sqlx::query(&sql).execute(p).await.map(|_| ())?
         );
-        "#,
+    }
+    Ok(())
+}
+
+/// Apply pending migrations from `migrations/` (startup and `--init-db`).
+///
+/// A database created before migrations existed has tables but no
+/// `_sqlx_migrations`; it is patched up to the baseline first, which then
+/// applies as a no-op and is recorded like on a fresh database.
+pub async fn init_db(pool: &DbPool) -> Result<(), sqlx::Error> {
+    if !table_exists(pool, "_sqlx_migrations").await? && table_exists(pool, "jobs").await? {
+        info!("📦 database predates migrations, upgrading it to the baseline schema");
+        upgrade_legacy_schema(pool).await?;
+    }
+    match pool {
+        DbPool::Sqlite(p) => SQLITE_MIGRATIONS.run(p).await?,
+        DbPool::Postgres(p) => POSTGRES_MIGRATIONS.run(p).await?,
+    }
+    Ok(())
+}
+
+/// Trivial round-trip used by the readiness probe
+pub async fn ping(pool: &DbPool) -> Result<(), sqlx::Error> {
+    on_pool!(
+        pool,
+        "SELECT 1",
+        "SELECT 1",
+        |p, sql| sqlx::query(sql).execute(p).await.map(|_| ())
+    )
+}
+
+//
+// ---------------------- Jobs API ----------------------
+//
+
+/// Insert a new job (queued), with optional per-job registry credentials
+/// `(username, password)` written in the same transaction.
+/// Higher `priority` is claimed first.
+///
+/// With `dedupe`, an active (`queued`/`running`) job for the same image is
+/// returned instead and nothing is inserted. The lookup and the insert share
+/// one transaction that is serialized per image (`BEGIN IMMEDIATE` on SQLite,
+/// an advisory lock on the image on Postgres), so concurrent identical
+/// requests coalesce onto one job. Jobs created without `dedupe` (or via the
+/// batch endpoint) are not serialized and can still duplicate an active one.
+pub async fn insert_job(
+    pool: &DbPool,
+    job: &NewJob<'_>,
+    dedupe: bool,
+) -> Result<Option<DbJobListItem>, sqlx::Error> {
+    let NewJob { id, image, priority, ref options, credentials, request_id, traceparent, metadata_json } = *job;
+    let registry = parse_registry(image);
+    on_pool!(
+        pool,
+        (
+            "BEGIN IMMEDIATE",
+            None,
+            FIND_ACTIVE_JOB_SQLITE,
+            "INSERT INTO jobs (id, image, status, priority, dry_run, pre_remove, post_remove, \
+                               expected_digest, require_digest_match, callback_url, request_id, traceparent, \
+                               metadata_json, skip_if_pulled_within_secs, registry) \
+             VALUES (?, ?, 'queued', ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            "INSERT INTO job_credentials (job_id, username, password_b64) VALUES (?, ?, ?)",
+        ),
+        (
+            "BEGIN",
+            Some("SELECT pg_advisory_xact_lock(hashtext($1))"),
+            FIND_ACTIVE_JOB_PG,
+            "INSERT INTO jobs (id, image, status, priority, dry_run, pre_remove, post_remove, \
+                               expected_digest, require_digest_match, callback_url, request_id, traceparent, \
+                               metadata_json, skip_if_pulled_within_secs, registry) \
+             VALUES ($1, $2, 'queued', $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14)",
+            "INSERT INTO job_credentials (job_id, username, password_b64) VALUES ($1, $2, $3)",
+        ),
+        |p, sql| {
+            let (begin_sql, lock_sql, find_sql, job_sql, cred_sql) = sql;
+            let mut tx = if dedupe { p.begin_with(begin_sql).await? } else { p.begin().await? };
+            if dedupe {
+                if let Some(lock_sql) = lock_sql {
+                    sqlx::query(lock_sql).bind(image).execute(&mut *tx).await?;
+                }
+                let existing = sqlx::query_as::<_, DbJobListItem>(find_sql)
+                    .bind(image)
+                    .fetch_optional(&mut *tx)
+                    .await?;
+                if existing.is_some() {
+                    tx.rollback().await?;
+                    return Ok(existing);
+                }
+            }
+            sqlx::query(job_sql)
+                .bind(id)
+                .bind(image)
+                .bind(priority)
+                .bind(options.dry_run)
+                .bind(options.pre_remove)
+                .bind(options.post_remove)
+                .bind(options.expected_digest.as_deref())
+                .bind(options.require_digest_match)
+                .bind(options.callback_url.as_deref())
+                .bind(request_id)
+                .bind(traceparent)
+                .bind(metadata_json)
+                .bind(options.skip_if_pulled_within_secs)
+                .bind(&registry)
+                .execute(&mut *tx)
+                .await?;
+            if let Some((username, password)) = credentials {
+                sqlx::query(cred_sql)
+                    .bind(id)
+                    .bind(username)
+                    .bind(STANDARD.encode(password))
+                    .execute(&mut *tx)
+                    .await?;
+            }
+            tx.commit().await?;
+            Ok(None)
+        }
+    )
+}
+
+const FIND_ACTIVE_JOB_SQLITE: &str = "SELECT id, image, status FROM jobs \
+     WHERE image = ? AND status IN ('queued', 'running') ORDER BY created_at ASC LIMIT 1";
+const FIND_ACTIVE_JOB_PG: &str = "SELECT id, image, status FROM jobs \
+     WHERE image = $1 AND status IN ('queued', 'running') ORDER BY created_at ASC LIMIT 1";
+
+/// Oldest `queued`/`running` job for exactly `image`, if any
+pub async fn find_active_job_by_image(pool: &DbPool, image: &str) -> Result<Option<DbJobListItem>, sqlx::Error> {
+    on_pool!(
+        pool,
+        FIND_ACTIVE_JOB_SQLITE,
+        FIND_ACTIVE_JOB_PG,
+        |p, sql| {
+            sqlx::query_as::<_, DbJobListItem>(sql)
+                .bind(image)
+                .fetch_optional(p)
+                .await
+        }
     )
-    .execute(pool)
-    .await?;
+}
 
-    // Metrics
-    sqlx::query(
+/// A completed pull that lets a `skip_if_pulled_within_secs` job skip its own
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct RecentPull {
+    pub job_id: String,
+    /// Digest the pull reported, if any
+    pub digest: Option<String>,
+    /// Seconds since it finished
+    pub age_secs: i64,
+}
+
+/// Latest job other than `job_id` that really pulled `image` (not a dry run and
+/// not itself skipped, i.e. it has a `layers_observed` row), completed and
+/// finished at most `within_secs` ago. With `digest`, that pull must have
+/// resolved to it (compared case-insensitively).
+pub async fn find_recent_pull(
+    pool: &DbPool,
+    job_id: &str,
+    image: &str,
+    digest: Option<&str>,
+    within_secs: i64,
+) -> Result<Option<RecentPull>, sqlx::Error> {
+    on_pool!(
+        pool,
         r#"
-        CREATE TABLE IF NOT EXISTS metrics (
-            id           INTEGER PRIMARY KEY AUTOINCREMENT,
-            job_id       TEXT NOT NULL,
-            key          TEXT NOT NULL,
-            value        REAL NOT NULL,
-            unit         TEXT,
-            labels_json  TEXT,
-            created_at   TEXT NOT NULL DEFAULT (datetime('now'))
-        );
+        SELECT j.id AS job_id,
+               json_extract(m.labels_json, '$.digest') AS digest,
+               CAST(strftime('%s', 'now') AS INTEGER) - CAST(strftime('%s', j.finished_at) AS INTEGER) AS age_secs
+          FROM jobs j
+          JOIN metrics m ON m.job_id = j.id AND m.key = 'layers_observed'
+         WHERE j.image = ?1
+           AND j.id <> ?2
+           AND j.status = 'completed'
+           AND j.dry_run = 0
+           AND j.finished_at >= datetime('now', '-' || ?3 || ' seconds')
+           AND (?4 IS NULL OR lower(json_extract(m.labels_json, '$.digest')) = lower(?4))
+      ORDER BY j.finished_at DESC
+         LIMIT 1
         "#,
+        r#"
+        SELECT j.id AS job_id,
+               m.labels_json::jsonb ->> 'digest' AS digest,
+               extract(epoch FROM now() - j.finished_at)::BIGINT AS age_secs
+          FROM jobs j
+          JOIN metrics m ON m.job_id = j.id AND m.key = 'layers_observed'
+         WHERE j.image = $1
+           AND j.id <> $2
+           AND j.status = 'completed'
+           AND NOT j.dry_run
+           AND j.finished_at >= now() - make_interval(secs => $3::DOUBLE PRECISION)
+           AND ($4::TEXT IS NULL OR lower(m.labels_json::jsonb ->> 'digest') = lower($4))
+      ORDER BY j.finished_at DESC
+         LIMIT 1
+        "#,
+        |p, sql| sqlx::query_as::<_, RecentPull>(sql)
+            .bind(image)
+            .bind(job_id)
+            .bind(within_secs)
+            .bind(digest)
+            .fetch_optional(p)
+            .await
+    )
+}
+
+/// Insert many queued jobs `(id, image)` in a single transaction
+pub async fn insert_jobs_batch(
+    pool: &DbPool,
+    jobs: &[(&str, &str)],
+    priority: i32,
+    request_id: Option<&str>,
+    traceparent: Option<&str>,
+) -> Result<(), sqlx::Error> {
+    on_pool!(
+        pool,
+        "INSERT INTO jobs (id, image, status, priority, request_id, traceparent, registry) \
+         VALUES (?, ?, 'queued', ?, ?, ?, ?)",
+        "INSERT INTO jobs (id, image, status, priority, request_id, traceparent, registry) \
+         VALUES ($1, $2, 'queued', $3, $4, $5, $6)",
+        |p, sql| {
+            let mut tx = p.begin().await?;
+            for (id, image) in jobs {
+                sqlx::query(sql)
+                    .bind(*id)
+                    .bind(*image)
+                    .bind(priority)
+                    .bind(request_id)
+                    .bind(traceparent)
+                    .bind(parse_registry(image))
+                    .execute(&mut *tx)
+                    .await?;
+            }
+            tx.commit().await
+        }
     )
-    .execute(pool)
-    .await?;
+}
 
-    // Helpful index
-    sqlx::query(
+/// `callback_url` the job was created with
+pub async fn get_callback_url(pool: &DbPool, job_id: &str) -> Result<Option<String>, sqlx::Error> {
+    let url: Option<Option<String>> = on_pool!(
+        pool,
+        "SELECT callback_url FROM jobs WHERE id = ?",
+        "SELECT callback_url FROM jobs WHERE id = $1",
+        |p, sql| sqlx::query_scalar(sql).bind(job_id).fetch_optional(p).await
+    )?;
+    Ok(url.flatten())
+}
+
+/// Per-job credentials as `(username, password)`
+pub async fn get_job_credentials(pool: &DbPool, job_id: &str) -> Result<Option<(String, String)>, sqlx::Error> {
+    let row: Option<(String, String)> = on_pool!(
+        pool,
+        "SELECT username, password_b64 FROM job_credentials WHERE job_id = ?",
+        "SELECT username, password_b64 FROM job_credentials WHERE job_id = $1",
+        |p, sql| sqlx::query_as(sql).bind(job_id).fetch_optional(p).await
+    )?;
+
+    Ok(row.and_then(|(username, b64)| {
+        let password = String::from_utf8(STANDARD.decode(b64).ok()?).ok()?;
+        Some((username, password))
+    }))
+}
+
+/// What `GET /jobs` narrows the list to; every given filter must match
+#[derive(Debug, Default)]
+pub struct JobFilter {
+    pub status: Option<JobStatus>,
+    /// Created at or after
+    pub from: Option<DateTime<Utc>>,
+    /// Created at or before
+    pub to: Option<DateTime<Utc>>,
+    /// `(key, value)` pairs the job's metadata must contain
+    pub metadata: Vec<(String, String)>,
+}
+
+/// Jobs matching `filter`, newest first
+pub async fn list_jobs_filtered(
+    pool: &DbPool,
+    filter: &JobFilter,
+    limit: i64,
+    offset: i64,
+) -> Result<Vec<DbJobListItem>, sqlx::Error> {
+    match pool {
+        DbPool::Sqlite(p) => {
+            let mut qb = QueryBuilder::<Sqlite>::new("SELECT id, image, status FROM jobs WHERE 1 = 1");
+            push_job_filters(&mut qb, filter, sqlite_ts, ("json_extract(metadata_json, '$.' || ", ")"));
+            qb.push(" ORDER BY created_at DESC, id DESC LIMIT ").push_bind(limit);
+            qb.push(" OFFSET ").push_bind(offset);
+            qb.build_query_as::<DbJobListItem>().fetch_all(p).await
+        }
+        DbPool::Postgres(p) => {
+            let mut qb = QueryBuilder::<Postgres>::new("SELECT id, image, status FROM jobs WHERE 1 = 1");
+            push_job_filters(&mut qb, filter, |t| t, ("(metadata_json::jsonb ->> ", ")"));
+            qb.push(" ORDER BY created_at DESC, id DESC LIMIT ").push_bind(limit);
+            qb.push(" OFFSET ").push_bind(offset);
+            qb.build_query_as::<DbJobListItem>().fetch_all(p).await
+        }
+    }
+}
+
+/// Number of jobs [`list_jobs_filtered`] pages through
+pub async fn count_jobs(pool: &DbPool, filter: &JobFilter) -> Result<i64, sqlx::Error> {
+    match pool {
+        DbPool::Sqlite(p) => {
+            let mut qb = QueryBuilder::<Sqlite>::new("SELECT COUNT(*) FROM jobs WHERE 1 = 1");
+            push_job_filters(&mut qb, filter, sqlite_ts, ("json_extract(metadata_json, '$.' || ", ")"));
+            qb.build_query_scalar::<i64>().fetch_one(p).await
+        }
+        DbPool::Postgres(p) => {
+            let mut qb = QueryBuilder::<Postgres>::new("SELECT COUNT(*) FROM jobs WHERE 1 = 1");
+            push_job_filters(&mut qb, filter, |t| t, ("(metadata_json::jsonb ->> ", ")"));
+            qb.build_query_scalar::<i64>().fetch_one(p).await
+        }
+    }
+}
+
+/// SQLite stores `datetime('now')` text, which compares as a string
+fn sqlite_ts(t: DateTime<Utc>) -> String {
+    t.format("%Y-%m-%d %H:%M:%S").to_string()
+}
+
+/// `AND ...` for each set field of `filter`. Timestamps are bound as `ts(t)`;
+/// a metadata key is bound between the two halves of `metadata_expr`.
+fn push_job_filters<'a, DB, T>(
+    qb: &mut QueryBuilder<'a, DB>,
+    filter: &'a JobFilter,
+    ts: fn(DateTime<Utc>) -> T,
+    metadata_expr: (&str, &str),
+) where
+    DB: sqlx::Database,
+    &'a str: sqlx::Encode<'a, DB> + sqlx::Type<DB>,
+    T: 'a + sqlx::Encode<'a, DB> + sqlx::Type<DB>,
+{
+    if let Some(status) = filter.status {
+        qb.push(" AND status = ").push_bind(status.as_str());
+    }
+    if let Some(from) = filter.from {
+        qb.push(" AND created_at >= ").push_bind(ts(from));
+    }
+    if let Some(to) = filter.to {
+        qb.push(" AND created_at <= ").push_bind(ts(to));
+    }
+    for (key, value) in &filter.metadata {
+        qb.push(" AND ")
+            .push(metadata_expr.0)
+            .push_bind(key.as_str())
+            .push(metadata_expr.1)
+            .push(" = ")
+            .push_bind(value.as_str());
+    }
+}
+
+/// `needle` as a LIKE pattern matching it anywhere, `%` and `_` taken literally
+fn like_contains(needle: &str) -> String {
+    let escaped = needle.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_");
+    format!("%{}%", escaped)
+}
+
+/// Jobs whose image contains `needle` (case-insensitive), newest first
+pub async fn search_jobs_by_image(
+    pool: &DbPool,
+    needle: &str,
+    limit: i64,
+    offset: i64,
+) -> Result<Vec<DbJobListItem>, sqlx::Error> {
+    let pattern = like_contains(needle);
+    on_pool!(
+        pool,
         r#"
-        CREATE INDEX IF NOT EXISTS idx_metrics_job_created
-            ON metrics(job_id, created_at DESC);
+        SELECT id, image, status
+          FROM jobs
+         WHERE image LIKE ? ESCAPE '\'
+      ORDER BY created_at DESC, id DESC
+         LIMIT ? OFFSET ?
+        "#,
+        r#"
+        SELECT id, image, status
+          FROM jobs
+         WHERE image ILIKE $1 ESCAPE '\'
+      ORDER BY created_at DESC, id DESC
+         LIMIT $2 OFFSET $3
         "#,
+        |p, sql| sqlx::query_as::<_, DbJobListItem>(sql)
+            .bind(&pattern)
+            .bind(limit)
+            .bind(offset)
+            .fetch_all(p)
+            .await
     )
-    .execute(pool)
-    .await?;
+}
 
-    Ok(())
+pub async fn count_jobs_by_image(pool: &DbPool, needle: &str) -> Result<i64, sqlx::Error> {
+    let pattern = like_contains(needle);
+    on_pool!(
+        pool,
+        r"SELECT COUNT(*) FROM jobs WHERE image LIKE ? ESCAPE '\'",
+        r"SELECT COUNT(*) FROM jobs WHERE image ILIKE $1 ESCAPE '\'",
+        |p, sql| sqlx::query_scalar::<_, i64>(sql).bind(&pattern).fetch_one(p).await
+    )
 }
 
-//
-// ---------------------- Jobs API ----------------------
-//
+/// `(status, count)` for every status that has at least one job
+pub async fn count_jobs_by_status(pool: &DbPool) -> Result<Vec<(String, i64)>, sqlx::Error> {
+    on_pool!(
+        pool,
+        "SELECT status, COUNT(*) FROM jobs GROUP BY status",
+        "SELECT status, COUNT(*) FROM jobs GROUP BY status",
+        |p, sql| sqlx::query_as::<_, (String, i64)>(sql).fetch_all(p).await
+    )
+}
+
+/// Jobs per registry host, for `GET /registries`
+#[derive(Debug, Clone, sqlx::FromRow, serde::Serialize, utoipa::ToSchema)]
+pub struct RegistrySummary {
+    pub registry: String,
+    pub total: i64,
+    pub success: i64,
+    pub failed: i64,
+    pub avg_speed_mbps: Option<f64>,
+}
 
-/// Insert a new job (queued)
-pub async fn insert_job(pool: &SqlitePool, id: &str, image: &str) -> Result<(), sqlx::Error> {
-    sqlx::query(
+/// Job counts and average pull speed per registry. The registry comes from
+/// the `registry_host` label, which only pulls that got to recording
+/// metrics have; every other job counts under `unknown`.
+pub async fn summarize_registries(pool: &DbPool) -> Result<Vec<RegistrySummary>, sqlx::Error> {
+    on_pool!(
+        pool,
         r#"
-        INSERT INTO jobs (id, image, status)
-        VALUES (?, ?, 'queued')
+        SELECT COALESCE(r.registry, 'unknown') AS registry,
+               COUNT(*) AS total,
+               SUM(CASE WHEN j.status = 'completed' THEN 1 ELSE 0 END) AS success,
+               SUM(CASE WHEN j.status = 'failed' THEN 1 ELSE 0 END) AS failed,
+               AVG(s.speed) AS avg_speed_mbps
+          FROM jobs j
+     LEFT JOIN (SELECT job_id, MAX(json_extract(labels_json, '$.registry_host')) AS registry
+                  FROM metrics
+                 WHERE labels_json IS NOT NULL
+              GROUP BY job_id) r ON r.job_id = j.id
+     LEFT JOIN (SELECT job_id, AVG(value) AS speed
+                  FROM metrics
+                 WHERE key = 'average_speed_mbps'
+              GROUP BY job_id) s ON s.job_id = j.id
+      GROUP BY COALESCE(r.registry, 'unknown')
+      ORDER BY total DESC, registry
         "#,
+        r#"
+        SELECT COALESCE(r.registry, 'unknown') AS registry,
+               COUNT(*) AS total,
+               COUNT(*) FILTER (WHERE j.status = 'completed') AS success,
+               COUNT(*) FILTER (WHERE j.status = 'failed') AS failed,
+               AVG(s.speed) AS avg_speed_mbps
+          FROM jobs j
+     LEFT JOIN (SELECT job_id, MAX(labels_json::jsonb ->> 'registry_host') AS registry
+                  FROM metrics
+                 WHERE labels_json IS NOT NULL
+              GROUP BY job_id) r ON r.job_id = j.id
+     LEFT JOIN (SELECT job_id, AVG(value) AS speed
+                  FROM metrics
+                 WHERE key = 'average_speed_mbps'
+              GROUP BY job_id) s ON s.job_id = j.id
+      GROUP BY COALESCE(r.registry, 'unknown')
+      ORDER BY total DESC, registry
+        "#,
+        |p, sql| sqlx::query_as::<_, RegistrySummary>(sql).fetch_all(p).await
     )
-    .bind(id)
-    .bind(image)
-    .execute(pool)
-    .await?;
-    Ok(())
 }
 
-/// List jobs (short)
-pub async fn list_jobs(pool: &SqlitePool) -> Result<Vec<DbJobListItem>, sqlx::Error> {
-    let rows = sqlx::query(
+/// One row of `queue_samples`
+#[derive(Debug, Clone, sqlx::FromRow, serde::Serialize, utoipa::ToSchema)]
+pub struct QueueSample {
+    pub sampled_at: String,
+    pub queued: i64,
+    pub running: i64,
+}
+
+/// Record how many jobs are queued and running right now
+pub async fn insert_queue_sample(pool: &DbPool) -> Result<(), sqlx::Error> {
+    on_pool!(
+        pool,
         r#"
-        SELECT id, image, status
+        INSERT INTO queue_samples (queued, running)
+        SELECT COALESCE(SUM(status = 'queued'), 0), COALESCE(SUM(status = 'running'), 0)
+          FROM jobs
+        "#,
+        r#"
+        INSERT INTO queue_samples (queued, running)
+        SELECT COUNT(*) FILTER (WHERE status = 'queued'), COUNT(*) FILTER (WHERE status = 'running')
           FROM jobs
-      ORDER BY created_at DESC
         "#,
+        |p, sql| sqlx::query(sql).execute(p).await.map(|_| ())
     )
-    .fetch_all(pool)
-    .await?;
+}
 
-    let items = rows
-        .into_iter()
-        .map(|r| DbJobListItem {
-            id: r.get::<String, _>("id"),
-            image: r.get::<String, _>("image"),
-            status: r.get::<String, _>("status"),
-        })
-        .collect();
+/// Samples of the last `window_secs`, oldest first
+pub async fn list_queue_samples(pool: &DbPool, window_secs: i64) -> Result<Vec<QueueSample>, sqlx::Error> {
+    on_pool!(
+        pool,
+        r#"
+        SELECT sampled_at, queued, running
+          FROM queue_samples
+         WHERE sampled_at >= datetime('now', '-' || ? || ' seconds')
+      ORDER BY sampled_at ASC, id ASC
+        "#,
+        r#"
+        SELECT ts_text(sampled_at) AS sampled_at, queued, running
+          FROM queue_samples
+         WHERE sampled_at >= now() - make_interval(secs => $1::DOUBLE PRECISION)
+      ORDER BY queue_samples.sampled_at ASC, id ASC
+        "#,
+        |p, sql| sqlx::query_as::<_, QueueSample>(sql).bind(window_secs).fetch_all(p).await
+    )
+}
 
-    Ok(items)
+/// Delete samples taken before `cutoff`, same retention as `purge_old_jobs`
+pub async fn purge_old_queue_samples(pool: &DbPool, cutoff: DateTime<Utc>) -> Result<u64, sqlx::Error> {
+    on_pool!(
+        pool,
+        (
+            "DELETE FROM queue_samples WHERE sampled_at < ?",
+            cutoff.format("%Y-%m-%d %H:%M:%S").to_string(),
+        ),
+        ("DELETE FROM queue_samples WHERE sampled_at < $1", cutoff),
+        |p, sql| {
+            let (sql, cutoff) = sql;
+            sqlx::query(sql).bind(cutoff).execute(p).await.map(|r| r.rows_affected())
+        }
+    )
 }
 
 /// Get job detail
-pub async fn get_job_by_id(pool: &SqlitePool, id: &str) -> Result<Option<DbJobDetail>, sqlx::Error> {
-    let row = sqlx::query(
+pub async fn get_job_by_id(pool: &DbPool, id: &str) -> Result<Option<DbJobDetail>, sqlx::Error> {
+    on_pool!(
+        pool,
         r#"
-        SELECT id, image, status, result, error_detail, retry_count, created_at, finished_at
+        SELECT id, image, status, result, error_detail, retry_count, max_attempts, priority,
+               created_at, started_at, finished_at, COALESCE(updated_at, created_at) AS updated_at,
+               lease_expires_at, last_heartbeat, bytes_downloaded, metadata_json, progress_pct,
+               (CAST(strftime('%s', finished_at) AS INTEGER) - CAST(strftime('%s', started_at) AS INTEGER)) * 1000
+                   AS duration_ms
           FROM jobs
          WHERE id = ?
         "#,
+        r#"
+        SELECT id, image, status, result, error_detail, retry_count, max_attempts, priority,
+               ts_text(created_at) AS created_at, ts_text(started_at) AS started_at,
+               ts_text(finished_at) AS finished_at, ts_text(COALESCE(updated_at, created_at)) AS updated_at,
+               ts_text(lease_expires_at) AS lease_expires_at, ts_text(last_heartbeat) AS last_heartbeat,
+               bytes_downloaded, metadata_json, progress_pct,
+               (extract(epoch FROM finished_at - started_at) * 1000)::BIGINT AS duration_ms
+          FROM jobs
+         WHERE id = $1
+        "#,
+        |p, sql| sqlx::query_as::<_, DbJobDetail>(sql).bind(id).fetch_optional(p).await
     )
-    .bind(id)
-    .fetch_optional(pool)
-    .await?;
-
-    Ok(row.map(|r| DbJobDetail {
-        id: r.get("id"),
-        image: r.get("image"),
-        status: r.get("status"),
-        result: r.get("result"),
-        error_detail: r.get("error_detail"),
-        retry_count: r.get("retry_count"),
-        created_at: r.get("created_at"),
-        finished_at: r.get("finished_at"),
-    }))
 }
 
-/// Update status; if completed/failed, set finished_at
+/// Tables with rows per job. Those rows are deleted along with the job rather
+/// than left to ON DELETE CASCADE, which databases upgraded from the
+/// pre-migration schema don't have on `metrics`.
+const JOB_CHILD_TABLES: &[&str] = &["metrics", "job_logs", "job_credentials"];
+
+/// Delete a job with its metrics, logs and credentials.
+/// Returns false when no job has that id.
+pub async fn delete_job(pool: &DbPool, id: &str) -> Result<bool, sqlx::Error> {
+    let affected = on_pool!(
+        pool,
+        ("job_id = ?", "DELETE FROM jobs WHERE id = ?"),
+        ("job_id = $1", "DELETE FROM jobs WHERE id = $1"),
+        |p, sql| {
+            let (child_match, job_sql) = sql;
+            let mut tx = p.begin().await?;
+            for table in JOB_CHILD_TABLES {
+                sqlx::query(&format!("DELETE FROM {} WHERE {}", table, child_match))
+                    .bind(id)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+            let affected = sqlx::query(job_sql).bind(id).execute(&mut *tx).await?.rows_affected();
+            tx.commit().await?;
+            Ok::<_, sqlx::Error>(affected)
+        }
+    )?;
+    Ok(affected > 0)
+}
+
+/// Put a finished job back in the queue. retry_count is kept as history.
+/// Returns false when the job doesn't exist or isn't completed/failed/canceled.
+pub async fn requeue_job(pool: &DbPool, id: &str) -> Result<bool, sqlx::Error> {
+    let affected = on_pool!(
+        pool,
+        r#"
+        UPDATE jobs
+           SET status = 'queued',
+               error_detail = NULL,
+               finished_at = NULL,
+               next_attempt_at = NULL,
+               lease_expires_at = NULL,
+               started_at = NULL,
+               last_heartbeat = NULL,
+               updated_at = datetime('now')
+         WHERE id = ? AND status IN ('completed', 'failed', 'canceled')
+        "#,
+        r#"
+        UPDATE jobs
+           SET status = 'queued',
+               error_detail = NULL,
+               finished_at = NULL,
+               next_attempt_at = NULL,
+               lease_expires_at = NULL,
+               started_at = NULL,
+               last_heartbeat = NULL,
+               updated_at = now()
+         WHERE id = $1 AND status IN ('completed', 'failed', 'canceled')
+        "#,
+        |p, sql| sqlx::query(sql).bind(id).execute(p).await.map(|r| r.rows_affected())
+    )?;
+    Ok(affected > 0)
+}
+
+/// Delete finished (completed/failed/canceled) jobs that finished before
+/// `cutoff`, with their metrics, logs and credentials.
+/// Returns the number of jobs deleted.
+pub async fn purge_old_jobs(pool: &DbPool, cutoff: DateTime<Utc>) -> Result<u64, sqlx::Error> {
+    on_pool!(
+        pool,
+        (
+            "status IN ('completed', 'failed', 'canceled') AND COALESCE(finished_at, created_at) < ?",
+            // SQLite stores `datetime('now')` text, which compares as a string
+            cutoff.format("%Y-%m-%d %H:%M:%S").to_string(),
+        ),
+        (
+            "status IN ('completed', 'failed', 'canceled') AND COALESCE(finished_at, created_at) < $1",
+            cutoff,
+        ),
+        |p, sql| {
+            let (old_finished, cutoff) = &sql;
+            let mut tx = p.begin().await?;
+            for table in JOB_CHILD_TABLES {
+                sqlx::query(&format!(
+                    "DELETE FROM {} WHERE job_id IN (SELECT id FROM jobs WHERE {})",
+                    table, old_finished
+                ))
+                .bind(cutoff)
+                .execute(&mut *tx)
+                .await?;
+            }
+            let deleted = sqlx::query(&format!("DELETE FROM jobs WHERE {}", old_finished))
+                .bind(cutoff)
+                .execute(&mut *tx)
+                .await?
+                .rows_affected();
+            tx.commit().await?;
+            Ok(deleted)
+        }
+    )
+}
+
+/// Mark a queued or running job `canceled`. Returns false when the job doesn't
+/// exist or has already finished. The worker's own status writes skip canceled
+/// jobs, so a pull that is still winding down can't overwrite this.
+pub async fn cancel_job(pool: &DbPool, id: &str) -> Result<bool, sqlx::Error> {
+    let affected = on_pool!(
+        pool,
+        r#"
+        UPDATE jobs
+           SET status = 'canceled',
+               finished_at = datetime('now'),
+               next_attempt_at = NULL,
+               lease_expires_at = NULL,
+               updated_at = datetime('now')
+         WHERE id = ? AND status IN ('queued', 'running')
+        "#,
+        r#"
+        UPDATE jobs
+           SET status = 'canceled',
+               finished_at = now(),
+               next_attempt_at = NULL,
+               lease_expires_at = NULL,
+               updated_at = now()
+         WHERE id = $1 AND status IN ('queued', 'running')
+        "#,
+        |p, sql| sqlx::query(sql).bind(id).execute(p).await.map(|r| r.rows_affected())
+    )?;
+    Ok(affected > 0)
+}
+
+/// Update status; if completed/failed, set finished_at and drop the lease. Canceled jobs are left alone.
 pub async fn update_job_status(
-    pool: &SqlitePool,
+    pool: &DbPool,
     id: &str,
-    status: &str,
+    status: JobStatus,
     result: Option<&str>,
 ) -> Result<(), sqlx::Error> {
-    sqlx::query(
+    let status = status.as_str();
+    on_pool!(
+        pool,
         r#"
         UPDATE jobs
-           SET status = ?,
-               result = COALESCE(?, result),
-               finished_at = CASE WHEN ? IN ('completed', 'failed')
+           SET status = ?1,
+               result = COALESCE(?2, result),
+               finished_at = CASE WHEN ?1 IN ('completed', 'failed')
                                   THEN datetime('now')
                                   ELSE finished_at
-                             END
-         WHERE id = ?
+                             END,
+               lease_expires_at = CASE WHEN ?1 IN ('completed', 'failed')
+                                       THEN NULL
+                                       ELSE lease_expires_at
+                                  END,
+               updated_at = datetime('now')
+         WHERE id = ?3 AND status <> 'canceled'
+        "#,
+        r#"
+        UPDATE jobs
+           SET status = $1,
+               result = COALESCE($2, result),
+               finished_at = CASE WHEN $1 IN ('completed', 'failed')
+                                  THEN now()
+                                  ELSE finished_at
+                             END,
+               lease_expires_at = CASE WHEN $1 IN ('completed', 'failed')
+                                       THEN NULL
+                                       ELSE lease_expires_at
+                                  END,
+               updated_at = now()
+         WHERE id = $3 AND status <> 'canceled'
         "#,
+        |p, sql| sqlx::query(sql)
+            .bind(status)
+            .bind(result)
+            .bind(id)
+            .execute(p)
+            .await
+            .map(|_| ())
     )
-    .bind(status)
-    .bind(result)
-    .bind(status)
-    .bind(id)
-    .execute(pool)
-    .await?;
-    Ok(())
 }
 
 /// Set error_detail; optionally mark failed
 pub async fn set_job_error(
-    pool: &SqlitePool,
+    pool: &DbPool,
     id: &str,
     error_detail: &str,
     mark_failed: bool,
 ) -> Result<(), sqlx::Error> {
     if mark_failed {
-        sqlx::query(
+        on_pool!(
+            pool,
             r#"
             UPDATE jobs
                SET error_detail = ?,
                    status = 'failed',
-                   finished_at = COALESCE(finished_at, datetime('now'))
-             WHERE id = ?
+                   finished_at = COALESCE(finished_at, datetime('now')),
+                   lease_expires_at = NULL,
+                   updated_at = datetime('now')
+             WHERE id = ? AND status <> 'canceled'
             "#,
+            r#"
+            UPDATE jobs
+               SET error_detail = $1,
+                   status = 'failed',
+                   finished_at = COALESCE(finished_at, now()),
+                   lease_expires_at = NULL,
+                   updated_at = now()
+             WHERE id = $2 AND status <> 'canceled'
+            "#,
+            |p, sql| sqlx::query(sql).bind(error_detail).bind(id).execute(p).await.map(|_| ())
         )
-        .bind(error_detail)
-        .bind(id)
-        .execute(pool)
-        .await?;
     } else {
-        sqlx::query(
+        on_pool!(
+            pool,
             r#"
             UPDATE jobs
-               SET error_detail = ?
+               SET error_detail = ?,
+                   updated_at = datetime('now')
              WHERE id = ?
             "#,
+            r#"
+            UPDATE jobs
+               SET error_detail = $1,
+                   updated_at = now()
+             WHERE id = $2
+            "#,
+            |p, sql| sqlx::query(sql).bind(error_detail).bind(id).execute(p).await.map(|_| ())
         )
-        .bind(error_detail)
-        .bind(id)
-        .execute(pool)
-        .await?;
     }
-    Ok(())
 }
 
-pub async fn complete_job(pool: &SqlitePool, id: &str, result: Option<&str>) -> Result<(), sqlx::Error> {
-    update_job_status(pool, id, "completed", result).await
+/// Record a failed attempt. `max_attempts` counts total attempts, so the job
+/// goes back to `queued` while `retry_count + 1 < max_attempts` and becomes a
+/// terminal `failed` once they are used up. Retries are delayed through
+/// `next_attempt_at` by 5s * 2^retry_count, capped at 300s. Returns the resulting status,
+/// or `None` if the job was canceled (or deleted) meanwhile.
+pub async fn fail_or_retry_job(
+    pool: &DbPool,
+    id: &str,
+    error_detail: &str,
+) -> Result<Option<JobStatus>, sqlx::Error> {
+    let status = on_pool!(
+        pool,
+        r#"
+        UPDATE jobs
+           SET retry_count = retry_count + 1,
+               error_detail = ?,
+               status = CASE WHEN retry_count + 1 < max_attempts
+                             THEN 'queued'
+                             ELSE 'failed'
+                        END,
+               finished_at = CASE WHEN retry_count + 1 < max_attempts
+                                  THEN NULL
+                                  ELSE datetime('now')
+                             END,
+               next_attempt_at = CASE WHEN retry_count + 1 < max_attempts
+                                      THEN datetime('now', '+' || MIN(300, 5 << MIN(retry_count, 6)) || ' seconds')
+                                      ELSE NULL
+                                 END,
+               lease_expires_at = NULL,
+               updated_at = datetime('now')
+         WHERE id = ? AND status <> 'canceled'
+     RETURNING status
+        "#,
+        r#"
+        UPDATE jobs
+           SET retry_count = retry_count + 1,
+               error_detail = $1,
+               status = CASE WHEN retry_count + 1 < max_attempts
+                             THEN 'queued'
+                             ELSE 'failed'
+                        END,
+               finished_at = CASE WHEN retry_count + 1 < max_attempts
+                                  THEN NULL
+                                  ELSE now()
+                             END,
+               next_attempt_at = CASE WHEN retry_count + 1 < max_attempts
+                                      THEN now() + make_interval(
+                                               secs => LEAST(300, 5::BIGINT << LEAST(retry_count, 6)::INT)::DOUBLE PRECISION)
+                                      ELSE NULL
+                                 END,
+               lease_expires_at = NULL,
+               updated_at = now()
+         WHERE id = $2 AND status <> 'canceled'
+     RETURNING status
+        "#,
+        |p, sql| sqlx::query_scalar::<_, String>(sql)
+            .bind(error_detail)
+            .bind(id)
+            .fetch_optional(p)
+            .await
+    )?;
+    status
+        .map(|s| s.parse().map_err(|e: String| sqlx::Error::Decode(e.into())))
+        .transpose()
 }
 
-/// Optimistic claim: read one queued, then flip to running if still queued.
+/// Mark a job completed. `bytes_downloaded` is what its pull downloaded (`None`
+/// when nothing was pulled); the duration is `finished_at - started_at`.
+pub async fn complete_job(
+    pool: &DbPool,
+    id: &str,
+    result: Option<&str>,
+    bytes_downloaded: Option<u64>,
+) -> Result<(), sqlx::Error> {
+    update_job_status(pool, id, JobStatus::Completed, result).await?;
+    let Some(bytes) = bytes_downloaded else {
+        return Ok(());
+    };
+    let bytes = i64::try_from(bytes).unwrap_or(i64::MAX);
+    on_pool!(
+        pool,
+        "UPDATE jobs SET bytes_downloaded = ? WHERE id = ? AND status = 'completed'",
+        "UPDATE jobs SET bytes_downloaded = $1 WHERE id = $2 AND status = 'completed'",
+        |p, sql| sqlx::query(sql).bind(bytes).bind(id).execute(p).await.map(|_| ())
+    )
+}
+
+/// Claim the highest-priority (then oldest) queued job whose retry delay (if any) has passed and flip it to running.
+/// Only `queued` rows are considered, so canceled jobs are never picked up.
+/// Jobs of a registry in `saturated` (every per-registry slot taken) are passed
+/// over, so they don't hold a global slot while waiting for their registry;
+/// jobs queued before the `0006` migration have no registry and never are.
+/// The claim holds a lease of `lease_secs`; the worker extends it via `heartbeat_job`.
 pub async fn claim_next_job(
+    pool: &DbPool,
+    lease_secs: i64,
+    saturated: &[String],
+) -> Result<Option<ClaimedJob>, sqlx::Error> {
+    match pool {
+        DbPool::Sqlite(p) => claim_next_job_sqlite(p, lease_secs, saturated).await,
+        DbPool::Postgres(p) => {
+            // Single statement; SKIP LOCKED keeps concurrent claimers off the same row.
+            let mut qb = QueryBuilder::<Postgres>::new(
+                r#"
+                UPDATE jobs
+                   SET status = 'running',
+                       lease_expires_at = now() + make_interval(secs => "#,
+            );
+            qb.push_bind(lease_secs).push(
+                r#"::DOUBLE PRECISION),
+                       started_at = now(),
+                       last_heartbeat = now(),
+                       progress_pct = NULL,
+                       updated_at = now()
+                 WHERE id = (
+                        SELECT id
+                          FROM jobs
+                         WHERE status = 'queued'
+                           AND (next_attempt_at IS NULL OR next_attempt_at <= now())"#,
+            );
+            push_registry_exclusion(&mut qb, saturated);
+            qb.push(
+                r#"
+                      ORDER BY priority DESC, created_at ASC
+                         LIMIT 1
+                           FOR UPDATE SKIP LOCKED
+                       )
+             RETURNING id, image, retry_count, request_id, traceparent, dry_run, pre_remove, post_remove,
+                       expected_digest, require_digest_match, callback_url, skip_if_pulled_within_secs
+                "#,
+            );
+            qb.build_query_as::<ClaimedJob>().fetch_optional(p).await
+        }
+    }
+}
+
+/// Optimistic claim: read one queued, then flip to running if still queued.
+async fn claim_next_job_sqlite(
     pool: &SqlitePool,
-    _lease_secs: i64,
-) -> Result<Option<(String, String)>, sqlx::Error> {
+    lease_secs: i64,
+    saturated: &[String],
+) -> Result<Option<ClaimedJob>, sqlx::Error> {
     loop {
-        let row_opt = sqlx::query(
+        let mut qb = QueryBuilder::<Sqlite>::new(
             r#"
-            SELECT id, image
+            SELECT id, image, retry_count, request_id, traceparent, dry_run, pre_remove, post_remove,
+                   expected_digest, require_digest_match, callback_url, skip_if_pulled_within_secs
               FROM jobs
              WHERE status = 'queued'
-          ORDER BY created_at ASC
+               AND (next_attempt_at IS NULL OR next_attempt_at <= datetime('now'))"#,
+        );
+        push_registry_exclusion(&mut qb, saturated);
+        qb.push(
+            r#"
+          ORDER BY priority DESC, created_at ASC
              LIMIT 1
             "#,
-        )
-        .fetch_optional(pool)
-        .await?;
+        );
+        let row_opt = qb.build_query_as::<ClaimedJob>().fetch_optional(pool).await?;
 
-        let Some(row) = row_opt else {
+        let Some(job) = row_opt else {
             return Ok(None);
         };
 
-        let id: String = row.get("id");
-        let image: String = row.get("image");
-
         let res = sqlx::query(
             r#"
             UPDATE jobs
-               SET status = 'running'
+               SET status = 'running',
+                   lease_expires_at = datetime('now', '+' || ? || ' seconds'),
+                   started_at = datetime('now'),
+                   last_heartbeat = datetime('now'),
+                   progress_pct = NULL,
+                   updated_at = datetime('now')
              WHERE id = ? AND status = 'queued'
             "#,
         )
-        .bind(&id)
+        .bind(lease_secs)
+        .bind(&job.id)
         .execute(pool)
         .await?;
 
         if res.rows_affected() == 1 {
-            return Ok(Some((id, image)));
+            return Ok(Some(job));
         }
 
         // Lost the race; loop again.
     }
 }
 
-/// No-op heartbeat to keep runner signature compatible
-pub async fn heartbeat_job(_pool: &SqlitePool, _job_id: &str, _lease_secs: i64) -> Result<(), sqlx::Error> {
-    Ok(())
+/// `AND registry NOT IN (..)` for the claim; nothing when no registry is saturated.
+fn push_registry_exclusion<'a, DB>(qb: &mut QueryBuilder<'a, DB>, saturated: &'a [String])
+where
+    DB: sqlx::Database,
+    &'a str: sqlx::Encode<'a, DB> + sqlx::Type<DB>,
+{
+    if saturated.is_empty() {
+        return;
+    }
+    qb.push("\n               AND (registry IS NULL OR registry NOT IN (");
+    let mut hosts = qb.separated(", ");
+    for host in saturated {
+        hosts.push_bind(host.as_str());
+    }
+    qb.push("))");
+}
+
+/// Hand a claimed job back to the queue before its pull started; the attempt
+/// doesn't count. Also stores `registry`, which jobs queued before the `0006`
+/// migration lack, so the next claim can pass it over.
+/// Returns false when the job is no longer running (canceled meanwhile).
+pub async fn release_claim(pool: &DbPool, id: &str, registry: &str) -> Result<bool, sqlx::Error> {
+    let affected = on_pool!(
+        pool,
+        r#"
+        UPDATE jobs
+           SET status = 'queued',
+               registry = ?,
+               lease_expires_at = NULL,
+               started_at = NULL,
+               last_heartbeat = NULL,
+               updated_at = datetime('now')
+         WHERE id = ? AND status = 'running'
+        "#,
+        r#"
+        UPDATE jobs
+           SET status = 'queued',
+               registry = $1,
+               lease_expires_at = NULL,
+               started_at = NULL,
+               last_heartbeat = NULL,
+               updated_at = now()
+         WHERE id = $2 AND status = 'running'
+        "#,
+        |p, sql| sqlx::query(sql).bind(registry).bind(id).execute(p).await.map(|r| r.rows_affected())
+    )?;
+    Ok(affected > 0)
+}
+
+/// Store the download progress of a running job, `None` while unknown.
+pub async fn set_job_progress(pool: &DbPool, id: &str, progress_pct: Option<i32>) -> Result<(), sqlx::Error> {
+    on_pool!(
+        pool,
+        r#"
+        UPDATE jobs
+           SET progress_pct = ?,
+               updated_at = datetime('now')
+         WHERE id = ? AND status = 'running'
+        "#,
+        r#"
+        UPDATE jobs
+           SET progress_pct = $1,
+               updated_at = now()
+         WHERE id = $2 AND status = 'running'
+        "#,
+        |p, sql| sqlx::query(sql).bind(progress_pct).bind(id).execute(p).await.map(|_| ())
+    )
+}
+
+/// Extend the lease of a running job by `lease_secs` from now
+pub async fn heartbeat_job(pool: &DbPool, job_id: &str, lease_secs: i64) -> Result<(), sqlx::Error> {
+    on_pool!(
+        pool,
+        r#"
+        UPDATE jobs
+           SET lease_expires_at = datetime('now', '+' || ? || ' seconds'),
+               last_heartbeat = datetime('now'),
+               updated_at = datetime('now')
+         WHERE id = ? AND status = 'running'
+        "#,
+        r#"
+        UPDATE jobs
+           SET lease_expires_at = now() + make_interval(secs => $1::DOUBLE PRECISION),
+               last_heartbeat = now(),
+               updated_at = now()
+         WHERE id = $2 AND status = 'running'
+        "#,
+        |p, sql| sqlx::query(sql).bind(lease_secs).bind(job_id).execute(p).await.map(|_| ())
+    )
+}
+
+/// Running jobs whose lease ran out (worker died or lost the DB) count as a
+/// failed attempt: requeued while attempts remain, otherwise failed. Returns how many were touched.
+pub async fn recover_stale_jobs(pool: &DbPool) -> Result<u64, sqlx::Error> {
+    on_pool!(
+        pool,
+        r#"
+        UPDATE jobs
+           SET retry_count = retry_count + 1,
+               error_detail = 'lease expired (worker stopped heartbeating)',
+               status = CASE WHEN retry_count + 1 < max_attempts
+                             THEN 'queued'
+                             ELSE 'failed'
+                        END,
+               finished_at = CASE WHEN retry_count + 1 < max_attempts
+                                  THEN NULL
+                                  ELSE datetime('now')
+                             END,
+               next_attempt_at = NULL,
+               lease_expires_at = NULL,
+               updated_at = datetime('now')
+         WHERE status = 'running'
+           AND lease_expires_at IS NOT NULL
+           AND lease_expires_at < datetime('now')
+        "#,
+        r#"
+        UPDATE jobs
+           SET retry_count = retry_count + 1,
+               error_detail = 'lease expired (worker stopped heartbeating)',
+               status = CASE WHEN retry_count + 1 < max_attempts
+                             THEN 'queued'
+                             ELSE 'failed'
+                        END,
+               finished_at = CASE WHEN retry_count + 1 < max_attempts
+                                  THEN NULL
+                                  ELSE now()
+                             END,
+               next_attempt_at = NULL,
+               lease_expires_at = NULL,
+               updated_at = now()
+         WHERE status = 'running'
+           AND lease_expires_at IS NOT NULL
+           AND lease_expires_at < now()
+        "#,
+        |p, sql| sqlx::query(sql).execute(p).await.map(|r| r.rows_affected())
+    )
+}
+
+pub async fn insert_job_log(pool: &DbPool, job_id: &str, log_text: &str) -> Result<(), sqlx::Error> {
+    on_pool!(
+        pool,
+        "INSERT INTO job_logs (job_id, log_text) VALUES (?, ?)",
+        "INSERT INTO job_logs (job_id, log_text) VALUES ($1, $2)",
+        |p, sql| sqlx::query(sql).bind(job_id).bind(log_text).execute(p).await.map(|_| ())
+    )
+}
+
+/// Log of the most recent attempt
+pub async fn get_latest_job_log(pool: &DbPool, job_id: &str) -> Result<Option<JobLogRow>, sqlx::Error> {
+    on_pool!(
+        pool,
+        r#"
+        SELECT job_id, log_text, created_at
+          FROM job_logs
+         WHERE job_id = ?
+      ORDER BY id DESC
+         LIMIT 1
+        "#,
+        r#"
+        SELECT job_id, log_text, ts_text(created_at) AS created_at
+          FROM job_logs
+         WHERE job_id = $1
+      ORDER BY id DESC
+         LIMIT 1
+        "#,
+        |p, sql| sqlx::query_as::<_, JobLogRow>(sql).bind(job_id).fetch_optional(p).await
+    )
 }
 
 //
@@ -289,103 +1502,900 @@ pub async fn heartbeat_job(_pool: &SqlitePool, _job_id: &str, _lease_secs: i64)
 //
 
 pub async fn insert_metric(
-    pool: &SqlitePool,
+    pool: &DbPool,
     job_id: &str,
     key: &str,
     value: f64,
-    unit: Option<&str>,
+    unit: Option<MetricUnit>,
 ) -> Result<(), sqlx::Error> {
-    sqlx::query(
+    on_pool!(
+        pool,
         r#"
         INSERT INTO metrics (job_id, key, value, unit, created_at)
         VALUES (?, ?, ?, ?, datetime('now'))
         "#,
+        r#"
+        INSERT INTO metrics (job_id, key, value, unit, created_at)
+        VALUES ($1, $2, $3, $4, now())
+        "#,
+        |p, sql| sqlx::query(sql)
+            .bind(job_id)
+            .bind(key)
+            .bind(value)
+            .bind(unit.map(MetricUnit::as_str))
+            .execute(p)
+            .await
+            .map(|_| ())
     )
-    .bind(job_id)
-    .bind(key)
-    .bind(value)
-    .bind(unit)
-    .execute(pool)
-    .await?;
-    Ok(())
 }
 
 pub async fn insert_metric_labeled(
-    pool: &SqlitePool,
+    pool: &DbPool,
     job_id: &str,
     key: &str,
     value: f64,
-    unit: Option<&str>,
+    unit: Option<MetricUnit>,
     labels_json: Option<&str>,
 ) -> Result<(), sqlx::Error> {
-    sqlx::query(
+    on_pool!(
+        pool,
         r#"
         INSERT INTO metrics (job_id, key, value, unit, labels_json, created_at)
         VALUES (?, ?, ?, ?, ?, datetime('now'))
         "#,
+        r#"
+        INSERT INTO metrics (job_id, key, value, unit, labels_json, created_at)
+        VALUES ($1, $2, $3, $4, $5, now())
+        "#,
+        |p, sql| sqlx::query(sql)
+            .bind(job_id)
+            .bind(key)
+            .bind(value)
+            .bind(unit.map(MetricUnit::as_str))
+            .bind(labels_json)
+            .execute(p)
+            .await
+            .map(|_| ())
     )
-    .bind(job_id)
-    .bind(key)
-    .bind(value)
-    .bind(unit)
-    .bind(labels_json)
-    .execute(pool)
-    .await?;
-    Ok(())
 }
 
-pub async fn get_metrics_by_job(pool: &SqlitePool, job_id: &str) -> Result<Vec<MetricRow>, sqlx::Error> {
-    let rows = sqlx::query(
+pub async fn get_metrics_by_job(pool: &DbPool, job_id: &str) -> Result<Vec<MetricRow>, sqlx::Error> {
+    on_pool!(
+        pool,
         r#"
         SELECT job_id, key, value, unit, labels_json, created_at
           FROM metrics
          WHERE job_id = ?
       ORDER BY created_at DESC
         "#,
+        r#"
+        SELECT job_id, key, value, unit, labels_json, ts_text(created_at) AS created_at
+          FROM metrics
+         WHERE job_id = $1
+      ORDER BY metrics.created_at DESC
+        "#,
+        |p, sql| sqlx::query_as::<_, MetricRow>(sql).bind(job_id).fetch_all(p).await
     )
-    .bind(job_id)
-    .fetch_all(pool)
-    .await?;
+}
 
-    let items = rows
-        .into_iter()
-        .map(|r| MetricRow {
-            job_id: r.get("job_id"),
-            key: r.get("key"),
-            value: r.get("value"),
-            unit: r.get("unit"),
-            labels_json: r.get("labels_json"),
-            created_at: r.get("created_at"),
-        })
-        .collect();
+/// Newest row of `key` for a job. Keys recorded once per layer (`layer_bytes`, ..)
+/// have a row per label set; this is the latest of them.
+pub async fn get_metric(pool: &DbPool, job_id: &str, key: &str) -> Result<Option<MetricRow>, sqlx::Error> {
+    on_pool!(
+        pool,
+        r#"
+        SELECT job_id, key, value, unit, labels_json, created_at
+          FROM metrics
+         WHERE job_id = ? AND key = ?
+      ORDER BY id DESC
+         LIMIT 1
+        "#,
+        r#"
+        SELECT job_id, key, value, unit, labels_json, ts_text(created_at) AS created_at
+          FROM metrics
+         WHERE job_id = $1 AND key = $2
+      ORDER BY id DESC
+         LIMIT 1
+        "#,
+        |p, sql| sqlx::query_as::<_, MetricRow>(sql).bind(job_id).bind(key).fetch_optional(p).await
+    )
+}
 
-    Ok(items)
+/// Metrics of all `job_ids` in one `WHERE job_id IN (..)` query, newest first
+pub async fn get_metrics_by_jobs(pool: &DbPool, job_ids: &[&str]) -> Result<Vec<MetricRow>, sqlx::Error> {
+    if job_ids.is_empty() {
+        return Ok(Vec::new());
+    }
+    match pool {
+        DbPool::Sqlite(p) => {
+            let mut qb = QueryBuilder::<Sqlite>::new(
+                "SELECT job_id, key, value, unit, labels_json, created_at FROM metrics WHERE job_id IN (",
+            );
+            let mut ids = qb.separated(", ");
+            for id in job_ids {
+                ids.push_bind(*id);
+            }
+            qb.push(") ORDER BY created_at DESC");
+            qb.build_query_as::<MetricRow>().fetch_all(p).await
+        }
+        DbPool::Postgres(p) => {
+            let mut qb = QueryBuilder::<Postgres>::new(
+                "SELECT job_id, key, value, unit, labels_json, ts_text(created_at) AS created_at \
+                 FROM metrics WHERE job_id IN (",
+            );
+            let mut ids = qb.separated(", ");
+            for id in job_ids {
+                ids.push_bind(*id);
+            }
+            qb.push(") ORDER BY metrics.created_at DESC");
+            qb.build_query_as::<MetricRow>().fetch_all(p).await
+        }
+    }
 }
 
-pub async fn list_recent_metrics(pool: &SqlitePool, limit: i64) -> Result<Vec<MetricRow>, sqlx::Error> {
-    let rows = sqlx::query(
+/// Most recent metrics whose labels have `label_key = label_value`. The key is
+/// bound into the JSON path, so callers should restrict it to plain names.
+pub async fn list_metrics_by_label(
+    pool: &DbPool,
+    label_key: &str,
+    label_value: &str,
+    limit: i64,
+    offset: i64,
+) -> Result<Vec<MetricRow>, sqlx::Error> {
+    on_pool!(
+        pool,
         r#"
         SELECT job_id, key, value, unit, labels_json, created_at
           FROM metrics
+         WHERE labels_json IS NOT NULL
+           AND json_extract(labels_json, '$.' || ?) = ?
       ORDER BY created_at DESC
-         LIMIT ?
+         LIMIT ? OFFSET ?
+        "#,
+        r#"
+        SELECT job_id, key, value, unit, labels_json, ts_text(created_at) AS created_at
+          FROM metrics
+         WHERE labels_json IS NOT NULL
+           AND labels_json::jsonb ->> $1 = $2
+      ORDER BY metrics.created_at DESC
+         LIMIT $3 OFFSET $4
+        "#,
+        |p, sql| sqlx::query_as::<_, MetricRow>(sql)
+            .bind(label_key)
+            .bind(label_value)
+            .bind(limit)
+            .bind(offset)
+            .fetch_all(p)
+            .await
+    )
+}
+
+/// Number of metrics `list_metrics_by_label` pages through
+pub async fn count_metrics_by_label(
+    pool: &DbPool,
+    label_key: &str,
+    label_value: &str,
+) -> Result<i64, sqlx::Error> {
+    on_pool!(
+        pool,
+        r#"
+        SELECT COUNT(*)
+          FROM metrics
+         WHERE labels_json IS NOT NULL
+           AND json_extract(labels_json, '$.' || ?) = ?
+        "#,
+        r#"
+        SELECT COUNT(*)
+          FROM metrics
+         WHERE labels_json IS NOT NULL
+           AND labels_json::jsonb ->> $1 = $2
+        "#,
+        |p, sql| sqlx::query_scalar::<_, i64>(sql)
+            .bind(label_key)
+            .bind(label_value)
+            .fetch_one(p)
+            .await
+    )
+}
+
+/// Most recent metric rows, optionally narrowed to one `key` and/or to jobs
+/// whose labels carry `registry_host = registry` (that label sits on the
+/// job's `layers_observed` row, so the registry is matched per job).
+pub async fn list_recent_metrics_filtered(
+    pool: &DbPool,
+    key: Option<&str>,
+    registry: Option<&str>,
+    limit: i64,
+    offset: i64,
+) -> Result<Vec<MetricRow>, sqlx::Error> {
+    match pool {
+        DbPool::Sqlite(p) => {
+            let mut qb = QueryBuilder::<Sqlite>::new(
+                "SELECT job_id, key, value, unit, labels_json, created_at FROM metrics WHERE 1 = 1",
+            );
+            push_recent_filters(&mut qb, key, registry, "json_extract(labels_json, '$.registry_host')");
+            qb.push(" ORDER BY created_at DESC LIMIT ").push_bind(limit);
+            qb.push(" OFFSET ").push_bind(offset);
+            qb.build_query_as::<MetricRow>().fetch_all(p).await
+        }
+        DbPool::Postgres(p) => {
+            let mut qb = QueryBuilder::<Postgres>::new(
+                "SELECT job_id, key, value, unit, labels_json, ts_text(created_at) AS created_at FROM metrics WHERE 1 = 1",
+            );
+            push_recent_filters(&mut qb, key, registry, "(labels_json::jsonb ->> 'registry_host')");
+            qb.push(" ORDER BY metrics.created_at DESC LIMIT ").push_bind(limit);
+            qb.push(" OFFSET ").push_bind(offset);
+            qb.build_query_as::<MetricRow>().fetch_all(p).await
+        }
+    }
+}
+
+/// Number of rows `list_recent_metrics_filtered` pages through
+pub async fn count_recent_metrics_filtered(
+    pool: &DbPool,
+    key: Option<&str>,
+    registry: Option<&str>,
+) -> Result<i64, sqlx::Error> {
+    match pool {
+        DbPool::Sqlite(p) => {
+            let mut qb = QueryBuilder::<Sqlite>::new("SELECT COUNT(*) FROM metrics WHERE 1 = 1");
+            push_recent_filters(&mut qb, key, registry, "json_extract(labels_json, '$.registry_host')");
+            qb.build_query_scalar::<i64>().fetch_one(p).await
+        }
+        DbPool::Postgres(p) => {
+            let mut qb = QueryBuilder::<Postgres>::new("SELECT COUNT(*) FROM metrics WHERE 1 = 1");
+            push_recent_filters(&mut qb, key, registry, "(labels_json::jsonb ->> 'registry_host')");
+            qb.build_query_scalar::<i64>().fetch_one(p).await
+        }
+    }
+}
+
+/// Shared WHERE tail for `list_recent_metrics_filtered` and its count; values are always bound.
+fn push_recent_filters<'a, DB>(
+    qb: &mut QueryBuilder<'a, DB>,
+    key: Option<&'a str>,
+    registry: Option<&'a str>,
+    registry_expr: &str,
+) where
+    DB: sqlx::Database,
+    &'a str: sqlx::Encode<'a, DB> + sqlx::Type<DB>,
+{
+    if let Some(key) = key {
+        qb.push(" AND key = ").push_bind(key);
+    }
+    if let Some(registry) = registry {
+        qb.push(" AND job_id IN (SELECT job_id FROM metrics WHERE ")
+            .push(registry_expr)
+            .push(" = ")
+            .push_bind(registry)
+            .push(")");
+    }
+}
+
+/// Send every metric with `from <= created_at < to` (either bound optional) to
+/// `tx` in insertion order, streaming rows from the database instead of
+/// collecting them. A full channel pauses the query, so memory stays bounded
+/// by the channel size; a closed channel (client gone) ends it early.
+/// The query holds one pool connection until it finishes.
+pub async fn export_metrics(
+    pool: &DbPool,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    tx: &tokio::sync::mpsc::Sender<MetricRow>,
+) -> Result<(), sqlx::Error> {
+    use futures_util::TryStreamExt;
+
+    let sqlite_ts = |t: Option<DateTime<Utc>>| t.map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string());
+    on_pool!(
+        pool,
+        (
+            r#"
+            SELECT job_id, key, value, unit, labels_json, created_at
+              FROM metrics
+             WHERE (?1 IS NULL OR created_at >= ?1)
+               AND (?2 IS NULL OR created_at < ?2)
+          ORDER BY id ASC
+            "#,
+            sqlite_ts(from),
+            sqlite_ts(to),
+        ),
+        (
+            r#"
+            SELECT job_id, key, value, unit, labels_json, ts_text(created_at) AS created_at
+              FROM metrics
+             WHERE ($1::timestamptz IS NULL OR created_at >= $1)
+               AND ($2::timestamptz IS NULL OR created_at < $2)
+          ORDER BY id ASC
+            "#,
+            from,
+            to,
+        ),
+        |p, sql| {
+            let (sql, from, to) = sql;
+            let mut rows = sqlx::query_as::<_, MetricRow>(sql).bind(from).bind(to).fetch(p);
+            while let Some(row) = rows.try_next().await? {
+                if tx.send(row).await.is_err() {
+                    break;
+                }
+            }
+            Ok(())
+        }
+    )
+}
+
+/// Latest row for every (job_id, key, labels) series, ordered by key (used by the Prometheus exporter)
+pub async fn list_all_latest_metrics(pool: &DbPool) -> Result<Vec<LatestMetricRow>, sqlx::Error> {
+    on_pool!(
+        pool,
+        r#"
+        SELECT m.job_id, j.image, m.key, m.value, m.unit, m.labels_json
+          FROM metrics m
+          JOIN jobs j ON j.id = m.job_id
+         WHERE m.id IN (SELECT MAX(id) FROM metrics GROUP BY job_id, key, labels_json)
+      ORDER BY m.key, m.job_id
+        "#,
+        r#"
+        SELECT m.job_id, j.image, m.key, m.value, m.unit, m.labels_json
+          FROM metrics m
+          JOIN jobs j ON j.id = m.job_id
+         WHERE m.id IN (SELECT MAX(id) FROM metrics GROUP BY job_id, key, labels_json)
+      ORDER BY m.key, m.job_id
         "#,
+        |p, sql| sqlx::query_as::<_, LatestMetricRow>(sql).fetch_all(p).await
     )
-    .bind(limit)
-    .fetch_all(pool)
-    .await?;
-
-    let items = rows
-        .into_iter()
-        .map(|r| MetricRow {
-            job_id: r.get("job_id"),
-            key: r.get("key"),
-            value: r.get("value"),
-            unit: r.get("unit"),
-            labels_json: r.get("labels_json"),
-            created_at: r.get("created_at"),
-        })
-        .collect();
-
-    Ok(items)
+}
+
+/// Every value of `key` over the last `window_secs` (all time when `None`), NaN dropped.
+pub async fn metric_values(
+    pool: &DbPool,
+    key: &str,
+    window_secs: Option<i64>,
+) -> Result<Vec<f64>, sqlx::Error> {
+    let mut values: Vec<f64> = on_pool!(
+        pool,
+        r#"
+        SELECT value
+          FROM metrics
+         WHERE key = ?
+           AND (? IS NULL OR created_at >= datetime('now', '-' || ? || ' seconds'))
+        "#,
+        r#"
+        SELECT value
+          FROM metrics
+         WHERE key = $1
+           AND ($2::BIGINT IS NULL OR created_at >= now() - make_interval(secs => $3::DOUBLE PRECISION))
+        "#,
+        |p, sql| sqlx::query_scalar::<_, f64>(sql)
+            .bind(key)
+            .bind(window_secs)
+            .bind(window_secs)
+            .fetch_all(p)
+            .await
+    )?;
+    values.retain(|v| !v.is_nan());
+    Ok(values)
+}
+
+/// count/min/max/avg/p50/p95/p99 of `key` over the last `window_secs`
+/// (all time when `None`). Neither backend has a portable percentile
+/// function, so values are fetched and summarized here.
+pub async fn aggregate_metric(
+    pool: &DbPool,
+    key: &str,
+    window_secs: Option<i64>,
+) -> Result<MetricAggregate, sqlx::Error> {
+    let mut values = metric_values(pool, key, window_secs).await?;
+    values.sort_by(|a, b| a.total_cmp(b));
+
+    let unit: Option<String> = on_pool!(
+        pool,
+        "SELECT unit FROM metrics WHERE key = ? AND unit IS NOT NULL ORDER BY created_at DESC LIMIT 1",
+        "SELECT unit FROM metrics WHERE key = $1 AND unit IS NOT NULL ORDER BY created_at DESC LIMIT 1",
+        |p, sql| sqlx::query_scalar::<_, String>(sql).bind(key).fetch_optional(p).await
+    )?;
+
+    let count = values.len();
+    let avg = (count > 0).then(|| values.iter().sum::<f64>() / count as f64);
+
+    Ok(MetricAggregate {
+        key: key.to_string(),
+        window_secs,
+        unit,
+        count,
+        min: values.first().copied(),
+        max: values.last().copied(),
+        avg,
+        p50: percentile(&values, 0.50),
+        p95: percentile(&values, 0.95),
+        p99: percentile(&values, 0.99),
+    })
+}
+
+/// Linear interpolation between closest ranks; `sorted` must be ascending.
+fn percentile(sorted: &[f64], q: f64) -> Option<f64> {
+    match sorted.len() {
+        0 => None,
+        1 => Some(sorted[0]),
+        n => {
+            let rank = q * (n - 1) as f64;
+            let lo = rank.floor() as usize;
+            let hi = rank.ceil() as usize;
+            Some(sorted[lo] + (sorted[hi] - sorted[lo]) * (rank - lo as f64))
+        }
+    }
+}
+
+/// `agg` of `key` per `bucket_secs`-wide bucket of `created_at`, oldest first.
+/// Buckets are aligned to the Unix epoch, so `1h` buckets start on the hour
+/// (UTC). Empty buckets aren't returned. `from` is inclusive, `to` exclusive.
+pub async fn timeseries_metric(
+    pool: &DbPool,
+    key: &str,
+    bucket_secs: i64,
+    agg: TimeseriesAgg,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+) -> Result<Vec<TimeseriesBucket>, sqlx::Error> {
+    let sqlite_ts = |t: Option<DateTime<Utc>>| t.map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string());
+    let aggregate = agg.sql();
+    on_pool!(
+        pool,
+        (
+            format!(
+                r#"
+                SELECT (CAST(strftime('%s', created_at) AS INTEGER) / ?1) * ?1 AS start,
+                       {aggregate} AS value
+                  FROM metrics
+                 WHERE key = ?2
+                   AND (?3 IS NULL OR created_at >= ?3)
+                   AND (?4 IS NULL OR created_at < ?4)
+              GROUP BY start
+              ORDER BY start
+                "#
+            ),
+            sqlite_ts(from),
+            sqlite_ts(to),
+        ),
+        (
+            format!(
+                r#"
+                SELECT (floor(extract(epoch FROM created_at) / $1) * $1)::BIGINT AS start,
+                       {aggregate} AS value
+                  FROM metrics
+                 WHERE key = $2
+                   AND ($3::timestamptz IS NULL OR created_at >= $3)
+                   AND ($4::timestamptz IS NULL OR created_at < $4)
+              GROUP BY 1
+              ORDER BY 1
+                "#
+            ),
+            from,
+            to,
+        ),
+        |p, sql| {
+            let (sql, from, to) = sql;
+            sqlx::query_as::<_, TimeseriesBucket>(&sql)
+                .bind(bucket_secs)
+                .bind(key)
+                .bind(from)
+                .bind(to)
+                .fetch_all(p)
+                .await
+        }
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{bare_pool, execute, pool, queue_job};
+
+    #[tokio::test]
+    async fn insert_job_keeps_the_given_id() {
+        let pool = pool().await;
+        queue_job(&pool, "job-1", "alpine:3.20", 0).await;
+
+        let job = get_job_by_id(&pool, "job-1").await.unwrap().expect("job row");
+        assert_eq!(job.id, "job-1");
+        assert_eq!(job.image, "alpine:3.20");
+        assert_eq!(job.status, JobStatus::Queued);
+        assert!(get_job_by_id(&pool, "job-2").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn list_jobs_returns_summaries_newest_first() {
+        let pool = pool().await;
+        queue_job(&pool, "old", "alpine:3.19", 0).await;
+        queue_job(&pool, "mid", "ghcr.io/org/app:1", 0).await;
+        queue_job(&pool, "new", "busybox", 0).await;
+        execute(&pool, "UPDATE jobs SET created_at = datetime('now', '-2 hours') WHERE id = 'old'").await;
+        execute(&pool, "UPDATE jobs SET created_at = datetime('now', '-1 hours') WHERE id = 'mid'").await;
+        execute(&pool, "UPDATE jobs SET status = 'running' WHERE id = 'mid'").await;
+
+        let jobs = list_jobs_filtered(&pool, &JobFilter::default(), 10, 0).await.unwrap();
+        let summary: Vec<_> = jobs.iter().map(|j| (j.id.as_str(), j.image.as_str(), j.status)).collect();
+        assert_eq!(
+            summary,
+            [
+                ("new", "busybox", JobStatus::Queued),
+                ("mid", "ghcr.io/org/app:1", JobStatus::Running),
+                ("old", "alpine:3.19", JobStatus::Queued),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_job_detail_carries_the_error() {
+        let pool = pool().await;
+        queue_job(&pool, "job-1", "alpine", 0).await;
+        set_job_error(&pool, "job-1", "manifest unknown", true).await.unwrap();
+
+        let job = get_job_by_id(&pool, "job-1").await.unwrap().expect("job row");
+        assert_eq!(job.status, JobStatus::Failed);
+        assert_eq!(job.error_detail.as_deref(), Some("manifest unknown"));
+        assert_eq!(job.retry_count, 0i64);
+        assert!(!job.created_at.is_empty());
+        assert!(job.finished_at.is_some());
+    }
+
+    #[tokio::test]
+    async fn complete_job_updates_the_jobs_row() {
+        let pool = pool().await;
+        queue_job(&pool, "job-1", "alpine", 0).await;
+        claim_next_job(&pool, 60, &[]).await.unwrap().expect("claim");
+        execute(&pool, "UPDATE jobs SET started_at = datetime('now', '-90 seconds')").await;
+        complete_job(&pool, "job-1", Some("pulled 3 layers"), Some(31_457_280)).await.unwrap();
+
+        let job = get_job_by_id(&pool, "job-1").await.unwrap().expect("job row");
+        assert_eq!(job.status, JobStatus::Completed);
+        assert_eq!(job.result.as_deref(), Some("pulled 3 layers"));
+        assert!(job.finished_at.is_some());
+        assert!(job.lease_expires_at.is_none());
+        assert_eq!(job.bytes_downloaded, Some(31_457_280));
+        assert!(job.duration_ms.is_some_and(|ms| (90_000..=91_000).contains(&ms)), "{:?}", job.duration_ms);
+
+        // nothing pulled, nothing recorded
+        queue_job(&pool, "job-2", "alpine", 0).await;
+        complete_job(&pool, "job-2", Some("skipped"), None).await.unwrap();
+        assert_eq!(get_job_by_id(&pool, "job-2").await.unwrap().unwrap().bytes_downloaded, None);
+
+        // a canceled job stays canceled and gets no bytes
+        queue_job(&pool, "job-3", "alpine", 0).await;
+        cancel_job(&pool, "job-3").await.unwrap();
+        complete_job(&pool, "job-3", None, Some(1)).await.unwrap();
+        let job = get_job_by_id(&pool, "job-3").await.unwrap().unwrap();
+        assert_eq!((job.status, job.bytes_downloaded), (JobStatus::Canceled, None));
+    }
+
+    #[tokio::test]
+    async fn failed_attempt_is_retried_until_max_attempts() {
+        let pool = pool().await;
+        queue_job(&pool, "job-1", "alpine", 0).await;
+
+        // max_attempts defaults to 3 attempts in total
+        for attempt in 1..=2 {
+            assert_eq!(fail_or_retry_job(&pool, "job-1", "timeout").await.unwrap(), Some(JobStatus::Queued));
+            let job = get_job_by_id(&pool, "job-1").await.unwrap().unwrap();
+            assert_eq!((job.status, job.retry_count), (JobStatus::Queued, attempt));
+            assert!(job.finished_at.is_none());
+        }
+
+        assert_eq!(fail_or_retry_job(&pool, "job-1", "timeout").await.unwrap(), Some(JobStatus::Failed));
+        let job = get_job_by_id(&pool, "job-1").await.unwrap().unwrap();
+        assert_eq!((job.status, job.retry_count), (JobStatus::Failed, 3));
+        assert_eq!(job.error_detail.as_deref(), Some("timeout"));
+        assert!(job.finished_at.is_some());
+    }
+
+    #[tokio::test]
+    async fn failing_a_canceled_job_leaves_it_canceled() {
+        let pool = pool().await;
+        queue_job(&pool, "job-1", "alpine", 0).await;
+        assert!(cancel_job(&pool, "job-1").await.unwrap());
+
+        assert_eq!(fail_or_retry_job(&pool, "job-1", "timeout").await.unwrap(), None);
+        let job = get_job_by_id(&pool, "job-1").await.unwrap().unwrap();
+        assert_eq!((job.status, job.retry_count), (JobStatus::Canceled, 0));
+    }
+
+    #[tokio::test]
+    async fn retried_job_waits_out_its_backoff() {
+        let pool = pool().await;
+        queue_job(&pool, "job-1", "alpine", 0).await;
+        execute(&pool, "UPDATE jobs SET max_attempts = 10 WHERE id = 'job-1'").await;
+        let DbPool::Sqlite(p) = &pool else { unreachable!() };
+        let delay_secs = || {
+            sqlx::query_scalar::<_, i64>(
+                "SELECT CAST(strftime('%s', next_attempt_at) AS INTEGER) - CAST(strftime('%s', 'now') AS INTEGER) \
+                   FROM jobs WHERE id = 'job-1'",
+            )
+            .fetch_one(p)
+        };
+
+        let mut delays = Vec::new();
+        for _ in 0..8 {
+            assert_eq!(fail_or_retry_job(&pool, "job-1", "registry down").await.unwrap(), Some(JobStatus::Queued));
+            assert!(claim_next_job(&pool, 60, &[]).await.unwrap().is_none(), "claimable during its backoff");
+            delays.push(delay_secs().await.unwrap());
+        }
+        // 5s * 2^retry_count, give or take the second the clock may tick over
+        for (delay, expected) in delays.iter().zip([5, 10, 20, 40, 80, 160, 300, 300]) {
+            assert!((expected - 1..=expected).contains(delay), "{delays:?}");
+        }
+
+        execute(&pool, "UPDATE jobs SET next_attempt_at = datetime('now', '-1 seconds') WHERE id = 'job-1'").await;
+        let claimed = claim_next_job(&pool, 60, &[]).await.unwrap().expect("claimable once the delay ran out");
+        assert_eq!((claimed.id.as_str(), claimed.retry_count), ("job-1", 8));
+    }
+
+    #[tokio::test]
+    async fn expired_lease_is_recovered() {
+        let pool = pool().await;
+        queue_job(&pool, "stale", "alpine", 0).await;
+        queue_job(&pool, "live", "busybox", 0).await;
+        queue_job(&pool, "last-try", "nginx", 0).await;
+        execute(&pool, "UPDATE jobs SET status = 'running', lease_expires_at = datetime('now', '-1 minutes')").await;
+        execute(&pool, "UPDATE jobs SET lease_expires_at = datetime('now', '+5 minutes') WHERE id = 'live'").await;
+        execute(&pool, "UPDATE jobs SET retry_count = 2 WHERE id = 'last-try'").await;
+
+        assert_eq!(recover_stale_jobs(&pool).await.unwrap(), 2);
+
+        let stale = get_job_by_id(&pool, "stale").await.unwrap().unwrap();
+        assert_eq!((stale.status, stale.retry_count), (JobStatus::Queued, 1));
+        assert!(stale.lease_expires_at.is_none());
+        let last_try = get_job_by_id(&pool, "last-try").await.unwrap().unwrap();
+        assert_eq!((last_try.status, last_try.retry_count), (JobStatus::Failed, 3));
+        let live = get_job_by_id(&pool, "live").await.unwrap().unwrap();
+        assert_eq!(live.status, JobStatus::Running);
+    }
+
+    #[tokio::test]
+    async fn higher_priority_is_claimed_before_older_jobs() {
+        let pool = pool().await;
+        queue_job(&pool, "backlog", "alpine", 0).await;
+        queue_job(&pool, "urgent", "busybox", 50).await;
+        queue_job(&pool, "later", "nginx", 0).await;
+        execute(&pool, "UPDATE jobs SET created_at = datetime('now', '-1 hours') WHERE id = 'backlog'").await;
+
+        let mut order = Vec::new();
+        while let Some(job) = claim_next_job(&pool, 60, &[]).await.unwrap() {
+            order.push(job.id);
+        }
+        assert_eq!(order, ["urgent", "backlog", "later"]);
+    }
+
+    #[tokio::test]
+    async fn saturated_registry_is_passed_over() {
+        let pool = pool().await;
+        let jobs = [("hub", "alpine"), ("ghcr", "ghcr.io/acme/tool:1")];
+        insert_jobs_batch(&pool, &jobs, 0, None, None).await.unwrap();
+        execute(&pool, "UPDATE jobs SET created_at = datetime('now', '-1 hours') WHERE id = 'hub'").await;
+
+        let saturated = ["docker.io".to_string()];
+        let job = claim_next_job(&pool, 60, &saturated).await.unwrap().unwrap();
+        assert_eq!(job.id, "ghcr");
+        assert!(claim_next_job(&pool, 60, &saturated).await.unwrap().is_none());
+        assert_eq!(claim_next_job(&pool, 60, &[]).await.unwrap().unwrap().id, "hub");
+    }
+
+    #[tokio::test]
+    async fn released_claim_is_queued_again_without_using_an_attempt() {
+        let pool = pool().await;
+        queue_job(&pool, "job-1", "alpine", 0).await;
+        // as if queued before the registry column existed
+        execute(&pool, "UPDATE jobs SET registry = NULL").await;
+        claim_next_job(&pool, 60, &[]).await.unwrap().unwrap();
+
+        assert!(release_claim(&pool, "job-1", "docker.io").await.unwrap());
+        let job = get_job_by_id(&pool, "job-1").await.unwrap().unwrap();
+        assert_eq!((job.status, job.retry_count), (JobStatus::Queued, 0));
+        assert_eq!((job.started_at, job.lease_expires_at), (None, None));
+        // the stored registry lets the next claim pass it over
+        assert!(claim_next_job(&pool, 60, &["docker.io".to_string()]).await.unwrap().is_none());
+
+        claim_next_job(&pool, 60, &[]).await.unwrap().unwrap();
+        assert!(cancel_job(&pool, "job-1").await.unwrap());
+        assert!(!release_claim(&pool, "job-1", "docker.io").await.unwrap());
+        let job = get_job_by_id(&pool, "job-1").await.unwrap().unwrap();
+        assert_eq!(job.status, JobStatus::Canceled);
+    }
+
+    #[tokio::test]
+    async fn queue_sample_counts_queued_and_running_jobs() {
+        let pool = pool().await;
+        insert_queue_sample(&pool).await.unwrap();
+        execute(&pool, "UPDATE queue_samples SET sampled_at = datetime('now', '-2 hours')").await;
+        for id in ["a", "b", "c"] {
+            queue_job(&pool, id, "alpine", 0).await;
+        }
+        claim_next_job(&pool, 60, &[]).await.unwrap().unwrap();
+        insert_queue_sample(&pool).await.unwrap();
+
+        let samples = list_queue_samples(&pool, 3600).await.unwrap();
+        let counts: Vec<_> = samples.iter().map(|s| (s.queued, s.running)).collect();
+        assert_eq!(counts, [(2, 1)]);
+        assert_eq!(list_queue_samples(&pool, 3 * 3600).await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn purge_deletes_only_old_finished_jobs() {
+        let pool = pool().await;
+        for id in ["old-done", "old-failed", "old-queued", "new-done"] {
+            queue_job(&pool, id, "alpine", 0).await;
+            insert_metric(&pool, id, "pull_duration_ms", 1.0, None).await.unwrap();
+        }
+        complete_job(&pool, "old-done", None, None).await.unwrap();
+        complete_job(&pool, "new-done", None, None).await.unwrap();
+        set_job_error(&pool, "old-failed", "boom", true).await.unwrap();
+        execute(
+            &pool,
+            "UPDATE jobs SET created_at = datetime('now', '-40 days'), finished_at = datetime('now', '-40 days') \
+              WHERE id LIKE 'old-%' AND status <> 'queued'",
+        )
+        .await;
+        execute(&pool, "UPDATE jobs SET created_at = datetime('now', '-40 days') WHERE id = 'old-queued'").await;
+
+        let cutoff = Utc::now() - chrono::Duration::days(30);
+        assert_eq!(purge_old_jobs(&pool, cutoff).await.unwrap(), 2);
+
+        let left = list_jobs_filtered(&pool, &JobFilter::default(), 10, 0).await.unwrap();
+        let mut left: Vec<_> = left.into_iter().map(|j| j.id).collect();
+        left.sort();
+        assert_eq!(left, ["new-done", "old-queued"]);
+        assert!(get_metrics_by_job(&pool, "old-done").await.unwrap().is_empty());
+        assert_eq!(get_metrics_by_job(&pool, "new-done").await.unwrap().len(), 1);
+    }
+
+    #[test]
+    fn sqlite_urls_resolve_like_the_pool_opens_them() {
+        let cwd = std::env::current_dir().unwrap();
+        assert_eq!(sqlite_target("sqlite://db.sqlite"), Ok(SqliteTarget::File(cwd.join("db.sqlite"))));
+        assert_eq!(sqlite_target("sqlite:data/db.sqlite?mode=rwc"), Ok(SqliteTarget::File(cwd.join("data/db.sqlite"))));
+        assert_eq!(sqlite_target("sqlite:///abs/path.db"), Ok(SqliteTarget::File("/abs/path.db".into())));
+        assert_eq!(sqlite_target("sqlite::memory:"), Ok(SqliteTarget::Memory));
+        assert_eq!(sqlite_target("sqlite://:memory:"), Ok(SqliteTarget::Memory));
+        assert!(sqlite_target("sqlite://data/").is_err());
+        assert!(sqlite_target("sqlite://").is_err());
+        assert!(sqlite_target("postgres://localhost/db").is_err());
+    }
+
+    #[tokio::test]
+    async fn in_memory_pool_is_shared_by_its_connections_only() {
+        let pool = pool().await;
+        queue_job(&pool, "job-1", "alpine", 0).await;
+
+        let DbPool::Sqlite(p) = &pool else { unreachable!() };
+        // held at once, so they are distinct connections
+        let mut a = p.acquire().await.unwrap();
+        let mut b = p.acquire().await.unwrap();
+        for conn in [&mut a, &mut b] {
+            let n: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM jobs").fetch_one(&mut **conn).await.unwrap();
+            assert_eq!(n, 1);
+        }
+
+        let other = crate::test_support::pool().await;
+        assert!(get_job_by_id(&other, "job-1").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn concurrent_metric_inserts_do_not_lock_each_other_out() {
+        let path = std::env::temp_dir().join(format!("imgpuller-wal-{}.db", std::process::id()));
+        let url = format!("sqlite://{}?mode=rwc", path.display());
+        // WAL, synchronous=NORMAL and a 5s busy timeout unless SQLITE_* say otherwise
+        let settings = PoolSettings::from_config(&crate::test_support::config(&[]));
+        let pool = init_pool(&url, &settings).await.unwrap();
+        init_db(&pool).await.unwrap();
+        queue_job(&pool, "job-1", "alpine", 0).await;
+
+        let writers = (0..8).map(|w| {
+            let pool = pool.clone();
+            tokio::spawn(async move {
+                for i in 0..25 {
+                    insert_metric(&pool, "job-1", &format!("custom_{w}_{i}"), i as f64, None).await?;
+                }
+                Ok::<_, sqlx::Error>(())
+            })
+        });
+        for writer in futures_util::future::join_all(writers).await {
+            writer.unwrap().unwrap();
+        }
+        assert_eq!(get_metrics_by_job(&pool, "job-1").await.unwrap().len(), 200);
+
+        let DbPool::Sqlite(p) = &pool else { unreachable!() };
+        let mode: String = sqlx::query_scalar("PRAGMA journal_mode").fetch_one(p).await.unwrap();
+        assert_eq!(mode, "wal");
+        p.close().await;
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+    }
+
+    /// `jobs` and `metrics` as the first release's `--init-db` created them
+    const FIRST_RELEASE_SCHEMA: &[&str] = &[
+        "CREATE TABLE jobs (
+            id           TEXT PRIMARY KEY,
+            image        TEXT NOT NULL,
+            status       TEXT NOT NULL DEFAULT 'queued',
+            result       TEXT,
+            error_detail TEXT,
+            retry_count  INTEGER NOT NULL DEFAULT 0,
+            created_at   TEXT NOT NULL DEFAULT (datetime('now')),
+            finished_at  TEXT
+        )",
+        "CREATE TABLE metrics (
+            id           INTEGER PRIMARY KEY AUTOINCREMENT,
+            job_id       TEXT NOT NULL,
+            key          TEXT NOT NULL,
+            value        REAL NOT NULL,
+            unit         TEXT,
+            labels_json  TEXT,
+            created_at   TEXT NOT NULL DEFAULT (datetime('now'))
+        )",
+        "CREATE INDEX idx_metrics_job_created ON metrics(job_id, created_at DESC)",
+        "INSERT INTO jobs (id, image, status) VALUES ('legacy', 'alpine', 'completed')",
+    ];
+
+    async fn legacy_pool() -> DbPool {
+        let pool = bare_pool().await;
+        for sql in FIRST_RELEASE_SCHEMA {
+            execute(&pool, sql).await;
+        }
+        init_db(&pool).await.unwrap();
+        pool
+    }
+
+    /// Every column (name, type, not null, default, pk) and index, per table
+    async fn schema(pool: &DbPool) -> Vec<String> {
+        let DbPool::Sqlite(p) = pool else { unreachable!() };
+        let mut rows: Vec<String> = sqlx::query_scalar(
+            "SELECT m.name || '.' || c.name || ' ' || c.type || ' ' || c.\"notnull\" || ' ' \
+                    || COALESCE(c.dflt_value, '-') || ' ' || c.pk \
+               FROM sqlite_master m, pragma_table_info(m.name) c \
+              WHERE m.type = 'table' AND m.name NOT LIKE 'sqlite_%' \
+             UNION ALL \
+             SELECT 'index ' || name FROM sqlite_master WHERE type = 'index' AND name NOT LIKE 'sqlite_%'",
+        )
+        .fetch_all(p)
+        .await
+        .unwrap();
+        rows.sort();
+        rows
+    }
+
+    #[tokio::test]
+    async fn legacy_database_migrates_to_the_fresh_schema() {
+        let fresh = pool().await;
+        let legacy = legacy_pool().await;
+        assert_eq!(schema(&legacy).await, schema(&fresh).await);
+
+        let DbPool::Sqlite(p) = &legacy else { unreachable!() };
+        let applied: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM _sqlx_migrations").fetch_one(p).await.unwrap();
+        assert_eq!(applied, SQLITE_MIGRATIONS.iter().count() as i64);
+        let job = get_job_by_id(&legacy, "legacy").await.unwrap().expect("legacy row kept");
+        assert_eq!((job.status, job.max_attempts), (JobStatus::Completed, 3));
+    }
+
+    #[tokio::test]
+    async fn deleting_from_a_legacy_database_leaves_no_orphans() {
+        let pool = legacy_pool().await;
+        for id in ["gone", "purged", "kept"] {
+            queue_job(&pool, id, "alpine", 0).await;
+            insert_metric(&pool, id, "pull_duration_ms", 1.0, None).await.unwrap();
+            insert_job_log(&pool, id, "pulled").await.unwrap();
+        }
+        complete_job(&pool, "purged", None, None).await.unwrap();
+        execute(&pool, "UPDATE jobs SET finished_at = datetime('now', '-2 days') WHERE id = 'purged'").await;
+
+        assert!(delete_job(&pool, "gone").await.unwrap());
+        assert_eq!(purge_old_jobs(&pool, Utc::now() - chrono::Duration::days(1)).await.unwrap(), 1);
+
+        let DbPool::Sqlite(p) = &pool else { unreachable!() };
+        let owners: Vec<String> = sqlx::query_scalar(
+            "SELECT DISTINCT job_id FROM metrics UNION SELECT DISTINCT job_id FROM job_logs ORDER BY 1",
+        )
+        .fetch_all(p)
+        .await
+        .unwrap();
+        assert_eq!(owners, ["kept"]);
+    }
 }
@@ -1,3 +1,6 @@
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
 use actix_web::{http::StatusCode, HttpResponse, ResponseError};
 use crate::model::ErrorResponse;
 use std::fmt::{self, Display};
@@ -5,13 +8,26 @@ use std::fmt::{self, Display};
 #[derive(Debug)]
 pub enum AppError {
     BadRequest(String),
+    Unauthorized(String),
+    Forbidden(String),
     NotFound(String),
+    Conflict(String),
+    /// None of the `Accept`ed media types can be produced
+    NotAcceptable(String),
+    Unavailable(String),
+    /// Rate limit hit; seconds until the client may retry
+    RateLimited(u64),
     Internal(String),
 }
 
 impl AppError {
     pub fn bad_request(msg: impl Into<String>) -> Self { Self::BadRequest(msg.into()) }
+    pub fn unauthorized(msg: impl Into<String>) -> Self { Self::Unauthorized(msg.into()) }
+    pub fn forbidden(msg: impl Into<String>) -> Self { Self::Forbidden(msg.into()) }
     pub fn not_found(msg: impl Into<String>) -> Self { Self::NotFound(msg.into()) }
+    pub fn conflict(msg: impl Into<String>) -> Self { Self::Conflict(msg.into()) }
+    pub fn not_acceptable(msg: impl Into<String>) -> Self { Self::NotAcceptable(msg.into()) }
+    pub fn unavailable(msg: impl Into<String>) -> Self { Self::Unavailable(msg.into()) }
 
     #[allow(dead_code)]
     pub fn internal(msg: impl Into<String>) -> Self { Self::Internal(msg.into()) }
@@ -21,7 +37,13 @@ impl Display for AppError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             AppError::BadRequest(m) => write!(f, "bad request: {}", m),
+            AppError::Unauthorized(m) => write!(f, "unauthorized: {}", m),
+            AppError::Forbidden(m) => write!(f, "forbidden: {}", m),
             AppError::NotFound(m)  => write!(f, "not found: {}", m),
+            AppError::Conflict(m)  => write!(f, "conflict: {}", m),
+            AppError::NotAcceptable(m) => write!(f, "not acceptable: {}", m),
+            AppError::Unavailable(m) => write!(f, "service unavailable: {}", m),
+            AppError::RateLimited(secs) => write!(f, "too many requests: retry after {}s", secs),
             AppError::Internal(m)  => write!(f, "internal error: {}", m),
         }
     }
@@ -39,22 +61,140 @@ impl From<std::io::Error> for AppError {
 impl From<bollard::errors::Error> for AppError {
     fn from(e: bollard::errors::Error) -> Self { Self::Internal(e.to_string()) }
 }
+/// A request body that failed its `#[validate]` rules: `image: must be 1 to 512 characters, got 0; ...`
+impl From<validator::ValidationErrors> for AppError {
+    fn from(e: validator::ValidationErrors) -> Self {
+        let fields: Vec<String> = crate::config::validation_failures(&e)
+            .into_iter()
+            .map(|(field, reason)| format!("{}: {}", field, reason))
+            .collect();
+        Self::BadRequest(fields.join("; "))
+    }
+}
 
 impl ResponseError for AppError {
     fn status_code(&self) -> StatusCode {
         match self {
             AppError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            AppError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            AppError::Forbidden(_) => StatusCode::FORBIDDEN,
             AppError::NotFound(_)  => StatusCode::NOT_FOUND,
+            AppError::Conflict(_)  => StatusCode::CONFLICT,
+            AppError::NotAcceptable(_) => StatusCode::NOT_ACCEPTABLE,
+            AppError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
             AppError::Internal(_)  => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
 
     fn error_response(&self) -> HttpResponse {
+        self.response_for(None)
+    }
+}
+
+impl AppError {
+    /// The error body, with `path` filled in when the request is known.
+    pub fn response_for(&self, path: Option<&str>) -> HttpResponse {
+        let with_path = |body: ErrorResponse| match path {
+            Some(p) => body.with_path(p),
+            None => body,
+        };
+        if let AppError::RateLimited(secs) = self {
+            let err = format!("rate limit exceeded, retry in {}s", secs);
+            return HttpResponse::TooManyRequests()
+                .insert_header((actix_web::http::header::RETRY_AFTER, secs.to_string()))
+                .json(with_path(ErrorResponse::new(429, "too many requests", &err)));
+        }
+
         let (status, msg, err) = match self {
             AppError::BadRequest(m) => (StatusCode::BAD_REQUEST, "bad request", m.as_str()),
+            AppError::Unauthorized(m) => (StatusCode::UNAUTHORIZED, "unauthorized", m.as_str()),
+            AppError::Forbidden(m) => (StatusCode::FORBIDDEN, "forbidden", m.as_str()),
             AppError::NotFound(m)  => (StatusCode::NOT_FOUND, "not found", m.as_str()),
+            AppError::Conflict(m)  => (StatusCode::CONFLICT, "conflict", m.as_str()),
+            AppError::NotAcceptable(m) => (StatusCode::NOT_ACCEPTABLE, "not acceptable", m.as_str()),
+            AppError::Unavailable(m) => (StatusCode::SERVICE_UNAVAILABLE, "service unavailable", m.as_str()),
             AppError::Internal(m)  => (StatusCode::INTERNAL_SERVER_ERROR, "internal error", m.as_str()),
+            AppError::RateLimited(_) => unreachable!("handled above"),
         };
-        HttpResponse::build(status).json(ErrorResponse::new(status.as_u16(), msg, err))
+        let mut res = HttpResponse::build(status);
+        if let AppError::Unauthorized(_) = self {
+            res.insert_header((actix_web::http::header::WWW_AUTHENTICATE, "Bearer"));
+        }
+        res.json(with_path(ErrorResponse::new(status.as_u16(), msg, err)))
+    }
+}
+
+/// Middleware: re-render `AppError` responses with the request path, which
+/// `ResponseError::error_response` has no access to.
+pub async fn add_request_path(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, actix_web::Error> {
+    // a clone of the HttpRequest would keep NormalizePath from rewriting it
+    let path = req.path().to_string();
+    let res = next.call(req).await?;
+    match res.response().error().and_then(|e| e.as_error::<AppError>()) {
+        Some(err) => {
+            let body = err.response_for(Some(&path));
+            Ok(res.into_response(body).map_into_right_body())
+        }
+        None => Ok(res.map_into_left_body()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::body::to_bytes;
+
+    use super::*;
+
+    async fn render(err: &AppError) -> (StatusCode, serde_json::Value) {
+        let res = err.response_for(Some("/api/v1/jobs"));
+        let status = res.status();
+        let bytes = to_bytes(res.into_body()).await.unwrap();
+        (status, serde_json::from_slice(&bytes).unwrap())
+    }
+
+    #[actix_web::test]
+    async fn every_variant_renders_its_status() {
+        let cases = [
+            (AppError::bad_request("x"), 400, "bad request"),
+            (AppError::unauthorized("x"), 401, "unauthorized"),
+            (AppError::forbidden("x"), 403, "forbidden"),
+            (AppError::not_found("x"), 404, "not found"),
+            (AppError::conflict("x"), 409, "conflict"),
+            (AppError::not_acceptable("x"), 406, "not acceptable"),
+            (AppError::unavailable("x"), 503, "service unavailable"),
+            (AppError::RateLimited(7), 429, "too many requests"),
+            (AppError::internal("x"), 500, "internal error"),
+        ];
+        for (err, code, message) in cases {
+            assert_eq!(err.status_code().as_u16(), code, "{err}");
+            let (status, body) = render(&err).await;
+            assert_eq!(status.as_u16(), code, "{err}");
+            assert_eq!(body["success"], false);
+            assert_eq!(body["status_code"], code);
+            assert_eq!(body["message"], message);
+            assert_eq!(body["path"], "/api/v1/jobs");
+        }
+    }
+
+    #[actix_web::test]
+    async fn forbidden_and_conflict_carry_their_reason() {
+        let (_, body) = render(&AppError::forbidden("image is denied")).await;
+        assert_eq!(body["error"], "image is denied");
+        let (_, body) = render(&AppError::conflict("job is running")).await;
+        assert_eq!(body["error"], "job is running");
+        assert_eq!(AppError::forbidden("image is denied").to_string(), "forbidden: image is denied");
+        assert_eq!(AppError::conflict("job is running").to_string(), "conflict: job is running");
+    }
+
+    #[test]
+    fn rate_limited_and_unauthorized_headers() {
+        let res = AppError::RateLimited(7).error_response();
+        assert_eq!(res.headers().get("retry-after").unwrap(), "7");
+        let res = AppError::unauthorized("x").error_response();
+        assert_eq!(res.headers().get("www-authenticate").unwrap(), "Bearer");
     }
 }
@@ -0,0 +1,143 @@
+//! Job lifecycle events as JSON lines on the `job_events` log target.
+//!
+//! The shape is meant to be ingested as-is (e.g. by Loki), so fields are only
+//! ever added, never renamed:
+//!
+//! `{"event":"claimed","job_id":"..","image":"..","registry_host":"..","attempt":1,"request_id":"..","claimed_at":"..","ts":".."}`
+//!
+//! `request_id` is the `X-Request-Id` of the request that created the job, or null.
+//!
+//! Also home of [`JobUpdate`], the in-process broadcast that feeds the SSE endpoint.
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use log::info;
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+use crate::model::JobStatus;
+
+pub const TARGET: &str = "job_events";
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Transition {
+    Claimed,
+    Running,
+    Heartbeat,
+    Completed,
+    Failed,
+    Retried,
+    Canceled,
+    /// Claimed but handed back to the queue without an attempt (no registry slot came free)
+    Released,
+}
+
+/// Identity of one job attempt; every event for that attempt carries it.
+#[derive(Debug, Clone)]
+pub struct JobLifecycle {
+    pub job_id: String,
+    pub image: String,
+    pub registry_host: String,
+    /// 1-based attempt number (retry_count + 1)
+    pub attempt: i64,
+    pub request_id: Option<String>,
+    pub claimed_at: DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+struct EventLine<'a> {
+    event: Transition,
+    job_id: &'a str,
+    image: &'a str,
+    registry_host: &'a str,
+    attempt: i64,
+    request_id: Option<&'a str>,
+    claimed_at: String,
+    ts: String,
+}
+
+impl JobLifecycle {
+    pub fn new(
+        job_id: &str,
+        image: &str,
+        registry_host: &str,
+        retry_count: i64,
+        request_id: Option<&str>,
+    ) -> Self {
+        Self {
+            job_id: job_id.to_string(),
+            image: image.to_string(),
+            registry_host: registry_host.to_string(),
+            attempt: retry_count + 1,
+            request_id: request_id.map(str::to_string),
+            claimed_at: Utc::now(),
+        }
+    }
+
+    pub fn emit(&self, event: Transition) {
+        let line = EventLine {
+            event,
+            job_id: &self.job_id,
+            image: &self.image,
+            registry_host: &self.registry_host,
+            attempt: self.attempt,
+            request_id: self.request_id.as_deref(),
+            claimed_at: self.claimed_at.to_rfc3339_opts(SecondsFormat::Millis, true),
+            ts: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+        };
+        if let Ok(json) = serde_json::to_string(&line) {
+            info!(target: TARGET, "{}", json);
+        }
+    }
+}
+
+// ---------- live updates (SSE) ----------
+
+/// Sender side of the live update channel; cloned into the worker and pull code.
+pub type JobUpdates = broadcast::Sender<JobUpdate>;
+
+pub const UPDATES_CAPACITY: usize = 1024;
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum JobUpdate {
+    Status {
+        job_id: String,
+        status: JobStatus,
+    },
+    Progress {
+        job_id: String,
+        layer: String,
+        current: u64,
+        total: u64,
+    },
+}
+
+impl JobUpdate {
+    pub fn status(job_id: &str, status: JobStatus) -> Self {
+        Self::Status { job_id: job_id.to_string(), status }
+    }
+
+    pub fn job_id(&self) -> &str {
+        match self {
+            Self::Status { job_id, .. } | Self::Progress { job_id, .. } => job_id,
+        }
+    }
+
+    /// SSE event name
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Status { .. } => "status",
+            Self::Progress { .. } => "progress",
+        }
+    }
+
+    pub fn is_terminal(&self) -> bool {
+        matches!(self, Self::Status { status, .. } if status.is_terminal())
+    }
+}
+
+/// Publish without caring whether anyone is listening.
+pub fn publish(updates: &JobUpdates, update: JobUpdate) {
+    let _ = updates.send(update);
+}
@@ -1,25 +1,60 @@
+mod access_log;
+mod auth;
+mod backend;
 mod config;
+mod config_file;
 mod db;
 mod model;
+mod rate_limit;
 mod error;
+mod events;
+mod registry_auth;
+mod request_id;
 mod routes;
+mod telemetry;
+#[cfg(test)]
+mod test_support;
+mod tls;
+mod webhook;
 mod worker;
 
-use std::{collections::HashMap, sync::Arc};
-use actix_web::{get, web, App, HttpResponse, HttpServer, Responder};
-use actix_web::middleware::{Logger, NormalizePath, TrailingSlash};
-use tokio::sync::{Mutex, Semaphore};
+use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{PoisonError, RwLock, RwLockReadGuard};
+use actix_web::{get, web, App, HttpRequest, HttpResponse, HttpServer, Responder};
+use actix_cors::Cors;
+use actix_web::middleware::{self, Condition, Logger, NormalizePath, TrailingSlash};
+use tokio::sync::{Mutex, Notify, Semaphore};
 use clap::Parser;
 use log::info;
 
-use crate::config::AppConfig;
-use crate::db::{init_pool, init_db};
+use crate::backend::PullBackend;
+use crate::config::{AppConfig, ConfigReload};
+use crate::db::{init_pool, init_db, is_postgres_url, sqlite_target, PoolSettings, SqliteTarget};
+use crate::events::{JobUpdates, UPDATES_CAPACITY};
 
 #[derive(Clone)]
 pub struct AppState {
-    pub config: AppConfig,
+    /// Read through [`AppState::config`]; a reload swaps the hot-reloadable fields in place.
+    pub config: Arc<RwLock<AppConfig>>,
     pub global_pull_sem: Arc<Semaphore>,
+    /// Current target size of `global_pull_sem` (it can be resized at runtime).
+    pub pull_concurrency: Arc<AtomicUsize>,
     pub registry_sems: Arc<Mutex<HashMap<String, Arc<Semaphore>>>>,
+    pub updates: JobUpdates,
+    /// Set on SIGTERM/ctrl-c or `POST /admin/shutdown`: no new jobs are accepted or claimed.
+    pub draining: Arc<AtomicBool>,
+    /// Wakes the shutdown task for `POST /admin/shutdown`, which then drains like on SIGTERM.
+    pub shutdown_requested: Arc<Notify>,
+    /// Wakes the idle worker as soon as a job is queued.
+    pub job_notify: Arc<Notify>,
+    pub rate_limiter: rate_limit::RateLimiter,
+    /// Cancel flags of the jobs this process is pulling, checked by the pull loop.
+    pub cancel_flags: Arc<Mutex<HashMap<String, Arc<AtomicBool>>>>,
+    /// Runtime the worker pulls into (`PULL_BACKEND`)
+    pub backend: Arc<backend::Backend>,
+    /// Sends `callback_url` notifications
+    pub webhook_client: reqwest::Client,
 }
 
 #[derive(Parser, Debug)]
@@ -31,15 +66,141 @@ struct CliArgs {
 }
 
 impl AppState {
+    pub fn new(cfg: &AppConfig) -> Self {
+        Self {
+            global_pull_sem: Arc::new(Semaphore::new(cfg.max_concurrent_pulls)),
+            pull_concurrency: Arc::new(AtomicUsize::new(cfg.max_concurrent_pulls)),
+            registry_sems: Arc::new(Mutex::new(HashMap::new())),
+            updates: tokio::sync::broadcast::channel(UPDATES_CAPACITY).0,
+            draining: Arc::new(AtomicBool::new(false)),
+            shutdown_requested: Arc::new(Notify::new()),
+            job_notify: Arc::new(Notify::new()),
+            rate_limiter: rate_limit::RateLimiter::new(cfg.rate_limit_rpm),
+            cancel_flags: Arc::new(Mutex::new(HashMap::new())),
+            backend: Arc::new(backend::Backend::from_config(cfg)),
+            webhook_client: reqwest::Client::new(),
+            config: Arc::new(RwLock::new(cfg.clone())),
+        }
+    }
+
+    /// The running configuration. Don't hold the guard across an `.await`.
+    pub fn config(&self) -> RwLockReadGuard<'_, AppConfig> {
+        self.config.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Take the hot-reloadable settings from `new` and resize the pull
+    /// semaphores to match; see [`AppConfig::apply_reloadable`].
+    pub async fn reload_config(&self, new: &AppConfig) -> ConfigReload {
+        let sems = self.registry_sems.lock().await;
+        let reload = {
+            let mut cfg = self.config.write().unwrap_or_else(PoisonError::into_inner);
+            for (host, sem) in sems.iter() {
+                resize_semaphore(sem, cfg.registry_limit(host), new.registry_limit(host));
+            }
+            ConfigReload {
+                changed: cfg.apply_reloadable(new),
+                ignored: cfg.restart_only_changes(new),
+            }
+        };
+        drop(sems);
+        self.resize_pull_concurrency(new.max_concurrent_pulls);
+        reload
+    }
+
     pub async fn registry_sem(&self, registry: &str) -> Arc<Semaphore> {
         let mut map = self.registry_sems.lock().await;
         Arc::clone(
             map.entry(registry.to_string())
-               .or_insert_with(|| Arc::new(Semaphore::new(self.config.per_registry_max)))
+               .or_insert_with(|| Arc::new(Semaphore::new(self.config().registry_limit(registry))))
         )
     }
+
+    /// Registries with no pull slot free right now, for `db::claim_next_job` to pass over.
+    pub async fn saturated_registries(&self) -> Vec<String> {
+        self.registry_sems
+            .lock()
+            .await
+            .iter()
+            .filter(|(_, sem)| sem.available_permits() == 0)
+            .map(|(host, _)| host.clone())
+            .collect()
+    }
+
+    /// Fresh cancel flag for a job this process is about to pull.
+    pub async fn register_cancel_flag(&self, job_id: &str) -> Arc<AtomicBool> {
+        let flag = Arc::new(AtomicBool::new(false));
+        self.cancel_flags.lock().await.insert(job_id.to_string(), Arc::clone(&flag));
+        flag
+    }
+
+    pub async fn clear_cancel_flag(&self, job_id: &str) {
+        self.cancel_flags.lock().await.remove(job_id);
+    }
+
+    /// Ask an in-flight pull to stop; false if this process isn't pulling `job_id`.
+    pub async fn request_cancel(&self, job_id: &str) -> bool {
+        match self.cancel_flags.lock().await.get(job_id) {
+            Some(flag) => {
+                flag.store(true, Ordering::SeqCst);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    /// Start the graceful shutdown without a signal; false if it is already underway.
+    pub fn request_shutdown(&self) -> bool {
+        if self.draining.swap(true, Ordering::SeqCst) {
+            return false;
+        }
+        self.shutdown_requested.notify_one();
+        true
+    }
+
+    /// Tell the worker there is something to claim (stored if it isn't waiting yet).
+    pub fn notify_new_job(&self) {
+        self.job_notify.notify_one();
+    }
+
+    /// Resize the global pull semaphore to `target` permits, returning the previous size.
+    ///
+    /// Growing takes effect immediately. Shrinking forgets idle permits right away;
+    /// permits held by in-flight pulls are retired as those pulls finish.
+    pub fn resize_pull_concurrency(&self, target: usize) -> usize {
+        let previous = self.pull_concurrency.swap(target, Ordering::SeqCst);
+        self.config.write().unwrap_or_else(PoisonError::into_inner).max_concurrent_pulls = target;
+        resize_semaphore(&self.global_pull_sem, previous, target);
+        previous
+    }
+}
+
+/// Grow or shrink `sem` from `previous` to `target` permits, retiring permits
+/// that are in use once they are released.
+fn resize_semaphore(sem: &Arc<Semaphore>, previous: usize, target: usize) {
+    if target > previous {
+        sem.add_permits(target - previous);
+    } else if target < previous {
+        let excess = previous - target;
+        let remaining = excess - sem.forget_permits(excess);
+        if remaining > 0 {
+            let sem = sem.clone();
+            tokio::spawn(async move {
+                if let Ok(permits) = sem.acquire_many_owned(remaining as u32).await {
+                    permits.forget();
+                }
+            });
+        }
+    }
 }
 
+#[utoipa::path(
+    tag = "health",
+    responses((status = 200, description = "The process is up", body = model::ApiResponse<serde_json::Value>))
+)]
 #[get("/health")]
 async fn health() -> impl Responder {
     HttpResponse::Ok().json(model::ApiResponse::ok(
@@ -48,23 +209,86 @@ async fn health() -> impl Responder {
     ))
 }
 
+/// Readiness: the pull backend (Docker daemon by default) and database must both answer.
+#[utoipa::path(
+    tag = "health",
+    responses(
+        (status = 200, description = "Pull backend and database answer", body = model::ApiResponse<serde_json::Value>),
+        (status = 503, description = "A check failed; `data.checks` says which", body = model::ApiResponse<serde_json::Value>),
+    )
+)]
+#[get("/health/deep")]
+async fn health_deep(pool: web::Data<db::DbPool>, state: web::Data<AppState>) -> impl Responder {
+    let probe_timeout = std::time::Duration::from_secs(5);
+
+    let runtime = match tokio::time::timeout(probe_timeout, state.backend.ping()).await {
+        Ok(r) => r,
+        Err(_) => Err("ping timed out".to_string()),
+    };
+    let database = match tokio::time::timeout(probe_timeout, db::ping(pool.get_ref())).await {
+        Ok(Ok(())) => Ok(()),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Err("query timed out".to_string()),
+    };
+
+    let check = |r: &Result<(), String>| match r {
+        Ok(()) => serde_json::json!({"ok": true}),
+        Err(e) => serde_json::json!({"ok": false, "error": e}),
+    };
+    let healthy = runtime.is_ok() && database.is_ok();
+    let body = model::ApiResponse {
+        success: healthy,
+        message: if healthy { "Service is ready" } else { "Service is not ready" }.to_string(),
+        data: serde_json::json!({
+            "status": if healthy { "ok" } else { "unavailable" },
+            "checks": { state.backend.name(): check(&runtime), "database": check(&database) },
+        }),
+    };
+
+    if healthy {
+        HttpResponse::Ok().json(body)
+    } else {
+        HttpResponse::ServiceUnavailable().json(body)
+    }
+}
+
+/// `Logger::default()` plus the request id, so access log lines can be matched to job logs.
+const TEXT_ACCESS_LOG: &str = r#"%a "%r" %s %b "%{Referer}i" "%{User-Agent}i" %T rid=%{x-request-id}o"#;
+
+/// CORS for browser dashboards on other origins. Only used when
+/// `CORS_ALLOWED_ORIGINS` is set; credentials (cookies) are never allowed,
+/// the API key goes in the `Authorization` header instead.
+fn cors(origins: &[String]) -> Cors {
+    let mut cors = Cors::default()
+        .allowed_methods(["GET", "POST", "DELETE", "PATCH"])
+        .allowed_headers([
+            actix_web::http::header::AUTHORIZATION,
+            actix_web::http::header::CONTENT_TYPE,
+            request_id::HEADER,
+        ])
+        .expose_headers([
+            actix_web::http::header::ETAG,
+            actix_web::http::header::RETRY_AFTER,
+            request_id::HEADER,
+        ])
+        .max_age(3600);
+    for origin in origins {
+        cors = if origin == "*" { cors.allow_any_origin() } else { cors.allowed_origin(origin) };
+    }
+    cors
+}
+
 // 400 JSON limit/parse error
-fn bad_request_json() -> HttpResponse {
-    HttpResponse::BadRequest().json(model::ErrorResponse {
-        success: false,
-        status_code: 400,
-        message: "Bad Request".into(),
-        error: "Invalid JSON format or request payload size exceeded".into(),
-    })
+fn bad_request_json(path: &str) -> HttpResponse {
+    HttpResponse::BadRequest().json(
+        model::ErrorResponse::new(400, "Bad Request", "Invalid JSON format or request payload size exceeded")
+            .with_path(path),
+    )
 }
 
 // 404
-async fn not_found() -> impl Responder {
-    HttpResponse::NotFound().json(model::ErrorResponse::new(
-        404,
-        "Not Found",
-        "No route found",
-    ))
+async fn not_found(req: HttpRequest) -> impl Responder {
+    HttpResponse::NotFound().json(model::ErrorResponse::new(404, "Not Found", "No route found").with_path(req.path()))
 }
 
 #[actix_web::main]
@@ -72,44 +296,54 @@ async fn main() -> std::io::Result<()> {
     env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
 
     let args = CliArgs::parse();
-    let cfg = AppConfig::from_env();
+    let cfg = AppConfig::from_env().unwrap_or_else(|e| exit_with(e));
     info!("🔧 Configuration: {:?}", cfg);
 
     // --init-db mode: เตรียมไฟล์/ไดเรกทอรี แล้วสร้างตาราง จากนั้นออกเลย
     if args.init_db {
         info!("--init-db with DATABASE_URL = {}", cfg.database_url);
 
-        // รองรับเฉพาะไฟล์ (sqlite://...) ถ้าเป็น sqlite::memory: จะข้ามส่วนจัดการไฟล์
-        if let Some(path_str) = cfg.database_url.strip_prefix("sqlite://") {
-            let path = std::path::Path::new(path_str);
-
-            // สร้างโฟลเดอร์เฉพาะกรณีมี parent และไม่ว่าง
-            if let Some(parent) = path.parent() {
-                if !parent.as_os_str().is_empty() && !parent.exists() {
-                    info!("📁 Creating directory for database: {}", parent.display());
-                    if let Err(e) = std::fs::create_dir_all(parent) {
-                        eprintln!("❌ Failed to create directory {}: {e}", parent.display());
-                        return Ok(());
-                    }
+        if is_postgres_url(&cfg.database_url) {
+            // Postgres: ไม่ลบข้อมูลเดิม รันเฉพาะ migration ที่ยังไม่ได้รัน
+            info!("🐘 Migrating schema in PostgreSQL (existing data is kept)");
+        } else {
+            // same resolution as init_pool, so the schema lands in the file the server opens
+            match sqlite_target(&cfg.database_url) {
+                Ok(SqliteTarget::Memory) => {
+                    info!("⚠️ in-memory databases are migrated on every start, --init-db has nothing to keep");
                 }
-            }
+                Ok(SqliteTarget::File(path)) => {
+                    // สร้างโฟลเดอร์ถ้ายังไม่มี
+                    if let Some(parent) = path.parent()
+                        && !parent.exists()
+                    {
+                        info!("📁 Creating directory for database: {}", parent.display());
+                        if let Err(e) = std::fs::create_dir_all(parent) {
+                            eprintln!("❌ Failed to create directory {}: {e}", parent.display());
+                            return Ok(());
+                        }
+                    }
+
+                    // ลบไฟล์เดิม (ถ้ามี)
+                    if path.exists() {
+                        info!("🗑️ Removing existing database file: {}", path.display());
+                        if let Err(e) = std::fs::remove_file(&path) {
+                            eprintln!("❌ Failed to remove old DB file {}: {e}", path.display());
+                            return Ok(());
+                        }
+                    }
 
-            // ลบไฟล์เดิม (ถ้ามี) ในตำแหน่ง relative เดิม (ไม่เติม '/')
-            if path.exists() {
-                info!("🗑️ Removing existing database file: {}", path.display());
-                if let Err(e) = std::fs::remove_file(path) {
-                    eprintln!("❌ Failed to remove old DB file {}: {e}", path.display());
+                    info!("🆕 Creating new database file at {}", path.display());
+                }
+                Err(e) => {
+                    eprintln!("❌ Invalid DATABASE_URL: {e}");
                     return Ok(());
                 }
             }
-
-            info!("🆕 Creating new database file at {}", path.display());
-        } else {
-            info!("⚠️ --init-db works only with sqlite:// URLs (current: {})", cfg.database_url);
         }
 
         // สร้าง pool แล้ว init schema (แสดง error แทน panic)
-        match init_pool(&cfg.database_url).await {
+        match init_pool(&cfg.database_url, &PoolSettings::from_config(&cfg)).await {
             Ok(pool) => {
                 match init_db(&pool).await {
                     Ok(()) => {
@@ -129,54 +363,172 @@ async fn main() -> std::io::Result<()> {
     }
     
     // normal server mode
-    let pool = init_pool(&cfg.database_url)
+    let tls_config = match (cfg.tls_cert_path.as_deref(), cfg.tls_key_path.as_deref()) {
+        (Some(cert), Some(key)) => {
+            Some(tls::server_config(cert, key).unwrap_or_else(|e| exit_with(format!("TLS: {e}"))))
+        }
+        _ => None,
+    };
+    let telemetry = telemetry::init(&cfg);
+    let tracing_enabled = telemetry.is_some();
+
+    let pool = init_pool(&cfg.database_url, &PoolSettings::from_config(&cfg))
         .await
-        .expect("❌ Failed to initialize database");
+        .unwrap_or_else(|e| exit_with(format!("Failed to initialize database: {e}")));
+    if let Err(e) = init_db(&pool).await {
+        exit_with(format!("Failed to apply database migrations: {e}"));
+    }
+    if sqlite_target(&cfg.database_url) == Ok(SqliteTarget::Memory) {
+        info!("🧪 Using an in-memory SQLite database, data is lost on exit");
+    }
 
     // เตรียม AppState
-    let app_state = AppState {
-        global_pull_sem: Arc::new(Semaphore::new(cfg.max_concurrent_pulls)),
-        registry_sems: Arc::new(Mutex::new(HashMap::new())),
-        config: cfg.clone(),
-    };
+    let app_state = AppState::new(&cfg);
 
-    // ค่าไว้ใช้ใน worker โดยไม่จับ cfg ทั้งก้อน (กัน move)
-    let max_concurrent_pulls = cfg.max_concurrent_pulls;
-    let per_registry_max = cfg.per_registry_max;
+    let max_json_body_bytes = cfg.max_json_body_bytes;
+    let cors_origins = cfg.cors_allowed_origins.clone();
+    let json_access_log = cfg.log_format == access_log::LogFormat::Json;
+    if app_state.rate_limiter.is_enabled() {
+        app_state.rate_limiter.spawn_sweeper();
+    }
+    if cfg.api_keys.is_empty() {
+        log::warn!("⚠️ API_KEYS is not set: /api/v1 is open to anyone who can reach it");
+        if cfg.enable_admin_shutdown {
+            log::warn!("⚠️ ENABLE_ADMIN_SHUTDOWN has no effect without API_KEYS");
+        }
+    }
 
-    // start worker
+    // start worker (shares semaphores with AppState so they can be resized at runtime)
     let runner_pool = pool.clone();
-    tokio::spawn(async move {
+    let runner_state = app_state.clone();
+    let runner = tokio::spawn(async move {
         worker::run_job_runner(
+            runner_state,
             runner_pool,
-            max_concurrent_pulls,
-            per_registry_max,
             300, // lease time (secs)
         )
         .await;
     });
 
-    let addr = format!("0.0.0.0:{}", cfg.app_port);
-    info!("🚀 Server running at http://{addr}");
-
-    HttpServer::new(move || {
+    let shutdown_state = app_state.clone();
+    let server = HttpServer::new(move || {
         App::new()
+            .wrap(middleware::from_fn(error::add_request_path))
             .wrap(NormalizePath::new(TrailingSlash::Trim))
-            .wrap(Logger::default())
+            .wrap(Condition::new(!cors_origins.is_empty(), cors(&cors_origins)))
+            .wrap(Condition::new(tracing_enabled, middleware::from_fn(telemetry::trace_request)))
+            .wrap(middleware::from_fn(request_id::assign_request_id))
+            .wrap(Condition::new(json_access_log, middleware::from_fn(access_log::log_json)))
+            .wrap(Condition::new(!json_access_log, Logger::new(TEXT_ACCESS_LOG)))
             .app_data(web::Data::new(app_state.clone()))
             .app_data(web::Data::new(pool.clone()))
             .app_data(
                 web::JsonConfig::default()
-                    .limit(4096)
-                    .error_handler(|err, _req| {
-                        actix_web::error::InternalError::from_response(err, bad_request_json()).into()
+                    .limit(max_json_body_bytes)
+                    .error_handler(|err, req| {
+                        actix_web::error::InternalError::from_response(err, bad_request_json(req.path())).into()
                     }),
             )
             .configure(routes::service_config)
             .service(health)
+            .service(health_deep)
             .default_service(web::route().to(not_found))
-    })
-    .bind(addr)?
-    .run()
-    .await
+    });
+    let server = match cfg.bind_uds.as_deref() {
+        #[cfg(unix)]
+        Some(path) => {
+            remove_stale_socket(path)?;
+            info!("🚀 Server running at unix:{}", path.display());
+            server.bind_uds(path)?
+        }
+        #[cfg(not(unix))]
+        Some(_) => {
+            return Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "BIND_UDS needs a unix platform"));
+        }
+        None => {
+            let addr = SocketAddr::new(cfg.bind_address, cfg.app_port);
+            match tls_config {
+                Some(tls) => {
+                    info!("🚀 Server running at https://{addr}");
+                    server.bind_rustls_0_23(addr, tls)?
+                }
+                None => {
+                    info!("🚀 Server running at http://{addr}");
+                    server.bind(addr)?
+                }
+            }
+        }
+    }
+    // signals are handled below so in-flight pulls can drain before the server stops
+    .disable_signals()
+    .run();
+
+    let server_handle = server.handle();
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = shutdown_signal() => {}
+            _ = shutdown_state.shutdown_requested.notified() => {}
+        }
+        info!("🛑 Shutdown requested: draining in-flight pulls");
+        shutdown_state.draining.store(true, Ordering::SeqCst);
+        // wake an idle runner so it sees the flag right away
+        shutdown_state.notify_new_job();
+
+        if let Err(e) = runner.await {
+            log::warn!("job-runner task ended abnormally: {e}");
+        }
+        info!("✅ Drain complete, stopping HTTP server");
+        server_handle.stop(true).await;
+    });
+
+    let res = server.await;
+    if let Some(telemetry) = telemetry {
+        telemetry.shutdown();
+    }
+    res
+}
+
+/// Startup failure: print `err` and exit with status 1, without a panic backtrace.
+fn exit_with(err: impl std::fmt::Display) -> ! {
+    eprintln!("❌ {err}");
+    std::process::exit(1)
+}
+
+/// A socket left at `path` by an earlier run would make the bind fail.
+#[cfg(unix)]
+fn remove_stale_socket(path: &std::path::Path) -> std::io::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+
+    if std::fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+        std::fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+/// Resolves on ctrl-c, or SIGTERM on unix.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        let _ = tokio::signal::ctrl_c().await;
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut sig) => {
+                sig.recv().await;
+            }
+            Err(e) => {
+                log::warn!("cannot install SIGTERM handler: {e}");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
 }
@@ -1,6 +1,7 @@
 use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
 pub struct ApiResponse<T> {
     pub success: bool,
     pub message: String,
@@ -17,12 +18,43 @@ impl<T> ApiResponse<T> {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// List envelope: `data` is one page of `total` items, starting at `offset`.
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct PaginatedResponse<T> {
+    pub success: bool,
+    pub message: String,
+    pub total: i64,
+    pub limit: i64,
+    pub offset: i64,
+    pub data: Vec<T>,
+}
+
+impl<T> PaginatedResponse<T> {
+    pub fn ok(message: &str, data: Vec<T>, total: i64, limit: i64, offset: i64) -> Self {
+        Self {
+            success: true,
+            message: message.into(),
+            total,
+            limit,
+            offset,
+            data,
+        }
+    }
+}
+
+/// Body of every 4xx/5xx answer
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
 pub struct ErrorResponse {
     pub success: bool,
     pub status_code: u16,
     pub message: String,
     pub error: String,
+    /// When the error happened, RFC 3339 UTC
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<String>,
+    /// Request path that failed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
 }
 
 impl ErrorResponse {
@@ -32,6 +64,326 @@ impl ErrorResponse {
             status_code,
             message: message.into(),
             error: error.into(),
+            timestamp: Some(chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true)),
+            path: None,
+        }
+    }
+
+    pub fn with_path(mut self, path: &str) -> Self {
+        self.path = Some(path.to_string());
+        self
+    }
+}
+
+/// What `GET /api/v1/version` reports; filled in at compile time by `build.rs`.
+#[derive(Serialize, Debug, ToSchema)]
+pub struct VersionInfo {
+    pub version: &'static str,
+    pub git_sha: &'static str,
+    /// RFC 3339, UTC
+    pub built_at: String,
+    pub rustc: &'static str,
+}
+
+impl VersionInfo {
+    pub fn current() -> Self {
+        let built_at = env!("BUILD_TIMESTAMP")
+            .parse::<i64>()
+            .ok()
+            .and_then(|secs| chrono::DateTime::from_timestamp(secs, 0))
+            .map(|t| t.to_rfc3339_opts(chrono::SecondsFormat::Secs, true))
+            .unwrap_or_else(|| "unknown".to_string());
+        Self {
+            version: env!("CARGO_PKG_VERSION"),
+            git_sha: env!("BUILD_GIT_SHA"),
+            built_at,
+            rustc: env!("BUILD_RUSTC_VERSION"),
+        }
+    }
+}
+
+/// Where a job is in its lifecycle; the only values the `status` column holds.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    /// Older rows may say `success`
+    #[serde(alias = "success")]
+    Completed,
+    Failed,
+    Canceled,
+}
+
+impl JobStatus {
+    pub const ALL: [JobStatus; 5] = [Self::Queued, Self::Running, Self::Completed, Self::Failed, Self::Canceled];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Queued => "queued",
+            Self::Running => "running",
+            Self::Completed => "completed",
+            Self::Failed => "failed",
+            Self::Canceled => "canceled",
+        }
+    }
+
+    /// Nothing happens to the job any more unless it is retried.
+    pub fn is_terminal(self) -> bool {
+        matches!(self, Self::Completed | Self::Failed | Self::Canceled)
+    }
+
+    /// Transitions the worker and the API make:
+    ///
+    /// - queued -> running (claimed), canceled
+    /// - running -> completed, failed, canceled, queued (retry after a failed attempt or an expired lease)
+    /// - completed / failed / canceled -> queued (`POST /jobs/{id}/retry`)
+    pub fn can_transition_to(self, next: JobStatus) -> bool {
+        use JobStatus::*;
+        matches!(
+            (self, next),
+            (Queued, Running | Canceled)
+                | (Running, Completed | Failed | Canceled | Queued)
+                | (Completed | Failed | Canceled, Queued)
+        )
+    }
+
+    /// `"queued or running"`: the statuses that may move to `next`, for error messages
+    pub fn sources_of(next: JobStatus) -> String {
+        let from: Vec<&str> = Self::ALL
+            .into_iter()
+            .filter(|s| s.can_transition_to(next))
+            .map(JobStatus::as_str)
+            .collect();
+        match from.split_last() {
+            Some((last, [])) => last.to_string(),
+            Some((last, rest)) => format!("{} or {}", rest.join(", "), last),
+            None => String::new(),
+        }
+    }
+}
+
+impl std::fmt::Display for JobStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl std::str::FromStr for JobStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "queued" => Ok(Self::Queued),
+            "running" => Ok(Self::Running),
+            "completed" | "success" => Ok(Self::Completed),
+            "failed" => Ok(Self::Failed),
+            "canceled" => Ok(Self::Canceled),
+            other => Err(format!("unknown job status '{}'", other)),
         }
     }
 }
+
+/// Lets rows decode `status` straight into [`JobStatus`] (`#[sqlx(try_from = "String")]`).
+impl TryFrom<String> for JobStatus {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// Units a metric may be stored with; the `unit` column holds [`MetricUnit::as_str`].
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, ToSchema)]
+#[serde(try_from = "String")]
+pub enum MetricUnit {
+    #[serde(rename = "ms")]
+    Milliseconds,
+    #[serde(rename = "s")]
+    Seconds,
+    #[serde(rename = "bytes")]
+    Bytes,
+    /// 10^6 bytes, like the size in a job's summary
+    #[serde(rename = "MB")]
+    Megabytes,
+    /// 10^6 bits per second
+    #[serde(rename = "Mbps")]
+    Mbps,
+    #[serde(rename = "%")]
+    Percent,
+}
+
+impl MetricUnit {
+    pub const ALL: [MetricUnit; 6] =
+        [Self::Milliseconds, Self::Seconds, Self::Bytes, Self::Megabytes, Self::Mbps, Self::Percent];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Milliseconds => "ms",
+            Self::Seconds => "s",
+            Self::Bytes => "bytes",
+            Self::Megabytes => "MB",
+            Self::Mbps => "Mbps",
+            Self::Percent => "%",
+        }
+    }
+
+    /// `value` expressed in `to`, if both units measure the same thing.
+    pub fn convert(self, value: f64, to: MetricUnit) -> Option<f64> {
+        use MetricUnit::*;
+        let factor = match (self, to) {
+            _ if self == to => 1.0,
+            (Seconds, Milliseconds) => 1000.0,
+            (Milliseconds, Seconds) => 0.001,
+            (Megabytes, Bytes) => 1_000_000.0,
+            (Bytes, Megabytes) => 1.0 / 1_000_000.0,
+            _ => return None,
+        };
+        Some(value * factor)
+    }
+}
+
+/// Bytes as decimal megabytes
+pub fn bytes_to_mb(bytes: f64) -> f64 {
+    bytes / 1_000_000.0
+}
+
+impl std::fmt::Display for MetricUnit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl std::str::FromStr for MetricUnit {
+    type Err = String;
+
+    /// The canonical spelling or a spelled-out name, in any case (`MS`, `seconds`, `megabytes`).
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "ms" | "milliseconds" => Ok(Self::Milliseconds),
+            "s" | "seconds" => Ok(Self::Seconds),
+            "bytes" | "byte" => Ok(Self::Bytes),
+            "mb" | "megabytes" => Ok(Self::Megabytes),
+            "mbps" => Ok(Self::Mbps),
+            "%" | "percent" => Ok(Self::Percent),
+            _ => {
+                let known: Vec<&str> = Self::ALL.into_iter().map(MetricUnit::as_str).collect();
+                Err(format!("unknown unit '{}', expected one of: {}", s, known.join(", ")))
+            }
+        }
+    }
+}
+
+impl TryFrom<String> for MetricUnit {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::JobStatus::{self, *};
+
+    #[test]
+    fn status_round_trips_through_text_and_json() {
+        for status in JobStatus::ALL {
+            assert_eq!(status.as_str().parse::<JobStatus>(), Ok(status));
+            assert_eq!(status.to_string(), status.as_str());
+            let json = serde_json::to_string(&status).unwrap();
+            assert_eq!(json, format!("\"{}\"", status));
+            assert_eq!(serde_json::from_str::<JobStatus>(&json).unwrap(), status);
+        }
+    }
+
+    #[test]
+    fn legacy_success_reads_as_completed() {
+        assert_eq!("success".parse::<JobStatus>(), Ok(Completed));
+        assert_eq!(serde_json::from_str::<JobStatus>("\"success\"").unwrap(), Completed);
+        assert_eq!(Completed.as_str(), "completed");
+    }
+
+    #[test]
+    fn unknown_statuses_are_rejected() {
+        for raw in ["", "Queued", "cancelled", "done", " running"] {
+            assert!(raw.parse::<JobStatus>().is_err(), "{raw:?}");
+        }
+        assert_eq!(JobStatus::try_from("done".to_string()), Err("unknown job status 'done'".to_string()));
+    }
+
+    #[test]
+    fn transition_matrix() {
+        // rows: from, columns: to, in JobStatus::ALL order
+        let allowed = [
+            (Queued, [false, true, false, false, true]),
+            (Running, [true, false, true, true, true]),
+            (Completed, [true, false, false, false, false]),
+            (Failed, [true, false, false, false, false]),
+            (Canceled, [true, false, false, false, false]),
+        ];
+        for (from, row) in allowed {
+            for (to, expected) in JobStatus::ALL.into_iter().zip(row) {
+                assert_eq!(from.can_transition_to(to), expected, "{from} -> {to}");
+            }
+        }
+        assert_eq!(JobStatus::sources_of(Canceled), "queued or running");
+        assert_eq!(JobStatus::sources_of(Queued), "running, completed, failed or canceled");
+        assert_eq!(JobStatus::sources_of(Running), "queued");
+    }
+
+    #[test]
+    fn only_finished_statuses_are_terminal() {
+        let terminal: Vec<_> = JobStatus::ALL.into_iter().filter(|s| s.is_terminal()).collect();
+        assert_eq!(terminal, [Completed, Failed, Canceled]);
+    }
+
+    #[test]
+    fn paginated_response_serializes_flat() {
+        let page = super::PaginatedResponse::ok("ok", vec![serde_json::json!({ "id": "job-2" })], 7, 1, 1);
+        assert_eq!(
+            serde_json::to_value(&page).unwrap(),
+            serde_json::json!({
+                "success": true,
+                "message": "ok",
+                "total": 7,
+                "limit": 1,
+                "offset": 1,
+                "data": [{ "id": "job-2" }],
+            })
+        );
+        let empty = super::PaginatedResponse::<()>::ok("ok", vec![], 0, 50, 0);
+        assert_eq!(serde_json::to_string(&empty).unwrap(), r#"{"success":true,"message":"ok","total":0,"limit":50,"offset":0,"data":[]}"#);
+    }
+
+    #[test]
+    fn metric_units_parse_to_their_canonical_form() {
+        use super::MetricUnit::{self, *};
+
+        for unit in MetricUnit::ALL {
+            assert_eq!(unit.as_str().parse::<MetricUnit>(), Ok(unit));
+            assert_eq!(serde_json::to_string(&unit).unwrap(), format!("\"{}\"", unit));
+        }
+        for (raw, unit) in [("MS", Milliseconds), (" seconds ", Seconds), ("byte", Bytes), ("megabytes", Megabytes), ("MBPS", Mbps), ("percent", Percent)] {
+            assert_eq!(raw.parse::<MetricUnit>(), Ok(unit), "{raw:?}");
+        }
+        assert_eq!(
+            "millis".parse::<MetricUnit>(),
+            Err("unknown unit 'millis', expected one of: ms, s, bytes, MB, Mbps, %".to_string())
+        );
+    }
+
+    #[test]
+    fn metric_units_convert_within_a_dimension_only() {
+        use super::MetricUnit::*;
+
+        assert_eq!(Seconds.convert(1.5, Milliseconds), Some(1500.0));
+        assert_eq!(Milliseconds.convert(250.0, Seconds), Some(0.25));
+        assert_eq!(Bytes.convert(3_000_000.0, Megabytes), Some(3.0));
+        assert_eq!(Megabytes.convert(2.0, Bytes), Some(2_000_000.0));
+        assert_eq!(Mbps.convert(80.0, Mbps), Some(80.0));
+        assert_eq!(Bytes.convert(1.0, Milliseconds), None);
+        assert_eq!(Percent.convert(50.0, Mbps), None);
+        assert_eq!(super::bytes_to_mb(1_500_000.0), 1.5);
+    }
+}
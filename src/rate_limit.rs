@@ -0,0 +1,178 @@
+//! Per-client token bucket for the `/api/v1` scope.
+//!
+//! Clients are keyed by their bearer token when `API_KEYS` is set (the auth
+//! check has validated it by then), otherwise by client IP (`Forwarded` / `X-Forwarded-For`, then the peer address). The
+//! forwarded headers are taken at face value, so only rely on IP keying behind
+//! a proxy that sets them.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::AUTHORIZATION;
+use actix_web::middleware::Next;
+use actix_web::web;
+use log::info;
+
+use crate::error::AppError;
+use crate::AppState;
+
+/// A bucket that has been idle this long is full again and can be dropped.
+const IDLE_EVICT: Duration = Duration::from_secs(120);
+const SWEEP_EVERY: Duration = Duration::from_secs(60);
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// `rpm` requests per minute per client, with bursts of up to `rpm`.
+#[derive(Clone)]
+pub struct RateLimiter {
+    rpm: u32,
+    buckets: Arc<Mutex<HashMap<String, Bucket>>>,
+}
+
+impl RateLimiter {
+    pub fn new(rpm: u32) -> Self {
+        Self { rpm, buckets: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.rpm > 0
+    }
+
+    /// Take one token for `key`; `Err(secs)` is how long until one is available.
+    pub fn check(&self, key: &str) -> Result<(), u64> {
+        let capacity = self.rpm as f64;
+        let per_sec = capacity / 60.0;
+        let now = Instant::now();
+
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        let bucket = buckets
+            .entry(key.to_string())
+            .or_insert(Bucket { tokens: capacity, updated: now });
+
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * per_sec).min(capacity);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(((1.0 - bucket.tokens) / per_sec).ceil().max(1.0) as u64)
+        }
+    }
+
+    /// Drop buckets that have been idle long enough to have refilled.
+    pub fn evict_idle(&self) -> usize {
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        let before = buckets.len();
+        buckets.retain(|_, b| b.updated.elapsed() < IDLE_EVICT);
+        before - buckets.len()
+    }
+
+    /// Periodically evict idle buckets so one-off clients don't accumulate.
+    pub fn spawn_sweeper(&self) {
+        let limiter = self.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(SWEEP_EVERY).await;
+                let n = limiter.evict_idle();
+                if n > 0 {
+                    info!("rate limiter: evicted {} idle client(s)", n);
+                }
+            }
+        });
+    }
+}
+
+/// Bucket name for `req`; tokens only count when `by_token`, since without
+/// `API_KEYS` every made-up token would get a fresh bucket.
+fn client_key(req: &ServiceRequest, by_token: bool) -> String {
+    let token = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(str::trim);
+    if let Some(t) = token.filter(|_| by_token) {
+        return format!("key:{}", t);
+    }
+    let info = req.connection_info();
+    format!("ip:{}", info.realip_remote_addr().unwrap_or("unknown"))
+}
+
+/// Middleware: answer 429 with `Retry-After` once a client's bucket is empty.
+pub async fn limit_requests(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let (limiter, by_token) = match req.app_data::<web::Data<AppState>>() {
+        Some(state) => (state.rate_limiter.clone(), !state.config().api_keys.is_empty()),
+        None => return Ok(req.error_response(AppError::internal("app state missing")).map_into_right_body()),
+    };
+    if limiter.is_enabled()
+        && let Err(retry_after) = limiter.check(&client_key(&req, by_token))
+    {
+        return Ok(req.error_response(AppError::RateLimited(retry_after)).map_into_right_body());
+    }
+    next.call(req).await.map(ServiceResponse::map_into_left_body)
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::StatusCode;
+    use actix_web::test::{self, TestRequest};
+
+    use super::*;
+    use crate::test_support::{self, pool};
+
+    #[test]
+    fn bucket_runs_dry_after_rpm_requests() {
+        let limiter = RateLimiter::new(3);
+        for _ in 0..3 {
+            assert_eq!(limiter.check("ip:10.0.0.1"), Ok(()));
+        }
+        // one token comes back every 20s at 3 rpm
+        assert_eq!(limiter.check("ip:10.0.0.1"), Err(20));
+        assert_eq!(limiter.check("ip:10.0.0.2"), Ok(()));
+    }
+
+    fn get(token: &str, ip: &str) -> TestRequest {
+        TestRequest::get()
+            .uri("/api/v1/health")
+            .insert_header(("Authorization", format!("Bearer {}", token)))
+            .peer_addr(format!("{}:40000", ip).parse().unwrap())
+    }
+
+    #[actix_web::test]
+    async fn made_up_tokens_share_their_ip_bucket() {
+        let state = test_support::state(&[("RATE_LIMIT_RPM", "2")]);
+        let app = test::init_service(test_support::app(state, pool().await)).await;
+
+        for token in ["a", "b"] {
+            assert_eq!(test::call_service(&app, get(token, "10.0.0.1").to_request()).await.status(), StatusCode::OK);
+        }
+        let res = test::call_service(&app, get("c", "10.0.0.1").to_request()).await;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(res.headers().contains_key("retry-after"));
+        assert_eq!(test::call_service(&app, get("c", "10.0.0.2").to_request()).await.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn api_keys_get_a_bucket_each() {
+        let state = test_support::state(&[("RATE_LIMIT_RPM", "2"), ("API_KEYS", "k1,k2")]);
+        let app = test::init_service(test_support::app(state, pool().await)).await;
+
+        for _ in 0..2 {
+            assert_eq!(test::call_service(&app, get("k1", "10.0.0.1").to_request()).await.status(), StatusCode::OK);
+        }
+        let res = test::call_service(&app, get("k1", "10.0.0.1").to_request()).await;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(test::call_service(&app, get("k2", "10.0.0.1").to_request()).await.status(), StatusCode::OK);
+    }
+}
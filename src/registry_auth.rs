@@ -0,0 +1,115 @@
+//! Registry credentials for `create_image`.
+//!
+//! Lookup order for a registry host:
+//! 1. `REGISTRY_AUTH_<HOST>=user:pass`, where `<HOST>` is the host upper-cased with
+//!    every non-alphanumeric character replaced by `_` (`ghcr.io` -> `REGISTRY_AUTH_GHCR_IO`,
+//!    `localhost:5000` -> `REGISTRY_AUTH_LOCALHOST_5000`)
+//! 2. `auths` in `$DOCKER_CONFIG/config.json`, or `~/.docker/config.json`
+
+use std::path::PathBuf;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bollard::auth::DockerCredentials;
+use log::warn;
+
+const DOCKER_HUB_SERVER: &str = "https://index.docker.io/v1/";
+
+/// Env var consulted for `registry_host`
+pub fn env_key(registry_host: &str) -> String {
+    let host: String = registry_host
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' })
+        .collect();
+    format!("REGISTRY_AUTH_{}", host)
+}
+
+/// Credentials for `registry_host`, if any are configured.
+pub fn credentials_for(registry_host: &str) -> Option<DockerCredentials> {
+    if let Ok(v) = std::env::var(env_key(registry_host)) {
+        match v.split_once(':') {
+            Some((user, pass)) => return Some(basic(registry_host, user, pass)),
+            None => warn!("{} must be user:pass; ignoring it", env_key(registry_host)),
+        }
+    }
+    from_docker_config(registry_host)
+}
+
+pub fn basic(registry_host: &str, username: &str, password: &str) -> DockerCredentials {
+    DockerCredentials {
+        username: Some(username.to_string()),
+        password: Some(password.to_string()),
+        serveraddress: Some(server_address(registry_host)),
+        ..Default::default()
+    }
+}
+
+fn server_address(registry_host: &str) -> String {
+    if registry_host == "docker.io" {
+        DOCKER_HUB_SERVER.to_string()
+    } else {
+        registry_host.to_string()
+    }
+}
+
+fn docker_config_path() -> Option<PathBuf> {
+    if let Ok(dir) = std::env::var("DOCKER_CONFIG") {
+        return Some(PathBuf::from(dir).join("config.json"));
+    }
+    std::env::var("HOME")
+        .ok()
+        .map(|home| PathBuf::from(home).join(".docker").join("config.json"))
+}
+
+/// `{"auths": {"<host>": {"auth": base64("user:pass")} | {"identitytoken": ".."}}}`
+fn from_docker_config(registry_host: &str) -> Option<DockerCredentials> {
+    let raw = std::fs::read_to_string(docker_config_path()?).ok()?;
+    let cfg: serde_json::Value = serde_json::from_str(&raw).ok()?;
+    let auths = cfg.get("auths")?.as_object()?;
+
+    // keys may be bare hosts or URLs ("https://ghcr.io", the Hub's index URL)
+    let entry = auths.iter().find_map(|(k, v)| {
+        let host = k
+            .trim_start_matches("https://")
+            .trim_start_matches("http://")
+            .split('/')
+            .next()
+            .unwrap_or("");
+        let matches = host == registry_host
+            || (registry_host == "docker.io"
+                && matches!(host, "index.docker.io" | "registry-1.docker.io"));
+        matches.then_some(v)
+    })?;
+
+    if let Some(token) = entry.get("identitytoken").and_then(|v| v.as_str()) {
+        return Some(DockerCredentials {
+            identitytoken: Some(token.to_string()),
+            serveraddress: Some(server_address(registry_host)),
+            ..Default::default()
+        });
+    }
+
+    let auth = entry.get("auth")?.as_str()?;
+    let decoded = String::from_utf8(STANDARD.decode(auth).ok()?).ok()?;
+    let (user, pass) = decoded.split_once(':')?;
+    Some(basic(registry_host, user, pass))
+}
+
+/// Does a pull error look like the registry refused us for lack of (valid) credentials?
+pub fn is_auth_error(msg: &str) -> bool {
+    let m = msg.to_ascii_lowercase();
+    m.contains("unauthorized")
+        || m.contains("authentication required")
+        || m.contains("no basic auth credentials")
+        || mentions_code(&m, "401")
+        || (m.contains("denied") && !m.contains("permission denied"))
+}
+
+/// Does `msg` contain `code` as a word of its own? A digest or size that
+/// happens to contain the digits doesn't count.
+pub fn mentions_code(msg: &str, code: &str) -> bool {
+    msg.match_indices(code).any(|(at, _)| {
+        let before = msg[..at].chars().next_back();
+        let after = msg[at + code.len()..].chars().next();
+        [before, after].iter().all(|c| c.is_none_or(|c| !c.is_ascii_alphanumeric()))
+    })
+}
@@ -0,0 +1,189 @@
+use actix_web::{patch, post, web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::config::{AppConfig, ConfigReload, MAX_PULLS_CEILING};
+use crate::error::AppError;
+use crate::model::{ApiResponse, ErrorResponse};
+use crate::AppState;
+
+pub fn config_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(set_concurrency).service(reload_config).service(shutdown);
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct ConcurrencyRequest {
+    pub max_concurrent_pulls: usize,
+}
+
+#[derive(Serialize, ToSchema)]
+struct ConcurrencyChange {
+    previous: usize,
+    max_concurrent_pulls: usize,
+}
+
+/// Resize the global pull limit without a restart.
+///
+/// Raising it lets queued jobs start right away. Lowering it only takes effect as
+/// in-flight pulls finish; running pulls are never interrupted.
+#[utoipa::path(
+    tag = "config",
+    request_body = ConcurrencyRequest,
+    responses(
+        (status = 200, description = "New limit applied", body = ApiResponse<ConcurrencyChange>),
+        (status = 400, description = "max_concurrent_pulls out of range", body = ErrorResponse),
+    )
+)]
+#[patch("/config/concurrency")]
+pub async fn set_concurrency(
+    state: web::Data<AppState>,
+    body: web::Json<ConcurrencyRequest>,
+) -> Result<HttpResponse, AppError> {
+    let target = body.max_concurrent_pulls;
+    if !(1..=MAX_PULLS_CEILING).contains(&target) {
+        return Err(AppError::bad_request(format!(
+            "max_concurrent_pulls must be between 1 and {}",
+            MAX_PULLS_CEILING
+        )));
+    }
+
+    let previous = state.resize_pull_concurrency(target);
+
+    Ok(HttpResponse::Ok().json(ApiResponse::ok(
+        "concurrency updated",
+        ConcurrencyChange { previous, max_concurrent_pulls: target },
+    )))
+}
+
+/// Read the configuration again, the same way as at startup, and apply the
+/// settings that don't need a restart. Changed startup-only variables are
+/// listed under `ignored`; an invalid configuration changes nothing.
+///
+/// A process can't have its environment changed from outside, and a set
+/// variable wins over the file, so in practice only edits to `CONFIG_FILE`
+/// (for keys not also set in the environment) take effect.
+#[utoipa::path(
+    tag = "config",
+    responses(
+        (status = 200, description = "Reloadable settings applied", body = ApiResponse<ConfigReload>),
+        (status = 400, description = "The new configuration is invalid; nothing changed", body = ErrorResponse),
+    )
+)]
+#[post("/admin/reload-config")]
+pub async fn reload_config(state: web::Data<AppState>) -> Result<HttpResponse, AppError> {
+    let new = AppConfig::from_env().map_err(|e| AppError::bad_request(e.errors.join("; ")))?;
+    let reload = state.reload_config(&new).await;
+    for c in &reload.changed {
+        log::info!("🔧 reload: {} {} -> {}", c.variable, c.previous, c.current);
+    }
+
+    Ok(HttpResponse::Ok().json(ApiResponse::ok("configuration reloaded", reload)))
+}
+
+/// Drain and stop the server like SIGTERM does, for environments that can't
+/// send signals: new jobs are refused and nothing more is claimed at once, the
+/// server exits once in-flight pulls are done. Off unless `ENABLE_ADMIN_SHUTDOWN`,
+/// and refused while `API_KEYS` is empty so an open API can't be stopped by anyone.
+#[utoipa::path(
+    tag = "config",
+    responses(
+        (status = 202, description = "Shutdown started or already underway; `data` is `{\"draining\": true, \"in_flight\": ..}`", body = ApiResponse<serde_json::Value>),
+        (status = 403, description = "ENABLE_ADMIN_SHUTDOWN is off or API_KEYS is empty", body = ErrorResponse),
+    )
+)]
+#[post("/admin/shutdown")]
+pub async fn shutdown(state: web::Data<AppState>) -> Result<HttpResponse, AppError> {
+    if !state.config().enable_admin_shutdown {
+        return Err(AppError::forbidden("admin shutdown is disabled; set ENABLE_ADMIN_SHUTDOWN=true"));
+    }
+    if state.config().api_keys.is_empty() {
+        return Err(AppError::forbidden("admin shutdown needs API_KEYS to be set"));
+    }
+    let message = match state.request_shutdown() {
+        true => {
+            log::info!("🛑 shutdown requested through the API");
+            "shutting down after in-flight pulls finish"
+        }
+        false => "shutdown already in progress",
+    };
+    let in_flight = state.cancel_flags.lock().await.len();
+    Ok(HttpResponse::Accepted().json(ApiResponse::ok(
+        message,
+        serde_json::json!({ "draining": true, "in_flight": in_flight }),
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use actix_web::http::StatusCode;
+    use actix_web::test::{self, TestRequest};
+
+    use crate::test_support;
+
+    fn write_config_file(name: &str, contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("imgpuller-{}-{}.toml", std::process::id(), name));
+        std::fs::write(&path, contents).expect("write config file");
+        path
+    }
+
+    #[tokio::test]
+    async fn reload_applies_file_edits_and_reports_restart_only_ones() {
+        let path = write_config_file("reload", "max_concurrent_pulls = 4\napp_port = 8080\n");
+        let file = path.to_str().unwrap();
+        let state = test_support::state(&[("CONFIG_FILE", file)]);
+
+        std::fs::write(&path, "max_concurrent_pulls = 7\napp_port = 9090\n").unwrap();
+        let reload = state.reload_config(&test_support::config(&[("CONFIG_FILE", file)])).await;
+        std::fs::remove_file(&path).unwrap();
+
+        let changed: Vec<_> = reload.changed.iter().map(|c| (c.variable, c.current.clone())).collect();
+        assert_eq!(changed, [("MAX_CONCURRENT_PULLS", serde_json::json!(7))]);
+        assert_eq!(reload.ignored, ["APP_PORT"]);
+        assert_eq!(state.config().max_concurrent_pulls, 7);
+        assert_eq!(state.config().app_port, 8080);
+    }
+
+    #[tokio::test]
+    async fn environment_wins_over_a_file_edit() {
+        let path = write_config_file("reload-env", "max_concurrent_pulls = 4\n");
+        let vars = [("CONFIG_FILE", path.to_str().unwrap()), ("MAX_CONCURRENT_PULLS", "3")];
+        let state = test_support::state(&vars);
+
+        std::fs::write(&path, "max_concurrent_pulls = 7\n").unwrap();
+        let reload = state.reload_config(&test_support::config(&vars)).await;
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(reload.changed.is_empty(), "{:?}", reload.changed);
+        assert_eq!(state.config().max_concurrent_pulls, 3);
+    }
+
+    #[actix_web::test]
+    async fn shutdown_drains_once_and_needs_api_keys() {
+        let enabled = [("ENABLE_ADMIN_SHUTDOWN", "true"), ("API_KEYS", "k1")];
+        let shutdown = || TestRequest::post().uri("/api/v1/admin/shutdown").insert_header(("Authorization", "Bearer k1"));
+
+        let state = test_support::state(&enabled);
+        let app = test::init_service(test_support::app(state.clone(), test_support::pool().await)).await;
+        let res = test::call_service(&app, shutdown().to_request()).await;
+        assert_eq!(res.status(), StatusCode::ACCEPTED);
+        let body: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(body["message"], "shutting down after in-flight pulls finish");
+        assert_eq!(body["data"], serde_json::json!({ "draining": true, "in_flight": 0 }));
+        assert!(state.is_draining());
+
+        let res = test::call_service(&app, shutdown().to_request()).await;
+        assert_eq!(res.status(), StatusCode::ACCEPTED);
+        let body: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(body["message"], "shutdown already in progress");
+
+        for vars in [&[("API_KEYS", "k1")][..], &[("ENABLE_ADMIN_SHUTDOWN", "true")][..]] {
+            let state = test_support::state(vars);
+            let app = test::init_service(test_support::app(state.clone(), test_support::pool().await)).await;
+            let res = test::call_service(&app, shutdown().to_request()).await;
+            assert_eq!(res.status(), StatusCode::FORBIDDEN, "{vars:?}");
+            assert!(!state.is_draining());
+        }
+    }
+}
@@ -1,73 +1,495 @@
-use std::collections::HashMap;
-use std::time::Instant;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::time::{Duration, Instant};
 
-use actix_web::{get, post, web, HttpResponse};
-use bollard::query_parameters::{CreateImageOptions, RemoveImageOptions};
-use bollard::Docker;
-use futures_util::TryStreamExt;
+use actix_web::http::header::{ContentType, ETag, EntityTag, IfNoneMatch};
+use actix_web::{delete, get, post, web, HttpMessage, HttpRequest, HttpResponse};
+use bytes::Bytes;
 use log::warn;
 use serde::Deserialize;
-use sqlx::SqlitePool;
+use utoipa::ToSchema;
+use validator::{Validate, ValidationError};
 
-use crate::db;
+use crate::backend::{self, PullBackend, PullCanceled, PullMetrics, PullRequest, NO_PROGRESS};
+use crate::config::AppConfig;
+use crate::db::{self, DbPool};
 use crate::error::AppError;
-use crate::model::ApiResponse;
+use crate::events::{self, JobUpdate};
+use crate::model::{self, ApiResponse, ErrorResponse, JobStatus, MetricUnit, PaginatedResponse};
+use crate::registry_auth;
+use crate::request_id::RequestId;
+use crate::telemetry;
+use crate::webhook;
+use crate::worker::{is_registry_host, parse_registry};
+use crate::AppState;
 
 pub fn job_routes(cfg: &mut web::ServiceConfig) {
-    cfg.service(create_job).service(list_jobs).service(get_job);
+    cfg.service(create_job)
+        .service(create_jobs_batch)
+        .service(list_jobs)
+        .service(purge_jobs)
+        // before /jobs/{id}, which would match them too
+        .service(compare_jobs)
+        .service(search_jobs)
+        .service(get_job)
+        .service(delete_job)
+        .service(retry_job)
+        .service(cancel_job)
+        .service(get_job_logs)
+        .service(job_events);
 }
 
-#[derive(Deserialize)]
+/// Field limits are checked with `validate()`; a missing `image` is reported
+/// like an empty one rather than as a JSON error.
+#[derive(Deserialize, ToSchema, Validate)]
 pub struct CreateJobRequest {
+    #[serde(default)]
+    #[schema(required = true)]
+    #[validate(length(min = 1, max = MAX_IMAGE_LEN))]
     pub image: String,
+    /// -100..=100, higher is claimed first (default 0)
+    #[validate(range(min = MIN_PRIORITY, max = MAX_PRIORITY))]
+    pub priority: Option<i32>,
+    /// Only inspect the registry manifest, no layers are downloaded
+    #[serde(default)]
+    pub dry_run: bool,
+    /// Remove the image before/after pulling; defaults to PRE_PULL_REMOVE / POST_PULL_REMOVE
+    pub pre_remove: Option<bool>,
+    pub post_remove: Option<bool>,
+    /// Optional registry credentials for this job only; never returned by the API.
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Digest (`sha256:...`) the pull is expected to resolve to
+    pub expected_digest: Option<String>,
+    /// Fail the job on a digest mismatch instead of only recording `digest_match`
+    #[serde(default)]
+    pub require_digest_match: bool,
+    /// http(s) URL POSTed to once the job is completed, failed or canceled
+    #[validate(length(max = MAX_CALLBACK_URL_LEN))]
+    pub callback_url: Option<String>,
+    /// Complete without pulling if the same image was pulled successfully at most
+    /// this many seconds ago (and resolved to `expected_digest`, if given)
+    #[validate(range(min = 1, max = MAX_SKIP_WINDOW_SECS))]
+    pub skip_if_pulled_within_secs: Option<i64>,
+    /// Free-form strings to find the job by later (`GET /jobs?meta.team=payments`)
+    #[validate(custom(function = "validate_metadata"))]
+    pub metadata: Option<BTreeMap<String, String>>,
 }
 
-#[derive(serde::Serialize)]
+#[derive(serde::Serialize, ToSchema)]
 struct JobListItem {
     id: String,
     image: String,
-    status: String,
+    status: JobStatus,
 }
 
-#[derive(serde::Serialize)]
+#[derive(serde::Serialize, ToSchema)]
 struct JobDetail {
     id: String,
     image: String,
-    status: String,
+    status: JobStatus,
     result: Option<String>,
     error_detail: Option<String>,
     retry_count: i64,
+    max_attempts: i64,
+    priority: i32,
     created_at: String,
+    /// Start of the current (or last) attempt
+    started_at: Option<String>,
     finished_at: Option<String>,
+    updated_at: String,
+    /// While running: when the worker's lease runs out unless it heartbeats
+    lease_expires_at: Option<String>,
+    last_heartbeat: Option<String>,
+    /// `finished_at - started_at`, once both are set
+    duration_ms: Option<i64>,
+    /// Downloaded by the pull that completed the job; null for dry runs and skipped pulls
+    bytes_downloaded: Option<i64>,
+    /// As given at create time, `{}` if none
+    metadata: BTreeMap<String, String>,
+    /// Share of the layer bytes downloaded by the current (or last) attempt, 0–100;
+    /// null before the first progress report, on cache hits and when sizes are unknown
+    progress_pct: Option<i32>,
 }
 
+const MIN_PRIORITY: i32 = -100;
+const MAX_PRIORITY: i32 = 100;
+const MAX_IMAGE_LEN: u64 = 512;
+const MAX_CALLBACK_URL_LEN: u64 = 2048;
+/// 30 days
+const MAX_SKIP_WINDOW_SECS: i64 = 30 * 86400;
+const MAX_METADATA_ENTRIES: usize = 20;
+const MAX_METADATA_KEY_LEN: usize = 64;
+const MAX_METADATA_VALUE_LEN: usize = 256;
+
+/// Metadata keys end up in a JSON path (`meta.<key>` filters), so they are kept to `[A-Za-z0-9_]`.
+fn is_metadata_key(key: &str) -> bool {
+    !key.is_empty() && key.len() <= MAX_METADATA_KEY_LEN && key.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_')
+}
+
+fn validate_metadata(metadata: &BTreeMap<String, String>) -> Result<(), ValidationError> {
+    let fail = |msg: String| {
+        let mut err = ValidationError::new("invalid_metadata");
+        err.message = Some(msg.into());
+        Err(err)
+    };
+    if metadata.len() > MAX_METADATA_ENTRIES {
+        return fail(format!("at most {} entries", MAX_METADATA_ENTRIES));
+    }
+    if let Some(key) = metadata.keys().find(|k| !is_metadata_key(k)) {
+        return fail(format!(
+            "key '{}' must be 1 to {} letters, digits or '_'",
+            key, MAX_METADATA_KEY_LEN
+        ));
+    }
+    if let Some((key, _)) = metadata.iter().find(|(_, v)| v.chars().count() > MAX_METADATA_VALUE_LEN) {
+        return fail(format!("value of '{}' is longer than {} characters", key, MAX_METADATA_VALUE_LEN));
+    }
+    Ok(())
+}
+
+/// `?dedupe=true` returns the active (queued/running) job for the same image,
+/// if there is one, instead of queueing another pull of it.
+#[utoipa::path(
+    tag = "jobs",
+    request_body = CreateJobRequest,
+    params(
+        ("dedupe" = Option<bool>, Query, description = "Return the active job for the same image instead of queueing another"),
+    ),
+    responses(
+        (status = 200, description = "Job queued, or the existing active job with `dedupe`", body = ApiResponse<JobListItem>),
+        (status = 400, description = "Invalid image, options or body", body = ErrorResponse),
+        (status = 403, description = "Image matches IMAGE_DENY_PATTERNS or no IMAGE_ALLOW_PATTERNS", body = ErrorResponse),
+        (status = 503, description = "Server is shutting down", body = ErrorResponse),
+        (status = 500, description = "Database error", body = ErrorResponse),
+    )
+)]
 #[post("/jobs")]
 pub async fn create_job(
-    pool: web::Data<SqlitePool>,
+    pool: web::Data<DbPool>,
+    state: web::Data<AppState>,
+    query: web::Query<HashMap<String, String>>,
+    request_id: web::ReqData<RequestId>,
     body: web::Json<CreateJobRequest>,
 ) -> Result<HttpResponse, AppError> {
-    let image = body.image.trim();
-    if image.is_empty() {
-        return Err(AppError::bad_request("image is required"));
+    if state.is_draining() {
+        return Err(AppError::unavailable("server is shutting down; not accepting new jobs"));
     }
 
+    body.validate()?;
+    let image = validate_image(&body.image).map_err(AppError::bad_request)?;
+    check_registry(&state.config(), image).map_err(AppError::bad_request)?;
+    check_image_patterns(&state.config(), image).map_err(AppError::forbidden)?;
+
+    let credentials = match (body.username.as_deref(), body.password.as_deref()) {
+        (Some(u), Some(p)) if !u.is_empty() => Some((u, p)),
+        (None, None) => None,
+        _ => return Err(AppError::bad_request("username and password must be given together")),
+    };
+
+    let priority = body.priority.unwrap_or(0);
+
+    if body.dry_run && body.skip_if_pulled_within_secs.is_some() {
+        return Err(AppError::bad_request("skip_if_pulled_within_secs can't be combined with dry_run"));
+    }
+    if body.dry_run && !state.backend.supports_dry_run() {
+        return Err(AppError::bad_request(format!(
+            "dry_run is not supported by the {} backend",
+            state.backend.name()
+        )));
+    }
+
+    let expected_digest = match body.expected_digest.as_deref().map(str::trim) {
+        None | Some("") => None,
+        Some(d) if is_valid_digest(d) => Some(d.to_string()),
+        Some(_) => return Err(AppError::bad_request("expected_digest must look like sha256:<hex>")),
+    };
+    if body.require_digest_match && expected_digest.is_none() {
+        return Err(AppError::bad_request("require_digest_match needs expected_digest"));
+    }
+
+    let callback_url = match body.callback_url.as_deref().map(str::trim) {
+        None | Some("") => None,
+        Some(url) => Some(webhook::validate_url(url).map_err(AppError::bad_request)?),
+    };
+
+    let dedupe = query_flag(&query, "dedupe")?;
+
+    let metadata_json = match body.metadata.as_ref().filter(|m| !m.is_empty()) {
+        Some(m) => Some(serde_json::to_string(m).map_err(|e| AppError::internal(e.to_string()))?),
+        None => None,
+    };
+
     let id = uuid::Uuid::new_v4().to_string();
-    db::insert_job(pool.get_ref(), &id, image).await.map_err(AppError::from)?;
+    let traceparent = telemetry::current_traceparent();
+    let new_job = db::NewJob {
+        id: &id,
+        image,
+        priority,
+        options: db::JobOptions {
+            dry_run: body.dry_run,
+            pre_remove: body.pre_remove,
+            post_remove: body.post_remove,
+            expected_digest,
+            require_digest_match: body.require_digest_match,
+            callback_url,
+            skip_if_pulled_within_secs: body.skip_if_pulled_within_secs,
+        },
+        credentials,
+        request_id: Some(&request_id.0),
+        traceparent: traceparent.as_deref(),
+        metadata_json: metadata_json.as_deref(),
+    };
+    // cheap check first; insert_job repeats it under the per-image lock
+    let mut existing = None;
+    if dedupe {
+        existing = db::find_active_job_by_image(pool.get_ref(), image)
+            .await
+            .map_err(AppError::from)?;
+    }
+    if existing.is_none() {
+        existing = db::insert_job(pool.get_ref(), &new_job, dedupe)
+            .await
+            .map_err(AppError::from)?;
+    }
+    if let Some(job) = existing {
+        return Ok(HttpResponse::Ok().json(ApiResponse::ok(
+            "existing active job returned",
+            JobListItem { id: job.id, image: job.image, status: job.status },
+        )));
+    }
+    state.notify_new_job();
 
     Ok(HttpResponse::Ok().json(ApiResponse::ok(
         "job created",
         JobListItem {
             id,
             image: image.to_string(),
-            status: "queued".to_string(),
+            status: JobStatus::Queued,
         },
     )))
 }
 
+#[derive(Deserialize, ToSchema, Validate)]
+pub struct CreateJobsBatchRequest {
+    pub images: Vec<String>,
+    /// -100..=100 for every job of the batch (default 0)
+    #[validate(range(min = MIN_PRIORITY, max = MAX_PRIORITY))]
+    pub priority: Option<i32>,
+}
+
+#[derive(serde::Serialize, ToSchema)]
+struct BatchItemError {
+    index: usize,
+    image: String,
+    error: String,
+}
+
+#[derive(serde::Serialize, ToSchema)]
+struct BatchResult {
+    jobs: Vec<JobListItem>,
+    errors: Vec<BatchItemError>,
+}
+
+/// Create many jobs in one transaction. Invalid entries are reported in
+/// `errors` (by index) while the valid ones are still queued.
+#[utoipa::path(
+    tag = "jobs",
+    request_body = CreateJobsBatchRequest,
+    responses(
+        (status = 200, description = "Valid images queued; invalid ones listed in `errors`", body = ApiResponse<BatchResult>),
+        (status = 400, description = "Empty or oversized batch, bad priority, or no valid image", body = ErrorResponse),
+        (status = 503, description = "Server is shutting down", body = ErrorResponse),
+        (status = 500, description = "Database error", body = ErrorResponse),
+    )
+)]
+#[post("/jobs/batch")]
+pub async fn create_jobs_batch(
+    pool: web::Data<DbPool>,
+    state: web::Data<AppState>,
+    request_id: web::ReqData<RequestId>,
+    body: web::Json<CreateJobsBatchRequest>,
+) -> Result<HttpResponse, AppError> {
+    if state.is_draining() {
+        return Err(AppError::unavailable("server is shutting down; not accepting new jobs"));
+    }
+
+    let max = state.config().max_batch_jobs;
+    if body.images.is_empty() {
+        return Err(AppError::bad_request("images must not be empty"));
+    }
+    if body.images.len() > max {
+        return Err(AppError::bad_request(format!("at most {} images per batch", max)));
+    }
+
+    body.validate()?;
+    let priority = body.priority.unwrap_or(0);
+
+    let mut jobs = Vec::new();
+    let mut errors = Vec::new();
+    for (index, raw) in body.images.iter().enumerate() {
+        let checked = validate_image(raw)
+            .and_then(|image| check_registry(&state.config(), image).map(|()| image))
+            .map_err(str::to_string)
+            .and_then(|image| check_image_patterns(&state.config(), image).map(|()| image));
+        match checked {
+            Ok(image) => jobs.push(JobListItem {
+                id: uuid::Uuid::new_v4().to_string(),
+                image: image.to_string(),
+                status: JobStatus::Queued,
+            }),
+            Err(error) => errors.push(BatchItemError { index, image: raw.clone(), error }),
+        }
+    }
+
+    if jobs.is_empty() {
+        return Err(AppError::bad_request("no valid images in batch"));
+    }
+
+    let rows: Vec<(&str, &str)> = jobs.iter().map(|j| (j.id.as_str(), j.image.as_str())).collect();
+    let traceparent = telemetry::current_traceparent();
+    db::insert_jobs_batch(pool.get_ref(), &rows, priority, Some(&request_id.0), traceparent.as_deref())
+        .await
+        .map_err(AppError::from)?;
+    state.notify_new_job();
+
+    Ok(HttpResponse::Ok().json(ApiResponse::ok("jobs created", BatchResult { jobs, errors })))
+}
+
+/// Trimmed image reference, or why it was rejected
+fn validate_image(raw: &str) -> Result<&str, &'static str> {
+    let image = raw.trim();
+    if image.is_empty() {
+        return Err("image is required");
+    }
+    Ok(image)
+}
+
+/// `ALLOWED_REGISTRIES` check, against the host the worker will pull from
+fn check_registry(cfg: &AppConfig, image: &str) -> Result<(), &'static str> {
+    if cfg.registry_allowed(&parse_registry(image)) {
+        Ok(())
+    } else {
+        Err("registry not allowed")
+    }
+}
+
+/// `IMAGE_DENY_PATTERNS` / `IMAGE_ALLOW_PATTERNS` check. Patterns are tried on
+/// the image as written and on its full name (`nginx` -> `docker.io/library/nginx:latest`),
+/// so neither spelling gets around a rule. The error names the pattern only in development.
+fn check_image_patterns(cfg: &AppConfig, image: &str) -> Result<(), String> {
+    let full = canonical_image_ref(image);
+    let matches = |p: &glob::Pattern| p.matches(image) || p.matches(&full);
+    let show = |why: String| match cfg.is_development() {
+        true => format!("image not allowed ({})", why),
+        false => "image not allowed".to_string(),
+    };
+
+    if let Some(p) = cfg.image_deny_patterns.iter().find(|p| matches(p)) {
+        return Err(show(format!("matches deny pattern '{}'", p)));
+    }
+    if !cfg.image_allow_patterns.is_empty() && !cfg.image_allow_patterns.iter().any(matches) {
+        return Err(show("no allow pattern matches".to_string()));
+    }
+    Ok(())
+}
+
+/// `?name=true|1|false|0`, false when absent
+fn query_flag(q: &HashMap<String, String>, name: &str) -> Result<bool, AppError> {
+    match q.get(name).map(String::as_str) {
+        None | Some("false") | Some("0") => Ok(false),
+        Some("true") | Some("1") => Ok(true),
+        Some(_) => Err(AppError::bad_request(format!("{} must be true or false", name))),
+    }
+}
+
+/// Metric keys the worker records; `POST /jobs/{id}/metrics` can't write them.
+pub const WORKER_METRIC_KEYS: &[&str] = &[
+    "download_time_ms",
+    "image_size_bytes",
+    "bytes_downloaded_total",
+    "image_size_reported_bytes",
+    "download_ttfb_ms",
+    "download_duration_ms",
+    "average_speed_mbps",
+    "average_speed_elapsed_mbps",
+    "cache_hit",
+    "layers_observed",
+    "layer_bytes",
+    "layer_total_bytes",
+    "layer_duration_ms",
+    "manifest_size_bytes",
+    "download_timeout",
+    "pull_failure_reason",
+    "digest_match",
+    "cache_warm_skip",
+];
+
+const DEFAULT_PAGE_LIMIT: i64 = 50;
+const MAX_PAGE_LIMIT: i64 = 500;
+
+#[utoipa::path(
+    tag = "jobs",
+    params(
+        ("status" = Option<JobStatus>, Query, description = "Only jobs in this status"),
+        ("from" = Option<String>, Query, description = "Created at or after; RFC 3339 or `YYYY-MM-DD`"),
+        ("to" = Option<String>, Query, description = "Created at or before; RFC 3339 or `YYYY-MM-DD` (the whole day)"),
+        ("meta.{key}" = Option<String>, Query, description = "Only jobs whose metadata has `key` set to this value, e.g. `meta.team=payments`; may be repeated with other keys"),
+        ("limit" = Option<i64>, Query, description = "Page size, default 50, at most 500"),
+        ("offset" = Option<i64>, Query, description = "Jobs to skip, default 0"),
+    ),
+    responses(
+        (status = 200, description = "Newest jobs first", body = PaginatedResponse<JobListItem>),
+        (status = 400, description = "Unknown status, invalid from / to, or an invalid metadata key", body = ErrorResponse),
+        (status = 500, description = "Database error", body = ErrorResponse),
+    )
+)]
 #[get("/jobs")]
-pub async fn list_jobs(pool: web::Data<SqlitePool>) -> Result<HttpResponse, AppError> {
-    let rows = db::list_jobs(pool.get_ref()).await.map_err(AppError::from)?;
-    let data: Vec<JobListItem> = rows
+pub async fn list_jobs(
+    pool: web::Data<DbPool>,
+    q: web::Query<HashMap<String, String>>,
+) -> Result<HttpResponse, AppError> {
+    // invalid / negative values fall back to the defaults instead of erroring
+    let (limit, offset) = page_params(&q);
+
+    let status = match q.get("status").map(|s| s.trim()).filter(|s| !s.is_empty()) {
+        Some(s) => Some(s.parse::<JobStatus>().map_err(|_| {
+            let known: Vec<&str> = JobStatus::ALL.into_iter().map(JobStatus::as_str).collect();
+            AppError::bad_request(format!("unknown status '{}', expected one of: {}", s, known.join(", ")))
+        })?),
+        None => None,
+    };
+
+    let from = range_bound(&q, "from", false)?;
+    let to = range_bound(&q, "to", true)?;
+    if let (Some(from), Some(to)) = (from, to)
+        && from > to
+    {
+        return Err(AppError::bad_request("from must not be after to"));
+    }
+
+    let mut metadata = Vec::new();
+    for (param, value) in q.iter() {
+        let Some(key) = param.strip_prefix("meta.") else {
+            continue;
+        };
+        if !is_metadata_key(key) {
+            return Err(AppError::bad_request(format!(
+                "'{}': metadata keys are 1 to {} letters, digits or '_'",
+                param, MAX_METADATA_KEY_LEN
+            )));
+        }
+        metadata.push((key.to_string(), value.clone()));
+    }
+    let filter = db::JobFilter { status, from, to, metadata };
+
+    let total = db::count_jobs(pool.get_ref(), &filter)
+        .await
+        .map_err(AppError::from)?;
+    let rows = db::list_jobs_filtered(pool.get_ref(), &filter, limit, offset)
+        .await
+        .map_err(AppError::from)?;
+    let items: Vec<JobListItem> = rows
         .into_iter()
         .map(|r| JobListItem {
             id: r.id,
@@ -76,15 +498,204 @@ pub async fn list_jobs(pool: web::Data<SqlitePool>) -> Result<HttpResponse, AppE
         })
         .collect();
 
-    Ok(HttpResponse::Ok().json(ApiResponse::ok("ok", data)))
+    Ok(HttpResponse::Ok().json(PaginatedResponse::ok("ok", items, total, limit, offset)))
+}
+
+/// `limit` (default 50, at most 500) and `offset` (default 0) of the job list endpoints
+fn page_params(q: &HashMap<String, String>) -> (i64, i64) {
+    let limit = q
+        .get("limit")
+        .and_then(|s| s.parse::<i64>().ok())
+        .filter(|&n| n > 0)
+        .map_or(DEFAULT_PAGE_LIMIT, |n| n.min(MAX_PAGE_LIMIT));
+    let offset = q
+        .get("offset")
+        .and_then(|s| s.parse::<i64>().ok())
+        .filter(|&n| n >= 0)
+        .unwrap_or(0);
+    (limit, offset)
+}
+
+/// `from` / `to` of `GET /jobs`: RFC 3339, or a date meaning its first
+/// (`from`) or last (`to`) moment in UTC, so a date range covers whole days.
+fn range_bound(
+    q: &HashMap<String, String>,
+    name: &str,
+    end_of_day: bool,
+) -> Result<Option<chrono::DateTime<chrono::Utc>>, AppError> {
+    let Some(raw) = q.get(name).map(|s| s.trim()).filter(|s| !s.is_empty()) else {
+        return Ok(None);
+    };
+    if let Ok(t) = chrono::DateTime::parse_from_rfc3339(raw) {
+        return Ok(Some(t.with_timezone(&chrono::Utc)));
+    }
+    let day = chrono::NaiveDate::parse_from_str(raw, "%Y-%m-%d")
+        .map_err(|_| AppError::bad_request(format!("{} must be an RFC 3339 timestamp or YYYY-MM-DD", name)))?;
+    let time = match end_of_day {
+        true => chrono::NaiveTime::from_hms_micro_opt(23, 59, 59, 999_999),
+        false => chrono::NaiveTime::from_hms_opt(0, 0, 0),
+    }
+    .expect("valid time of day");
+    Ok(Some(day.and_time(time).and_utc()))
+}
+
+/// Longest `image` substring `GET /jobs/search` accepts
+const MAX_SEARCH_LEN: usize = 255;
+
+/// `GET /jobs/search?image=nginx&limit=50&offset=0`: jobs whose image contains
+/// the text, case-insensitive, newest first. `%` and `_` match themselves.
+#[utoipa::path(
+    tag = "jobs",
+    params(
+        ("image" = String, Query, description = "Text the image must contain"),
+        ("limit" = Option<i64>, Query, description = "Page size, default 50, at most 500"),
+        ("offset" = Option<i64>, Query, description = "Jobs to skip, default 0"),
+    ),
+    responses(
+        (status = 200, description = "Matching jobs, newest first", body = PaginatedResponse<JobListItem>),
+        (status = 400, description = "image missing or too long", body = ErrorResponse),
+        (status = 500, description = "Database error", body = ErrorResponse),
+    )
+)]
+#[get("/jobs/search")]
+pub async fn search_jobs(
+    pool: web::Data<DbPool>,
+    q: web::Query<HashMap<String, String>>,
+) -> Result<HttpResponse, AppError> {
+    let Some(needle) = q.get("image").map(|s| s.trim()).filter(|s| !s.is_empty()) else {
+        return Err(AppError::bad_request("image is required"));
+    };
+    if needle.len() > MAX_SEARCH_LEN {
+        return Err(AppError::bad_request(format!("image must be at most {} characters", MAX_SEARCH_LEN)));
+    }
+    let (limit, offset) = page_params(&q);
+
+    let total = db::count_jobs_by_image(pool.get_ref(), needle)
+        .await
+        .map_err(AppError::from)?;
+    let items: Vec<JobListItem> = db::search_jobs_by_image(pool.get_ref(), needle, limit, offset)
+        .await
+        .map_err(AppError::from)?
+        .into_iter()
+        .map(|r| JobListItem { id: r.id, image: r.image, status: r.status })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(PaginatedResponse::ok("ok", items, total, limit, offset)))
+}
+
+#[derive(serde::Serialize, ToSchema)]
+struct MetricDiff {
+    key: String,
+    labels: Option<serde_json::Value>,
+    unit: Option<String>,
+    value_a: Option<f64>,
+    value_b: Option<f64>,
+    /// `value_b - value_a`
+    delta: Option<f64>,
+    /// `delta` in percent of `value_a`; null when `value_a` is 0
+    pct_change: Option<f64>,
 }
 
+/// `GET /jobs/compare?a=<id>&b=<id>`: the latest value of every metric of both
+/// jobs side by side, matched by key and labels. A metric only one of the jobs
+/// has gets null on the other side and no delta.
+#[utoipa::path(
+    tag = "jobs",
+    params(
+        ("a" = String, Query, description = "First job id"),
+        ("b" = String, Query, description = "Second job id"),
+    ),
+    responses(
+        (status = 200, description = "`data` is `{\"a\": .., \"b\": .., \"metrics\": [MetricDiff]}`", body = ApiResponse<serde_json::Value>),
+        (status = 400, description = "a or b missing", body = ErrorResponse),
+        (status = 404, description = "Either job not found", body = ErrorResponse),
+        (status = 500, description = "Database error", body = ErrorResponse),
+    )
+)]
+#[get("/jobs/compare")]
+pub async fn compare_jobs(
+    pool: web::Data<DbPool>,
+    query: web::Query<HashMap<String, String>>,
+) -> Result<HttpResponse, AppError> {
+    let param = |name: &str| {
+        query
+            .get(name)
+            .map(|v| v.trim())
+            .filter(|v| !v.is_empty())
+            .ok_or_else(|| AppError::bad_request(format!("{} is required", name)))
+    };
+    let (a, b) = (param("a")?, param("b")?);
+
+    for id in [a, b] {
+        if db::get_job_by_id(pool.get_ref(), id).await.map_err(AppError::from)?.is_none() {
+            return Err(AppError::not_found(format!("job {} not found", id)));
+        }
+    }
+    let rows_a = db::get_metrics_by_job(pool.get_ref(), a).await.map_err(AppError::from)?;
+    let rows_b = db::get_metrics_by_job(pool.get_ref(), b).await.map_err(AppError::from)?;
+
+    // keyed by (key, labels); rows come newest first, so the first one per side wins
+    let mut merged: std::collections::BTreeMap<(String, Option<String>), MetricDiff> = Default::default();
+    for (is_a, rows) in [(true, rows_a), (false, rows_b)] {
+        for m in rows {
+            let diff = merged.entry((m.key.clone(), m.labels_json.clone())).or_insert_with(|| MetricDiff {
+                key: m.key,
+                labels: m.labels_json.as_deref().and_then(|s| serde_json::from_str(s).ok()),
+                unit: m.unit,
+                value_a: None,
+                value_b: None,
+                delta: None,
+                pct_change: None,
+            });
+            let value = if is_a { &mut diff.value_a } else { &mut diff.value_b };
+            value.get_or_insert(m.value);
+        }
+    }
+
+    let metrics: Vec<MetricDiff> = merged
+        .into_values()
+        .map(|mut diff| {
+            if let (Some(va), Some(vb)) = (diff.value_a, diff.value_b) {
+                diff.delta = Some(vb - va);
+                diff.pct_change = (va != 0.0).then(|| (vb - va) / va * 100.0);
+            }
+            diff
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(ApiResponse::ok(
+        "ok",
+        serde_json::json!({ "a": a, "b": b, "metrics": metrics }),
+    )))
+}
+
+/// Sends a weak ETag over the response body; a matching `If-None-Match` gets
+/// `304 Not Modified` so pollers only download the job when it changes.
+#[utoipa::path(
+    tag = "jobs",
+    params(
+        ("id" = String, Path, description = "Job id"),
+        ("full" = Option<bool>, Query, description = "Return `result` in full instead of cut to RESULT_TRUNCATE_LEN bytes"),
+        ("If-None-Match" = Option<String>, Header, description = "ETag of a previous answer"),
+    ),
+    responses(
+        (status = 200, description = "Job detail, with an `ETag`", body = ApiResponse<JobDetail>),
+        (status = 304, description = "Unchanged since the `If-None-Match` ETag"),
+        (status = 400, description = "`full` is not a boolean", body = ErrorResponse),
+        (status = 404, description = "Job not found", body = ErrorResponse),
+        (status = 500, description = "Database error", body = ErrorResponse),
+    )
+)]
 #[get("/jobs/{id}")]
 pub async fn get_job(
+    req: HttpRequest,
     path: web::Path<String>,
-    pool: web::Data<SqlitePool>,
+    pool: web::Data<DbPool>,
+    state: web::Data<AppState>,
+    query: web::Query<HashMap<String, String>>,
 ) -> Result<HttpResponse, AppError> {
     let id = path.into_inner();
+    let full = query_flag(&query, "full")?;
 
     let row = db::get_job_by_id(pool.get_ref(), &id)
         .await
@@ -94,110 +705,524 @@ pub async fn get_job(
         return Err(AppError::not_found("job not found"));
     };
 
-    let result_short = r.result.as_ref().map(|s| truncate(s, 500));
+    let result = match full {
+        true => r.result,
+        false => r.result.as_deref().map(|s| truncate(s, state.config().result_truncate_len)),
+    };
     let detail = JobDetail {
         id: r.id,
         image: r.image,
         status: r.status,
-        result: result_short,
+        result,
         error_detail: r.error_detail,
         retry_count: r.retry_count,
+        max_attempts: r.max_attempts,
+        priority: r.priority,
         created_at: r.created_at,
+        started_at: r.started_at,
         finished_at: r.finished_at,
+        updated_at: r.updated_at,
+        lease_expires_at: r.lease_expires_at,
+        last_heartbeat: r.last_heartbeat,
+        duration_ms: r.duration_ms,
+        bytes_downloaded: r.bytes_downloaded,
+        // written by create_job from a map of strings
+        metadata: r.metadata_json.as_deref().and_then(|j| serde_json::from_str(j).ok()).unwrap_or_default(),
+        progress_pct: r.progress_pct,
+    };
+
+    let body = serde_json::to_vec(&ApiResponse::ok("ok", detail))
+        .map_err(|e| AppError::internal(e.to_string()))?;
+    let etag = body_etag(&body);
+
+    let not_modified = match req.get_header::<IfNoneMatch>() {
+        Some(IfNoneMatch::Any) => true,
+        Some(IfNoneMatch::Items(tags)) => tags.iter().any(|t| t.weak_eq(&etag)),
+        None => false,
     };
+    if not_modified {
+        return Ok(HttpResponse::NotModified().insert_header(ETag(etag)).finish());
+    }
+
+    Ok(HttpResponse::Ok()
+        .insert_header(ETag(etag))
+        .content_type(ContentType::json())
+        .body(body))
+}
+
+/// Weak validator for a serialized body: any change to the job (status,
+/// result, error_detail, ...) changes the bytes and therefore the tag.
+fn body_etag(body: &[u8]) -> EntityTag {
+    use std::hash::{Hash, Hasher};
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    body.hash(&mut hasher);
+    EntityTag::new_weak(format!("{:016x}", hasher.finish()))
+}
+
+/// Largest `older_than_days` accepted by `DELETE /jobs`
+const MAX_PURGE_DAYS: i64 = 36500;
+
+/// `DELETE /jobs?older_than_days=N`: purge finished jobs now, the same way the
+/// hourly `RETENTION_DAYS` task does.
+#[utoipa::path(
+    tag = "jobs",
+    params(
+        ("older_than_days" = i64, Query, description = "Delete finished jobs older than this, 1..=36500"),
+    ),
+    responses(
+        (status = 200, description = "`data` is `{\"deleted\": n, \"older_than_days\": d}`", body = ApiResponse<serde_json::Value>),
+        (status = 400, description = "older_than_days missing or out of range", body = ErrorResponse),
+        (status = 500, description = "Database error", body = ErrorResponse),
+    )
+)]
+#[delete("/jobs")]
+pub async fn purge_jobs(
+    pool: web::Data<DbPool>,
+    query: web::Query<HashMap<String, String>>,
+) -> Result<HttpResponse, AppError> {
+    let days = query
+        .get("older_than_days")
+        .ok_or_else(|| AppError::bad_request("older_than_days is required"))?
+        .parse::<i64>()
+        .ok()
+        .filter(|d| (1..=MAX_PURGE_DAYS).contains(d))
+        .ok_or_else(|| {
+            AppError::bad_request(format!("older_than_days must be between 1 and {}", MAX_PURGE_DAYS))
+        })?;
+
+    let cutoff = chrono::Utc::now() - chrono::Duration::days(days);
+    let deleted = db::purge_old_jobs(pool.get_ref(), cutoff)
+        .await
+        .map_err(AppError::from)?;
+
+    Ok(HttpResponse::Ok().json(ApiResponse::ok(
+        "jobs purged",
+        serde_json::json!({ "deleted": deleted, "older_than_days": days }),
+    )))
+}
+
+#[utoipa::path(
+    tag = "jobs",
+    params(
+        ("id" = String, Path, description = "Job id"),
+    ),
+    responses(
+        (status = 200, description = "`data` is `{\"id\": ..}`", body = ApiResponse<serde_json::Value>),
+        (status = 404, description = "Job not found", body = ErrorResponse),
+        (status = 500, description = "Database error", body = ErrorResponse),
+    )
+)]
+#[delete("/jobs/{id}")]
+pub async fn delete_job(
+    path: web::Path<String>,
+    pool: web::Data<DbPool>,
+) -> Result<HttpResponse, AppError> {
+    let id = path.into_inner();
+
+    if !db::delete_job(pool.get_ref(), &id).await.map_err(AppError::from)? {
+        return Err(AppError::not_found("job not found"));
+    }
 
-    Ok(HttpResponse::Ok().json(ApiResponse::ok("ok", detail)))
+    Ok(HttpResponse::Ok().json(ApiResponse::ok("job deleted", serde_json::json!({ "id": id }))))
+}
+
+/// Queued jobs are canceled outright. For a running job the status flips right
+/// away and the pull is aborted at its next progress message.
+#[utoipa::path(
+    tag = "jobs",
+    params(
+        ("id" = String, Path, description = "Job id"),
+    ),
+    responses(
+        (status = 200, description = "Job canceled", body = ApiResponse<JobListItem>),
+        (status = 404, description = "Job not found", body = ErrorResponse),
+        (status = 409, description = "Job is not queued or running", body = ErrorResponse),
+        (status = 500, description = "Database error", body = ErrorResponse),
+    )
+)]
+#[post("/jobs/{id}/cancel")]
+pub async fn cancel_job(
+    path: web::Path<String>,
+    pool: web::Data<DbPool>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    let id = path.into_inner();
+
+    if !db::cancel_job(pool.get_ref(), &id).await.map_err(AppError::from)? {
+        return match db::get_job_by_id(pool.get_ref(), &id).await.map_err(AppError::from)? {
+            None => Err(AppError::not_found("job not found")),
+            Some(r) => Err(AppError::conflict(format!(
+                "job is {}; only {} jobs can be canceled",
+                r.status,
+                JobStatus::sources_of(JobStatus::Canceled)
+            ))),
+        };
+    }
+
+    let aborting = state.request_cancel(&id).await;
+    let r = db::get_job_by_id(pool.get_ref(), &id)
+        .await
+        .map_err(AppError::from)?
+        .ok_or_else(|| AppError::not_found("job not found"))?;
+
+    if !aborting {
+        // nothing in this process will report it
+        events::publish(&state.updates, JobUpdate::status(&id, JobStatus::Canceled));
+        if let Some(url) = db::get_callback_url(pool.get_ref(), &id).await.map_err(AppError::from)? {
+            webhook::spawn_notify(
+                pool.get_ref().clone(),
+                state.webhook_client.clone(),
+                Duration::from_secs(state.config().webhook_timeout_secs),
+                url,
+                id.clone(),
+                r.image.clone(),
+                JobStatus::Canceled,
+            );
+        }
+    }
+
+    Ok(HttpResponse::Ok().json(ApiResponse::ok(
+        if aborting { "job canceled, aborting pull" } else { "job canceled" },
+        JobListItem {
+            id: r.id,
+            image: r.image,
+            status: r.status,
+        },
+    )))
+}
+
+#[utoipa::path(
+    tag = "jobs",
+    params(
+        ("id" = String, Path, description = "Job id"),
+    ),
+    responses(
+        (status = 200, description = "Job queued again", body = ApiResponse<JobListItem>),
+        (status = 404, description = "Job not found", body = ErrorResponse),
+        (status = 409, description = "Job is not completed, failed or canceled", body = ErrorResponse),
+        (status = 500, description = "Database error", body = ErrorResponse),
+    )
+)]
+#[post("/jobs/{id}/retry")]
+pub async fn retry_job(
+    path: web::Path<String>,
+    pool: web::Data<DbPool>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    let id = path.into_inner();
+
+    if !db::requeue_job(pool.get_ref(), &id).await.map_err(AppError::from)? {
+        // tell "missing" apart from "not in a retryable state"
+        return match db::get_job_by_id(pool.get_ref(), &id).await.map_err(AppError::from)? {
+            None => Err(AppError::not_found("job not found")),
+            Some(r) => Err(AppError::conflict(format!(
+                "job is {}; only {} jobs can be retried",
+                r.status,
+                JobStatus::sources_of(JobStatus::Queued)
+            ))),
+        };
+    }
+
+    state.notify_new_job();
+
+    let r = db::get_job_by_id(pool.get_ref(), &id)
+        .await
+        .map_err(AppError::from)?
+        .ok_or_else(|| AppError::not_found("job not found"))?;
+
+    Ok(HttpResponse::Ok().json(ApiResponse::ok(
+        "job requeued",
+        JobListItem {
+            id: r.id,
+            image: r.image,
+            status: r.status,
+        },
+    )))
+}
+
+#[derive(serde::Serialize, ToSchema)]
+struct JobLogs {
+    job_id: String,
+    created_at: Option<String>,
+    line_count: usize,
+    log: String,
+}
+
+/// Pull log of the latest attempt; `?tail=N` keeps only the last N lines.
+#[utoipa::path(
+    tag = "jobs",
+    params(
+        ("id" = String, Path, description = "Job id"),
+        ("tail" = Option<usize>, Query, description = "Only the last n lines"),
+    ),
+    responses(
+        (status = 200, description = "Log of the latest attempt", body = ApiResponse<JobLogs>),
+        (status = 400, description = "tail is not a number", body = ErrorResponse),
+        (status = 404, description = "Job not found", body = ErrorResponse),
+        (status = 500, description = "Database error", body = ErrorResponse),
+    )
+)]
+#[get("/jobs/{id}/logs")]
+pub async fn get_job_logs(
+    path: web::Path<String>,
+    pool: web::Data<DbPool>,
+    q: web::Query<HashMap<String, String>>,
+) -> Result<HttpResponse, AppError> {
+    let id = path.into_inner();
+
+    if db::get_job_by_id(pool.get_ref(), &id).await.map_err(AppError::from)?.is_none() {
+        return Err(AppError::not_found("job not found"));
+    }
+
+    let tail = match q.get("tail") {
+        Some(s) => Some(
+            s.parse::<usize>()
+                .map_err(|_| AppError::bad_request("tail must be a non-negative integer"))?,
+        ),
+        None => None,
+    };
+
+    // a queued job simply has no log yet
+    let row = db::get_latest_job_log(pool.get_ref(), &id)
+        .await
+        .map_err(AppError::from)?;
+    let (job_id, created_at, text) = match row {
+        Some(r) => (r.job_id, Some(r.created_at), r.log_text),
+        None => (id, None, String::new()),
+    };
+
+    let mut lines: Vec<&str> = text.lines().collect();
+    if let Some(n) = tail {
+        lines.drain(..lines.len().saturating_sub(n));
+    }
+
+    Ok(HttpResponse::Ok().json(ApiResponse::ok(
+        "ok",
+        JobLogs {
+            job_id,
+            created_at,
+            line_count: lines.len(),
+            log: lines.join("\n"),
+        },
+    )))
+}
+
+const SSE_KEEPALIVE: Duration = Duration::from_secs(15);
+
+struct EventStreamState {
+    rx: tokio::sync::broadcast::Receiver<JobUpdate>,
+    job_id: String,
+    first: Option<JobUpdate>,
+    done: bool,
+}
+
+fn sse_frame(update: &JobUpdate) -> Bytes {
+    let data = serde_json::to_string(update).unwrap_or_default();
+    Bytes::from(format!("event: {}\ndata: {}\n\n", update.kind(), data))
+}
+
+/// Server-Sent Events stream of status changes and per-layer progress.
+/// Starts with the current status and closes after a terminal status.
+#[utoipa::path(
+    tag = "jobs",
+    params(
+        ("id" = String, Path, description = "Job id"),
+    ),
+    responses(
+        (status = 200, description = "Server-sent `status` and `progress` events until the job finishes", content_type = "text/event-stream", body = String),
+        (status = 404, description = "Job not found", body = ErrorResponse),
+        (status = 500, description = "Database error", body = ErrorResponse),
+    )
+)]
+#[get("/jobs/{id}/events")]
+pub async fn job_events(
+    path: web::Path<String>,
+    pool: web::Data<DbPool>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    let id = path.into_inner();
+
+    // subscribe before reading the status so nothing slips between the two
+    let rx = state.updates.subscribe();
+    let Some(job) = db::get_job_by_id(pool.get_ref(), &id).await.map_err(AppError::from)? else {
+        return Err(AppError::not_found("job not found"));
+    };
+
+    let init = EventStreamState {
+        rx,
+        first: Some(JobUpdate::status(&id, job.status)),
+        job_id: id,
+        done: false,
+    };
+
+    let stream = futures_util::stream::unfold(init, |mut st| async move {
+        if st.done {
+            return None;
+        }
+        if let Some(first) = st.first.take() {
+            st.done = first.is_terminal();
+            return Some((Ok::<_, actix_web::Error>(sse_frame(&first)), st));
+        }
+        loop {
+            match tokio::time::timeout(SSE_KEEPALIVE, st.rx.recv()).await {
+                Err(_) => return Some((Ok(Bytes::from_static(b": keep-alive\n\n")), st)),
+                Ok(Ok(update)) if update.job_id() == st.job_id => {
+                    st.done = update.is_terminal();
+                    return Some((Ok(sse_frame(&update)), st));
+                }
+                Ok(Ok(_)) => continue,
+                // slow client: skipped some progress events, carry on
+                Ok(Err(tokio::sync::broadcast::error::RecvError::Lagged(_))) => continue,
+                Ok(Err(tokio::sync::broadcast::error::RecvError::Closed)) => return None,
+            }
+        }
+    });
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(stream))
+}
+
+/// How often a running pull's progress is copied to the job row
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Drive `pull`, writing `progress` to the job row when it changed, at most
+/// once per [`PROGRESS_INTERVAL`].
+async fn with_progress_updates<F: Future>(pool: &DbPool, job_id: &str, progress: &AtomicI32, pull: F) -> F::Output {
+    let mut pull = std::pin::pin!(pull);
+    let mut tick = tokio::time::interval(PROGRESS_INTERVAL);
+    tick.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let mut written = NO_PROGRESS;
+    loop {
+        tokio::select! {
+            res = &mut pull => return res,
+            _ = tick.tick() => {
+                let pct = progress.load(Ordering::Relaxed);
+                if pct != written {
+                    if let Err(e) = db::set_job_progress(pool, job_id, Some(pct)).await {
+                        warn!("job {}: cannot store progress: {:#}", job_id, e);
+                    }
+                    written = pct;
+                }
+            }
+        }
+    }
 }
 
 /// Worker entrypoint: pull image and record metrics.
 /// Performs optional pre/post removal for cold-pull benchmarking.
 pub async fn pull_image_and_record_metrics(
-    pool: &SqlitePool,
+    pool: &DbPool,
+    backend: &impl PullBackend,
     job_id: &str,
     image: &str,
+    options: &db::JobOptions,
+    state: &AppState,
+    cancel: &AtomicBool,
 ) -> anyhow::Result<()> {
-    let docker = Docker::connect_with_unix_defaults()
-        .map_err(|e| anyhow::anyhow!("docker connect error: {e}"))?;
+    if cancel.load(Ordering::SeqCst) {
+        return Err(PullCanceled.into());
+    }
+    let pull_timeout = Duration::from_secs(state.config().pull_timeout_secs);
+
+    // `reference` is a tag ("latest") or a digest ("sha256:...")
+    let (registry_host, repo, reference) = parse_image_ref(image);
+    let local_ref = with_reference(&local_name(&registry_host, &repo), &reference);
+
+    // per-job credentials win over the global ones
+    let credentials = match db::get_job_credentials(pool, job_id).await? {
+        Some((username, password)) => Some(registry_auth::basic(&registry_host, &username, &password)),
+        None => registry_auth::credentials_for(&registry_host),
+    };
+    let has_credentials = credentials.is_some();
 
-    let (registry_host, _, _) = parse_image_ref(image);
-    let (repo, tag) = split_repo_tag(image);
-    let full_ref_repo_tag = format!("{}:{}", repo, tag);
+    let from_image = build_from_image(&registry_host, &repo);
+    let name = with_reference(&from_image, &reference);
+
+    if options.dry_run {
+        return inspect_manifest_only(pool, backend, job_id, &name, &registry_host, credentials, options).await;
+    }
+    // before pre-removal, which would throw away the very image being kept warm
+    if skipped_as_recently_pulled(pool, job_id, image, &local_ref, options).await? {
+        return Ok(());
+    }
 
     // -------- optional pre-removal (cold start) --------
-    if env_flag("PRE_PULL_REMOVE", true) {
-        remove_image_thorough(&docker, &repo, &tag, &registry_host).await;
-    } else {
+    match options.pre_remove {
+        Some(true) => backend.remove(&local_ref, true).await,
+        // warm pull: keep whatever the daemon has cached
+        Some(false) => {}
+        None if env_flag("PRE_PULL_REMOVE", true) => backend.remove(&local_ref, true).await,
         // best-effort quick cleanup
-        remove_image_if_exists(&docker, &format!("{}/{}", registry_host, &full_ref_repo_tag)).await;
-        remove_image_if_exists(&docker, &full_ref_repo_tag).await;
+        None => backend.remove(&local_ref, false).await,
     }
 
-    let from_image = build_from_image(&registry_host, &repo);
     let started = Instant::now();
-
-    let opts = CreateImageOptions {
-        from_image: Some(from_image.clone()),
-        tag: Some(tag.clone()),
-        ..Default::default()
+    let progress = AtomicI32::new(NO_PROGRESS);
+    let req = PullRequest {
+        job_id,
+        from_image: &from_image,
+        reference: &reference,
+        name: &name,
+        local_ref: &local_ref,
+        credentials,
+        updates: &state.updates,
+        cancel,
+        progress: &progress,
     };
+    let mut pulled = PullMetrics::default();
 
-    let mut stream = docker.create_image(Some(opts), None, None);
-    let mut first_byte_at: Option<Instant> = None;
-    let mut layers: HashMap<String, (u64, u64)> = HashMap::new();
-    let mut logs = String::new();
-    let mut digest: Option<String> = None;
-
-    while let Some(item) = stream.try_next().await? {
-        if let Some(status) = item.status.as_deref() {
-            if status.starts_with("Digest:") {
-                digest = Some(status.trim_start_matches("Digest:").trim().to_string());
-            }
-            logs.push_str(status);
-            if let Some(id) = item.id.as_deref() {
-                logs.push_str(" [");
-                logs.push_str(id);
-                logs.push(']');
-            }
-            if let Some(progress) = item.progress.as_deref() {
-                logs.push_str(" - ");
-                logs.push_str(progress);
-            }
-            logs.push('\n');
-        }
+    // a hung registry must not hold the permits forever
+    let pull = with_progress_updates(pool, job_id, &progress, backend.pull(&req, &mut pulled));
+    let pull_res = tokio::time::timeout(pull_timeout, pull).await;
+    let stream_ended = Instant::now();
 
-        if let (Some(id), Some(detail)) = (item.id, item.progress_detail) {
-            let cur_u64 = detail.current.unwrap_or(0).max(0) as u64;
-            let tot_u64 = detail.total.unwrap_or(0).max(0) as u64;
-
-            if first_byte_at.is_none() && cur_u64 > 0 {
-                first_byte_at = Some(Instant::now());
-            }
+    // keep the pull log even when the pull failed; that's when it's most useful
+    if let Err(e) = db::insert_job_log(pool, job_id, &pulled.logs).await {
+        warn!("job {}: cannot store pull log: {:#}", job_id, e);
+    }
 
-            let entry = layers.entry(id).or_insert((0, 0));
-            if cur_u64 > entry.0 {
-                entry.0 = cur_u64;
-            }
-            if tot_u64 > entry.1 {
-                entry.1 = tot_u64;
-            }
+    let Ok(pull_res) = pull_res else {
+        let labels = serde_json::json!({ "timeout_secs": pull_timeout.as_secs() }).to_string();
+        db::insert_metric_labeled(pool, job_id, "download_timeout", 1.0, None, Some(&labels)).await?;
+        record_failure_reason(pool, job_id, PullFailureReason::Timeout).await;
+        anyhow::bail!("pull failed ({}): timed out after {}s", PullFailureReason::Timeout, pull_timeout.as_secs());
+    };
+    if let Err(e) = pull_res {
+        if e.is::<PullCanceled>() {
+            return Err(e);
         }
+        let reason = classify_pull_error(&e);
+        record_failure_reason(pool, job_id, reason).await;
+        let msg = format!("{:#}", e);
+        let e = if reason != PullFailureReason::AuthFailed {
+            e
+        } else if has_credentials {
+            anyhow::anyhow!("registry {} rejected the configured credentials: {}", registry_host, msg)
+        } else {
+            anyhow::anyhow!(
+                "registry {} requires authentication but no credentials are configured \
+                 (set {}=user:pass or add it to ~/.docker/config.json): {}",
+                registry_host,
+                registry_auth::env_key(&registry_host),
+                msg
+            )
+        };
+        return Err(e.context(format!("pull failed ({})", reason)));
     }
 
-    let elapsed_ms = started.elapsed().as_millis() as f64;
-
-    let (sum_cur, sum_tot) = layers
-        .values()
-        .fold((0u64, 0u64), |acc, &(c, t)| (acc.0.saturating_add(c), acc.1.saturating_add(t)));
-    let bytes_downloaded = if sum_tot > 0 { sum_tot } else { sum_cur };
+    let elapsed_ms = stream_ended.duration_since(started).as_millis() as f64;
 
-    let inspected_size_bytes = docker
-        .inspect_image(&full_ref_repo_tag)
-        .await
-        .ok()
-        .and_then(|ins| ins.size)
-        .unwrap_or(0) as f64;
+    let bytes_downloaded = pulled.bytes_downloaded();
+    let inspected_size_bytes = pulled.reported_size_bytes as f64;
+    let cache_hit = pulled.cache_hit;
 
-    let cache_hit = logs.contains("Image is up to date") || bytes_downloaded == 0;
+    // the last tick may have missed the end of the download
+    let final_pct = if cache_hit { None } else { pulled.progress_pct() };
+    if let Err(e) = db::set_job_progress(pool, job_id, final_pct).await {
+        warn!("job {}: cannot store progress: {:#}", job_id, e);
+    }
 
     let image_size_bytes = if inspected_size_bytes > 0.0 {
         inspected_size_bytes
@@ -205,68 +1230,331 @@ pub async fn pull_image_and_record_metrics(
         bytes_downloaded as f64
     };
 
-    let download_elapsed_ms = first_byte_at
-        .map(|t0| t0.elapsed().as_millis() as f64)
-        .unwrap_or(0.0);
+    // TTFB: start of the pull until the first layer reported bytes;
+    // download duration: from there until the stream ended
+    let (ttfb_ms, download_duration_ms) = pulled
+        .first_byte_at
+        .map(|t0| {
+            (
+                t0.duration_since(started).as_millis() as f64,
+                stream_ended.duration_since(t0).as_millis() as f64,
+            )
+        })
+        .unwrap_or((0.0, 0.0));
 
-    let avg_speed_mbps = if bytes_downloaded > 0 && elapsed_ms > 0.0 {
-        ((bytes_downloaded as f64) * 8.0) / (elapsed_ms / 1000.0) / 1_000_000.0
-    } else {
-        0.0
+    // over the download window only; a single progress event has no window,
+    // so fall back to the whole pull then
+    let download_window_ms = match (pulled.first_byte_at, pulled.last_byte_at) {
+        (Some(first), Some(last)) if last > first => last.duration_since(first).as_millis() as f64,
+        _ => elapsed_ms,
     };
+    let avg_speed_mbps = mbps(bytes_downloaded, download_window_ms);
+    // old definition (bytes over the whole pull), kept for one release
+    let avg_speed_elapsed_mbps = mbps(bytes_downloaded, elapsed_ms);
 
     // metrics
-    db::insert_metric(pool, job_id, "download_time_ms", elapsed_ms, Some("ms")).await?;
-    db::insert_metric(pool, job_id, "image_size_bytes", image_size_bytes, Some("bytes")).await?;
-    db::insert_metric(pool, job_id, "bytes_downloaded_total", bytes_downloaded as f64, Some("bytes")).await?;
-    db::insert_metric(pool, job_id, "image_size_reported_bytes", inspected_size_bytes, Some("bytes")).await?;
-    db::insert_metric(pool, job_id, "download_ttfb_ms", download_elapsed_ms, Some("ms")).await?;
-    db::insert_metric(pool, job_id, "average_speed_mbps", avg_speed_mbps, Some("Mbps")).await?;
+    db::insert_metric(pool, job_id, "download_time_ms", elapsed_ms, Some(MetricUnit::Milliseconds)).await?;
+    db::insert_metric(pool, job_id, "image_size_bytes", image_size_bytes, Some(MetricUnit::Bytes)).await?;
+    db::insert_metric(pool, job_id, "bytes_downloaded_total", bytes_downloaded as f64, Some(MetricUnit::Bytes)).await?;
+    db::insert_metric(pool, job_id, "image_size_reported_bytes", inspected_size_bytes, Some(MetricUnit::Bytes)).await?;
+    db::insert_metric(pool, job_id, "download_ttfb_ms", ttfb_ms, Some(MetricUnit::Milliseconds)).await?;
+    db::insert_metric(pool, job_id, "download_duration_ms", download_duration_ms, Some(MetricUnit::Milliseconds)).await?;
+    db::insert_metric(pool, job_id, "average_speed_mbps", avg_speed_mbps, Some(MetricUnit::Mbps)).await?;
+    // deprecated: remove in the next release
+    db::insert_metric(pool, job_id, "average_speed_elapsed_mbps", avg_speed_elapsed_mbps, Some(MetricUnit::Mbps)).await?;
     db::insert_metric(pool, job_id, "cache_hit", if cache_hit { 1.0 } else { 0.0 }, None).await?;
 
+    // a digest pull may not echo "Digest:", but then the requested one is what we got
+    let mut digest = pulled.digest.take();
+    if digest.is_none() && is_digest(&reference) {
+        digest = Some(reference.clone());
+    }
+
+    let layers = &pulled.layers;
     let labels = serde_json::json!({
-        "image": format!("{}:{}", repo, tag),
+        "image": local_ref,
         "registry_host": registry_host,
         "layer_count": layers.len(),
+        "digest": digest,
     })
     .to_string();
     db::insert_metric_labeled(pool, job_id, "layers_observed", layers.len() as f64, None, Some(&labels)).await?;
 
+    // per layer: one row per key, labeled by layer id (see GET /jobs/{id}/layers)
+    for (layer_id, l) in layers {
+        let labels = serde_json::json!({ "layer_id": layer_id }).to_string();
+        let duration_ms = l.last_seen.duration_since(l.first_seen).as_millis() as f64;
+        db::insert_metric_labeled(pool, job_id, "layer_bytes", l.current as f64, Some(MetricUnit::Bytes), Some(&labels)).await?;
+        db::insert_metric_labeled(pool, job_id, "layer_total_bytes", l.total as f64, Some(MetricUnit::Bytes), Some(&labels)).await?;
+        db::insert_metric_labeled(pool, job_id, "layer_duration_ms", duration_ms, Some(MetricUnit::Milliseconds), Some(&labels)).await?;
+    }
+
+    if !check_digest(pool, job_id, options, digest.as_deref()).await? {
+        // don't leave an image we didn't ask for in the daemon
+        backend.remove(&local_ref, true).await;
+        anyhow::bail!(
+            "digest mismatch: expected {}, pulled {}",
+            options.expected_digest.as_deref().unwrap_or("-"),
+            digest.as_deref().unwrap_or("none reported")
+        );
+    }
+
     let digest_str = digest.as_deref().unwrap_or("-");
     let summary = format!(
         "Pulled {} from {} • size ~{:.1} MB • layers {} • cache_hit={} • digest {}",
-        full_ref_repo_tag,
+        local_ref,
         registry_host,
-        image_size_bytes / 1_000_000.0,
+        model::bytes_to_mb(image_size_bytes),
         layers.len(),
         cache_hit,
         digest_str
     );
 
-    db::complete_job(pool, job_id, Some(&summary)).await?;
+    db::complete_job(pool, job_id, Some(&summary), Some(bytes_downloaded)).await?;
 
     // -------- optional post-removal (stateless runner) --------
-    if env_flag("POST_PULL_REMOVE", true) {
-        remove_image_thorough(&docker, &repo, &tag, &registry_host).await;
+    if options.post_remove.unwrap_or_else(|| env_flag("POST_PULL_REMOVE", true)) {
+        backend.remove(&local_ref, true).await;
     }
 
     Ok(())
 }
 
+/// `skip_if_pulled_within_secs`: complete the job without pulling when
+/// [`db::find_recent_pull`] finds a recent enough pull of the same image, and
+/// record the decision as `cache_warm_skip` (1 skipped, 0 pulled). Returns
+/// whether the job was completed here.
+async fn skipped_as_recently_pulled(
+    pool: &DbPool,
+    job_id: &str,
+    image: &str,
+    local_ref: &str,
+    options: &db::JobOptions,
+) -> anyhow::Result<bool> {
+    let Some(window) = options.skip_if_pulled_within_secs else {
+        return Ok(false);
+    };
+    let expected = options.expected_digest.as_deref();
+    let Some(recent) = db::find_recent_pull(pool, job_id, image, expected, window).await? else {
+        let labels = serde_json::json!({ "window_secs": window }).to_string();
+        db::insert_metric_labeled(pool, job_id, "cache_warm_skip", 0.0, None, Some(&labels)).await?;
+        return Ok(false);
+    };
+
+    let labels = serde_json::json!({
+        "window_secs": window,
+        "pulled_by": recent.job_id,
+        "age_secs": recent.age_secs,
+        "digest": recent.digest,
+    })
+    .to_string();
+    db::insert_metric_labeled(pool, job_id, "cache_warm_skip", 1.0, None, Some(&labels)).await?;
+    if expected.is_some() {
+        // matched in the query, so the digest is the expected one
+        check_digest(pool, job_id, options, recent.digest.as_deref()).await?;
+    }
+    let summary = format!(
+        "Skipped {}: pulled by job {} {}s ago (within {}s) • digest {}",
+        local_ref,
+        recent.job_id,
+        recent.age_secs,
+        window,
+        recent.digest.as_deref().unwrap_or("-")
+    );
+    db::complete_job(pool, job_id, Some(&summary), None).await?;
+    Ok(true)
+}
+
+/// Dry run: ask the registry for the manifest descriptor only. No layers are
+/// downloaded and the pre/post removal steps are skipped.
+async fn inspect_manifest_only(
+    pool: &DbPool,
+    backend: &impl PullBackend,
+    job_id: &str,
+    name: &str,
+    registry_host: &str,
+    credentials: Option<bollard::auth::DockerCredentials>,
+    options: &db::JobOptions,
+) -> anyhow::Result<()> {
+    let manifest = backend.inspect_manifest(name, credentials).await?;
+
+    let size = manifest.size as f64;
+    let labels = serde_json::json!({
+        "image": name,
+        "registry_host": registry_host,
+        "digest": manifest.digest,
+        "media_type": manifest.media_type,
+        "platforms": manifest.platforms,
+    })
+    .to_string();
+    db::insert_metric_labeled(pool, job_id, "manifest_size_bytes", size, Some(MetricUnit::Bytes), Some(&labels)).await?;
+
+    if !check_digest(pool, job_id, options, manifest.digest.as_deref()).await? {
+        anyhow::bail!(
+            "digest mismatch: expected {}, registry has {}",
+            options.expected_digest.as_deref().unwrap_or("-"),
+            manifest.digest.as_deref().unwrap_or("none reported")
+        );
+    }
+
+    let summary = format!(
+        "Dry run {} on {} • manifest {} bytes • platforms {} • digest {}",
+        name,
+        registry_host,
+        size,
+        manifest.platforms,
+        manifest.digest.as_deref().unwrap_or("-")
+    );
+    db::complete_job(pool, job_id, Some(&summary), None).await?;
+    Ok(())
+}
+
 // -------------- helpers --------------
 
+/// Why a pull failed, for alerting; stored as the `reason` label of
+/// `pull_failure_reason` and prefixed to the job's `error_detail`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PullFailureReason {
+    AuthFailed,
+    NotFound,
+    RateLimited,
+    Network,
+    Timeout,
+    Unknown,
+}
+
+impl PullFailureReason {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::AuthFailed => "auth_failed",
+            Self::NotFound => "not_found",
+            Self::RateLimited => "rate_limited",
+            Self::Network => "network",
+            Self::Timeout => "timeout",
+            Self::Unknown => "unknown",
+        }
+    }
+
+    /// Registry errors mostly reach us as text in the pull stream, so this is
+    /// what most classifications come down to. Connection failures are checked
+    /// before "not found", which a failed DNS lookup may also say.
+    fn from_message(msg: &str) -> Self {
+        let m = msg.to_ascii_lowercase();
+        if m.contains("toomanyrequests")
+            || m.contains("too many requests")
+            || m.contains("rate limit")
+            || registry_auth::mentions_code(&m, "429")
+        {
+            Self::RateLimited
+        } else if registry_auth::is_auth_error(&m) {
+            Self::AuthFailed
+        } else if m.contains("dial tcp")
+            || m.contains("connection refused")
+            || m.contains("connection reset")
+            || m.contains("no such host")
+            || m.contains("tls handshake")
+            || m.contains("unexpected eof")
+        {
+            Self::Network
+        } else if m.contains("not found") || m.contains("manifest unknown") || m.contains("does not exist") {
+            Self::NotFound
+        } else if m.contains("timeout") || m.contains("timed out") || m.contains("deadline exceeded") {
+            Self::Timeout
+        } else {
+            Self::Unknown
+        }
+    }
+}
+
+impl std::fmt::Display for PullFailureReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Map an error from the `create_image` stream to a [`PullFailureReason`].
+fn classify_pull_error(err: &anyhow::Error) -> PullFailureReason {
+    use bollard::errors::Error as Bollard;
+
+    match err.downcast_ref::<Bollard>() {
+        Some(Bollard::DockerResponseServerError { status_code, message }) => match status_code {
+            401 | 403 => PullFailureReason::AuthFailed,
+            404 => PullFailureReason::NotFound,
+            429 => PullFailureReason::RateLimited,
+            _ => PullFailureReason::from_message(message),
+        },
+        Some(Bollard::DockerStreamError { error }) => PullFailureReason::from_message(error),
+        Some(Bollard::RequestTimeoutError) => PullFailureReason::Timeout,
+        Some(e) if backend::docker::is_transport_error(e) => PullFailureReason::Network,
+        Some(other) => PullFailureReason::from_message(&other.to_string()),
+        None => PullFailureReason::from_message(&format!("{:#}", err)),
+    }
+}
+
+async fn record_failure_reason(pool: &DbPool, job_id: &str, reason: PullFailureReason) {
+    let labels = serde_json::json!({ "reason": reason.as_str() }).to_string();
+    if let Err(e) = db::insert_metric_labeled(pool, job_id, "pull_failure_reason", 1.0, None, Some(&labels)).await {
+        warn!("job {}: cannot record pull failure reason: {:#}", job_id, e);
+    }
+}
+
+/// Record `digest_match` (1/0) when the job has an `expected_digest`; a pull
+/// that reported no digest counts as a mismatch. Returns false only when the
+/// job should fail, i.e. it doesn't match and `require_digest_match` is set.
+async fn check_digest(
+    pool: &DbPool,
+    job_id: &str,
+    options: &db::JobOptions,
+    observed: Option<&str>,
+) -> anyhow::Result<bool> {
+    let Some(expected) = options.expected_digest.as_deref() else {
+        return Ok(true);
+    };
+    let matched = observed.is_some_and(|d| digests_equal(d, expected));
+    let labels = serde_json::json!({ "expected": expected, "observed": observed }).to_string();
+    db::insert_metric_labeled(pool, job_id, "digest_match", if matched { 1.0 } else { 0.0 }, None, Some(&labels))
+        .await?;
+    Ok(matched || !options.require_digest_match)
+}
+
+fn digests_equal(a: &str, b: &str) -> bool {
+    a.trim().eq_ignore_ascii_case(b.trim())
+}
+
+/// `<algorithm>:<encoded>` as in the OCI image spec, e.g. `sha256:9b2a..`
+fn is_valid_digest(d: &str) -> bool {
+    let Some((algo, encoded)) = d.split_once(':') else {
+        return false;
+    };
+    !algo.is_empty()
+        && algo.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b"+._-".contains(&b))
+        && !encoded.is_empty()
+        && encoded.bytes().all(|b| b.is_ascii_alphanumeric() || b"=_-".contains(&b))
+}
+
+fn mbps(bytes: u64, window_ms: f64) -> f64 {
+    if bytes > 0 && window_ms > 0.0 {
+        (bytes as f64 * 8.0) / (window_ms / 1000.0) / 1_000_000.0
+    } else {
+        0.0
+    }
+}
+
+/// At most `max` bytes of `s`, cut before a character that doesn't fit
+/// whole, noting how many characters were left out
 fn truncate(s: &str, max: usize) -> String {
     if s.len() <= max {
-        s.to_string()
-    } else {
-        format!("{}… (+{} chars)", &s[..max], s.len() - max)
+        return s.to_string();
     }
+    let cut = s.floor_char_boundary(max);
+    format!("{}… (+{} chars)", &s[..cut], s[cut..].chars().count())
 }
 
 fn parse_image_ref(image: &str) -> (String, String, String) {
     let mut parts = image.split('/');
     let first = parts.next().unwrap_or("");
-    let (registry_host, remainder) = if first.contains('.') || first.contains(':') || first == "localhost" {
+    // a lone component ("alpine:3.20", "nginx@sha256:..") is never a registry
+    let (registry_host, remainder) = if image.contains('/') && is_registry_host(first) {
         (first.to_string(), parts.collect::<Vec<_>>().join("/"))
     } else {
         ("docker.io".to_string(), {
@@ -283,19 +1571,49 @@ fn parse_image_ref(image: &str) -> (String, String, String) {
     (registry_host, repo, tag)
 }
 
+/// `repo:tag`, `repo@sha256:...` or bare `repo` (tag defaults to `latest`).
+/// A ':' only starts a tag after the last '/', so `localhost:5000/app` has no tag.
+/// With both (`repo:tag@sha256:...`) the digest is pulled and the tag dropped, as docker does.
 fn split_repo_tag(image: &str) -> (String, String) {
-    if let Some((r, t)) = image.rsplit_once(':') {
-        (r.to_string(), t.to_string())
+    if let Some((r, d)) = image.split_once('@') {
+        return (split_repo_tag(r).0, d.to_string());
+    }
+    let name_start = image.rfind('/').map(|i| i + 1).unwrap_or(0);
+    match image[name_start..].rfind(':') {
+        Some(i) => (image[..name_start + i].to_string(), image[name_start + i + 1..].to_string()),
+        None => (image.to_string(), "latest".to_string()),
+    }
+}
+
+/// Tags can't contain ':', digests always do (`sha256:...`)
+fn is_digest(reference: &str) -> bool {
+    reference.contains(':')
+}
+
+fn with_reference(name: &str, reference: &str) -> String {
+    if is_digest(reference) {
+        format!("{}@{}", name, reference)
     } else {
-        (image.to_string(), "latest".to_string())
+        format!("{}:{}", name, reference)
     }
 }
 
-async fn remove_image_if_exists(docker: &Docker, name: &str) {
-    let opts = Some(RemoveImageOptions { force: true, noprune: false });
-    if let Err(e) = docker.remove_image(name, opts, None).await {
-        #[cfg(debug_assertions)]
-        warn!("remove_image_if_exists({}): {}", name, e);
+/// `registry/repo:tag` with every default filled in (`docker.io/library/nginx:latest`)
+fn canonical_image_ref(image: &str) -> String {
+    let (registry_host, repo, reference) = parse_image_ref(image);
+    let name = match registry_host.as_str() {
+        "docker.io" => format!("docker.io/{}", build_from_image(&registry_host, &repo)),
+        _ => build_from_image(&registry_host, &repo),
+    };
+    with_reference(&name, &reference)
+}
+
+/// Name the daemon stores the image under (Hub images have no registry prefix)
+fn local_name(registry_host: &str, repo: &str) -> String {
+    if registry_host == "docker.io" {
+        repo.trim_start_matches("library/").to_string()
+    } else {
+        format!("{}/{}", registry_host, repo)
     }
 }
 
@@ -308,54 +1626,675 @@ fn env_flag(name: &str, default: bool) -> bool {
     }
 }
 
-async fn rm_image(docker: &Docker, name: &str) {
-    let opts = Some(RemoveImageOptions { force: true, noprune: false });
-    if let Err(e) = docker.remove_image(name, opts, None).await {
-        #[cfg(debug_assertions)]
-        warn!("remove_image({}): {}", name, e);
+fn build_from_image(registry_host: &str, repo: &str) -> String {
+    if registry_host == "docker.io" {
+        if repo.contains('/') {
+            repo.to_string()
+        } else {
+            format!("library/{}", repo)
+        }
+    } else {
+        format!("{}/{}", registry_host, repo)
     }
 }
 
-/// Thorough removal: try short ref, full ref, then remove by id/tags/digests from inspect.
-async fn remove_image_thorough(docker: &Docker, repo: &str, tag: &str, registry_host: &str) {
-    let short_ref = format!("{}:{}", repo, tag);
-    let full_ref  = format!("{}/{}", registry_host, &short_ref);
+#[cfg(test)]
+mod tests {
+    use actix_web::http::{header, StatusCode};
+    use actix_web::test::{self, TestRequest};
+    use bollard::auth::DockerCredentials;
 
-    // ลบแบบรวดเร็วทั้งชื่อสั้น/ชื่อเต็มก่อน
-    rm_image(docker, &short_ref).await;
-    rm_image(docker, &full_ref).await;
+    use super::*;
+    use crate::backend::ManifestInfo;
+    use crate::test_support::{self, pool, queue_job};
 
-    // แก้จุดพัง: ห้าม await ใน .or_else() -> ใช้ match แทน
-    let inspected = match docker.inspect_image(&short_ref).await {
-        Ok(ins) => Ok(ins),
-        Err(_)  => docker.inspect_image(&full_ref).await,
-    };
+    #[actix_web::test]
+    async fn cancel_queued_job() {
+        let pool = pool().await;
+        queue_job(&pool, "job-1", "alpine", 0).await;
+        let app = test::init_service(test_support::app(test_support::state(&[]), pool.clone())).await;
 
-    if let Ok(ins) = inspected {
-        if let Some(id) = ins.id {
-            rm_image(docker, &id).await;
-        }
-        if let Some(tags) = ins.repo_tags {
-            for t in tags {
-                rm_image(docker, &t).await;
+        let req = TestRequest::post().uri("/api/v1/jobs/job-1/cancel").to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["message"], "job canceled");
+        assert_eq!(body["data"]["status"], "canceled");
+        assert!(db::claim_next_job(&pool, 60, &[]).await.unwrap().is_none());
+
+        // canceled once is final
+        let req = TestRequest::post().uri("/api/v1/jobs/job-1/cancel").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::CONFLICT);
+        let req = TestRequest::post().uri("/api/v1/jobs/nope/cancel").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn canceling_a_queued_job_calls_its_callback_url() {
+        let (url, mut received) = test_support::http_receiver(&[200]).await;
+        let app = test::init_service(test_support::app(test_support::state(&[]), pool().await)).await;
+        let req = TestRequest::post()
+            .uri("/api/v1/jobs")
+            .set_json(serde_json::json!({ "image": "alpine", "callback_url": url }));
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req.to_request()).await;
+        let id = body["data"]["id"].as_str().unwrap().to_string();
+
+        let req = TestRequest::post().uri(&format!("/api/v1/jobs/{id}/cancel")).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+        let sent = tokio::time::timeout(Duration::from_secs(5), received.recv()).await.expect("webhook sent").unwrap();
+        assert_eq!(sent["job_id"], id.as_str());
+        assert_eq!(sent["image"], "alpine");
+        assert_eq!(sent["status"], "canceled");
+    }
+
+    #[actix_web::test]
+    async fn job_credentials_never_show_up_in_job_bodies() {
+        let pool = pool().await;
+        let app = test::init_service(test_support::app(test_support::state(&[]), pool.clone())).await;
+        let req = TestRequest::post().uri("/api/v1/jobs").set_json(serde_json::json!({
+            "image": "ghcr.io/acme/app:1",
+            "username": "ci-robot",
+            "password": "s3cr3t-token",
+        }));
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req.to_request()).await;
+        let id = body["data"]["id"].as_str().unwrap().to_string();
+        assert!(!body.to_string().contains("s3cr3t-token"), "{body}");
+        assert_eq!(
+            db::get_job_credentials(&pool, &id).await.unwrap(),
+            Some(("ci-robot".to_string(), "s3cr3t-token".to_string()))
+        );
+
+        for uri in [format!("/api/v1/jobs/{id}"), "/api/v1/jobs".to_string(), "/api/v1/jobs/search?image=acme".to_string()] {
+            let res = test::call_service(&app, TestRequest::get().uri(&uri).to_request()).await;
+            assert_eq!(res.status(), StatusCode::OK, "{uri}");
+            let text = String::from_utf8(test::read_body(res).await.to_vec()).unwrap();
+            assert!(text.contains(&id), "{uri}: {text}");
+            for secret in ["ci-robot", "s3cr3t-token", "czNjcjN0LXRva2Vu", "password", "username"] {
+                assert!(!text.contains(secret), "{uri} shows {secret}: {text}");
             }
         }
-        if let Some(digests) = ins.repo_digests {
-            for d in digests {
-                rm_image(docker, &d).await;
+    }
+
+    fn create(image: &str) -> TestRequest {
+        TestRequest::post().uri("/api/v1/jobs").set_json(serde_json::json!({ "image": image }))
+    }
+
+    #[actix_web::test]
+    async fn only_allowed_registries_are_accepted() {
+        let state = test_support::state(&[("ALLOWED_REGISTRIES", "registry.internal")]);
+        let app = test::init_service(test_support::app(state, pool().await)).await;
+        for image in ["docker.io/nginx", "nginx", "ghcr.io/acme/app:1"] {
+            let res = test::call_service(&app, create(image).to_request()).await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{image}");
+            let body: serde_json::Value = test::read_body_json(res).await;
+            assert_eq!(body["error"], "registry not allowed", "{image}");
+        }
+        for image in ["registry.internal/team/app:1", "REGISTRY.internal/app"] {
+            let res = test::call_service(&app, create(image).to_request()).await;
+            assert_eq!(res.status(), StatusCode::OK, "{image}");
+        }
+
+        let app = test::init_service(test_support::app(test_support::state(&[]), pool().await)).await;
+        let res = test::call_service(&app, create("docker.io/nginx").to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    /// Status and `error` of creating a job for each image in `images`
+    async fn create_outcomes(vars: &[(&str, &str)], images: &[&str]) -> Vec<(StatusCode, String)> {
+        let app = test::init_service(test_support::app(test_support::state(vars), pool().await)).await;
+        let mut outcomes = Vec::new();
+        for image in images {
+            let res = test::call_service(&app, create(image).to_request()).await;
+            let status = res.status();
+            let body: serde_json::Value = test::read_body_json(res).await;
+            outcomes.push((status, body["error"].as_str().unwrap_or_default().to_string()));
+        }
+        outcomes
+    }
+
+    #[actix_web::test]
+    async fn deny_patterns_block_matching_images() {
+        let vars = [("IMAGE_DENY_PATTERNS", "*/crypto-miner*, docker.io/library/nginx:*")];
+        let outcomes = create_outcomes(&vars, &["evil/crypto-miner:1", "nginx", "docker.io/library/nginx:1.27", "alpine"]).await;
+        let denied = (StatusCode::FORBIDDEN, "image not allowed (matches deny pattern '*/crypto-miner*')".to_string());
+        assert_eq!(outcomes[0], denied);
+        // `nginx` is matched through its full name
+        assert_eq!(outcomes[1].0, StatusCode::FORBIDDEN);
+        assert_eq!(outcomes[2].0, StatusCode::FORBIDDEN);
+        assert_eq!(outcomes[3].0, StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn allow_patterns_admit_only_matching_images() {
+        let vars = [("IMAGE_ALLOW_PATTERNS", "registry.internal/*")];
+        let outcomes = create_outcomes(&vars, &["registry.internal/app:1", "alpine"]).await;
+        assert_eq!(outcomes[0].0, StatusCode::OK);
+        assert_eq!(outcomes[1], (StatusCode::FORBIDDEN, "image not allowed (no allow pattern matches)".to_string()));
+    }
+
+    #[actix_web::test]
+    async fn deny_wins_over_allow() {
+        let vars = [("IMAGE_ALLOW_PATTERNS", "registry.internal/*"), ("IMAGE_DENY_PATTERNS", "*/crypto-miner*")];
+        let outcomes = create_outcomes(&vars, &["registry.internal/crypto-miner:1", "registry.internal/app:1"]).await;
+        assert_eq!(
+            outcomes[0],
+            (StatusCode::FORBIDDEN, "image not allowed (matches deny pattern '*/crypto-miner*')".to_string())
+        );
+        assert_eq!(outcomes[1].0, StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn only_development_bodies_name_the_pattern() {
+        let outcome = |env| async move {
+            let vars = [("APP_ENV", env), ("IMAGE_DENY_PATTERNS", "*/crypto-miner*")];
+            create_outcomes(&vars, &["evil/crypto-miner:1"]).await.remove(0)
+        };
+        let dev = outcome("development").await;
+        let prod = outcome("production").await;
+        assert_eq!(dev, (StatusCode::FORBIDDEN, "image not allowed (matches deny pattern '*/crypto-miner*')".to_string()));
+        assert_eq!(prod, (StatusCode::FORBIDDEN, "image not allowed".to_string()));
+    }
+
+    #[actix_web::test]
+    async fn created_range_includes_both_bounds() {
+        let pool = pool().await;
+        for (id, created_at) in [
+            ("before", "2026-02-28 23:59:59"),
+            ("first", "2026-03-01 00:00:00"),
+            ("last", "2026-03-01 23:59:59"),
+            ("after", "2026-03-02 00:00:00"),
+        ] {
+            queue_job(&pool, id, "alpine", 0).await;
+            test_support::execute(&pool, &format!("UPDATE jobs SET created_at = '{created_at}' WHERE id = '{id}'")).await;
+        }
+        let app = test::init_service(test_support::app(test_support::state(&[]), pool)).await;
+
+        for (query, ids) in [
+            ("from=2026-03-01&to=2026-03-01", &["last", "first"][..]),
+            ("from=2026-03-01T23:59:59Z&to=2026-03-02T00:00:00Z", &["after", "last"]),
+            ("from=2026-03-01T01:00:00%2B01:00", &["after", "last", "first"]),
+            ("to=2026-02-28", &["before"]),
+        ] {
+            let req = TestRequest::get().uri(&format!("/api/v1/jobs?{query}")).to_request();
+            let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+            let listed: Vec<_> = body["data"].as_array().unwrap().iter().map(|j| j["id"].as_str().unwrap()).collect();
+            assert_eq!(listed, ids, "{query}");
+            assert_eq!(body["total"], ids.len(), "{query}");
+        }
+
+        for (query, error) in [
+            ("from=yesterday", "from must be an RFC 3339 timestamp or YYYY-MM-DD"),
+            ("to=2026-13-01", "to must be an RFC 3339 timestamp or YYYY-MM-DD"),
+            ("from=2026-03-02&to=2026-03-01", "from must not be after to"),
+        ] {
+            let req = TestRequest::get().uri(&format!("/api/v1/jobs?{query}")).to_request();
+            let res = test::call_service(&app, req).await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{query}");
+            let body: serde_json::Value = test::read_body_json(res).await;
+            assert_eq!(body["error"], error, "{query}");
+        }
+    }
+
+    #[actix_web::test]
+    async fn completed_job_detail_carries_its_timing() {
+        let pool = pool().await;
+        queue_job(&pool, "job-1", "alpine", 5).await;
+        queue_job(&pool, "job-2", "alpine", 0).await;
+        let claimed = db::claim_next_job(&pool, 60, &[]).await.unwrap().expect("claim");
+        assert_eq!(claimed.id, "job-1");
+        db::heartbeat_job(&pool, "job-1", 60).await.unwrap();
+        db::complete_job(&pool, "job-1", Some("pulled"), Some(4096)).await.unwrap();
+        test_support::execute(
+            &pool,
+            "UPDATE jobs SET started_at = '2026-03-01 10:00:00', finished_at = '2026-03-01 10:01:30' WHERE id = 'job-1'",
+        )
+        .await;
+        let app = test::init_service(test_support::app(test_support::state(&[]), pool)).await;
+        let get = |id: &str| TestRequest::get().uri(&format!("/api/v1/jobs/{id}")).to_request();
+
+        let body: serde_json::Value = test::call_and_read_body_json(&app, get("job-1")).await;
+        let job = &body["data"];
+        assert_eq!(job["status"], "completed");
+        assert_eq!((job["priority"].as_i64(), job["max_attempts"].as_i64()), (Some(5), Some(3)));
+        assert_eq!(job["started_at"], "2026-03-01 10:00:00");
+        assert_eq!(job["finished_at"], "2026-03-01 10:01:30");
+        assert_eq!(job["duration_ms"], 90_000);
+        assert_eq!(job["bytes_downloaded"], 4096);
+        // the lease ends with the job; the last heartbeat stays for debugging
+        assert_eq!(job["lease_expires_at"], serde_json::Value::Null);
+        assert!(job["last_heartbeat"].is_string() && job["updated_at"].is_string() && job["created_at"].is_string());
+
+        let body: serde_json::Value = test::call_and_read_body_json(&app, get("job-2")).await;
+        let job = &body["data"];
+        assert_eq!(job["status"], "queued");
+        for field in ["started_at", "finished_at", "duration_ms", "bytes_downloaded", "lease_expires_at", "last_heartbeat"] {
+            assert_eq!(job[field], serde_json::Value::Null, "{field}");
+        }
+    }
+
+    #[actix_web::test]
+    async fn jobs_are_found_by_their_metadata() {
+        let app = test::init_service(test_support::app(test_support::state(&[]), pool().await)).await;
+        let mut ids = HashMap::new();
+        for (name, metadata) in [
+            ("payments-ci", serde_json::json!({ "team": "payments", "ci_run": "1234" })),
+            ("payments-manual", serde_json::json!({ "team": "payments" })),
+            ("search-ci", serde_json::json!({ "team": "search", "ci_run": "1234" })),
+            ("untagged", serde_json::json!(null)),
+        ] {
+            let req = TestRequest::post().uri("/api/v1/jobs").set_json(serde_json::json!({ "image": "alpine", "metadata": metadata }));
+            let body: serde_json::Value = test::call_and_read_body_json(&app, req.to_request()).await;
+            ids.insert(body["data"]["id"].as_str().unwrap().to_string(), name);
+        }
+
+        let list = |query: &str| TestRequest::get().uri(&format!("/api/v1/jobs?{query}")).to_request();
+        for (query, expected) in [
+            ("meta.team=payments", &["payments-ci", "payments-manual"][..]),
+            ("meta.team=payments&meta.ci_run=1234", &["payments-ci"]),
+            ("meta.ci_run=1234", &["payments-ci", "search-ci"]),
+            ("meta.team=billing", &[]),
+            ("meta.owner=payments", &[]),
+        ] {
+            let body: serde_json::Value = test::call_and_read_body_json(&app, list(query)).await;
+            let mut found: Vec<_> = body["data"].as_array().unwrap().iter().map(|j| ids[j["id"].as_str().unwrap()]).collect();
+            found.sort();
+            assert_eq!(found, expected, "{query}");
+        }
+
+        let id = ids.iter().find(|(_, name)| **name == "payments-ci").unwrap().0;
+        let req = TestRequest::get().uri(&format!("/api/v1/jobs/{id}")).to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["data"]["metadata"], serde_json::json!({ "ci_run": "1234", "team": "payments" }));
+        let id = ids.iter().find(|(_, name)| **name == "untagged").unwrap().0;
+        let req = TestRequest::get().uri(&format!("/api/v1/jobs/{id}")).to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["data"]["metadata"], serde_json::json!({}));
+
+        let res = test::call_service(&app, list("meta.team.name=x")).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(body["error"], "'meta.team.name': metadata keys are 1 to 64 letters, digits or '_'");
+    }
+
+    #[actix_web::test]
+    async fn purge_endpoint_reports_what_it_deleted() {
+        let pool = pool().await;
+        queue_job(&pool, "old", "alpine", 0).await;
+        db::complete_job(&pool, "old", None, None).await.unwrap();
+        test_support::execute(&pool, "UPDATE jobs SET finished_at = datetime('now', '-10 days')").await;
+        let app = test::init_service(test_support::app(test_support::state(&[]), pool.clone())).await;
+
+        let req = TestRequest::delete().uri("/api/v1/jobs?older_than_days=7").to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["data"], serde_json::json!({ "deleted": 1, "older_than_days": 7 }));
+        for uri in ["/api/v1/jobs", "/api/v1/jobs?older_than_days=0", "/api/v1/jobs?older_than_days=x"] {
+            let res = test::call_service(&app, TestRequest::delete().uri(uri).to_request()).await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{uri}");
+        }
+    }
+
+    #[actix_web::test]
+    async fn job_etag_round_trip() {
+        let pool = pool().await;
+        queue_job(&pool, "job-1", "alpine", 0).await;
+        let app = test::init_service(test_support::app(test_support::state(&[]), pool.clone())).await;
+
+        let res = test::call_service(&app, TestRequest::get().uri("/api/v1/jobs/job-1").to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let etag = res.headers().get(header::ETAG).unwrap().clone();
+        assert!(etag.to_str().unwrap().starts_with("W/"), "{etag:?}");
+
+        let req = TestRequest::get().uri("/api/v1/jobs/job-1").insert_header((header::IF_NONE_MATCH, etag.clone()));
+        let res = test::call_service(&app, req.to_request()).await;
+        assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(res.headers().get(header::ETAG), Some(&etag));
+
+        db::complete_job(&pool, "job-1", Some("done"), None).await.unwrap();
+        let req = TestRequest::get().uri("/api/v1/jobs/job-1").insert_header((header::IF_NONE_MATCH, etag.clone()));
+        let res = test::call_service(&app, req.to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_ne!(res.headers().get(header::ETAG), Some(&etag));
+    }
+
+    #[actix_web::test]
+    async fn search_matches_substrings_and_takes_wildcards_literally() {
+        let pool = pool().await;
+        queue_job(&pool, "a", "docker.io/library/nginx:1.27", 0).await;
+        queue_job(&pool, "b", "ghcr.io/acme/NGINX_proxy:latest", 0).await;
+        queue_job(&pool, "c", "ghcr.io/acme/nginxproxy:latest", 0).await;
+        queue_job(&pool, "d", "quay.io/acme/redis:7", 0).await;
+        let app = test::init_service(test_support::app(test_support::state(&[]), pool.clone())).await;
+
+        let search = |q: &str| TestRequest::get().uri(&format!("/api/v1/jobs/search?{q}")).to_request();
+        let ids = |body: &serde_json::Value| {
+            let mut ids: Vec<_> = body["data"].as_array().unwrap().iter().map(|j| j["id"].as_str().unwrap().to_string()).collect();
+            ids.sort();
+            ids
+        };
+
+        let body: serde_json::Value = test::call_and_read_body_json(&app, search("image=nginx")).await;
+        assert_eq!(ids(&body), ["a", "b", "c"]);
+        assert_eq!(body["total"], 3);
+        // `_` is not a single-character wildcard, `%` not a wildcard at all
+        let body: serde_json::Value = test::call_and_read_body_json(&app, search("image=nginx_")).await;
+        assert_eq!(ids(&body), ["b"]);
+        let body: serde_json::Value = test::call_and_read_body_json(&app, search("image=acme%25proxy")).await;
+        assert_eq!(ids(&body), Vec::<String>::new());
+
+        let long = "x".repeat(MAX_SEARCH_LEN + 1);
+        for q in ["", "image=", "image=%20", &format!("image={long}")] {
+            let res = test::call_service(&app, search(q)).await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{q}");
+        }
+    }
+
+    #[actix_web::test]
+    async fn invalid_job_fields_are_rejected_with_their_errors() {
+        let app = test::init_service(test_support::app(test_support::state(&[]), pool().await)).await;
+        let long = "a".repeat(MAX_IMAGE_LEN as usize + 1);
+        let cases = [
+            ("/api/v1/jobs", serde_json::json!({ "image": "alpine", "priority": 101 }), "priority: "),
+            ("/api/v1/jobs", serde_json::json!({ "image": "alpine", "priority": -101 }), "priority: "),
+            ("/api/v1/jobs", serde_json::json!({ "image": "" }), "image: "),
+            ("/api/v1/jobs", serde_json::json!({}), "image: "),
+            ("/api/v1/jobs", serde_json::json!({ "image": long }), "image: "),
+            ("/api/v1/jobs/batch", serde_json::json!({ "images": ["alpine"], "priority": 101 }), "priority: "),
+        ];
+        for (uri, body, field) in cases {
+            let req = TestRequest::post().uri(uri).set_json(&body).to_request();
+            let res = test::call_service(&app, req).await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{body}");
+            let err: serde_json::Value = test::read_body_json(res).await;
+            assert!(err["error"].as_str().unwrap().starts_with(field), "{body}: {err}");
+        }
+    }
+
+    #[actix_web::test]
+    async fn result_is_cut_to_the_configured_length_unless_full() {
+        let pool = pool().await;
+        queue_job(&pool, "job-1", "alpine", 0).await;
+        db::complete_job(&pool, "job-1", Some("pulled ✓ größe 12 MB"), None).await.unwrap();
+        let state = test_support::state(&[("RESULT_TRUNCATE_LEN", "8")]);
+        let app = test::init_service(test_support::app(state, pool)).await;
+
+        let req = TestRequest::get().uri("/api/v1/jobs/job-1").to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        // 8 bytes end inside the 3-byte '✓'
+        assert_eq!(body["data"]["result"], "pulled … (+13 chars)");
+        let req = TestRequest::get().uri("/api/v1/jobs/job-1?full=true").to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["data"]["result"], "pulled ✓ größe 12 MB");
+        let req = TestRequest::get().uri("/api/v1/jobs/job-1?full=yes").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn image_refs_split_into_registry_repo_and_reference() {
+        let digest = "sha256:abababababababababababababababababababababababababababababababab";
+        let cases = [
+            ("nginx", ("docker.io", "nginx", "latest")),
+            ("nginx:1.27", ("docker.io", "nginx", "1.27")),
+            ("library/nginx:1.27-alpine", ("docker.io", "library/nginx", "1.27-alpine")),
+            ("bitnami/redis:7.2", ("docker.io", "bitnami/redis", "7.2")),
+            ("ghcr.io/org/app:v1", ("ghcr.io", "org/app", "v1")),
+            ("localhost:5000/app", ("localhost:5000", "app", "latest")),
+            ("localhost:5000/team/app:dev", ("localhost:5000", "team/app", "dev")),
+            ("localhost/app:1", ("localhost", "app", "1")),
+        ];
+        for (image, (registry, repo, reference)) in cases {
+            assert_eq!(parse_image_ref(image), (registry.into(), repo.into(), reference.into()), "{image}");
+        }
+
+        let by_digest = [
+            (format!("nginx@{digest}"), ("docker.io", "nginx")),
+            (format!("registry.io/ns/app@{digest}"), ("registry.io", "ns/app")),
+            (format!("localhost:5000/app@{digest}"), ("localhost:5000", "app")),
+            // the tag is dropped, the digest is what gets pulled
+            (format!("nginx:1.27@{digest}"), ("docker.io", "nginx")),
+            (format!("registry.io/ns/app:v2@{digest}"), ("registry.io", "ns/app")),
+        ];
+        for (image, (registry, repo)) in by_digest {
+            assert_eq!(parse_image_ref(&image), (registry.into(), repo.into(), digest.into()), "{image}");
+            assert!(is_digest(&parse_image_ref(&image).2));
+        }
+    }
+
+    #[test]
+    fn canonical_refs_fill_in_every_default() {
+        let digest = "sha256:abababababababababababababababababababababababababababababababab";
+        assert_eq!(canonical_image_ref("nginx"), "docker.io/library/nginx:latest");
+        assert_eq!(canonical_image_ref("bitnami/redis:7.2"), "docker.io/bitnami/redis:7.2");
+        assert_eq!(canonical_image_ref("localhost:5000/app"), "localhost:5000/app:latest");
+        assert_eq!(canonical_image_ref(&format!("nginx@{digest}")), format!("docker.io/library/nginx@{digest}"));
+        assert_eq!(canonical_image_ref(&format!("registry.io/ns/app:v2@{digest}")), format!("registry.io/ns/app@{digest}"));
+        assert_eq!(local_name("docker.io", "library/nginx"), "nginx");
+        assert_eq!(with_reference("nginx", "1.27"), "nginx:1.27");
+    }
+
+    #[test]
+    fn truncate_cuts_on_character_boundaries() {
+        assert_eq!(truncate("short", 5), "short");
+        assert_eq!(truncate("", 0), "");
+        assert_eq!(truncate("abc", 0), "… (+3 chars)");
+        assert_eq!(truncate("abcdef", 4), "abcd… (+2 chars)");
+        // the limit is in bytes; a character it would split is left out whole
+        assert_eq!(truncate("aé€😀b", 2), "a… (+4 chars)");
+        assert_eq!(truncate("aé€😀b", 3), "aé… (+3 chars)");
+        assert_eq!(truncate("aé€😀b", 5), "aé… (+3 chars)");
+        assert_eq!(truncate("aé€😀b", 6), "aé€… (+2 chars)");
+        assert_eq!(truncate("😀😀😀", 1), "… (+3 chars)");
+        assert_eq!(truncate("😀😀😀", 4), "😀… (+2 chars)");
+        assert_eq!(truncate("😀😀😀", 12), "😀😀😀");
+    }
+
+    #[test]
+    fn pull_errors_map_to_failure_reasons() {
+        use bollard::errors::Error as Bollard;
+        use PullFailureReason::*;
+
+        let server = |status_code: u16, message: &str| Bollard::DockerResponseServerError { status_code, message: message.into() };
+        let stream = |error: &str| Bollard::DockerStreamError { error: error.into() };
+        let io = |kind: std::io::ErrorKind| Bollard::IOError { err: std::io::Error::new(kind, "socket closed") };
+        let cases = [
+            (server(401, "unauthorized"), AuthFailed),
+            (server(403, "forbidden"), AuthFailed),
+            (server(404, "pull access denied for nope"), NotFound),
+            (server(429, "slow down"), RateLimited),
+            (server(500, "toomanyrequests: You have reached your pull rate limit"), RateLimited),
+            (server(500, "Get \"https://registry.internal/v2/\": dial tcp 10.0.0.9:443: connect: connection refused"), Network),
+            (server(500, "something broke"), Unknown),
+            (stream("unauthorized: authentication required"), AuthFailed),
+            (stream("manifest for alpine:nope not found: manifest unknown"), NotFound),
+            (stream("received unexpected HTTP status: 429 Too Many Requests"), RateLimited),
+            (stream("dial tcp: lookup registry.internal on 127.0.0.11:53: no such host"), Network),
+            (stream("net/http: TLS handshake timeout"), Network),
+            (stream("context deadline exceeded"), Timeout),
+            // digits inside a digest or a size are no status code
+            (stream("filesystem layer verification failed for digest sha256:4291ab401c"), Unknown),
+            (stream("read 14290 bytes, then: unexpected EOF"), Network),
+            (Bollard::RequestTimeoutError, Timeout),
+            (io(std::io::ErrorKind::ConnectionReset), Network),
+            (Bollard::SocketNotFoundError("/var/run/docker.sock".into()), Network),
+        ];
+        for (err, reason) in cases {
+            let msg = err.to_string();
+            assert_eq!(classify_pull_error(&anyhow::Error::new(err)), reason, "{msg}");
+        }
+
+        // not from bollard (containerd, skopeo, the timeout wrapper): the text decides
+        assert_eq!(classify_pull_error(&anyhow::anyhow!("skopeo copy failed: manifest unknown")), NotFound);
+        assert_eq!(classify_pull_error(&anyhow::anyhow!("pull timed out after 600s")), Timeout);
+        assert_eq!(classify_pull_error(&anyhow::anyhow!("exit status 1")), Unknown);
+    }
+
+    /// Backend whose pulls report `digest` and one layer of `bytes` right away;
+    /// `hang` makes them never finish.
+    #[derive(Default)]
+    struct FakeBackend {
+        digest: Option<&'static str>,
+        bytes: u64,
+        hang: bool,
+    }
+
+    impl PullBackend for FakeBackend {
+        fn name(&self) -> &'static str {
+            "fake"
+        }
+
+        async fn ping(&self) -> Result<(), String> {
+            Ok(())
+        }
+
+        async fn pull(&self, req: &PullRequest<'_>, metrics: &mut PullMetrics) -> anyhow::Result<()> {
+            if self.hang {
+                std::future::pending::<()>().await;
+            }
+            if self.bytes > 0 {
+                metrics.layer_progress(req, "layer-1".to_string(), self.bytes, self.bytes);
             }
+            metrics.digest = self.digest.map(str::to_string);
+            Ok(())
+        }
+
+        async fn remove(&self, _image_ref: &str, _thorough: bool) {}
+
+        async fn inspect_manifest(&self, _name: &str, _credentials: Option<DockerCredentials>) -> anyhow::Result<ManifestInfo> {
+            anyhow::bail!("no manifests here")
         }
     }
-}
 
-fn build_from_image(registry_host: &str, repo: &str) -> String {
-    if registry_host == "docker.io" {
-        if repo.contains('/') {
-            repo.to_string()
-        } else {
-            format!("library/{}", repo)
+    /// Options that leave the pre/post removal env flags out of it
+    fn pull_options() -> db::JobOptions {
+        db::JobOptions { pre_remove: Some(false), post_remove: Some(false), ..Default::default() }
+    }
+
+    /// Run the worker's pull of `job_id` (queued for alpine) against `backend`.
+    async fn pull(pool: &DbPool, backend: &FakeBackend, job_id: &str, options: &db::JobOptions) -> anyhow::Result<()> {
+        let state = test_support::state(&[]);
+        pull_image_and_record_metrics(pool, backend, job_id, "alpine", options, &state, &AtomicBool::new(false)).await
+    }
+
+    async fn metric(pool: &DbPool, job_id: &str, key: &str) -> Option<f64> {
+        db::get_metric(pool, job_id, key).await.unwrap().map(|m| m.value)
+    }
+
+    async fn status(pool: &DbPool, job_id: &str) -> JobStatus {
+        db::get_job_by_id(pool, job_id).await.unwrap().expect("job").status
+    }
+
+    const DIGEST: &str = "sha256:9b2a2f1c";
+
+    #[test]
+    fn digests_follow_the_oci_grammar() {
+        assert!(is_valid_digest("sha256:9b2a2f1c"));
+        assert!(is_valid_digest("sha512+b64u:Zm9v_YmFy-"));
+        for bad in ["", "sha256", "sha256:", ":9b2a", "SHA256:9b2a", "sha256:9b 2a"] {
+            assert!(!is_valid_digest(bad), "{bad:?}");
         }
-    } else {
-        format!("{}/{}", registry_host, repo)
+        assert!(digests_equal(" SHA256:9B2A2F1C ", DIGEST));
+    }
+
+    #[actix_web::test]
+    async fn matching_digest_completes_the_job() {
+        let pool = pool().await;
+        queue_job(&pool, "job-1", "alpine", 0).await;
+        let options = db::JobOptions { expected_digest: Some(DIGEST.into()), require_digest_match: true, ..pull_options() };
+        pull(&pool, &FakeBackend { digest: Some("SHA256:9B2A2F1C"), bytes: 4096, ..Default::default() }, "job-1", &options)
+            .await
+            .unwrap();
+        assert_eq!(metric(&pool, "job-1", "digest_match").await, Some(1.0));
+        let job = db::get_job_by_id(&pool, "job-1").await.unwrap().unwrap();
+        assert_eq!((job.status, job.bytes_downloaded), (JobStatus::Completed, Some(4096)));
+    }
+
+    #[actix_web::test]
+    async fn mismatched_or_missing_digest_fails_the_job_only_when_required() {
+        let pool = pool().await;
+        let cases = [
+            ("job-mismatch", Some("sha256:0000"), true),
+            ("job-missing", None, true),
+            ("job-tolerated", Some("sha256:0000"), false),
+        ];
+        for (job_id, observed, required) in cases {
+            queue_job(&pool, job_id, "alpine", 0).await;
+            let options = db::JobOptions {
+                expected_digest: Some(DIGEST.into()),
+                require_digest_match: required,
+                ..pull_options()
+            };
+            let res = pull(&pool, &FakeBackend { digest: observed, ..Default::default() }, job_id, &options).await;
+            assert_eq!(metric(&pool, job_id, "digest_match").await, Some(0.0), "{job_id}");
+            if required {
+                // the worker hands the error to fail_or_retry_job
+                let err = format!("{:#}", res.unwrap_err());
+                assert!(err.starts_with(&format!("digest mismatch: expected {DIGEST}")), "{err}");
+                assert_ne!(status(&pool, job_id).await, JobStatus::Completed, "{job_id}");
+            } else {
+                res.unwrap();
+                assert_eq!(status(&pool, job_id).await, JobStatus::Completed, "{job_id}");
+            }
+        }
+    }
+
+    #[actix_web::test]
+    async fn no_expected_digest_records_no_digest_match() {
+        let pool = pool().await;
+        queue_job(&pool, "job-1", "alpine", 0).await;
+        pull(&pool, &FakeBackend::default(), "job-1", &pull_options()).await.unwrap();
+        assert_eq!(metric(&pool, "job-1", "digest_match").await, None);
+    }
+
+    #[actix_web::test]
+    async fn recent_pull_inside_the_window_skips_the_pull() {
+        let pool = pool().await;
+        let backend = FakeBackend { digest: Some(DIGEST), ..Default::default() };
+        queue_job(&pool, "job-prev", "alpine", 0).await;
+        pull(&pool, &backend, "job-prev", &pull_options()).await.unwrap();
+
+        let within = |secs, digest: Option<&str>| db::JobOptions {
+            skip_if_pulled_within_secs: Some(secs),
+            expected_digest: digest.map(str::to_string),
+            ..pull_options()
+        };
+        let result = |job_id| {
+            let pool = pool.clone();
+            async move { db::get_job_by_id(&pool, job_id).await.unwrap().expect("job").result.unwrap_or_default() }
+        };
+
+        queue_job(&pool, "job-skip", "alpine", 0).await;
+        pull(&pool, &backend, "job-skip", &within(300, None)).await.unwrap();
+        assert_eq!(metric(&pool, "job-skip", "cache_warm_skip").await, Some(1.0));
+        assert_eq!(status(&pool, "job-skip").await, JobStatus::Completed);
+        assert!(result("job-skip").await.starts_with("Skipped alpine:latest: pulled by job job-prev"));
+
+        // a pull that resolved to another digest doesn't count
+        queue_job(&pool, "job-other-digest", "alpine", 0).await;
+        pull(&pool, &backend, "job-other-digest", &within(300, Some("sha256:0000"))).await.unwrap();
+        assert_eq!(metric(&pool, "job-other-digest", "cache_warm_skip").await, Some(0.0));
+
+        // outside the window it's pulled again
+        test_support::execute(&pool, "UPDATE jobs SET finished_at = datetime('now', '-1 hour')").await;
+        queue_job(&pool, "job-stale", "alpine", 0).await;
+        pull(&pool, &backend, "job-stale", &within(300, None)).await.unwrap();
+        assert_eq!(metric(&pool, "job-stale", "cache_warm_skip").await, Some(0.0));
+        assert_eq!(status(&pool, "job-stale").await, JobStatus::Completed);
+        assert!(result("job-stale").await.starts_with("Pulled alpine:latest"));
+    }
+
+    #[actix_web::test]
+    async fn hung_pull_times_out() {
+        let pool = pool().await;
+        queue_job(&pool, "job-1", "alpine", 0).await;
+        let state = test_support::state(&[]);
+        // below PULL_TIMEOUT_SECS's minimum, to keep the test short
+        state.config.write().unwrap().pull_timeout_secs = 1;
+        let backend = FakeBackend { hang: true, ..Default::default() };
+        let (options, cancel) = (pull_options(), AtomicBool::new(false));
+        let pull = pull_image_and_record_metrics(&pool, &backend, "job-1", "alpine", &options, &state, &cancel);
+
+        let err = tokio::time::timeout(Duration::from_secs(10), pull).await.expect("the pull was never cut off").unwrap_err();
+        assert_eq!(format!("{:#}", err), "pull failed (timeout): timed out after 1s");
+        let timeout = db::get_metric(&pool, "job-1", "download_timeout").await.unwrap().expect("download_timeout");
+        assert_eq!(timeout.value, 1.0);
+        assert_eq!(timeout.labels_json.as_deref(), Some(r#"{"timeout_secs":1}"#));
+        assert_ne!(status(&pool, "job-1").await, JobStatus::Completed);
     }
 }
@@ -1,13 +1,31 @@
-use actix_web::{get, web, HttpResponse};
-use sqlx::SqlitePool;
+use std::collections::BTreeMap;
 
-use crate::db;
+use actix_web::http::header::{self, Accept, Quality};
+use actix_web::{get, post, web, HttpMessage, HttpRequest, HttpResponse};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::db::{self, DbPool};
 use crate::error::AppError;
-use crate::model::ApiResponse;
+use crate::model::{ApiResponse, ErrorResponse, MetricUnit, PaginatedResponse};
+use crate::routes::job::WORKER_METRIC_KEYS;
+use crate::routes::prometheus;
 
+#[utoipa::path(
+    tag = "metrics",
+    params(
+        ("id" = String, Path, description = "Job id"),
+    ),
+    responses(
+        (status = 200, description = "Every metric of the job, newest first", body = ApiResponse<Vec<MetricItem>>),
+        (status = 500, description = "Database error", body = ErrorResponse),
+    )
+)]
 #[get("/jobs/{id}/metrics")]
 pub async fn get_job_metrics(
-    pool: web::Data<SqlitePool>,
+    pool: web::Data<DbPool>,
     path: web::Path<String>,
 ) -> Result<HttpResponse, AppError> {
     let job_id = path.into_inner();
@@ -15,43 +33,411 @@ pub async fn get_job_metrics(
         .await
         .map_err(AppError::from)?;
 
-    let data: Vec<_> = rows
-        .into_iter()
-        .map(|m| {
-            serde_json::json!({
-                "job_id": m.job_id,
-                "key": m.key,
-                "value": m.value,
-                "unit": m.unit,
-                "labels": m.labels_json
-                    .as_deref()
-                    .and_then(|s| serde_json::from_str::<serde_json::Value>(s).ok()),
-                "created_at": m.created_at,
-            })
-        })
-        .collect();
+    let data: Vec<_> = rows.into_iter().map(metric_item).collect();
 
     Ok(HttpResponse::Ok().json(ApiResponse::ok("ok", data)))
 }
 
-#[get("/metrics/recent")]
-pub async fn get_recent_metrics(
-    pool: web::Data<SqlitePool>,
-    q: web::Query<std::collections::HashMap<String, String>>,
+/// Latest value of one metric of a job
+#[utoipa::path(
+    tag = "metrics",
+    params(
+        ("id" = String, Path, description = "Job id"),
+        ("key" = String, Path, description = "Metric key"),
+    ),
+    responses(
+        (status = 200, description = "Newest row of the metric", body = ApiResponse<MetricItem>),
+        (status = 404, description = "Job not found, or it has no such metric", body = ErrorResponse),
+        (status = 500, description = "Database error", body = ErrorResponse),
+    )
+)]
+#[get("/jobs/{id}/metrics/{key}")]
+pub async fn get_job_metric(
+    pool: web::Data<DbPool>,
+    path: web::Path<(String, String)>,
+) -> Result<HttpResponse, AppError> {
+    let (job_id, key) = path.into_inner();
+    let Some(row) = db::get_metric(pool.get_ref(), &job_id, &key)
+        .await
+        .map_err(AppError::from)?
+    else {
+        if db::get_job_by_id(pool.get_ref(), &job_id).await.map_err(AppError::from)?.is_none() {
+            return Err(AppError::not_found("job not found"));
+        }
+        return Err(AppError::not_found(format!("job has no metric '{}'", key)));
+    };
+
+    Ok(HttpResponse::Ok().json(ApiResponse::ok("ok", metric_item(row))))
+}
+
+/// Most metrics one `POST /jobs/{id}/metrics` may carry
+const MAX_SUBMITTED_METRICS: usize = 100;
+
+/// Longest metric key accepted from outside
+const MAX_METRIC_KEY_LEN: usize = 100;
+
+#[derive(Deserialize, ToSchema)]
+pub struct NewMetric {
+    pub key: String,
+    pub value: f64,
+    pub unit: Option<String>,
+    pub labels: Option<serde_json::Map<String, serde_json::Value>>,
+}
+
+#[derive(Deserialize, ToSchema)]
+#[serde(untagged)]
+pub enum NewMetrics {
+    One(NewMetric),
+    Many(Vec<NewMetric>),
+}
+
+/// Checked form of one submitted metric, ready to insert
+struct ValidMetric {
+    key: String,
+    value: f64,
+    unit: Option<MetricUnit>,
+    labels_json: Option<String>,
+}
+
+fn validate_new_metric(m: NewMetric) -> Result<ValidMetric, String> {
+    let key = m.key.trim();
+    if key.is_empty() {
+        return Err("key is required".to_string());
+    }
+    if key.len() > MAX_METRIC_KEY_LEN || !key.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_') {
+        return Err(format!(
+            "key '{}' must be up to {} letters, digits or '_'",
+            key, MAX_METRIC_KEY_LEN
+        ));
+    }
+    if WORKER_METRIC_KEYS.contains(&key) {
+        return Err(format!("key '{}' is recorded by the worker and can't be submitted", key));
+    }
+    if !m.value.is_finite() {
+        return Err(format!("{}: value must be a finite number", key));
+    }
+    let unit = match m.unit.as_deref().map(str::trim).filter(|u| !u.is_empty()) {
+        Some(u) => Some(u.parse::<MetricUnit>().map_err(|e| format!("{}: {}", key, e))?),
+        None => None,
+    };
+    let labels_json = m
+        .labels
+        .filter(|l| !l.is_empty())
+        .map(|l| serde_json::Value::Object(l).to_string());
+    Ok(ValidMetric { key: key.to_string(), value: m.value, unit, labels_json })
+}
+
+/// `POST /jobs/{id}/metrics` with `{"key": .., "value": .., "unit": .., "labels": {..}}`
+/// or a list of them: metrics measured elsewhere, attached to the job.
+/// Nothing is stored unless every metric is valid.
+#[utoipa::path(
+    tag = "metrics",
+    request_body = NewMetrics,
+    params(
+        ("id" = String, Path, description = "Job id"),
+    ),
+    responses(
+        (status = 200, description = "Metrics recorded; `data` is `{\"job_id\": .., \"inserted\": n}`", body = ApiResponse<serde_json::Value>),
+        (status = 400, description = "Invalid metric or body", body = ErrorResponse),
+        (status = 404, description = "Job not found", body = ErrorResponse),
+        (status = 500, description = "Database error", body = ErrorResponse),
+    )
+)]
+#[post("/jobs/{id}/metrics")]
+pub async fn add_job_metrics(
+    pool: web::Data<DbPool>,
+    path: web::Path<String>,
+    body: web::Json<NewMetrics>,
 ) -> Result<HttpResponse, AppError> {
+    let job_id = path.into_inner();
+    let metrics = match body.into_inner() {
+        NewMetrics::One(m) => vec![m],
+        NewMetrics::Many(list) => list,
+    };
+    if metrics.is_empty() {
+        return Err(AppError::bad_request("no metrics given"));
+    }
+    if metrics.len() > MAX_SUBMITTED_METRICS {
+        return Err(AppError::bad_request(format!(
+            "at most {} metrics per request",
+            MAX_SUBMITTED_METRICS
+        )));
+    }
+    let metrics: Vec<ValidMetric> = metrics
+        .into_iter()
+        .map(validate_new_metric)
+        .collect::<Result<_, _>>()
+        .map_err(AppError::bad_request)?;
+
+    if db::get_job_by_id(pool.get_ref(), &job_id).await.map_err(AppError::from)?.is_none() {
+        return Err(AppError::not_found("job not found"));
+    }
+    for m in &metrics {
+        db::insert_metric_labeled(pool.get_ref(), &job_id, &m.key, m.value, m.unit, m.labels_json.as_deref())
+            .await
+            .map_err(AppError::from)?;
+    }
+
+    Ok(HttpResponse::Ok().json(ApiResponse::ok(
+        "metrics recorded",
+        serde_json::json!({ "job_id": job_id, "inserted": metrics.len() }),
+    )))
+}
+
+/// One metric as the JSON metric endpoints (and job webhooks) show it
+#[derive(Serialize, ToSchema)]
+pub struct MetricItem {
+    pub job_id: String,
+    pub key: String,
+    pub value: f64,
+    pub unit: Option<String>,
+    /// JSON object of labels, e.g. `{"layer_id": ".."}`
+    pub labels: Option<serde_json::Value>,
+    pub created_at: String,
+}
+
+pub fn metric_item(m: db::MetricRow) -> MetricItem {
+    MetricItem {
+        job_id: m.job_id,
+        key: m.key,
+        value: m.value,
+        unit: m.unit,
+        labels: m.labels_json
+            .as_deref()
+            .and_then(|s| serde_json::from_str::<serde_json::Value>(s).ok()),
+        created_at: m.created_at,
+    }
+}
+
+/// `limit` (default 200) and `offset` (default 0) of the metric list endpoints
+fn page_params(q: &std::collections::HashMap<String, String>) -> (i64, i64) {
     let limit = q
         .get("limit")
         .and_then(|s| s.parse::<i64>().ok())
         .unwrap_or(200);
+    let offset = q
+        .get("offset")
+        .and_then(|s| s.parse::<i64>().ok())
+        .filter(|&n| n >= 0)
+        .unwrap_or(0);
+    (limit, offset)
+}
+
+/// `key` and `registry` filters of `/metrics/recent` and `/metrics/recent.csv`
+fn recent_filters(q: &std::collections::HashMap<String, String>) -> (Option<&str>, Option<&str>) {
+    let key = q.get("key").map(|s| s.trim()).filter(|s| !s.is_empty());
+    let registry = q.get("registry").map(|s| s.trim()).filter(|s| !s.is_empty());
+    (key, registry)
+}
+
+/// What `/metrics/recent` answers with, picked from `Accept`
+enum RecentFormat {
+    Json,
+    Prometheus,
+}
+
+/// Highest-q media type of `Accept` that `/metrics/recent` can produce; JSON
+/// without the header (or with `*/*`), 406 when nothing listed fits.
+fn recent_format(req: &HttpRequest) -> Result<RecentFormat, AppError> {
+    let Some(accept) = req.get_header::<Accept>() else {
+        return Ok(RecentFormat::Json);
+    };
+    if accept.is_empty() {
+        return Ok(RecentFormat::Json);
+    }
+    let mut prefs: Vec<_> = accept.iter().filter(|p| p.quality > Quality::ZERO).collect();
+    prefs.sort_by_key(|p| std::cmp::Reverse(p.quality));
+    for pref in prefs {
+        match (pref.item.type_().as_str(), pref.item.subtype().as_str()) {
+            ("application", "json") | ("application", "*") | ("*", "*") => return Ok(RecentFormat::Json),
+            ("text", "plain") | ("text", "*") => return Ok(RecentFormat::Prometheus),
+            _ => {}
+        }
+    }
+    Err(AppError::not_acceptable("supported: application/json, text/plain"))
+}
+
+/// `GET /metrics/recent?limit=200&offset=0&key=average_speed_mbps&registry=gcr.io`
+///
+/// `Accept: text/plain` returns the same page as Prometheus text instead (see
+/// `prometheus::render_recent`).
+#[utoipa::path(
+    tag = "metrics",
+    params(
+        ("limit" = Option<i64>, Query, description = "Page size, default 200"),
+        ("offset" = Option<i64>, Query, description = "Rows to skip, default 0"),
+        ("key" = Option<String>, Query, description = "Only this metric key"),
+        ("registry" = Option<String>, Query, description = "Only metrics with this `registry_host` label"),
+    ),
+    responses(
+        (status = 200, description = "Newest metrics first; Prometheus text for `Accept: text/plain`", content(
+            (PaginatedResponse<MetricItem> = "application/json"),
+            (String = "text/plain; version=0.0.4"),
+        )),
+        (status = 406, description = "`Accept` lists neither JSON nor plain text", body = ErrorResponse),
+        (status = 500, description = "Database error", body = ErrorResponse),
+    )
+)]
+#[get("/metrics/recent")]
+pub async fn get_recent_metrics(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    q: web::Query<std::collections::HashMap<String, String>>,
+) -> Result<HttpResponse, AppError> {
+    let format = recent_format(&req)?;
+    let (limit, offset) = page_params(&q);
+    let (key, registry) = recent_filters(&q);
 
-    let rows = db::list_recent_metrics(pool.get_ref(), limit)
+    let rows = db::list_recent_metrics_filtered(pool.get_ref(), key, registry, limit, offset)
+        .await
+        .map_err(AppError::from)?;
+    if let RecentFormat::Prometheus = format {
+        return Ok(HttpResponse::Ok()
+            .insert_header((header::VARY, "Accept"))
+            .content_type(prometheus::CONTENT_TYPE)
+            .body(prometheus::render_recent(&rows)));
+    }
+    let total = db::count_recent_metrics_filtered(pool.get_ref(), key, registry)
         .await
         .map_err(AppError::from)?;
 
-    let data: Vec<_> = rows
-        .into_iter()
-        .map(|m| {
-            serde_json::json!({
+    let data: Vec<_> = rows.into_iter().map(metric_item).collect();
+
+    Ok(HttpResponse::Ok()
+        .insert_header((header::VARY, "Accept"))
+        .json(PaginatedResponse::ok("ok", data, total, limit, offset)))
+}
+
+/// Same rows and query params as `/metrics/recent`, as CSV
+#[utoipa::path(
+    tag = "metrics",
+    params(
+        ("limit" = Option<i64>, Query, description = "Page size, default 200"),
+        ("offset" = Option<i64>, Query, description = "Rows to skip, default 0"),
+        ("key" = Option<String>, Query, description = "Only this metric key"),
+        ("registry" = Option<String>, Query, description = "Only metrics with this `registry_host` label"),
+    ),
+    responses(
+        (status = 200, description = "`job_id,key,value,unit,created_at` rows", content_type = "text/csv", body = String),
+        (status = 500, description = "Database error", body = ErrorResponse),
+    )
+)]
+#[get("/metrics/recent.csv")]
+pub async fn get_recent_metrics_csv(
+    pool: web::Data<DbPool>,
+    q: web::Query<std::collections::HashMap<String, String>>,
+) -> Result<HttpResponse, AppError> {
+    let (limit, offset) = page_params(&q);
+    let (key, registry) = recent_filters(&q);
+    let rows = db::list_recent_metrics_filtered(pool.get_ref(), key, registry, limit, offset)
+        .await
+        .map_err(AppError::from)?;
+    Ok(csv_response(&rows))
+}
+
+#[utoipa::path(
+    tag = "metrics",
+    params(
+        ("id" = String, Path, description = "Job id"),
+    ),
+    responses(
+        (status = 200, description = "`job_id,key,value,unit,created_at` rows", content_type = "text/csv", body = String),
+        (status = 500, description = "Database error", body = ErrorResponse),
+    )
+)]
+#[get("/jobs/{id}/metrics.csv")]
+pub async fn get_job_metrics_csv(
+    pool: web::Data<DbPool>,
+    path: web::Path<String>,
+) -> Result<HttpResponse, AppError> {
+    let job_id = path.into_inner();
+    let rows = db::get_metrics_by_job(pool.get_ref(), &job_id)
+        .await
+        .map_err(AppError::from)?;
+    Ok(csv_response(&rows))
+}
+
+const CSV_HEADER: &str = "job_id,key,value,unit,created_at\r\n";
+
+fn csv_response(rows: &[db::MetricRow]) -> HttpResponse {
+    let mut out = String::from(CSV_HEADER);
+    for m in rows {
+        let value = m.value.to_string();
+        let fields = [
+            m.job_id.as_str(),
+            m.key.as_str(),
+            value.as_str(),
+            m.unit.as_deref().unwrap_or(""),
+            m.created_at.as_str(),
+        ];
+        for (i, f) in fields.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            push_csv_field(&mut out, f);
+        }
+        out.push_str("\r\n");
+    }
+    HttpResponse::Ok().content_type("text/csv; charset=utf-8").body(out)
+}
+
+/// RFC 4180: quote fields containing separators, quotes or line breaks, doubling inner quotes
+fn push_csv_field(out: &mut String, field: &str) {
+    if field.contains([',', '"', '\n', '\r']) {
+        out.push('"');
+        out.push_str(&field.replace('"', "\"\""));
+        out.push('"');
+    } else {
+        out.push_str(field);
+    }
+}
+
+/// Rows buffered between the export query and the response
+const EXPORT_BUFFER_ROWS: usize = 256;
+
+/// `GET /metrics/export?from=2026-01-01T00:00:00Z&to=...`: every metric as
+/// NDJSON, streamed. `from` is inclusive, `to` exclusive, both RFC 3339 and
+/// optional. A database error mid-stream ends it with an `{"error": ..}` line.
+#[utoipa::path(
+    tag = "metrics",
+    params(
+        ("from" = Option<String>, Query, description = "RFC 3339, inclusive"),
+        ("to" = Option<String>, Query, description = "RFC 3339, exclusive"),
+    ),
+    responses(
+        (status = 200, description = "One metric per line, streamed", content_type = "application/x-ndjson", body = String),
+        (status = 400, description = "Invalid query parameters", body = ErrorResponse),
+    )
+)]
+#[get("/metrics/export")]
+pub async fn export_metrics(
+    pool: web::Data<DbPool>,
+    q: web::Query<std::collections::HashMap<String, String>>,
+) -> Result<HttpResponse, AppError> {
+    let parse_ts = |name: &str| -> Result<Option<DateTime<Utc>>, AppError> {
+        match q.get(name).map(|s| s.trim()).filter(|s| !s.is_empty()) {
+            None => Ok(None),
+            Some(s) => DateTime::parse_from_rfc3339(s)
+                .map(|t| Some(t.with_timezone(&Utc)))
+                .map_err(|_| AppError::bad_request(format!("{} must be an RFC 3339 timestamp", name))),
+        }
+    };
+    let from = parse_ts("from")?;
+    let to = parse_ts("to")?;
+
+    let (tx, rx) = tokio::sync::mpsc::channel::<db::MetricRow>(EXPORT_BUFFER_ROWS);
+    let (err_tx, err_rx) = tokio::sync::oneshot::channel::<String>();
+    let pool = pool.get_ref().clone();
+    tokio::spawn(async move {
+        if let Err(e) = db::export_metrics(&pool, from, to, &tx).await {
+            log::warn!("metrics export failed: {:#}", e);
+            let _ = err_tx.send(e.to_string());
+        }
+    });
+
+    let stream = futures_util::stream::unfold((rx, Some(err_rx)), |(mut rx, err_rx)| async move {
+        if let Some(m) = rx.recv().await {
+            let line = serde_json::json!({
                 "job_id": m.job_id,
                 "key": m.key,
                 "value": m.value,
@@ -60,13 +446,816 @@ pub async fn get_recent_metrics(
                     .as_deref()
                     .and_then(|s| serde_json::from_str::<serde_json::Value>(s).ok()),
                 "created_at": m.created_at,
-            })
-        })
+            });
+            return Some((Ok::<_, actix_web::Error>(ndjson_line(&line)), (rx, err_rx)));
+        }
+        // sender dropped: finished, or failed
+        let error = err_rx?.await.ok()?;
+        Some((Ok(ndjson_line(&serde_json::json!({ "error": error }))), (rx, None)))
+    });
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/x-ndjson")
+        .streaming(stream))
+}
+
+fn ndjson_line(v: &serde_json::Value) -> Bytes {
+    let mut buf = serde_json::to_vec(v).unwrap_or_default();
+    buf.push(b'\n');
+    Bytes::from(buf)
+}
+
+#[derive(Serialize, ToSchema)]
+struct LayerStat {
+    layer_id: String,
+    downloaded_bytes: f64,
+    total_bytes: f64,
+    duration_ms: f64,
+}
+
+/// Per-layer bytes and duration recorded for a job (latest value per layer), slowest layer first.
+#[utoipa::path(
+    tag = "metrics",
+    params(
+        ("id" = String, Path, description = "Job id"),
+    ),
+    responses(
+        (status = 200, description = "Layers, slowest first", body = ApiResponse<Vec<LayerStat>>),
+        (status = 404, description = "Job not found", body = ErrorResponse),
+        (status = 500, description = "Database error", body = ErrorResponse),
+    )
+)]
+#[get("/jobs/{id}/layers")]
+pub async fn get_job_layers(
+    pool: web::Data<DbPool>,
+    path: web::Path<String>,
+) -> Result<HttpResponse, AppError> {
+    let job_id = path.into_inner();
+    if db::get_job_by_id(pool.get_ref(), &job_id).await.map_err(AppError::from)?.is_none() {
+        return Err(AppError::not_found("job not found"));
+    }
+
+    let rows = db::get_metrics_by_job(pool.get_ref(), &job_id)
+        .await
+        .map_err(AppError::from)?;
+
+    // rows are newest first, so the first value seen per (layer, key) wins
+    let mut layers: Vec<LayerStat> = Vec::new();
+    let mut seen: std::collections::HashSet<(String, String)> = std::collections::HashSet::new();
+    for m in rows {
+        if !matches!(m.key.as_str(), "layer_bytes" | "layer_total_bytes" | "layer_duration_ms") {
+            continue;
+        }
+        let Some(layer_id) = m.labels_json
+            .as_deref()
+            .and_then(|s| serde_json::from_str::<serde_json::Value>(s).ok())
+            .and_then(|v| v.get("layer_id").and_then(|id| id.as_str()).map(str::to_string))
+        else {
+            continue;
+        };
+        if !seen.insert((layer_id.clone(), m.key.clone())) {
+            continue;
+        }
+
+        let idx = match layers.iter().position(|l| l.layer_id == layer_id) {
+            Some(i) => i,
+            None => {
+                layers.push(LayerStat { layer_id, downloaded_bytes: 0.0, total_bytes: 0.0, duration_ms: 0.0 });
+                layers.len() - 1
+            }
+        };
+        let layer = &mut layers[idx];
+        match m.key.as_str() {
+            "layer_bytes" => layer.downloaded_bytes = m.value,
+            "layer_total_bytes" => layer.total_bytes = m.value,
+            _ => layer.duration_ms = m.value,
+        }
+    }
+
+    layers.sort_by(|a, b| b.duration_ms.total_cmp(&a.duration_ms));
+
+    Ok(HttpResponse::Ok().json(ApiResponse::ok("ok", layers)))
+}
+
+/// `GET /metrics/aggregate?key=download_time_ms&window=24h&unit=s`; `unit`
+/// converts the figures from the metric's own unit.
+#[utoipa::path(
+    tag = "metrics",
+    params(
+        ("key" = String, Query, description = "Metric key"),
+        ("window" = Option<String>, Query, description = "Only the last `90s`, `30m`, `24h`, `7d`, ...; all time when missing"),
+        ("unit" = Option<MetricUnit>, Query, description = "Convert the figures to this unit"),
+    ),
+    responses(
+        (status = 200, description = "Summary of the values", body = ApiResponse<db::MetricAggregate>),
+        (status = 400, description = "Missing key, bad window or unit that doesn't convert", body = ErrorResponse),
+        (status = 500, description = "Database error", body = ErrorResponse),
+    )
+)]
+#[get("/metrics/aggregate")]
+pub async fn get_metric_aggregate(
+    pool: web::Data<DbPool>,
+    q: web::Query<std::collections::HashMap<String, String>>,
+) -> Result<HttpResponse, AppError> {
+    let Some(key) = q.get("key").map(|k| k.trim()).filter(|k| !k.is_empty()) else {
+        return Err(AppError::bad_request("key is required"));
+    };
+
+    let window_secs = match q.get("window") {
+        Some(w) => Some(parse_window(w).ok_or_else(|| {
+            AppError::bad_request("window must look like 90s, 30m, 24h or 7d")
+        })?),
+        None => None,
+    };
+
+    let to = match q.get("unit").map(|u| u.trim()).filter(|u| !u.is_empty()) {
+        Some(u) => Some(u.parse::<MetricUnit>().map_err(AppError::bad_request)?),
+        None => None,
+    };
+
+    let agg = db::aggregate_metric(pool.get_ref(), key, window_secs)
+        .await
+        .map_err(AppError::from)?;
+    let agg = match to {
+        Some(to) => convert_aggregate(agg, to)?,
+        None => agg,
+    };
+
+    Ok(HttpResponse::Ok().json(ApiResponse::ok("ok", agg)))
+}
+
+/// Every figure of `agg` in `to`; 400 if the metric's unit doesn't convert.
+fn convert_aggregate(mut agg: db::MetricAggregate, to: MetricUnit) -> Result<db::MetricAggregate, AppError> {
+    if agg.count == 0 {
+        agg.unit = Some(to.to_string());
+        return Ok(agg);
+    }
+    let from = agg
+        .unit
+        .as_deref()
+        .and_then(|u| u.parse::<MetricUnit>().ok())
+        .ok_or_else(|| AppError::bad_request(format!("{} has no unit to convert from", agg.key)))?;
+    if from.convert(0.0, to).is_none() {
+        return Err(AppError::bad_request(format!("cannot convert {} from {} to {}", agg.key, from, to)));
+    }
+
+    for v in [&mut agg.min, &mut agg.max, &mut agg.avg, &mut agg.p50, &mut agg.p95, &mut agg.p99] {
+        *v = v.and_then(|x| from.convert(x, to));
+    }
+    agg.unit = Some(to.to_string());
+    Ok(agg)
+}
+
+/// Most bucket bounds `GET /metrics/histogram` accepts
+const MAX_HISTOGRAM_BUCKETS: usize = 50;
+
+#[derive(Serialize, ToSchema)]
+struct HistogramBucket {
+    /// Upper bound (inclusive), a number or `"+Inf"`
+    le: serde_json::Value,
+    count: usize,
+}
+
+#[derive(Serialize, ToSchema)]
+struct Histogram {
+    key: String,
+    window_secs: Option<i64>,
+    count: usize,
+    buckets: Vec<HistogramBucket>,
+}
+
+/// `GET /metrics/histogram?key=average_speed_mbps&buckets=0,10,50,100,500&window=24h`:
+/// how many values fall in each bucket. A bucket holds the values above the
+/// previous bound up to and including its own; `+Inf` takes the rest.
+#[utoipa::path(
+    tag = "metrics",
+    params(
+        ("key" = String, Query, description = "Metric key"),
+        ("buckets" = String, Query, description = "Ascending bucket bounds, e.g. `0,10,50,100`"),
+        ("window" = Option<String>, Query, description = "Only the last `90s`, `30m`, `24h`, `7d`, ...; all time when missing"),
+    ),
+    responses(
+        (status = 200, description = "Value count per bucket", body = ApiResponse<Histogram>),
+        (status = 400, description = "Invalid query parameters", body = ErrorResponse),
+        (status = 500, description = "Database error", body = ErrorResponse),
+    )
+)]
+#[get("/metrics/histogram")]
+pub async fn get_metric_histogram(
+    pool: web::Data<DbPool>,
+    q: web::Query<std::collections::HashMap<String, String>>,
+) -> Result<HttpResponse, AppError> {
+    let Some(key) = q.get("key").map(|k| k.trim()).filter(|k| !k.is_empty()) else {
+        return Err(AppError::bad_request("key is required"));
+    };
+    let Some(raw) = q.get("buckets") else {
+        return Err(AppError::bad_request("buckets is required"));
+    };
+    let bounds = parse_buckets(raw).map_err(AppError::bad_request)?;
+
+    let window_secs = match q.get("window") {
+        Some(w) => Some(parse_window(w).ok_or_else(|| {
+            AppError::bad_request("window must look like 90s, 30m, 24h or 7d")
+        })?),
+        None => None,
+    };
+
+    let values = db::metric_values(pool.get_ref(), key, window_secs)
+        .await
+        .map_err(AppError::from)?;
+
+    let counts = bucket_counts(&bounds, &values);
+    let buckets = bounds
+        .iter()
+        .map(|b| serde_json::json!(b))
+        .chain(std::iter::once(serde_json::json!("+Inf")))
+        .zip(counts)
+        .map(|(le, count)| HistogramBucket { le, count })
         .collect();
 
-    Ok(HttpResponse::Ok().json(ApiResponse::ok("ok", data)))
+    Ok(HttpResponse::Ok().json(ApiResponse::ok(
+        "ok",
+        Histogram { key: key.to_string(), window_secs, count: values.len(), buckets },
+    )))
+}
+
+/// `0,10,50` -> bounds; they must be finite and strictly ascending.
+fn parse_buckets(raw: &str) -> Result<Vec<f64>, String> {
+    let bounds = raw
+        .split(',')
+        .map(str::trim)
+        .filter(|b| !b.is_empty())
+        .map(|b| {
+            b.parse::<f64>()
+                .ok()
+                .filter(|n| n.is_finite())
+                .ok_or_else(|| format!("bucket '{}' is not a number", b))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if bounds.is_empty() {
+        return Err("buckets needs at least one bound".to_string());
+    }
+    if bounds.len() > MAX_HISTOGRAM_BUCKETS {
+        return Err(format!("at most {} buckets", MAX_HISTOGRAM_BUCKETS));
+    }
+    if bounds.windows(2).any(|w| w[0] >= w[1]) {
+        return Err("buckets must be in ascending order without repeats".to_string());
+    }
+    Ok(bounds)
+}
+
+/// Count per bucket, one more than `bounds` for `+Inf`.
+fn bucket_counts(bounds: &[f64], values: &[f64]) -> Vec<usize> {
+    let mut counts = vec![0; bounds.len() + 1];
+    for v in values {
+        counts[bounds.partition_point(|b| b < v)] += 1;
+    }
+    counts
+}
+
+/// Most points one `GET /metrics/timeseries` returns, gaps included
+const MAX_TIMESERIES_POINTS: i64 = 10_000;
+
+#[derive(Serialize, ToSchema)]
+struct TimeseriesPoint {
+    /// Bucket start, RFC 3339 UTC
+    t: String,
+    value: Option<f64>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct Timeseries {
+    key: String,
+    bucket_secs: i64,
+    agg: db::TimeseriesAgg,
+    points: Vec<TimeseriesPoint>,
+}
+
+/// `GET /metrics/timeseries?key=download_time_ms&bucket=1h&agg=avg&from=..&to=..&fill=null`:
+/// `agg` (avg, min, max or count) of `key` per bucket of `created_at`.
+/// Buckets from `from` (or the first one with data) up to `to` (or the last
+/// one) that have no rows are `null`, or `0` with `fill=zero`.
+#[utoipa::path(
+    tag = "metrics",
+    params(
+        ("key" = String, Query, description = "Metric key"),
+        ("bucket" = Option<String>, Query, description = "Bucket width like `30m`, `1h`, `1d`; default `1h`"),
+        ("agg" = Option<db::TimeseriesAgg>, Query, description = "Default `avg`"),
+        ("from" = Option<String>, Query, description = "RFC 3339, inclusive"),
+        ("to" = Option<String>, Query, description = "RFC 3339, exclusive"),
+        ("fill" = Option<String>, Query, description = "`null` (default) or `zero` for empty buckets"),
+    ),
+    responses(
+        (status = 200, description = "One point per bucket, oldest first", body = ApiResponse<Timeseries>),
+        (status = 400, description = "Invalid query parameters", body = ErrorResponse),
+        (status = 500, description = "Database error", body = ErrorResponse),
+    )
+)]
+#[get("/metrics/timeseries")]
+pub async fn get_metric_timeseries(
+    pool: web::Data<DbPool>,
+    q: web::Query<std::collections::HashMap<String, String>>,
+) -> Result<HttpResponse, AppError> {
+    let Some(key) = q.get("key").map(|k| k.trim()).filter(|k| !k.is_empty()) else {
+        return Err(AppError::bad_request("key is required"));
+    };
+    let bucket_secs = match q.get("bucket") {
+        Some(b) => parse_window(b)
+            .ok_or_else(|| AppError::bad_request("bucket must look like 90s, 30m, 1h or 1d"))?,
+        None => 3600,
+    };
+    let agg = match q.get("agg").map(|a| a.trim().to_ascii_lowercase()).as_deref() {
+        None | Some("") | Some("avg") => db::TimeseriesAgg::Avg,
+        Some("min") => db::TimeseriesAgg::Min,
+        Some("max") => db::TimeseriesAgg::Max,
+        Some("count") => db::TimeseriesAgg::Count,
+        Some(other) => {
+            return Err(AppError::bad_request(format!(
+                "unknown agg '{}', expected avg, min, max or count",
+                other
+            )))
+        }
+    };
+    let gap = match q.get("fill").map(|f| f.trim().to_ascii_lowercase()).as_deref() {
+        None | Some("") | Some("null") => None,
+        Some("zero") => Some(0.0),
+        Some(other) => {
+            return Err(AppError::bad_request(format!("unknown fill '{}', expected null or zero", other)))
+        }
+    };
+
+    let parse_ts = |name: &str| -> Result<Option<DateTime<Utc>>, AppError> {
+        match q.get(name).map(|s| s.trim()).filter(|s| !s.is_empty()) {
+            None => Ok(None),
+            Some(s) => DateTime::parse_from_rfc3339(s)
+                .map(|t| Some(t.with_timezone(&Utc)))
+                .map_err(|_| AppError::bad_request(format!("{} must be an RFC 3339 timestamp", name))),
+        }
+    };
+    let from = parse_ts("from")?;
+    let to = parse_ts("to")?;
+    if let (Some(from), Some(to)) = (from, to)
+        && from >= to
+    {
+        return Err(AppError::bad_request("from must be before to"));
+    }
+
+    let rows = db::timeseries_metric(pool.get_ref(), key, bucket_secs, agg, from, to)
+        .await
+        .map_err(AppError::from)?;
+
+    let align = |secs: i64| secs.div_euclid(bucket_secs) * bucket_secs;
+    let first = from.map(|t| align(t.timestamp())).or(rows.first().map(|r| r.start));
+    // `to` is exclusive, so the last bucket is the one holding the second before it
+    let last = to.map(|t| align(t.timestamp() - 1)).or(rows.last().map(|r| r.start));
+    let mut points = Vec::new();
+    if let (Some(first), Some(last)) = (first, last) {
+        if (last - first) / bucket_secs >= MAX_TIMESERIES_POINTS {
+            return Err(AppError::bad_request(format!(
+                "more than {} buckets, use a wider bucket or a shorter range",
+                MAX_TIMESERIES_POINTS
+            )));
+        }
+        let mut rows = rows.into_iter().peekable();
+        for start in (first..=last).step_by(bucket_secs as usize) {
+            let value = match rows.next_if(|r| r.start == start) {
+                Some(r) => Some(r.value),
+                None => gap,
+            };
+            points.push(TimeseriesPoint { t: bucket_time(start), value });
+        }
+    }
+
+    Ok(HttpResponse::Ok().json(ApiResponse::ok(
+        "ok",
+        Timeseries { key: key.to_string(), bucket_secs, agg, points },
+    )))
+}
+
+fn bucket_time(secs: i64) -> String {
+    DateTime::<Utc>::from_timestamp(secs, 0)
+        .map(|t| t.to_rfc3339_opts(chrono::SecondsFormat::Secs, true))
+        .unwrap_or_default()
+}
+
+/// `90s` / `30m` / `24h` / `7d` (or bare seconds) -> seconds
+pub fn parse_window(raw: &str) -> Option<i64> {
+    let raw = raw.trim();
+    let (num, mult) = match raw.char_indices().last()? {
+        (i, 's') => (&raw[..i], 1),
+        (i, 'm') => (&raw[..i], 60),
+        (i, 'h') => (&raw[..i], 3600),
+        (i, 'd') => (&raw[..i], 86400),
+        _ => (raw, 1),
+    };
+    let n = num.parse::<i64>().ok().filter(|n| *n > 0)?;
+    n.checked_mul(mult)
+}
+
+/// `GET /metrics/by-label?key=registry_host&value=gcr.io&limit=200&offset=0`: newest
+/// metrics carrying that label. `key` is a plain label name (letters, digits, `_`).
+#[utoipa::path(
+    tag = "metrics",
+    params(
+        ("key" = String, Query, description = "Label name"),
+        ("value" = String, Query, description = "Label value"),
+        ("limit" = Option<i64>, Query, description = "Page size, default 200"),
+        ("offset" = Option<i64>, Query, description = "Rows to skip, default 0"),
+    ),
+    responses(
+        (status = 200, description = "Newest metrics first", body = PaginatedResponse<MetricItem>),
+        (status = 400, description = "Invalid query parameters", body = ErrorResponse),
+        (status = 500, description = "Database error", body = ErrorResponse),
+    )
+)]
+#[get("/metrics/by-label")]
+pub async fn get_metrics_by_label(
+    pool: web::Data<DbPool>,
+    q: web::Query<std::collections::HashMap<String, String>>,
+) -> Result<HttpResponse, AppError> {
+    let Some(key) = q.get("key").map(|k| k.trim()).filter(|k| !k.is_empty()) else {
+        return Err(AppError::bad_request("key is required"));
+    };
+    if !key.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_') {
+        return Err(AppError::bad_request("key may only contain letters, digits and '_'"));
+    }
+    let Some(value) = q.get("value") else {
+        return Err(AppError::bad_request("value is required"));
+    };
+    let (limit, offset) = page_params(&q);
+
+    let total = db::count_metrics_by_label(pool.get_ref(), key, value)
+        .await
+        .map_err(AppError::from)?;
+    let rows = db::list_metrics_by_label(pool.get_ref(), key, value, limit, offset)
+        .await
+        .map_err(AppError::from)?;
+    let data: Vec<_> = rows.into_iter().map(metric_item).collect();
+
+    Ok(HttpResponse::Ok().json(PaginatedResponse::ok("ok", data, total, limit, offset)))
+}
+
+/// Most job ids accepted by `POST /metrics/by-jobs`
+const MAX_BY_JOBS_IDS: usize = 100;
+
+#[derive(Deserialize, ToSchema)]
+pub struct MetricsByJobsRequest {
+    pub job_ids: Vec<String>,
+}
+
+/// `POST /metrics/by-jobs` with `{"job_ids": [..]}`: metrics grouped by job id.
+/// Unknown ids map to an empty list.
+#[utoipa::path(
+    tag = "metrics",
+    request_body = MetricsByJobsRequest,
+    responses(
+        (status = 200, description = "Metrics per job id; unknown ids map to an empty list", body = ApiResponse<BTreeMap<String, Vec<MetricItem>>>),
+        (status = 400, description = "No or too many job_ids", body = ErrorResponse),
+        (status = 500, description = "Database error", body = ErrorResponse),
+    )
+)]
+#[post("/metrics/by-jobs")]
+pub async fn get_metrics_by_jobs(
+    pool: web::Data<DbPool>,
+    body: web::Json<MetricsByJobsRequest>,
+) -> Result<HttpResponse, AppError> {
+    let mut ids: Vec<&str> = body.job_ids.iter().map(|id| id.trim()).filter(|id| !id.is_empty()).collect();
+    ids.sort_unstable();
+    ids.dedup();
+    if ids.is_empty() {
+        return Err(AppError::bad_request("job_ids must not be empty"));
+    }
+    if ids.len() > MAX_BY_JOBS_IDS {
+        return Err(AppError::bad_request(format!("at most {} job_ids per request", MAX_BY_JOBS_IDS)));
+    }
+
+    let rows = db::get_metrics_by_jobs(pool.get_ref(), &ids)
+        .await
+        .map_err(AppError::from)?;
+
+    let mut grouped: BTreeMap<String, Vec<MetricItem>> =
+        ids.iter().map(|id| (id.to_string(), Vec::new())).collect();
+    for m in rows {
+        if let Some(list) = grouped.get_mut(&m.job_id) {
+            list.push(metric_item(m));
+        }
+    }
+
+    Ok(HttpResponse::Ok().json(ApiResponse::ok("ok", grouped)))
 }
 
 pub fn metrics_routes(cfg: &mut web::ServiceConfig) {
-    cfg.service(get_job_metrics).service(get_recent_metrics);
+    cfg.service(get_job_metrics)
+        .service(get_job_metric)
+        .service(add_job_metrics)
+        .service(get_job_metrics_csv)
+        .service(get_job_layers)
+        .service(get_recent_metrics)
+        .service(get_recent_metrics_csv)
+        .service(export_metrics)
+        .service(get_metric_aggregate)
+        .service(get_metric_histogram)
+        .service(get_metric_timeseries)
+        .service(get_metrics_by_jobs)
+        .service(get_metrics_by_label);
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::StatusCode;
+    use actix_web::test::{self, TestRequest};
+
+    use super::*;
+    use crate::test_support::{self, pool, queue_job};
+
+    fn recent(accept: Option<&str>) -> TestRequest {
+        let req = TestRequest::get().uri("/api/v1/metrics/recent");
+        match accept {
+            Some(accept) => req.insert_header((header::ACCEPT, accept)),
+            None => req,
+        }
+    }
+
+    #[actix_web::test]
+    async fn recent_metrics_follow_accept() {
+        let pool = pool().await;
+        queue_job(&pool, "job-1", "alpine", 0).await;
+        db::insert_metric(&pool, "job-1", "download_time_ms", 1500.0, None).await.unwrap();
+        let app = test::init_service(test_support::app(test_support::state(&[]), pool)).await;
+
+        for accept in [None, Some("application/json"), Some("*/*"), Some("text/plain;q=0.5, application/json")] {
+            let res = test::call_service(&app, recent(accept).to_request()).await;
+            assert_eq!(res.status(), StatusCode::OK, "{accept:?}");
+            assert_eq!(res.headers().get(header::CONTENT_TYPE).unwrap(), "application/json", "{accept:?}");
+            let body: serde_json::Value = test::read_body_json(res).await;
+            assert_eq!(body["total"], 1);
+            assert_eq!(body["data"][0]["key"], "download_time_ms");
+        }
+
+        for accept in ["text/plain", "application/json;q=0.1, text/plain"] {
+            let res = test::call_service(&app, recent(Some(accept)).to_request()).await;
+            assert_eq!(res.status(), StatusCode::OK, "{accept}");
+            assert_eq!(res.headers().get(header::CONTENT_TYPE).unwrap(), prometheus::CONTENT_TYPE);
+            assert_eq!(res.headers().get(header::VARY).unwrap(), "Accept");
+            let text = String::from_utf8(test::read_body(res).await.to_vec()).unwrap();
+            assert!(text.contains("imgpuller_download_time_ms{job_id=\"job-1\"} 1500"), "{text}");
+        }
+    }
+
+    #[actix_web::test]
+    async fn recent_metrics_refuse_other_media_types() {
+        let app = test::init_service(test_support::app(test_support::state(&[]), pool().await)).await;
+        for accept in ["text/csv", "application/xml, image/*", "application/json;q=0"] {
+            let res = test::call_service(&app, recent(Some(accept)).to_request()).await;
+            assert_eq!(res.status(), StatusCode::NOT_ACCEPTABLE, "{accept}");
+            let body: serde_json::Value = test::read_body_json(res).await;
+            assert_eq!(body["error"], "supported: application/json, text/plain");
+        }
+    }
+
+    #[actix_web::test]
+    async fn csv_has_a_header_and_quotes_odd_fields() {
+        let pool = pool().await;
+        queue_job(&pool, "job-1", "alpine", 0).await;
+        db::insert_metric(&pool, "job-1", "download_time_ms", 1500.0, Some(MetricUnit::Milliseconds)).await.unwrap();
+        db::insert_metric(&pool, "job-1", "odd,\"key\"", 0.5, None).await.unwrap();
+        let created_at = db::get_metric(&pool, "job-1", "download_time_ms").await.unwrap().unwrap().created_at;
+        let app = test::init_service(test_support::app(test_support::state(&[]), pool)).await;
+
+        for uri in ["/api/v1/jobs/job-1/metrics.csv", "/api/v1/metrics/recent.csv"] {
+            let res = test::call_service(&app, TestRequest::get().uri(uri).to_request()).await;
+            assert_eq!(res.status(), StatusCode::OK, "{uri}");
+            assert_eq!(res.headers().get(header::CONTENT_TYPE).unwrap(), "text/csv; charset=utf-8");
+            let text = String::from_utf8(test::read_body(res).await.to_vec()).unwrap();
+            let mut lines: Vec<_> = text.split_terminator("\r\n").collect();
+            assert_eq!(lines.remove(0), "job_id,key,value,unit,created_at", "{uri}");
+            lines.sort();
+            assert_eq!(
+                lines,
+                [
+                    format!("job-1,\"odd,\"\"key\"\"\",0.5,,{created_at}"),
+                    format!("job-1,download_time_ms,1500,ms,{created_at}"),
+                ],
+                "{uri}"
+            );
+        }
+
+        let res = test::call_service(&app, TestRequest::get().uri("/api/v1/metrics/recent.csv?limit=1").to_request()).await;
+        let text = String::from_utf8(test::read_body(res).await.to_vec()).unwrap();
+        assert_eq!(text.lines().count(), 2, "{text}");
+    }
+
+    #[actix_web::test]
+    async fn by_label_finds_present_labels_only() {
+        let pool = pool().await;
+        queue_job(&pool, "job-1", "alpine", 0).await;
+        let gcr = r#"{"registry_host":"gcr.io","layer_count":3}"#;
+        db::insert_metric_labeled(&pool, "job-1", "layers_observed", 3.0, None, Some(gcr)).await.unwrap();
+        let docker = r#"{"registry_host":"docker.io"}"#;
+        db::insert_metric_labeled(&pool, "job-1", "layers_observed", 5.0, None, Some(docker)).await.unwrap();
+        db::insert_metric(&pool, "job-1", "download_time_ms", 1500.0, None).await.unwrap();
+        let app = test::init_service(test_support::app(test_support::state(&[]), pool)).await;
+
+        let by_label = |query: &str| TestRequest::get().uri(&format!("/api/v1/metrics/by-label?{query}")).to_request();
+        let res = test::call_service(&app, by_label("key=registry_host&value=gcr.io")).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(body["total"], 1);
+        assert_eq!(body["data"][0]["value"], 3.0);
+        assert_eq!(body["data"][0]["labels"]["registry_host"], "gcr.io");
+
+        for query in ["key=registry_host&value=quay.io", "key=layer_id&value=gcr.io"] {
+            let res = test::call_service(&app, by_label(query)).await;
+            assert_eq!(res.status(), StatusCode::OK, "{query}");
+            let body: serde_json::Value = test::read_body_json(res).await;
+            assert_eq!(body["total"], 0, "{query}");
+            assert_eq!(body["data"], serde_json::json!([]), "{query}");
+        }
+
+        for (query, error) in [
+            ("value=gcr.io", "key is required"),
+            ("key=registry_host", "value is required"),
+            ("key=a.b&value=x", "key may only contain letters, digits and '_'"),
+        ] {
+            let res = test::call_service(&app, by_label(query)).await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{query}");
+            let body: serde_json::Value = test::read_body_json(res).await;
+            assert_eq!(body["error"], error, "{query}");
+        }
+    }
+
+    fn submit(job_id: &str, body: serde_json::Value) -> TestRequest {
+        TestRequest::post().uri(&format!("/api/v1/jobs/{job_id}/metrics")).set_json(body)
+    }
+
+    #[actix_web::test]
+    async fn submitted_metric_reads_back() {
+        let pool = pool().await;
+        queue_job(&pool, "job-1", "alpine", 0).await;
+        let app = test::init_service(test_support::app(test_support::state(&[]), pool)).await;
+
+        let metric = serde_json::json!({ "key": "registry_rtt_ms", "value": 42.5, "unit": "milliseconds", "labels": { "probe": "eu-1" } });
+        let res = test::call_service(&app, submit("job-1", metric).to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(body["data"], serde_json::json!({ "job_id": "job-1", "inserted": 1 }));
+
+        let req = TestRequest::get().uri("/api/v1/jobs/job-1/metrics/registry_rtt_ms").to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        let data = &body["data"];
+        assert_eq!(data["key"], "registry_rtt_ms");
+        assert_eq!(data["value"], 42.5);
+        // stored in its canonical spelling
+        assert_eq!(data["unit"], "ms");
+        assert_eq!(data["labels"], serde_json::json!({ "probe": "eu-1" }));
+    }
+
+    #[actix_web::test]
+    async fn bad_submissions_store_nothing() {
+        let pool = pool().await;
+        queue_job(&pool, "job-1", "alpine", 0).await;
+        let app = test::init_service(test_support::app(test_support::state(&[]), pool.clone())).await;
+
+        let res = test::call_service(&app, submit("nope", serde_json::json!({ "key": "rtt", "value": 1 })).to_request()).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        for (body, error) in [
+            (serde_json::json!({ "key": " ", "value": 1 }), "key is required".to_string()),
+            (
+                serde_json::json!({ "key": "download_time_ms", "value": 1 }),
+                "key 'download_time_ms' is recorded by the worker and can't be submitted".to_string(),
+            ),
+            (
+                serde_json::json!([{ "key": "rtt", "value": 1 }, { "key": "rtt2", "value": 1, "unit": "millis" }]),
+                "rtt2: unknown unit 'millis', expected one of: ms, s, bytes, MB, Mbps, %".to_string(),
+            ),
+            (serde_json::json!([]), "no metrics given".to_string()),
+        ] {
+            let res = test::call_service(&app, submit("job-1", body.clone()).to_request()).await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{body}");
+            let res: serde_json::Value = test::read_body_json(res).await;
+            assert_eq!(res["error"], error, "{body}");
+        }
+        assert!(db::get_metrics_by_job(&pool, "job-1").await.unwrap().is_empty());
+    }
+
+    #[actix_web::test]
+    async fn histogram_counts_values_per_bucket() {
+        let pool = pool().await;
+        queue_job(&pool, "job-1", "alpine", 0).await;
+        for v in [0.0, 5.0, 10.0, 10.5, 49.0, 75.0, 100.0, 480.0, 900.0, 2000.0] {
+            db::insert_metric(&pool, "job-1", "average_speed_mbps", v, None).await.unwrap();
+        }
+        db::insert_metric(&pool, "job-1", "download_time_ms", 30.0, None).await.unwrap();
+        let app = test::init_service(test_support::app(test_support::state(&[]), pool)).await;
+
+        let histogram = |query: &str| TestRequest::get().uri(&format!("/api/v1/metrics/histogram?{query}")).to_request();
+        let res = test::call_service(&app, histogram("key=average_speed_mbps&buckets=0,10,50,100,500")).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(body["data"]["count"], 10);
+        // upper bounds are inclusive: 10.0 counts under 10, 10.5 under 50
+        assert_eq!(
+            body["data"]["buckets"],
+            serde_json::json!([
+                { "le": 0.0, "count": 1 },
+                { "le": 10.0, "count": 2 },
+                { "le": 50.0, "count": 2 },
+                { "le": 100.0, "count": 2 },
+                { "le": 500.0, "count": 1 },
+                { "le": "+Inf", "count": 2 },
+            ])
+        );
+
+        for (query, error) in [
+            ("key=average_speed_mbps&buckets=10,5", "buckets must be in ascending order without repeats"),
+            ("key=average_speed_mbps&buckets=1,1", "buckets must be in ascending order without repeats"),
+            ("key=average_speed_mbps&buckets=1,fast", "bucket 'fast' is not a number"),
+            ("key=average_speed_mbps&buckets=,", "buckets needs at least one bound"),
+            ("buckets=1", "key is required"),
+        ] {
+            let res = test::call_service(&app, histogram(query)).await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{query}");
+            let body: serde_json::Value = test::read_body_json(res).await;
+            assert_eq!(body["error"], error, "{query}");
+        }
+    }
+
+    #[actix_web::test]
+    async fn timeseries_buckets_hours_across_midnight() {
+        let pool = pool().await;
+        queue_job(&pool, "job-1", "alpine", 0).await;
+        for (value, at) in [
+            (100.0, "2026-03-01 22:10:00"),
+            (300.0, "2026-03-01 22:59:59"),
+            (50.0, "2026-03-01 23:00:00"),
+            (700.0, "2026-03-02 01:30:00"),
+            // before `from`, so left out
+            (9999.0, "2026-03-01 21:59:59"),
+        ] {
+            db::insert_metric(&pool, "job-1", "download_time_ms", value, None).await.unwrap();
+            test_support::execute(
+                &pool,
+                &format!("UPDATE metrics SET created_at = '{at}' WHERE id = (SELECT MAX(id) FROM metrics)"),
+            )
+            .await;
+        }
+        let app = test::init_service(test_support::app(test_support::state(&[]), pool)).await;
+
+        let points = |query: &'static str| {
+            let uri = format!(
+                "/api/v1/metrics/timeseries?key=download_time_ms&from=2026-03-01T22:00:00Z&to=2026-03-02T02:00:00Z&{query}"
+            );
+            let app = &app;
+            async move {
+                let res = test::call_service(app, TestRequest::get().uri(&uri).to_request()).await;
+                assert_eq!(res.status(), StatusCode::OK, "{query}");
+                let body: serde_json::Value = test::read_body_json(res).await;
+                body["data"]["points"].clone()
+            }
+        };
+        let hours = ["2026-03-01T22:00:00Z", "2026-03-01T23:00:00Z", "2026-03-02T00:00:00Z", "2026-03-02T01:00:00Z"];
+        let expect = |values: [serde_json::Value; 4]| -> serde_json::Value {
+            hours.iter().zip(values).map(|(t, value)| serde_json::json!({ "t": t, "value": value })).collect()
+        };
+
+        assert_eq!(points("bucket=1h").await, expect([200.0.into(), 50.0.into(), serde_json::Value::Null, 700.0.into()]));
+        assert_eq!(points("bucket=1h&agg=count&fill=zero").await, expect([2.0.into(), 1.0.into(), 0.0.into(), 1.0.into()]));
+        let days = points("bucket=1d&agg=max").await;
+        assert_eq!(
+            days,
+            serde_json::json!([
+                { "t": "2026-03-01T00:00:00Z", "value": 300.0 },
+                { "t": "2026-03-02T00:00:00Z", "value": 700.0 },
+            ])
+        );
+    }
+
+    #[actix_web::test]
+    async fn single_metric_is_the_newest_row_or_404() {
+        let pool = pool().await;
+        queue_job(&pool, "job-1", "alpine", 0).await;
+        db::insert_metric(&pool, "job-1", "download_time_ms", 1500.0, Some(MetricUnit::Milliseconds)).await.unwrap();
+        db::insert_metric(&pool, "job-1", "download_time_ms", 1200.0, Some(MetricUnit::Milliseconds)).await.unwrap();
+        let app = test::init_service(test_support::app(test_support::state(&[]), pool)).await;
+        let get = |uri: &str| TestRequest::get().uri(uri).to_request();
+
+        let res = test::call_service(&app, get("/api/v1/jobs/job-1/metrics/download_time_ms")).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(body["data"]["job_id"], "job-1");
+        assert_eq!(body["data"]["value"], 1200.0);
+        assert_eq!(body["data"]["unit"], "ms");
+        assert!(body["data"]["created_at"].is_string());
+
+        for (uri, error) in [
+            ("/api/v1/jobs/job-1/metrics/cache_hit", "job has no metric 'cache_hit'"),
+            ("/api/v1/jobs/nope/metrics/download_time_ms", "job not found"),
+        ] {
+            let res = test::call_service(&app, get(uri)).await;
+            assert_eq!(res.status(), StatusCode::NOT_FOUND, "{uri}");
+            let body: serde_json::Value = test::read_body_json(res).await;
+            assert_eq!(body["error"], error, "{uri}");
+        }
+    }
 }
@@ -1,13 +1,29 @@
 use crate::model;
-use actix_web::{web, get, HttpResponse, Responder};
+use actix_web::{middleware, web, get, HttpResponse, Responder};
 use serde_json::json;
 
+pub mod config;
+pub use config::config_routes;
+
 pub mod job;
 pub use job::job_routes;
 
 pub mod metric;
 pub use metric::metrics_routes;
 
+pub mod openapi;
+pub use openapi::openapi_routes;
+
+pub mod prometheus;
+pub use prometheus::prometheus_routes;
+
+pub mod stats;
+pub use stats::stats_routes;
+
+#[utoipa::path(
+    tag = "health",
+    responses((status = 200, description = "The API is up", body = model::ApiResponse<serde_json::Value>))
+)]
 #[get("/health")]
 async fn apiv1status() -> impl Responder {
     HttpResponse::Ok().json(model::ApiResponse::ok(
@@ -16,11 +32,31 @@ async fn apiv1status() -> impl Responder {
     ))
 }
 
+#[utoipa::path(
+    tag = "health",
+    responses((status = 200, description = "Build info", body = model::ApiResponse<model::VersionInfo>))
+)]
+#[get("/version")]
+async fn version() -> impl Responder {
+    HttpResponse::Ok().json(model::ApiResponse::ok("ok", model::VersionInfo::current()))
+}
+
 pub fn service_config(cfg: &mut web::ServiceConfig) {
+    cfg.configure(openapi_routes);
+
     cfg.service(web::scope(
         "/api/v1"
     )
+    // wrap order: auth runs first, so only valid keys get a bucket of their own
+    .wrap(middleware::from_fn(crate::rate_limit::limit_requests))
+    .wrap(middleware::from_fn(crate::auth::require_api_key))
     .configure(job_routes)
     .configure(metrics_routes)
-    .service(apiv1status));
+    .configure(config_routes)
+    .configure(stats_routes)
+    .service(apiv1status)
+    .service(version));
+
+    // scraped by Prometheus directly, outside the versioned API
+    cfg.configure(prometheus_routes);
 }
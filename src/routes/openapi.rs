@@ -0,0 +1,143 @@
+//! OpenAPI 3.1 description of the HTTP API, generated from the `#[utoipa::path]`
+//! annotations on the handlers. The spec is served at `GET /api/v1/openapi.json`
+//! and browsable with Swagger UI at `/api/v1/docs`; both are outside the
+//! API key check, like `/health`.
+//!
+//! The 401 (`API_KEYS`) and 429 (`RATE_LIMIT_RPM`) answers come from middleware
+//! that wraps all of `/api/v1`, so [`MiddlewareResponses`] adds them to every
+//! operation there instead of each handler listing them.
+
+use actix_web::web;
+use utoipa::openapi::header::HeaderBuilder;
+use utoipa::openapi::path::Operation;
+use utoipa::openapi::security::{HttpAuthScheme, HttpBuilder, SecurityRequirement, SecurityScheme};
+use utoipa::openapi::{ContentBuilder, Object, Ref, ResponseBuilder, Type};
+use utoipa::{Modify, OpenApi};
+use utoipa_swagger_ui::SwaggerUi;
+
+use crate::routes::{config, job, metric, prometheus, stats};
+
+/// Prefix of the versioned API, which the auth and rate limit middleware cover
+const API_PREFIX: &str = "/api/v1";
+
+/// Name of the bearer scheme in `components.securitySchemes`
+const API_KEY_SCHEME: &str = "api_key";
+
+#[derive(OpenApi)]
+#[openapi(
+    paths(
+        job::create_job,
+        job::create_jobs_batch,
+        job::list_jobs,
+        job::purge_jobs,
+        job::compare_jobs,
+        job::search_jobs,
+        job::get_job,
+        job::delete_job,
+        job::cancel_job,
+        job::retry_job,
+        job::get_job_logs,
+        job::job_events,
+        metric::get_job_metrics,
+        metric::get_job_metric,
+        metric::add_job_metrics,
+        metric::get_job_metrics_csv,
+        metric::get_job_layers,
+        metric::get_recent_metrics,
+        metric::get_recent_metrics_csv,
+        metric::export_metrics,
+        metric::get_metric_aggregate,
+        metric::get_metric_histogram,
+        metric::get_metric_timeseries,
+        metric::get_metrics_by_label,
+        metric::get_metrics_by_jobs,
+        config::set_concurrency,
+        config::reload_config,
+        config::shutdown,
+        stats::get_stats,
+        stats::get_registries,
+        stats::get_queue_history,
+        super::apiv1status,
+        super::version,
+    ),
+    // query parameter types aren't collected from the paths
+    components(schemas(crate::model::ErrorResponse, crate::model::MetricUnit))
+)]
+struct V1Api;
+
+#[derive(OpenApi)]
+#[openapi(
+    info(description = "Pulls container images on request and records how each pull went."),
+    nest((path = "/api/v1", api = V1Api)),
+    paths(crate::health, crate::health_deep, prometheus::prometheus_metrics),
+    tags(
+        (name = "jobs", description = "Queue image pulls and follow them"),
+        (name = "metrics", description = "What the pulls measured"),
+        (name = "stats", description = "Queue, worker and registry summaries"),
+        (name = "config", description = "Runtime configuration"),
+        (name = "health", description = "Liveness, readiness and build info"),
+    ),
+    modifiers(&MiddlewareResponses)
+)]
+pub struct ApiDoc;
+
+/// Bearer auth and the 401/429 answers on every `/api/v1` operation.
+struct MiddlewareResponses;
+
+impl Modify for MiddlewareResponses {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        if let Some(components) = openapi.components.as_mut() {
+            components.add_security_scheme(
+                API_KEY_SCHEME,
+                SecurityScheme::Http(
+                    HttpBuilder::new()
+                        .scheme(HttpAuthScheme::Bearer)
+                        .description(Some("One of `API_KEYS`; not checked when API_KEYS is unset"))
+                        .build(),
+                ),
+            );
+        }
+
+        let error_body = || ContentBuilder::new().schema(Some(Ref::from_schema_name("ErrorResponse"))).build();
+        let unauthorized = ResponseBuilder::new()
+            .description("Missing or unknown API key")
+            .content("application/json", error_body())
+            .build();
+        let rate_limited = ResponseBuilder::new()
+            .description("Rate limit exceeded")
+            .header(
+                "Retry-After",
+                HeaderBuilder::new()
+                    .schema(Some(Object::with_type(Type::Integer)))
+                    .description(Some("Seconds until the next request is allowed"))
+                    .build(),
+            )
+            .content("application/json", error_body())
+            .build();
+
+        for (path, item) in openapi.paths.paths.iter_mut() {
+            if !path.starts_with(API_PREFIX) {
+                continue;
+            }
+            let operations: [&mut Option<Operation>; 4] =
+                [&mut item.get, &mut item.post, &mut item.patch, &mut item.delete];
+            for op in operations.into_iter().flatten() {
+                op.security = Some(vec![SecurityRequirement::new(API_KEY_SCHEME, Vec::<String>::new())]);
+                op.responses.responses.entry("401".to_string()).or_insert_with(|| unauthorized.clone().into());
+                op.responses.responses.entry("429".to_string()).or_insert_with(|| rate_limited.clone().into());
+            }
+        }
+    }
+}
+
+/// Spec and Swagger UI; registered ahead of the `/api/v1` scope, which would
+/// otherwise route (and authenticate) these paths too.
+pub fn openapi_routes(cfg: &mut web::ServiceConfig) {
+    let mut doc = ApiDoc::openapi();
+    // taken from Cargo.toml, which names none
+    doc.info.license = None;
+
+    // NormalizePath trims the trailing slash Swagger UI's relative asset URLs need
+    cfg.service(web::redirect("/api/v1/docs", "/api/v1/docs/index.html"))
+        .service(SwaggerUi::new("/api/v1/docs/{_:.*}").url("/api/v1/openapi.json", doc));
+}
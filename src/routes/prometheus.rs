@@ -0,0 +1,198 @@
+use std::fmt::Write;
+
+use actix_web::{get, web, HttpResponse};
+
+use crate::db::{self, DbPool, LatestMetricRow, MetricRow};
+use crate::error::AppError;
+use crate::model::ErrorResponse;
+use crate::routes::stats::{self, Stats};
+use crate::worker::parse_registry;
+use crate::AppState;
+
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// Prometheus text exposition of the latest value of every metric per job,
+/// followed by queue/worker gauges (same numbers as `GET /api/v1/stats`).
+#[utoipa::path(
+    tag = "stats",
+    responses(
+        (status = 200, description = "Prometheus text format", content_type = "text/plain; version=0.0.4", body = String),
+        (status = 500, description = "Database error", body = ErrorResponse),
+    )
+)]
+#[get("/metrics")]
+pub async fn prometheus_metrics(
+    pool: web::Data<DbPool>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    let rows = db::list_all_latest_metrics(pool.get_ref())
+        .await
+        .map_err(AppError::from)?;
+    let stats = stats::collect(pool.get_ref(), state.get_ref())
+        .await
+        .map_err(AppError::from)?;
+
+    let mut body = render(&rows);
+    render_stats(&stats, &mut body);
+
+    Ok(HttpResponse::Ok()
+        .content_type(CONTENT_TYPE)
+        .body(body))
+}
+
+pub fn prometheus_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(prometheus_metrics);
+}
+
+// -------------- rendering --------------
+
+/// Render rows (already ordered by key) as `imgpuller_<key>{...} <value>` lines,
+/// emitting `# HELP` / `# TYPE` once per metric name.
+fn render(rows: &[LatestMetricRow]) -> String {
+    let mut out = String::new();
+    let mut current: Option<&str> = None;
+
+    for row in rows {
+        let name = format!("imgpuller_{}", sanitize_name(&row.key));
+
+        if current != Some(row.key.as_str()) {
+            let help = match row.unit.as_deref() {
+                Some(unit) => format!("{} ({})", row.key, unit),
+                None => row.key.clone(),
+            };
+            let _ = writeln!(out, "# HELP {} {}", name, escape_help(&help));
+            let _ = writeln!(out, "# TYPE {} gauge", name);
+            current = Some(row.key.as_str());
+        }
+
+        let mut labels: Vec<(String, String)> = vec![
+            ("job_id".into(), row.job_id.clone()),
+            ("image".into(), row.image.clone()),
+            ("registry_host".into(), parse_registry(&row.image)),
+        ];
+        flatten_labels(row.labels_json.as_deref(), &mut labels);
+
+        let _ = writeln!(out, "{}{{{}}} {}", name, format_labels(&labels), format_value(row.value));
+    }
+
+    out
+}
+
+/// `GET /api/v1/metrics/recent` for `Accept: text/plain`: rows (newest first)
+/// grouped by key, labeled with `job_id` and their own labels. A series that
+/// shows up more than once keeps its newest value, so the page scrapes cleanly.
+pub fn render_recent(rows: &[MetricRow]) -> String {
+    let mut by_key: Vec<&MetricRow> = rows.iter().collect();
+    by_key.sort_by(|a, b| a.key.cmp(&b.key));
+
+    let mut out = String::new();
+    let mut current: Option<&str> = None;
+    let mut seen = std::collections::HashSet::new();
+
+    for row in by_key {
+        let name = format!("imgpuller_{}", sanitize_name(&row.key));
+
+        let mut labels: Vec<(String, String)> = vec![("job_id".into(), row.job_id.clone())];
+        flatten_labels(row.labels_json.as_deref(), &mut labels);
+        let labels = format_labels(&labels);
+        if !seen.insert((name.clone(), labels.clone())) {
+            continue;
+        }
+
+        if current != Some(row.key.as_str()) {
+            let help = match row.unit.as_deref() {
+                Some(unit) => format!("{} ({})", row.key, unit),
+                None => row.key.clone(),
+            };
+            let _ = writeln!(out, "# HELP {} {}", name, escape_help(&help));
+            let _ = writeln!(out, "# TYPE {} gauge", name);
+            current = Some(row.key.as_str());
+        }
+
+        let _ = writeln!(out, "{}{{{}}} {}", name, labels, format_value(row.value));
+    }
+
+    out
+}
+
+fn render_stats(stats: &Stats, out: &mut String) {
+    let _ = writeln!(out, "# HELP imgpuller_jobs Jobs per status");
+    let _ = writeln!(out, "# TYPE imgpuller_jobs gauge");
+    for (status, count) in &stats.jobs {
+        let _ = writeln!(out, "imgpuller_jobs{{status=\"{}\"}} {}", escape_label_value(status), count);
+    }
+
+    let _ = writeln!(out, "# HELP imgpuller_pull_concurrency Size of the global pull semaphore");
+    let _ = writeln!(out, "# TYPE imgpuller_pull_concurrency gauge");
+    let _ = writeln!(out, "imgpuller_pull_concurrency {}", stats.pull_concurrency);
+
+    let _ = writeln!(out, "# HELP imgpuller_pull_permits_available Free permits on the global pull semaphore");
+    let _ = writeln!(out, "# TYPE imgpuller_pull_permits_available gauge");
+    let _ = writeln!(out, "imgpuller_pull_permits_available {}", stats.pull_permits_available);
+
+    let _ = writeln!(out, "# HELP imgpuller_registry_in_flight Pulls in flight per registry");
+    let _ = writeln!(out, "# TYPE imgpuller_registry_in_flight gauge");
+    for (host, n) in &stats.registry_in_flight {
+        let _ = writeln!(out, "imgpuller_registry_in_flight{{registry_host=\"{}\"}} {}", escape_label_value(host), n);
+    }
+}
+
+/// Merge `labels_json` into the label set; keys already present (job_id, image, ...) win.
+fn flatten_labels(labels_json: Option<&str>, labels: &mut Vec<(String, String)>) {
+    let Some(serde_json::Value::Object(map)) =
+        labels_json.and_then(|s| serde_json::from_str::<serde_json::Value>(s).ok())
+    else {
+        return;
+    };
+
+    for (k, v) in map {
+        let name = sanitize_name(&k);
+        if labels.iter().any(|(existing, _)| *existing == name) {
+            continue;
+        }
+        let value = match v {
+            serde_json::Value::String(s) => s,
+            serde_json::Value::Null => continue,
+            other => other.to_string(),
+        };
+        labels.push((name, value));
+    }
+}
+
+fn format_labels(labels: &[(String, String)]) -> String {
+    labels
+        .iter()
+        .map(|(k, v)| format!("{}=\"{}\"", k, escape_label_value(v)))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Metric / label names must match `[a-zA-Z_][a-zA-Z0-9_]*`.
+fn sanitize_name(raw: &str) -> String {
+    let mut name: String = raw
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '_' { c } else { '_' })
+        .collect();
+    if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit()) {
+        name.insert(0, '_');
+    }
+    name
+}
+
+fn escape_label_value(v: &str) -> String {
+    v.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn escape_help(v: &str) -> String {
+    v.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+fn format_value(v: f64) -> String {
+    if v.is_nan() {
+        "NaN".to_string()
+    } else if v.is_infinite() {
+        if v > 0.0 { "+Inf".to_string() } else { "-Inf".to_string() }
+    } else {
+        v.to_string()
+    }
+}
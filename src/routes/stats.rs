@@ -0,0 +1,163 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::Ordering;
+
+use actix_web::{get, web, HttpResponse};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::db::{self, DbPool};
+use crate::error::AppError;
+use crate::model::{ApiResponse, ErrorResponse, JobStatus};
+use crate::routes::metric::parse_window;
+use crate::AppState;
+
+/// `window` of `/stats/queue-history` when none is given
+const DEFAULT_HISTORY_WINDOW_SECS: i64 = 6 * 3600;
+
+#[derive(Serialize, ToSchema)]
+pub struct Stats {
+    /// job count per status; every known status is present, even at 0
+    pub jobs: BTreeMap<String, i64>,
+    /// current size of the global pull semaphore
+    pub pull_concurrency: usize,
+    pub pull_permits_available: usize,
+    /// pulls holding a permit, per registry host
+    pub registry_in_flight: BTreeMap<String, usize>,
+}
+
+/// Snapshot used by both `GET /api/v1/stats` and the Prometheus exporter.
+pub async fn collect(pool: &DbPool, state: &AppState) -> Result<Stats, sqlx::Error> {
+    let mut jobs: BTreeMap<String, i64> =
+        JobStatus::ALL.iter().map(|s| (s.to_string(), 0)).collect();
+    for (status, count) in db::count_jobs_by_status(pool).await? {
+        // a legacy spelling counts under its status, anything else shows up as is
+        let key = status.parse::<JobStatus>().map(|s| s.to_string()).unwrap_or(status);
+        *jobs.entry(key).or_insert(0) += count;
+    }
+
+    let registry_in_flight = state
+        .registry_sems
+        .lock()
+        .await
+        .iter()
+        .map(|(host, sem)| {
+            let limit = state.config().registry_limit(host);
+            (host.clone(), limit.saturating_sub(sem.available_permits()))
+        })
+        .collect();
+
+    Ok(Stats {
+        jobs,
+        pull_concurrency: state.pull_concurrency.load(Ordering::SeqCst),
+        pull_permits_available: state.global_pull_sem.available_permits(),
+        registry_in_flight,
+    })
+}
+
+/// Queue depth and worker utilization
+#[utoipa::path(
+    tag = "stats",
+    responses(
+        (status = 200, description = "Job counts and pull slots", body = ApiResponse<Stats>),
+        (status = 500, description = "Database error", body = ErrorResponse),
+    )
+)]
+#[get("/stats")]
+pub async fn get_stats(
+    pool: web::Data<DbPool>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    let stats = collect(pool.get_ref(), state.get_ref())
+        .await
+        .map_err(AppError::from)?;
+
+    Ok(HttpResponse::Ok().json(ApiResponse::ok("ok", stats)))
+}
+
+/// Jobs, success/failure counts and average speed per registry
+#[utoipa::path(
+    tag = "stats",
+    responses(
+        (status = 200, description = "Busiest registry first", body = ApiResponse<Vec<db::RegistrySummary>>),
+        (status = 500, description = "Database error", body = ErrorResponse),
+    )
+)]
+#[get("/registries")]
+pub async fn get_registries(pool: web::Data<DbPool>) -> Result<HttpResponse, AppError> {
+    let registries = db::summarize_registries(pool.get_ref())
+        .await
+        .map_err(AppError::from)?;
+
+    Ok(HttpResponse::Ok().json(ApiResponse::ok("ok", registries)))
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct QueueHistory {
+    pub window_secs: i64,
+    /// one per minute while the worker runs, oldest first
+    pub samples: Vec<db::QueueSample>,
+}
+
+/// Queued and running job counts, sampled every minute
+#[utoipa::path(
+    tag = "stats",
+    params(
+        ("window" = Option<String>, Query, description = "The last `90s`, `30m`, `24h`, `7d`, ...; default `6h`"),
+    ),
+    responses(
+        (status = 200, description = "Samples within the window", body = ApiResponse<QueueHistory>),
+        (status = 400, description = "Bad window", body = ErrorResponse),
+        (status = 500, description = "Database error", body = ErrorResponse),
+    )
+)]
+#[get("/stats/queue-history")]
+pub async fn get_queue_history(
+    pool: web::Data<DbPool>,
+    q: web::Query<std::collections::HashMap<String, String>>,
+) -> Result<HttpResponse, AppError> {
+    let window_secs = match q.get("window") {
+        Some(w) => parse_window(w).ok_or_else(|| {
+            AppError::bad_request("window must look like 90s, 30m, 24h or 7d")
+        })?,
+        None => DEFAULT_HISTORY_WINDOW_SECS,
+    };
+    let samples = db::list_queue_samples(pool.get_ref(), window_secs)
+        .await
+        .map_err(AppError::from)?;
+
+    Ok(HttpResponse::Ok().json(ApiResponse::ok("ok", QueueHistory { window_secs, samples })))
+}
+
+pub fn stats_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_stats).service(get_registries).service(get_queue_history);
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::StatusCode;
+    use actix_web::test::{self, TestRequest};
+
+    use super::*;
+    use crate::test_support::{self, pool, queue_job};
+
+    #[actix_web::test]
+    async fn queue_history_returns_samples_within_the_window() {
+        let pool = pool().await;
+        queue_job(&pool, "job-1", "alpine", 0).await;
+        db::insert_queue_sample(&pool).await.unwrap();
+        let app = test::init_service(test_support::app(test_support::state(&[]), pool)).await;
+
+        let req = TestRequest::get().uri("/api/v1/stats/queue-history?window=30m").to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["data"]["window_secs"], 1800);
+        assert_eq!(body["data"]["samples"][0]["queued"], 1);
+        assert_eq!(body["data"]["samples"][0]["running"], 0);
+
+        let req = TestRequest::get().uri("/api/v1/stats/queue-history").to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["data"]["window_secs"], DEFAULT_HISTORY_WINDOW_SECS);
+
+        let req = TestRequest::get().uri("/api/v1/stats/queue-history?window=soon").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::BAD_REQUEST);
+    }
+}
@@ -0,0 +1,163 @@
+//! Optional OpenTelemetry tracing, on when `OTEL_EXPORTER_OTLP_ENDPOINT` is set.
+//!
+//! Spans are exported over OTLP/HTTP (protobuf); the exporter reads the other
+//! `OTEL_EXPORTER_OTLP_*` variables and `OTEL_SERVICE_NAME` itself. Logging
+//! doesn't change: `log`/`env_logger` write the same lines either way.
+//!
+//! - `HTTP request` for every API call, child of the caller's `traceparent`
+//! - `job.claim` once the worker has claimed a job, up to it getting a pull slot
+//! - `job.pull` around the pull itself, child of `job.claim`
+//!
+//! Jobs store the `traceparent` of the request that created them, so the
+//! worker spans of a job land in the caller's trace.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::HeaderMap;
+use actix_web::middleware::Next;
+use actix_web::HttpMessage;
+use log::{info, warn};
+use opentelemetry::propagation::{Extractor, TextMapPropagator};
+use opentelemetry::trace::{TraceContextExt, TracerProvider};
+use opentelemetry_otlp::SpanExporter;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use opentelemetry_sdk::Resource;
+use tracing::field::Empty;
+use tracing::{info_span, Instrument, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::Layer;
+use tracing_subscriber::util::SubscriberInitExt;
+
+use crate::config::AppConfig;
+use crate::request_id::RequestId;
+
+const TRACEPARENT: &str = "traceparent";
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Whether spans are exported. Callers skip creating spans otherwise: with no
+/// subscriber installed, `tracing` would mirror them into the log output.
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Keeps the exporter alive; [`Telemetry::shutdown`] flushes what is still buffered.
+pub struct Telemetry {
+    provider: SdkTracerProvider,
+}
+
+impl Telemetry {
+    pub fn shutdown(self) {
+        if let Err(e) = self.provider.shutdown() {
+            warn!("tracing: flushing spans on shutdown failed: {e}");
+        }
+    }
+}
+
+/// Install the OTLP pipeline if `OTEL_EXPORTER_OTLP_ENDPOINT` is set.
+pub fn init(cfg: &AppConfig) -> Option<Telemetry> {
+    let endpoint = cfg.otel_endpoint.as_deref()?;
+
+    let exporter = match SpanExporter::builder().with_http().build() {
+        Ok(e) => e,
+        Err(e) => {
+            warn!("tracing disabled: cannot set up the OTLP exporter: {e}");
+            return None;
+        }
+    };
+    let mut resource = Resource::builder();
+    if std::env::var_os("OTEL_SERVICE_NAME").is_none() {
+        resource = resource.with_service_name(env!("CARGO_PKG_NAME"));
+    }
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(resource.build())
+        .build();
+
+    // INFO keeps sqlx's per-statement debug events off the spans
+    let layer = tracing_opentelemetry::layer()
+        .with_tracer(provider.tracer(env!("CARGO_PKG_NAME")))
+        .with_filter(LevelFilter::INFO);
+    if let Err(e) = tracing_subscriber::registry().with(layer).try_init() {
+        warn!("tracing disabled: {e}");
+        return None;
+    }
+    ENABLED.store(true, Ordering::Relaxed);
+    info!("tracing: exporting spans to {}", endpoint);
+    Some(Telemetry { provider })
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|v| v.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|k| k.as_str()).collect()
+    }
+}
+
+/// `traceparent` of the current span, `None` while tracing is off.
+pub fn current_traceparent() -> Option<String> {
+    let cx = Span::current().context();
+    if !cx.span().span_context().is_valid() {
+        return None;
+    }
+    let mut carrier = HashMap::new();
+    TraceContextPropagator::new().inject_context(&cx, &mut carrier);
+    carrier.remove(TRACEPARENT)
+}
+
+/// Put `span` into the trace a job was created in.
+pub fn join_job_trace(span: &Span, traceparent: Option<&str>) {
+    let Some(traceparent) = traceparent else {
+        return;
+    };
+    let carrier = HashMap::from([(TRACEPARENT.to_string(), traceparent.to_string())]);
+    let _ = span.set_parent(TraceContextPropagator::new().extract(&carrier));
+}
+
+/// Middleware: one `HTTP request` span per request, only installed while tracing is on.
+pub async fn trace_request(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    // the app's resource map already knows the route; the span name can't change once it started
+    let route = req.match_pattern();
+    let name = match route.as_deref() {
+        Some(route) => format!("{} {}", req.method(), route),
+        None => req.method().to_string(),
+    };
+    let span = info_span!(
+        "HTTP request",
+        otel.name = %name,
+        otel.kind = "server",
+        otel.status_code = Empty,
+        http.request.method = %req.method(),
+        url.path = %req.path(),
+        http.route = route,
+        http.response.status_code = Empty,
+        request_id = Empty,
+    );
+    let parent = TraceContextPropagator::new().extract(&HeaderExtractor(req.headers()));
+    let _ = span.set_parent(parent);
+    if let Some(id) = req.extensions().get::<RequestId>() {
+        span.record("request_id", id.0.as_str());
+    }
+
+    let res = next.call(req).instrument(span.clone()).await?;
+
+    span.record("http.response.status_code", res.status().as_u16());
+    if res.status().is_server_error() {
+        span.record("otel.status_code", "ERROR");
+    }
+    Ok(res)
+}
@@ -0,0 +1,146 @@
+//! Fixtures shared by the unit tests.
+
+use std::time::Duration;
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceFactory, ServiceRequest, ServiceResponse};
+use actix_web::{middleware, web, App};
+use sqlx::sqlite::{SqliteJournalMode, SqliteSynchronous};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+
+use crate::config::AppConfig;
+use crate::config_file::ConfigSource;
+use crate::db::{self, DbPool, JobOptions, NewJob, PoolSettings};
+use crate::{error, request_id, routes, AppState};
+
+/// Read only for settings a test doesn't give itself; the skopeo binary
+/// doesn't exist, so a job that gets as far as pulling fails right away.
+const DEFAULT_VARS: &[(&str, &str)] = &[
+    ("DATABASE_URL", "sqlite::memory:"),
+    ("PULL_BACKEND", "skopeo"),
+    ("SKOPEO_PATH", "/nonexistent/skopeo"),
+];
+
+/// The configuration `vars` give, ignoring the process environment.
+pub fn config(vars: &[(&str, &str)]) -> AppConfig {
+    let mut all = vars.to_vec();
+    all.extend(DEFAULT_VARS.iter().filter(|(k, _)| !vars.iter().any(|(v, _)| v == k)));
+    let src = ConfigSource::from_vars(&all).expect("config source");
+    AppConfig::from_source(&src).unwrap_or_else(|e| panic!("{e}"))
+}
+
+/// App state for [`config`]`(vars)`
+pub fn state(vars: &[(&str, &str)]) -> AppState {
+    AppState::new(&config(vars))
+}
+
+/// A migrated `sqlite::memory:` database of its own
+pub async fn pool() -> DbPool {
+    let pool = bare_pool().await;
+    db::init_db(&pool).await.expect("migrations");
+    pool
+}
+
+/// An empty `sqlite::memory:` database, migrations not run
+pub async fn bare_pool() -> DbPool {
+    let settings = PoolSettings {
+        max_connections: 4,
+        acquire_timeout: Duration::from_secs(5),
+        sqlite_journal_mode: SqliteJournalMode::Memory,
+        sqlite_synchronous: SqliteSynchronous::Off,
+        sqlite_busy_timeout: Duration::from_secs(5),
+    };
+    db::init_pool("sqlite::memory:", &settings).await.expect("in-memory pool")
+}
+
+/// Queue job `id` for `image` with default options.
+pub async fn queue_job(pool: &DbPool, id: &str, image: &str, priority: i32) {
+    let job = NewJob {
+        id,
+        image,
+        priority,
+        options: JobOptions::default(),
+        credentials: None,
+        request_id: None,
+        traceparent: None,
+        metadata_json: None,
+    };
+    db::insert_job(pool, &job, false).await.expect("insert_job");
+}
+
+/// Run `sql` as is, for setting up rows the API can't produce (backdated, expired).
+pub async fn execute(pool: &DbPool, sql: &str) {
+    let DbPool::Sqlite(p) = pool else { unreachable!("tests run on SQLite") };
+    sqlx::query(sql).execute(p).await.expect(sql);
+}
+
+/// The app `main` serves, minus logging, CORS and tracing.
+pub fn app(
+    state: AppState,
+    pool: DbPool,
+) -> App<
+    impl ServiceFactory<
+        ServiceRequest,
+        Config = (),
+        Response = ServiceResponse<impl MessageBody>,
+        Error = actix_web::Error,
+        InitError = (),
+    >,
+> {
+    let max_json_body_bytes = state.config().max_json_body_bytes;
+    App::new()
+        .wrap(middleware::from_fn(error::add_request_path))
+        .wrap(middleware::from_fn(request_id::assign_request_id))
+        .app_data(web::Data::new(state))
+        .app_data(web::Data::new(pool))
+        .app_data(web::JsonConfig::default().limit(max_json_body_bytes).error_handler(|err, req| {
+            actix_web::error::InternalError::from_response(err, crate::bad_request_json(req.path())).into()
+        }))
+        .configure(routes::service_config)
+        .service(crate::health)
+        .default_service(web::route().to(crate::not_found))
+}
+
+/// A local HTTP endpoint answering the n-th request with `statuses[n]` (the
+/// last one repeats). Returns its URL and the JSON bodies it received.
+pub async fn http_receiver(statuses: &[u16]) -> (String, mpsc::UnboundedReceiver<serde_json::Value>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind receiver");
+    let url = format!("http://{}/hook", listener.local_addr().unwrap());
+    let statuses = statuses.to_vec();
+    let (tx, rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        for n in 0.. {
+            let Ok((mut conn, _)) = listener.accept().await else { return };
+            let body = read_request_body(&mut conn).await;
+            let _ = tx.send(serde_json::from_slice(&body).unwrap_or(serde_json::Value::Null));
+            let status = statuses[n.min(statuses.len() - 1)];
+            let head = format!("HTTP/1.1 {} Test\r\ncontent-length: 0\r\nconnection: close\r\n\r\n", status);
+            let _ = conn.write_all(head.as_bytes()).await;
+        }
+    });
+    (url, rx)
+}
+
+async fn read_request_body(conn: &mut TcpStream) -> Vec<u8> {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 4096];
+    loop {
+        if let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            let head = String::from_utf8_lossy(&buf[..end]).to_ascii_lowercase();
+            let len = head
+                .lines()
+                .find_map(|l| l.strip_prefix("content-length:"))
+                .and_then(|v| v.trim().parse::<usize>().ok())
+                .unwrap_or(0);
+            if buf.len() >= end + 4 + len {
+                return buf[end + 4..end + 4 + len].to_vec();
+            }
+        }
+        match conn.read(&mut chunk).await {
+            Ok(0) | Err(_) => return Vec::new(),
+            Ok(n) => buf.extend_from_slice(&chunk[..n]),
+        }
+    }
+}
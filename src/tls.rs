@@ -0,0 +1,37 @@
+//! In-process TLS for the HTTP server, on when `TLS_CERT_PATH` and
+//! `TLS_KEY_PATH` are set.
+//!
+//! Both files are PEM. The certificate file holds the server certificate
+//! followed by any intermediates; the key file holds one private key, PKCS#8
+//! (`BEGIN PRIVATE KEY`), PKCS#1 RSA (`BEGIN RSA PRIVATE KEY`) or SEC1 EC
+//! (`BEGIN EC PRIVATE KEY`). Files are read once at startup.
+
+use std::path::Path;
+use std::sync::Arc;
+
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::ServerConfig;
+
+/// Server config for `cert_path` / `key_path`, with HTTP/2 and HTTP/1.1 offered over ALPN.
+pub fn server_config(cert_path: &Path, key_path: &Path) -> Result<ServerConfig, String> {
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("cannot read certificates from {}: {}", cert_path.display(), e))?;
+    if certs.is_empty() {
+        return Err(format!("no certificate found in {}", cert_path.display()));
+    }
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .map_err(|e| format!("cannot read a private key from {}: {}", key_path.display(), e))?;
+
+    // rustls is built with both crypto providers here, so name one instead of relying on a process default
+    let provider = Arc::new(rustls::crypto::aws_lc_rs::default_provider());
+    let mut config = ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(|e| e.to_string())?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| format!("{} and {} don't make a usable pair: {}", cert_path.display(), key_path.display(), e))?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(config)
+}
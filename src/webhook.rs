@@ -0,0 +1,169 @@
+//! `callback_url` notifications: once the worker has a job in a terminal
+//! state it POSTs
+//!
+//! `{"job_id":"..","image":"alpine","status":"completed","metrics":[..]}`
+//!
+//! to the URL the job was created with. `metrics` is the same list as
+//! `GET /jobs/{id}/metrics`. Delivery is best effort and not persisted: 5xx
+//! answers and connection errors are retried with backoff until
+//! `WEBHOOK_TIMEOUT_SECS` is used up, any other answer ends it.
+
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+use reqwest::Url;
+use serde::Serialize;
+
+use crate::db::{self, DbPool};
+use crate::model::JobStatus;
+use crate::routes::metric::{metric_item, MetricItem};
+use crate::worker::{backoff_delay, jitter_seed};
+
+/// First retry delay, doubled per attempt
+const RETRY_BASE: Duration = Duration::from_millis(500);
+
+/// `callback_url` as given at create time, if it is an absolute http(s) URL.
+pub fn validate_url(raw: &str) -> Result<String, &'static str> {
+    let url = Url::parse(raw.trim()).map_err(|_| "callback_url is not a valid URL")?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err("callback_url must be an http or https URL");
+    }
+    if url.host_str().is_none() {
+        return Err("callback_url needs a host");
+    }
+    Ok(url.to_string())
+}
+
+#[derive(Serialize)]
+struct Payload<'a> {
+    job_id: &'a str,
+    image: &'a str,
+    status: JobStatus,
+    metrics: Vec<MetricItem>,
+}
+
+/// Notify `url` in the background; the caller doesn't wait for delivery.
+pub fn spawn_notify(
+    pool: DbPool,
+    client: reqwest::Client,
+    timeout: Duration,
+    url: String,
+    job_id: String,
+    image: String,
+    status: JobStatus,
+) {
+    tokio::spawn(async move {
+        let metrics = match db::get_metrics_by_job(&pool, &job_id).await {
+            Ok(rows) => rows.into_iter().map(metric_item).collect(),
+            Err(e) => {
+                warn!("job {}: webhook sent without metrics, cannot load them: {:#}", job_id, e);
+                Vec::new()
+            }
+        };
+        let payload = Payload { job_id: &job_id, image: &image, status, metrics };
+        deliver(&client, &url, &payload, timeout).await;
+    });
+}
+
+async fn deliver(client: &reqwest::Client, url: &str, payload: &Payload<'_>, timeout: Duration) {
+    let deadline = Instant::now() + timeout;
+    let mut attempt: u32 = 0;
+    loop {
+        attempt += 1;
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let error = match client.post(url).json(payload).timeout(remaining).send().await {
+            Ok(res) if res.status().is_server_error() => format!("HTTP {}", res.status()),
+            Ok(res) if res.status().is_success() => {
+                debug!("job {}: webhook delivered to {} ({})", payload.job_id, url, res.status());
+                return;
+            }
+            Ok(res) => {
+                warn!("job {}: webhook {} answered {}, not retrying", payload.job_id, url, res.status());
+                return;
+            }
+            Err(e) => e.to_string(),
+        };
+
+        let delay = backoff_delay(RETRY_BASE, attempt, jitter_seed());
+        if Instant::now() + delay >= deadline {
+            warn!(
+                "job {}: webhook {} failed after {} attempt(s), giving up: {}",
+                payload.job_id, url, attempt, error
+            );
+            return;
+        }
+        debug!("job {}: webhook {} attempt {} failed, retrying in {:?}: {}", payload.job_id, url, attempt, delay, error);
+        tokio::time::sleep(delay).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{self, http_receiver, pool, queue_job};
+
+    fn payload(status: JobStatus) -> Payload<'static> {
+        Payload { job_id: "job-1", image: "alpine", status, metrics: Vec::new() }
+    }
+
+    #[tokio::test]
+    async fn payload_carries_the_job_and_its_metrics() {
+        let pool = pool().await;
+        queue_job(&pool, "job-1", "alpine", 0).await;
+        db::insert_metric(&pool, "job-1", "download_time_ms", 1200.0, None).await.unwrap();
+        let (url, mut received) = http_receiver(&[200]).await;
+
+        let client = test_support::state(&[]).webhook_client.clone();
+        spawn_notify(pool, client, Duration::from_secs(5), url, "job-1".into(), "alpine".into(), JobStatus::Completed);
+        let body = tokio::time::timeout(Duration::from_secs(5), received.recv()).await.expect("webhook sent").unwrap();
+
+        let mut keys: Vec<_> = body.as_object().unwrap().keys().map(String::as_str).collect();
+        keys.sort();
+        assert_eq!(keys, ["image", "job_id", "metrics", "status"]);
+        assert_eq!(body["job_id"], "job-1");
+        assert_eq!(body["image"], "alpine");
+        assert_eq!(body["status"], "completed");
+        assert_eq!(body["metrics"][0]["key"], "download_time_ms");
+        assert_eq!(body["metrics"][0]["value"], 1200.0);
+    }
+
+    #[tokio::test]
+    async fn server_errors_are_retried() {
+        let (url, mut received) = http_receiver(&[503, 500, 204]).await;
+        deliver(&reqwest::Client::new(), &url, &payload(JobStatus::Failed), Duration::from_secs(10)).await;
+        for _ in 0..3 {
+            assert_eq!(received.try_recv().unwrap()["status"], "failed");
+        }
+        assert!(received.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn client_errors_are_not_retried() {
+        let (url, mut received) = http_receiver(&[404, 200]).await;
+        deliver(&reqwest::Client::new(), &url, &payload(JobStatus::Completed), Duration::from_secs(10)).await;
+        assert!(received.try_recv().is_ok());
+        assert!(received.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn retries_stop_at_the_timeout() {
+        let (url, mut received) = http_receiver(&[502]).await;
+        let started = Instant::now();
+        deliver(&reqwest::Client::new(), &url, &payload(JobStatus::Completed), Duration::from_secs(2)).await;
+        assert!(started.elapsed() < Duration::from_secs(3), "{:?}", started.elapsed());
+        let mut attempts = 0;
+        while received.try_recv().is_ok() {
+            attempts += 1;
+        }
+        assert!(attempts >= 2, "{attempts} attempt(s)");
+    }
+
+    #[test]
+    fn only_absolute_http_urls_are_accepted() {
+        assert_eq!(validate_url(" https://ci.example/hook ").unwrap(), "https://ci.example/hook");
+        assert!(validate_url("http://10.0.0.1:8080/x").is_ok());
+        assert!(validate_url("ftp://ci.example/hook").is_err());
+        assert!(validate_url("/hook").is_err());
+        assert!(validate_url("file:///etc/passwd").is_err());
+    }
+}
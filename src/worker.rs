@@ -1,108 +1,216 @@
-use std::collections::HashMap;
-use std::sync::Arc;
+use std::sync::atomic::Ordering;
 
 use log::{error, info, warn};
-use sqlx::SqlitePool;
-use tokio::sync::{Mutex, Semaphore};
-use tokio::time::{sleep, Duration};
+use tokio::time::{sleep, timeout, Duration};
+use tracing::field::Empty;
+use tracing::{info_span, Instrument, Span};
 
-use crate::db;
+use crate::backend::{PullBackend, PullCanceled};
+use crate::db::{self, DbPool};
+use crate::events::{self, JobLifecycle, JobUpdate, Transition};
+use crate::model::JobStatus;
 use crate::routes::job;
+use crate::telemetry;
+use crate::webhook;
+use crate::AppState;
 
 /// Parse registry host from an image reference.
 /// If no explicit registry is provided, default to "docker.io".
-fn parse_registry(image: &str) -> String {
+pub fn parse_registry(image: &str) -> String {
     // Docker heuristic:
     // If the first path component contains '.' or ':' or equals "localhost", treat it as a registry.
     // Otherwise default to docker.io
     let first = image.split('/').next().unwrap_or("");
-    if first.contains('.') || first.contains(':') || first == "localhost" {
+    if image.contains('/') && is_registry_host(first) {
         first.to_string()
     } else {
         "docker.io".to_string()
     }
 }
 
-/// Get or create a semaphore for a specific registry.
-async fn get_or_create_reg_sem(
-    map: &Arc<Mutex<HashMap<String, Arc<Semaphore>>>>,
-    registry: &str,
-    per_registry_max: usize,
-) -> Arc<Semaphore> {
-    let mut guard = map.lock().await;
-    guard
-        .entry(registry.to_string())
-        .or_insert_with(|| Arc::new(Semaphore::new(per_registry_max)))
-        .clone()
+/// Does the first path component of an image ref name a registry?
+pub fn is_registry_host(first: &str) -> bool {
+    first.contains('.') || first.contains(':') || first == "localhost"
 }
 
 /// Run the job runner loop.
 ///
+/// - `state`: shared app state; the global and per-registry semaphores live there,
+///   so the global limit can be resized via `PATCH /api/v1/config/concurrency`
 /// - `pool`: database pool
-/// - `concurrency`: global max concurrent pulls
-/// - `per_registry_max`: max concurrent pulls per registry (e.g., docker.io, gcr.io)
 /// - `lease_secs`: lease duration used by DB when claiming a job
-pub async fn run_job_runner(
-    pool: SqlitePool,
-    concurrency: usize,
-    per_registry_max: usize,
-    lease_secs: i64,
-) {
-    let global_sem = Arc::new(Semaphore::new(concurrency));
-    let reg_map: Arc<Mutex<HashMap<String, Arc<Semaphore>>>> =
-        Arc::new(Mutex::new(HashMap::new()));
+pub async fn run_job_runner(state: AppState, pool: DbPool, lease_secs: i64) {
+    // Stale lease recovery
+    let recover_pool = pool.clone();
+    let recover_state = state.clone();
+    tokio::spawn(async move {
+        let mut tick = tokio::time::interval(Duration::from_secs(30));
+        while !recover_state.is_draining() {
+            tick.tick().await;
+            match db::recover_stale_jobs(&recover_pool).await {
+                Ok(0) => {}
+                Ok(n) => warn!("recovered {} job(s) with an expired lease", n),
+                Err(e) => warn!("recover_stale_jobs error: {:#}", e),
+            }
+        }
+    });
+
+    // Retention; `RETENTION_DAYS` is read per run so a config reload applies
+    let purge_pool = pool.clone();
+    let purge_state = state.clone();
+    tokio::spawn(async move {
+        let mut tick = tokio::time::interval(PURGE_INTERVAL);
+        while !purge_state.is_draining() {
+            tick.tick().await;
+            let retention_days = purge_state.config().retention_days;
+            if retention_days == 0 {
+                continue;
+            }
+            let cutoff = chrono::Utc::now() - chrono::Duration::days(retention_days.into());
+            match db::purge_old_jobs(&purge_pool, cutoff).await {
+                Ok(0) => {}
+                Ok(n) => info!("purged {} job(s) finished more than {} day(s) ago", n, retention_days),
+                Err(e) => warn!("purge_old_jobs error: {:#}", e),
+            }
+            if let Err(e) = db::purge_old_queue_samples(&purge_pool, cutoff).await {
+                warn!("purge_old_queue_samples error: {:#}", e);
+            }
+        }
+    });
 
-    // Delays
-    let idle_delay = Duration::from_millis(500);
-    let error_delay = Duration::from_millis(1000);
+    // Queue depth history for `GET /api/v1/stats/queue-history`
+    let sample_pool = pool.clone();
+    let sample_state = state.clone();
+    tokio::spawn(async move {
+        let mut tick = tokio::time::interval(QUEUE_SAMPLE_INTERVAL);
+        while !sample_state.is_draining() {
+            tick.tick().await;
+            if let Err(e) = db::insert_queue_sample(&sample_pool).await {
+                warn!("insert_queue_sample error: {:#}", e);
+            }
+        }
+    });
 
-    info!(
-        "job-runner started: concurrency={}, per_registry_max={}, lease_secs={}",
-        concurrency, per_registry_max, lease_secs
-    );
+    let mut consecutive_errors: u32 = 0;
+
+    {
+        let cfg = state.config();
+        info!(
+            "job-runner started: concurrency={}, per_registry_max={}, per_registry_limits={:?}, lease_secs={}",
+            state.pull_concurrency.load(Ordering::SeqCst),
+            cfg.per_registry_max,
+            cfg.per_registry_limits,
+            lease_secs
+        );
+    }
 
     loop {
-        // claim_next_job ต้องรับ (pool, lease_secs)
-        let claim = db::claim_next_job(&pool, lease_secs).await;
+        if state.is_draining() {
+            info!("job-runner draining: no longer claiming jobs");
+            break;
+        }
 
-        match claim {
-            Ok(Some((job_id, image))) => {
-                // Global concurrency gate
-                let Ok(global_permit) = global_sem.clone().acquire_owned().await else {
-                    warn!("global semaphore closed; stopping runner loop");
-                    break;
-                };
+        // Global concurrency gate, before the claim: a claimed job waiting for a
+        // slot would hold a lease nothing renews, and `recover_stale_jobs` would
+        // requeue it (and spend an attempt) while this runner still pulls it
+        let Ok(global_permit) = state.global_pull_sem.clone().acquire_owned().await else {
+            warn!("global semaphore closed; stopping runner loop");
+            break;
+        };
+        if state.is_draining() {
+            continue;
+        }
 
-                // mark job as running
-                if let Err(e) = db::update_job_status(&pool, &job_id, "running", None).await {
-                    warn!("job {}: cannot mark running: {:#}", job_id, e);
-                }
+        // a job of a full registry would sit on a global slot until its registry frees up
+        let saturated = state.saturated_registries().await;
+        let claim = db::claim_next_job(&pool, lease_secs, &saturated).await;
+        if claim.is_ok() {
+            consecutive_errors = 0;
+        }
 
-                let pool_cloned = pool.clone();
-                let reg_map_cloned = reg_map.clone();
+        match claim {
+            Ok(Some(claimed)) => {
+                let db::ClaimedJob { id: job_id, image, retry_count, request_id, traceparent, options } = claimed;
 
                 // Determine registry from image ref
                 let registry = parse_registry(&image);
-                let per_reg = per_registry_max;
+                let lifecycle = JobLifecycle::new(&job_id, &image, &registry, retry_count, request_id.as_deref());
+                lifecycle.emit(Transition::Claimed);
+
+                // registered before any wait for a registry slot, so a cancel meanwhile is seen
+                let cancel = state.register_cancel_flag(&job_id).await;
+
+                let claim_span = match telemetry::is_enabled() {
+                    true => info_span!(
+                        "job.claim",
+                        job_id = %job_id,
+                        image = %image,
+                        registry = %registry,
+                        attempt = retry_count + 1,
+                    ),
+                    false => Span::none(),
+                };
+                telemetry::join_job_trace(&claim_span, traceparent.as_deref());
+
+                let pull_span = match telemetry::is_enabled() {
+                    true => info_span!(
+                        parent: &claim_span,
+                        "job.pull",
+                        job_id = %job_id,
+                        image = %image,
+                        registry = %registry,
+                        backend = state.backend.name(),
+                        otel.status_code = Empty,
+                        otel.status_message = Empty,
+                    ),
+                    false => Span::none(),
+                };
+                drop(claim_span);
+
+                // taken here so the next claim already sees this registry's slot as used;
+                // only waited for in the task if another pull got there first
+                let reg_sem = state.registry_sem(&registry).await;
+                let reg_permit = reg_sem.clone().try_acquire_owned().ok();
+
+                let pool_cloned = pool.clone();
+                let state_cloned = state.clone();
+                let updates = state.updates.clone();
 
                 tokio::spawn(async move {
                     // Per-registry concurrency gate
-                    let reg_sem = get_or_create_reg_sem(&reg_map_cloned, &registry, per_reg).await;
-                    let Ok(_reg_permit) = reg_sem.acquire_owned().await else {
+                    let reg_permit = match reg_permit {
+                        Some(permit) => Ok(permit),
+                        None => match timeout(REGISTRY_SLOT_WAIT, reg_sem.acquire_owned()).await {
+                            Ok(acquired) => acquired,
+                            Err(_) => {
+                                release_claim(&state_cloned, &pool_cloned, &job_id, &image, &registry, &options, &lifecycle)
+                                    .await;
+                                return;
+                            }
+                        },
+                    };
+                    let Ok(reg_permit) = reg_permit else {
                         warn!("registry semaphore closed for {}; job {}", registry, job_id);
                         // บันทึก error_detail แล้วปิดงาน
                         let _ = db::set_job_error(&pool_cloned, &job_id, "registry semaphore closed", true).await;
+                        state_cloned.clear_cancel_flag(&job_id).await;
+                        lifecycle.emit(Transition::Failed);
+                        events::publish(&updates, JobUpdate::status(&job_id, JobStatus::Failed));
+                        notify_callback(&state_cloned, &pool_cloned, &job_id, &image, &options, JobStatus::Failed);
                         drop(global_permit);
                         return;
                     };
 
                     info!(
-                        "job {}: starting pull for image '{}' (registry: {})",
-                        job_id, image, registry
+                        "job {}: starting pull for image '{}' (registry: {}, request_id: {})",
+                        job_id, image, registry, request_id.as_deref().unwrap_or("-")
                     );
+                    lifecycle.emit(Transition::Running);
+                    events::publish(&updates, JobUpdate::status(&job_id, JobStatus::Running));
 
                     let hb_pool = pool_cloned.clone();
                     let hb_job = job_id.clone();
+                    let hb_lifecycle = lifecycle.clone();
                     let hb_interval = Duration::from_secs((lease_secs / 2).max(1) as u64);
                     let (hb_tx, mut hb_rx) = tokio::sync::mpsc::unbounded_channel::<()>();
 
@@ -110,8 +218,9 @@ pub async fn run_job_runner(
                         loop {
                             tokio::select! {
                                 _ = sleep(hb_interval) => {
-                                    if let Err(e) = db::heartbeat_job(&hb_pool, &hb_job, lease_secs).await {
-                                        warn!("job {}: heartbeat failed: {:#}", hb_job, e);
+                                    match db::heartbeat_job(&hb_pool, &hb_job, lease_secs).await {
+                                        Ok(()) => hb_lifecycle.emit(Transition::Heartbeat),
+                                        Err(e) => warn!("job {}: heartbeat failed: {:#}", hb_job, e),
                                     }
                                 }
                                 _ = hb_rx.recv() => {
@@ -121,36 +230,365 @@ pub async fn run_job_runner(
                         }
                     });
 
-                    let pull_res = job::pull_image_and_record_metrics(&pool_cloned, &job_id, &image).await;
+                    let pull_res = job::pull_image_and_record_metrics(
+                        &pool_cloned,
+                        &*state_cloned.backend,
+                        &job_id,
+                        &image,
+                        &options,
+                        &state_cloned,
+                        &cancel,
+                    )
+                    .instrument(pull_span.clone())
+                    .await;
+                    if let Err(e) = &pull_res
+                        && !e.is::<PullCanceled>()
+                    {
+                        pull_span.record("otel.status_code", "ERROR");
+                        pull_span.record("otel.status_message", format!("{:#}", e));
+                    }
 
                     let _ = hb_tx.send(());
                     let _ = hb_handle.await;
+                    state_cloned.clear_cancel_flag(&job_id).await;
 
-                    match pull_res {
+                    let status = match pull_res {
+                        // the cancel endpoint already wrote the status
+                        Err(e) if e.is::<PullCanceled>() => {
+                            info!("job {}: pull aborted, job was canceled", job_id);
+                            lifecycle.emit(Transition::Canceled);
+                            JobStatus::Canceled
+                        }
                         Ok(()) => {
                             info!("job {}: completed successfully", job_id);
+                            lifecycle.emit(Transition::Completed);
+                            JobStatus::Completed
                         }
                         Err(e) => {
-                            error!("job {}: failed: {:#}", job_id, e);
-                            let _ = db::set_job_error(&pool_cloned, &job_id, &format!("{:#}", e), true).await;
+                            let detail = format!("{:#}", e);
+                            match db::fail_or_retry_job(&pool_cloned, &job_id, &detail).await {
+                                Ok(Some(JobStatus::Queued)) => {
+                                    warn!("job {}: attempt failed, requeued for retry: {}", job_id, detail);
+                                    lifecycle.emit(Transition::Retried);
+                                    JobStatus::Queued
+                                }
+                                Ok(None) => {
+                                    info!("job {}: canceled while failing: {}", job_id, detail);
+                                    lifecycle.emit(Transition::Canceled);
+                                    JobStatus::Canceled
+                                }
+                                Ok(_) => {
+                                    error!("job {}: failed: {}", job_id, detail);
+                                    lifecycle.emit(Transition::Failed);
+                                    JobStatus::Failed
+                                }
+                                Err(db_err) => {
+                                    error!("job {}: failed: {} (and could not record it: {:#})", job_id, detail, db_err);
+                                    lifecycle.emit(Transition::Failed);
+                                    JobStatus::Failed
+                                }
+                            }
                         }
+                    };
+                    events::publish(&updates, JobUpdate::status(&job_id, status));
+                    if status.is_terminal() {
+                        notify_callback(&state_cloned, &pool_cloned, &job_id, &image, &options, status);
                     }
 
+                    drop(reg_permit);
                     drop(global_permit);
+                    // jobs of this registry may have been passed over while it was full
+                    state_cloned.notify_new_job();
                 });
             }
 
             Ok(None) => {
-                // No job found; wait a bit
-                sleep(idle_delay).await;
+                drop(global_permit);
+                // No job found; wait for a new one, or a bit anyway for
+                // retry delays running out and recovered jobs
+                let idle_delay = Duration::from_millis(state.config().worker_idle_ms);
+                tokio::select! {
+                    _ = sleep(idle_delay) => {}
+                    _ = state.job_notify.notified() => {}
+                }
             }
 
             Err(e) => {
-                warn!("claim_next_job error: {:#}", e);
-                sleep(error_delay).await;
+                drop(global_permit);
+                consecutive_errors = consecutive_errors.saturating_add(1);
+                let error_delay = Duration::from_millis(state.config().worker_error_ms);
+                let delay = backoff_delay(error_delay, consecutive_errors, jitter_seed());
+                warn!(
+                    "claim_next_job error (#{} in a row, retrying in {:?}): {:#}",
+                    consecutive_errors, delay, e
+                );
+                sleep(delay).await;
             }
         }
     }
 
+    // every permit back in the semaphore == no pull in flight
+    let target = state.pull_concurrency.load(Ordering::SeqCst);
+    match state.global_pull_sem.acquire_many(target as u32).await {
+        Ok(_all) => info!("job-runner: all in-flight pulls finished"),
+        Err(_) => warn!("global semaphore closed while draining"),
+    }
+
     info!("job-runner stopped");
 }
+
+/// POST the job's terminal `status` to its `callback_url`, if it has one.
+fn notify_callback(
+    state: &AppState,
+    pool: &DbPool,
+    job_id: &str,
+    image: &str,
+    options: &db::JobOptions,
+    status: JobStatus,
+) {
+    if let Some(url) = options.callback_url.clone() {
+        webhook::spawn_notify(
+            pool.clone(),
+            state.webhook_client.clone(),
+            Duration::from_secs(state.config().webhook_timeout_secs),
+            url,
+            job_id.to_string(),
+            image.to_string(),
+            status,
+        );
+    }
+}
+
+/// Give a claimed job back to the queue when its registry had no free slot
+/// after all (the claim raced another pull for it), so it doesn't sit in
+/// `running` with a lease while waiting. The registry is saturated by now, so
+/// the next claim passes the job over until a slot frees up.
+async fn release_claim(
+    state: &AppState,
+    pool: &DbPool,
+    job_id: &str,
+    image: &str,
+    registry: &str,
+    options: &db::JobOptions,
+    lifecycle: &JobLifecycle,
+) {
+    state.clear_cancel_flag(job_id).await;
+    match db::release_claim(pool, job_id, registry).await {
+        Ok(true) => {
+            info!("job {}: no {} slot free after {:?}, back in the queue", job_id, registry, REGISTRY_SLOT_WAIT);
+            lifecycle.emit(Transition::Released);
+            events::publish(&state.updates, JobUpdate::status(job_id, JobStatus::Queued));
+        }
+        Ok(false) => {
+            info!("job {}: canceled while waiting for a {} slot", job_id, registry);
+            lifecycle.emit(Transition::Canceled);
+            events::publish(&state.updates, JobUpdate::status(job_id, JobStatus::Canceled));
+            notify_callback(state, pool, job_id, image, options, JobStatus::Canceled);
+        }
+        // the lease runs out and `recover_stale_jobs` requeues it
+        Err(e) => warn!("job {}: cannot release the claim: {:#}", job_id, e),
+    }
+}
+
+/// How long a claimed job waits for its registry slot before it is released
+const REGISTRY_SLOT_WAIT: Duration = Duration::from_secs(5);
+
+/// How often the queue depth is sampled into `queue_samples`
+const QUEUE_SAMPLE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often finished jobs past `RETENTION_DAYS` are deleted
+const PURGE_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Longest wait between claim attempts while the DB keeps failing
+const MAX_ERROR_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Exponential backoff with "equal jitter": the delay for the n-th consecutive
+/// error is `base * 2^(n-1)` capped at `MAX_ERROR_BACKOFF`, of which the upper
+/// half is randomized by `seed` so several runners don't retry in lockstep.
+pub fn backoff_delay(base: Duration, consecutive_errors: u32, seed: u64) -> Duration {
+    let exp = consecutive_errors.saturating_sub(1).min(16);
+    let full = base.saturating_mul(1 << exp).min(MAX_ERROR_BACKOFF.max(base));
+    let half = full / 2;
+    let jitter_ms = match half.as_millis() as u64 {
+        0 => 0,
+        h => seed % (h + 1),
+    };
+    half + Duration::from_millis(jitter_ms)
+}
+
+/// Cheap per-call randomness for jitter; no need for a real RNG here.
+pub fn jitter_seed() -> u64 {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.subsec_nanos() as u64)
+        .unwrap_or(0);
+    // splitmix64 finalizer to spread the low-entropy nanos
+    let mut z = nanos.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+    use crate::test_support::{self, pool, queue_job};
+
+    /// Poll every few ms until `done`, for at most `limit`; false on timeout.
+    async fn eventually<F: Future<Output = bool>>(limit: Duration, mut done: impl FnMut() -> F) -> bool {
+        let deadline = Instant::now() + limit;
+        while Instant::now() < deadline {
+            if done().await {
+                return true;
+            }
+            sleep(Duration::from_millis(5)).await;
+        }
+        false
+    }
+
+    async fn stop_runner(state: &AppState, runner: tokio::task::JoinHandle<()>) {
+        state.request_shutdown();
+        state.notify_new_job();
+        timeout(Duration::from_secs(5), runner).await.expect("runner drains").unwrap();
+    }
+
+    #[tokio::test]
+    async fn new_job_wakes_the_idle_runner() {
+        let pool = pool().await;
+        // long enough that only the notify can explain a quick start
+        let state = test_support::state(&[("WORKER_IDLE_MS", "60000")]);
+        let runner = tokio::spawn(run_job_runner(state.clone(), pool.clone(), 60));
+        sleep(Duration::from_millis(50)).await;
+
+        queue_job(&pool, "job-1", "alpine", 0).await;
+        let queued_at = Instant::now();
+        state.notify_new_job();
+        let started = eventually(Duration::from_secs(2), || async {
+            db::get_job_by_id(&pool, "job-1").await.unwrap().unwrap().started_at.is_some()
+        })
+        .await;
+        assert!(started, "job not claimed");
+        let latency = queued_at.elapsed();
+        assert!(latency < Duration::from_millis(500), "enqueue-to-start took {latency:?}");
+
+        stop_runner(&state, runner).await;
+    }
+
+    #[actix_web::test]
+    async fn job_canceled_while_waiting_for_a_slot_is_not_pulled() {
+        let pool = pool().await;
+        let state = test_support::state(&[("MAX_CONCURRENT_PULLS", "1")]);
+        let mut updates = state.updates.subscribe();
+        // the one global slot is taken, so the job stays queued
+        let held = state.global_pull_sem.clone().acquire_owned().await.unwrap();
+        queue_job(&pool, "job-1", "alpine", 0).await;
+        let runner = tokio::spawn(run_job_runner(state.clone(), pool.clone(), 60));
+        sleep(Duration::from_millis(50)).await;
+
+        let app = actix_web::test::init_service(test_support::app(state.clone(), pool.clone())).await;
+        let req = actix_web::test::TestRequest::post().uri("/api/v1/jobs/job-1/cancel").to_request();
+        let body: serde_json::Value = actix_web::test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["message"], "job canceled");
+        let update = timeout(Duration::from_secs(2), updates.recv()).await.expect("status update").unwrap();
+        assert!(matches!(update, JobUpdate::Status { status: JobStatus::Canceled, .. }), "{update:?}");
+        drop(held);
+        sleep(Duration::from_millis(50)).await;
+
+        stop_runner(&state, runner).await;
+        // no Running update: the pull never started
+        assert!(updates.try_recv().is_err());
+        assert!(state.cancel_flags.lock().await.is_empty());
+        assert_eq!(state.global_pull_sem.available_permits(), 1);
+        let job = db::get_job_by_id(&pool, "job-1").await.unwrap().unwrap();
+        assert_eq!((job.status, job.retry_count, job.started_at), (JobStatus::Canceled, 0, None));
+    }
+
+    #[tokio::test]
+    async fn job_is_not_claimed_while_every_slot_is_busy() {
+        let pool = pool().await;
+        let state = test_support::state(&[("MAX_CONCURRENT_PULLS", "1")]);
+        // a pull that outlasts the 1s lease holds the only slot
+        let held = state.global_pull_sem.clone().acquire_owned().await.unwrap();
+        queue_job(&pool, "job-1", "alpine", 0).await;
+        let runner = tokio::spawn(run_job_runner(state.clone(), pool.clone(), 1));
+
+        sleep(Duration::from_millis(2500)).await;
+        assert_eq!(db::recover_stale_jobs(&pool).await.unwrap(), 0);
+        let job = db::get_job_by_id(&pool, "job-1").await.unwrap().unwrap();
+        assert_eq!((job.status, job.retry_count, job.started_at), (JobStatus::Queued, 0, None));
+
+        drop(held);
+        let claimed = eventually(Duration::from_secs(2), || async {
+            db::get_job_by_id(&pool, "job-1").await.unwrap().unwrap().started_at.is_some()
+        })
+        .await;
+        assert!(claimed, "job not claimed once the slot freed up");
+        stop_runner(&state, runner).await;
+    }
+
+    #[tokio::test]
+    async fn claimed_job_is_marked_running_and_started_once() {
+        let pool = pool().await;
+        queue_job(&pool, "job-1", "alpine", 0).await;
+        test_support::execute(&pool, "UPDATE jobs SET max_attempts = 1").await;
+        // every write of started_at, and every write that says `running`
+        test_support::execute(&pool, "CREATE TABLE writes (kind TEXT NOT NULL)").await;
+        test_support::execute(
+            &pool,
+            "CREATE TRIGGER count_started_at AFTER UPDATE OF started_at ON jobs
+             BEGIN INSERT INTO writes VALUES ('started_at'); END",
+        )
+        .await;
+        test_support::execute(
+            &pool,
+            "CREATE TRIGGER count_running AFTER UPDATE OF status ON jobs WHEN NEW.status = 'running'
+             BEGIN INSERT INTO writes VALUES ('running'); END",
+        )
+        .await;
+
+        // the pull fails right away (no skopeo), which ends the only attempt
+        let state = test_support::state(&[]);
+        let runner = tokio::spawn(run_job_runner(state.clone(), pool.clone(), 60));
+        let failed = eventually(Duration::from_secs(5), || async {
+            db::get_job_by_id(&pool, "job-1").await.unwrap().unwrap().status == JobStatus::Failed
+        })
+        .await;
+        assert!(failed, "job not run");
+        stop_runner(&state, runner).await;
+
+        let DbPool::Sqlite(p) = &pool else { unreachable!() };
+        let writes: Vec<String> = sqlx::query_scalar("SELECT kind FROM writes ORDER BY kind").fetch_all(p).await.unwrap();
+        assert_eq!(writes, ["running", "started_at"]);
+        assert!(db::get_job_by_id(&pool, "job-1").await.unwrap().unwrap().started_at.is_some());
+    }
+
+    #[test]
+    fn backoff_grows_up_to_the_cap() {
+        let base = Duration::from_millis(500);
+        let mut previous = Duration::ZERO;
+        for n in 1..=20 {
+            // the lowest and highest delay a seed can give for this step
+            let low = backoff_delay(base, n, 0);
+            let high = (0..64).map(|seed| backoff_delay(base, n, seed * 7919)).max().unwrap();
+            assert!(low >= previous, "error #{n}: {low:?} < {previous:?}");
+            assert!(high <= MAX_ERROR_BACKOFF, "error #{n}: {high:?}");
+            previous = low;
+        }
+        assert_eq!(backoff_delay(base, 1, 0), base / 2);
+        assert_eq!(backoff_delay(base, 20, 0), MAX_ERROR_BACKOFF / 2);
+        assert_eq!(backoff_delay(base, u32::MAX, u64::MAX), backoff_delay(base, 20, u64::MAX));
+    }
+
+    #[tokio::test]
+    async fn registry_semaphores_follow_per_registry_limits() {
+        let state = test_support::state(&[("PER_REGISTRY_MAX", "3"), ("PER_REGISTRY_LIMITS", "docker.io=1,registry.internal=10")]);
+        assert_eq!(state.registry_sem("docker.io").await.available_permits(), 1);
+        assert_eq!(state.registry_sem("registry.internal").await.available_permits(), 10);
+        assert_eq!(state.registry_sem("ghcr.io").await.available_permits(), 3);
+
+        let _slot = state.registry_sem("docker.io").await.acquire_owned().await.unwrap();
+        assert_eq!(state.saturated_registries().await, ["docker.io"]);
+    }
+}